@@ -0,0 +1,102 @@
+use crate::{SourceSpan, TemplateAnalysis};
+
+const RESET: &str = "\x1b[0m";
+const EXTERNAL: &str = "\x1b[1;34m";
+const LOOP: &str = "\x1b[33m";
+const INTERNAL: &str = "\x1b[2m";
+const NOTE: &str = "\x1b[2m";
+
+// Re-emits the template source with every recorded variable occurrence
+// followed by `⟨kind path: type⟩`, where kind is `external`, `loop` (read
+// through a loop variable) or `internal` (read through a local alias); with
+// `color` the occurrence is highlighted instead of spelling out the kind.
+// Occurrences come from the provenance of the analysis
+pub(crate) fn annotate_source(analysis: &TemplateAnalysis, source: &str, color: bool) -> String {
+    let Some(provenance) = &analysis.provenance else {
+        return source.to_string();
+    };
+
+    let mut occurrences = provenance
+        .iter()
+        .flat_map(|(path, origins)| {
+            origins
+                .iter()
+                .map(move |origin| (origin.span, origin.expression.as_str(), path.as_str()))
+        })
+        .collect::<Vec<_>>();
+    // Outermost expression first, so `message` inside `message.role` is skipped
+    occurrences.sort_by_key(|(span, _, _)| (span.start_offset, std::cmp::Reverse(span.end_offset)));
+
+    let mut out = String::new();
+    let mut cursor = 0;
+    for (span, expression, path) in occurrences {
+        let SourceSpan {
+            start_offset,
+            end_offset,
+            ..
+        } = span;
+        let (start, end) = (start_offset as usize, end_offset as usize);
+        if start < cursor || source.get(start..end).is_none() {
+            continue;
+        }
+        out.push_str(&source[cursor..start]);
+
+        let kind = kind(analysis, expression);
+        let type_name = analysis
+            .type_of(path)
+            .map_or_else(|| "unknown".to_string(), |shape| shape.type_name());
+        let note = format!("{path}: {type_name}");
+        if color {
+            let style = match kind {
+                "external" => EXTERNAL,
+                "loop" => LOOP,
+                _ => INTERNAL,
+            };
+            out.push_str(&format!(
+                "{style}{}{RESET}{NOTE}⟨{note}⟩{RESET}",
+                &source[start..end]
+            ));
+        } else {
+            out.push_str(&format!("{}⟨{kind} {note}⟩", &source[start..end]));
+        }
+        cursor = end;
+    }
+    out.push_str(&source[cursor..]);
+    out
+}
+
+// Whether an expression reads the context directly, through a loop variable
+// or through a template-local alias
+fn kind(analysis: &TemplateAnalysis, expression: &str) -> &'static str {
+    let base = expression
+        .split(['.', '[', '{', '(', ' ', '|'])
+        .next()
+        .unwrap_or(expression);
+    if analysis.loop_vars.contains_key(base) {
+        "loop"
+    } else if analysis.internal_vars.contains(base) {
+        "internal"
+    } else {
+        "external"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{analyze_with, AnalyzeOptions};
+
+    #[test]
+    fn test_annotated_source() {
+        let template = "{% set history = messages %}{% for message in history %}{{ message.role | upper }}{% endfor %}{{ bos_token ~ '' }}";
+        let options = AnalyzeOptions {
+            provenance: true,
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+
+        assert_eq!(
+            analysis.annotate_source(template, false),
+            "{% set history = messages⟨external messages: array⟩ %}{% for message in history⟨internal messages: array⟩ %}{{ message.role⟨loop messages[].role: unknown⟩ | upper }}{% endfor %}{{ bos_token⟨external bos_token: string⟩ ~ '' }}"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use crate::shape::Shape;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Description and type override attached to a schema path by a template comment
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Annotation {
+    pub description: Option<String>,
+    /// Type given in braces (`{string|null}`), replacing the inferred type
+    pub shape: Option<Shape>,
+}
+
+// Collects `@param` annotations from the comments of a template:
+//
+//   {# @param messages: array of chat messages #}
+//   {# @param {string|null} messages[].content: text of the message #}
+pub(crate) fn parse_annotations(source: &str) -> BTreeMap<String, Annotation> {
+    let mut annotations = BTreeMap::new();
+    let mut rest = source;
+
+    while let Some(start) = rest.find("{#") {
+        let body = &rest[start + 2..];
+        let Some(end) = body.find("#}") else {
+            break;
+        };
+        for line in body[..end].lines() {
+            if let Some((path, annotation)) = parse_param(line) {
+                annotations.insert(path, annotation);
+            }
+        }
+        rest = &body[end + 2..];
+    }
+
+    annotations
+}
+
+fn parse_param(line: &str) -> Option<(String, Annotation)> {
+    let line = line.trim().trim_start_matches('-').trim_start();
+    let line = line.strip_prefix("@param")?.trim_start();
+
+    // Optional `{type}` before the path
+    let (shape, line) = match line.strip_prefix('{') {
+        Some(typed) => {
+            let (ty, rest) = typed.split_once('}')?;
+            (Some(parse_type(ty)?), rest.trim_start())
+        }
+        None => (None, line),
+    };
+
+    let (path, description) = match line.split_once(':') {
+        Some((path, description)) => (path.trim(), description.trim()),
+        None => (line.trim(), ""),
+    };
+    if path.is_empty() || path.contains(char::is_whitespace) {
+        return None;
+    }
+
+    let annotation = Annotation {
+        description: (!description.is_empty()).then(|| description.to_string()),
+        shape,
+    };
+    Some((path.to_string(), annotation))
+}
+
+// Parses a type such as `string`, `integer|null` or `array`
+pub(crate) fn parse_type(ty: &str) -> Option<Shape> {
+    let mut shape = Shape::Unknown;
+    for name in ty.split('|') {
+        let variant = match name.trim() {
+            "string" => Shape::String,
+            "number" => Shape::Number,
+            "integer" => Shape::Integer,
+            "boolean" | "bool" => Shape::Bool,
+            "null" => Shape::Null,
+            "array" => Shape::Array(Box::new(Shape::Unknown)),
+            "object" => Shape::Object(BTreeMap::new()),
+            "map" => Shape::Map(Box::new(Shape::Unknown)),
+            "any" => Shape::Any,
+            _ => return None,
+        };
+        shape = shape.merge(variant);
+    }
+    Some(shape)
+}
+
+// Applies an annotated type to the inferred shape; bare `array`/`object`/`map`
+// annotations keep the structure inferred for them
+pub(crate) fn refine(inferred: Shape, annotated: &Shape) -> Shape {
+    let inferred = match inferred {
+        Shape::Union(variants) => variants,
+        shape => vec![shape],
+    };
+    let annotated = match annotated {
+        Shape::Union(variants) => variants.clone(),
+        shape => vec![shape.clone()],
+    };
+
+    let mut shape = Shape::Unknown;
+    for variant in annotated {
+        let structured = match &variant {
+            Shape::Array(_) | Shape::Object(_) | Shape::Map(_) => inferred
+                .iter()
+                .find(|shape| std::mem::discriminant(*shape) == std::mem::discriminant(&variant))
+                .cloned(),
+            _ => None,
+        };
+        shape = shape.merge(structured.unwrap_or(variant));
+    }
+    shape
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+    use serde_json::json;
+
+    #[test]
+    fn test_comment_annotations() {
+        let template = "{#\n  @param messages: array of chat messages\n  @param {integer|null} messages[].weight: sampling weight\n#}{% for message in messages %}{{ message.role ~ message.weight }}{% endfor %}{# @param {string} undocumented #}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(
+            analysis.annotations["messages"].description.as_deref(),
+            Some("array of chat messages")
+        );
+
+        let schema = analysis.to_json_schema();
+        let messages = &schema["properties"]["messages"];
+        assert_eq!(messages["description"], json!("array of chat messages"));
+        assert_eq!(
+            messages["items"]["properties"]["role"],
+            json!({"type": "string"})
+        );
+        assert_eq!(
+            messages["items"]["properties"]["weight"],
+            json!({"type": ["integer", "null"], "description": "sampling weight"})
+        );
+    }
+}
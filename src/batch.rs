@@ -0,0 +1,500 @@
+use crate::corpus::{BucketOrder, CorpusReport, CorpusTemplate, TemplateCorpus};
+use crate::{AnalysisWarning, TemplateAnalysis, SCHEMA_VERSION};
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Member templates listed in the shape frequencies of a batch run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateListing {
+    /// Every template source
+    All,
+    /// The template ids
+    Ids,
+    /// The first `n` template sources of each shape
+    FirstN(usize),
+}
+
+/// Result of one template of a batch run, as written to the results file:
+/// its source, models and analysis summary (or error) with the layout
+/// `schema_version`; `None` until the template is analyzed
+pub fn template_result(template: &CorpusTemplate) -> Option<Value> {
+    let result = match template.outcome.as_ref()? {
+        Ok(analysis) => json!({
+            "schema_version": SCHEMA_VERSION,
+            "template": template.source,
+            "model_ids": template.model_ids,
+            "external_vars": analysis.external_vars,
+            "internal_vars": analysis.internal_vars,
+            "loop_vars": analysis.loop_vars,
+            "object_shapes_json": analysis.object_shapes_json(),
+            "warnings": analysis
+                .warnings
+                .iter()
+                .map(AnalysisWarning::summary_json)
+                .collect::<Vec<_>>(),
+            "status": "success"
+        }),
+        Err(err) => json!({
+            "schema_version": SCHEMA_VERSION,
+            "template": template.source,
+            "model_ids": template.model_ids,
+            "error": err,
+            "status": "error"
+        }),
+    };
+    Some(result)
+}
+
+/// Writes the CSV summary of the analyzed templates: one row per template with
+/// its id, model count, external variables (`;`-separated), shape hash and
+/// status
+pub fn write_csv(out: &mut dyn Write, corpus: &TemplateCorpus) -> io::Result<()> {
+    writeln!(
+        out,
+        "template_id,model_count,external_vars,shape_hash,status"
+    )?;
+    for template in corpus.templates() {
+        let (id, model_count) = (&template.name, template.model_ids.len());
+        match &template.outcome {
+            Some(Ok(analysis)) => {
+                let external_vars = Vec::from_iter(analysis.external_vars.iter().cloned());
+                writeln!(
+                    out,
+                    "{id},{model_count},{},{:016x},success",
+                    csv_field(&external_vars.join(";")),
+                    analysis.shape_hash()
+                )?;
+            }
+            Some(Err(_)) => writeln!(out, "{id},{model_count},,,error")?,
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Lists the member templates of each bucket of `report` (see
+/// `ShapeBucket::templates`) as `listing` asks
+pub fn list_templates(
+    report: &mut CorpusReport,
+    corpus: &TemplateCorpus,
+    listing: TemplateListing,
+) {
+    for (template, analysis) in corpus.analyses() {
+        let Some(bucket) = report.buckets.get_mut(&analysis.shape_hash()) else {
+            continue;
+        };
+        match listing {
+            TemplateListing::All => bucket.templates.push(template.source.clone()),
+            TemplateListing::Ids => bucket.templates.push(template.name.clone()),
+            TemplateListing::FirstN(limit) if bucket.templates.len() < limit => {
+                bucket.templates.push(template.source.clone())
+            }
+            TemplateListing::FirstN(_) => {}
+        }
+    }
+}
+
+/// Families of similar shapes as JSON, the most used first: the
+/// representative's skeleton, schema and field presence, with the template
+/// and model counts of the family
+pub fn shape_families(corpus: &TemplateCorpus, threshold: f64) -> Vec<Value> {
+    let mut families = corpus
+        .clusters(threshold)
+        .iter()
+        .map(|cluster| {
+            let model_ids = cluster
+                .members
+                .iter()
+                .flat_map(|&idx| &corpus.templates()[idx].model_ids)
+                .collect::<BTreeSet<_>>();
+            json!({
+                "object_shapes_json": cluster.representative.object_shapes_json(),
+                "schema": cluster.schema(),
+                "presence": cluster.representative.presence,
+                "template_count": cluster.members.len(),
+                "model_id_count": model_ids.len(),
+            })
+        })
+        .collect::<Vec<_>>();
+    families.sort_by_key(|family| Reverse(family["model_id_count"].as_u64().unwrap_or(0)));
+    families
+}
+
+/// Number of warnings of the analyzed templates by warning code
+pub fn warning_counts(corpus: &TemplateCorpus) -> BTreeMap<&'static str, usize> {
+    let mut counts = BTreeMap::new();
+    for (_, analysis) in corpus.analyses() {
+        for warning in &analysis.warnings {
+            *counts.entry(warning.code.as_str()).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// Table of the shapes in `order` with the share of the models each covers,
+/// until they cover `target` percent of them or `top` shapes are listed;
+/// empty when no template was analyzed
+pub fn coverage_table(
+    report: &CorpusReport,
+    order: BucketOrder,
+    target: f64,
+    top: Option<usize>,
+) -> String {
+    if report.buckets.is_empty() {
+        return String::new();
+    }
+    let mut table = format!(
+        "| index | {:^14} | {:^14} | {:^13} | {:^9} |\n",
+        "template_count", "model_id_count", "Pct of models", "Covered"
+    );
+    table.push_str(&format!(
+        "|{:-<7}|{:-<16}|{:-<16}|{:-<15}|{:-<11}|\n",
+        "", "", "", "", ""
+    ));
+    let rows = report.coverage(order, target);
+    for (i, row) in rows.iter().take(top.unwrap_or(usize::MAX)).enumerate() {
+        table.push_str(&format!(
+            "| {:^5} | {:^14} | {:^14} | {:^13} | {:^9} |\n",
+            format!("{:02}", i + 1),
+            row.bucket.template_count.to_string(),
+            row.bucket.model_ids.len().to_string(),
+            format!("{:.2}%", row.percent),
+            format!("{:.2}%", row.cumulative)
+        ));
+    }
+    table
+}
+
+/// Writes one line of a batch checkpoint: the template id with its outcome
+pub fn write_checkpoint(out: &mut dyn Write, template: &CorpusTemplate) -> io::Result<()> {
+    if let Some(outcome) = &template.outcome {
+        writeln!(
+            out,
+            "{}",
+            serde_json::to_string(&(&template.name, outcome))?
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads the outcomes recorded in a batch checkpoint by template id (none when
+/// the file doesn't exist yet), dropping a last line cut short by an
+/// interruption so new entries start on a fresh line
+pub fn read_checkpoint(
+    path: &Path,
+) -> io::Result<HashMap<String, Result<TemplateAnalysis, String>>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let complete = content.rfind('\n').map_or(0, |end| end + 1);
+    if complete < content.len() {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(complete as u64)?;
+    }
+    // Outcomes of an older layout version are upgraded as they are read
+    Ok(content[..complete]
+        .lines()
+        .filter_map(|line| {
+            let (id, outcome) =
+                serde_json::from_str::<(String, Result<Value, String>)>(line).ok()?;
+            let outcome = match outcome {
+                Ok(analysis) => Ok(TemplateAnalysis::from_value(analysis).ok()?),
+                Err(err) => Err(err),
+            };
+            Some((id, outcome))
+        })
+        .collect())
+}
+
+/// Single HTML page with the summary, the shapes (with model coverage), the
+/// shape families, the failures and every template, all tables sortable
+pub fn html_report(corpus: &TemplateCorpus, shapes: &[Value], families: &[Value]) -> String {
+    let total_model_ids = corpus
+        .templates()
+        .iter()
+        .flat_map(|template| &template.model_ids)
+        .collect::<BTreeSet<_>>()
+        .len();
+    let failures = corpus.failures().collect::<Vec<_>>();
+    let percent =
+        |count: &Value| count.as_f64().unwrap_or(0.0) / total_model_ids.max(1) as f64 * 100.0;
+    let shape_details = |shape: &Value| {
+        details(
+            "shape",
+            &serde_json::to_string_pretty(shape).unwrap_or_default(),
+        )
+    };
+
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>cleanplate corpus report</title>\n<style>\n",
+    );
+    html.push_str(REPORT_CSS);
+    html.push_str("</style>\n</head>\n<body>\n<h1>cleanplate corpus report</h1>\n");
+    html.push_str(&format!(
+        "<p>{} templates, {} analyzed, {} failed, {} unique shapes, {} shape families, {total_model_ids} model ids.</p>\n",
+        corpus.len(),
+        corpus.analyses().count(),
+        failures.len(),
+        shapes.len(),
+        families.len()
+    ));
+
+    html.push_str("<h2>Shapes</h2>\n<table class=\"sortable\">\n<thead><tr><th>#</th><th>Shape hash</th><th>Templates</th><th>Model ids</th><th>Pct of models</th><th>Covered</th><th>Shape</th></tr></thead>\n<tbody>\n");
+    let mut covered = 0.0;
+    for (i, shape) in shapes.iter().enumerate() {
+        let pct = percent(&shape["model_id_count"]);
+        covered += pct;
+        html.push_str(&format!(
+            "<tr><td>{}</td><td><code>{}</code></td><td>{}</td><td>{}</td><td>{pct:.2}%</td><td>{covered:.2}%</td><td>{}</td></tr>\n",
+            i + 1,
+            escape_html(shape["shape_hash"].as_str().unwrap_or("")),
+            shape["template_count"],
+            shape["model_id_count"],
+            shape_details(&shape["object_shapes_json"])
+        ));
+    }
+    html.push_str("</tbody>\n</table>\n");
+
+    html.push_str("<h2>Shape families</h2>\n<table class=\"sortable\">\n<thead><tr><th>#</th><th>Templates</th><th>Model ids</th><th>Pct of models</th><th>Representative</th></tr></thead>\n<tbody>\n");
+    for (i, family) in families.iter().enumerate() {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:.2}%</td><td>{}</td></tr>\n",
+            i + 1,
+            family["template_count"],
+            family["model_id_count"],
+            percent(&family["model_id_count"]),
+            shape_details(&family["object_shapes_json"])
+        ));
+    }
+    html.push_str("</tbody>\n</table>\n");
+
+    html.push_str("<h2>Failures</h2>\n<table class=\"sortable\">\n<thead><tr><th>Template id</th><th>Model ids</th><th>Error</th><th>Template</th></tr></thead>\n<tbody>\n");
+    for (template, err) in &failures {
+        html.push_str(&format!(
+            "<tr><td><code>{}</code></td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&template.name),
+            template.model_ids.len(),
+            escape_html(err),
+            details("source", &template.source)
+        ));
+    }
+    html.push_str("</tbody>\n</table>\n");
+
+    html.push_str("<h2>Templates</h2>\n<table class=\"sortable\">\n<thead><tr><th>Template id</th><th>Status</th><th>Model ids</th><th>Shape hash</th><th>External variables</th><th>Details</th></tr></thead>\n<tbody>\n");
+    for template in corpus.templates() {
+        let (status, shape_hash, external_vars) = match &template.outcome {
+            Some(Ok(analysis)) => (
+                "success",
+                format!("{:016x}", analysis.shape_hash()),
+                Vec::from_iter(analysis.external_vars.iter().cloned()).join(", "),
+            ),
+            Some(Err(_)) => ("error", String::new(), String::new()),
+            None => continue,
+        };
+        html.push_str(&format!(
+            "<tr><td><code>{}</code></td><td>{status}</td><td>{}</td><td><code>{shape_hash}</code></td><td>{}</td><td>{}{}</td></tr>\n",
+            escape_html(&template.name),
+            template.model_ids.len(),
+            escape_html(&external_vars),
+            details("models", &template.model_ids.join("\n")),
+            details("source", &template.source)
+        ));
+    }
+    html.push_str("</tbody>\n</table>\n<script>\n");
+    html.push_str(REPORT_JS);
+    html.push_str("</script>\n</body>\n</html>\n");
+    html
+}
+
+// Collapsed block with preformatted content
+fn details(summary: &str, content: &str) -> String {
+    format!(
+        "<details><summary>{summary}</summary><pre>{}</pre></details>",
+        escape_html(content)
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Quotes a CSV field when it contains a separator, quote or newline
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+const REPORT_CSS: &str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }
+th { background: #f0f0f0; cursor: pointer; user-select: none; }
+pre { max-width: 60em; max-height: 30em; overflow: auto; }
+";
+
+// Sorts a table by the clicked column, numerically when both cells are numbers
+const REPORT_JS: &str = "document.querySelectorAll('table.sortable th').forEach((th, column) => {
+  th.addEventListener('click', () => {
+    const body = th.closest('table').tBodies[0];
+    const ascending = th.dataset.order !== 'asc';
+    th.dataset.order = ascending ? 'asc' : 'desc';
+    const key = row => row.cells[column].textContent;
+    const rows = Array.from(body.rows).sort((a, b) => {
+      const x = parseFloat(key(a)), y = parseFloat(key(b));
+      const order = !isNaN(x) && !isNaN(y) ? x - y : key(a).localeCompare(key(b));
+      return ascending ? order : -order;
+    });
+    rows.forEach(row => body.appendChild(row));
+  });
+});
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corpus() -> TemplateCorpus {
+        let mut corpus = TemplateCorpus::new();
+        corpus.insert(
+            "chat",
+            "{% for m in messages %}{{ m.content }}{% endfor %}",
+            ["a", "b"],
+        );
+        corpus.insert("tools", "{{ tools }}{{ prompt }}", ["c"]);
+        corpus.insert("broken", "{% if %}", ["d"]);
+        corpus.analyze(&Default::default());
+        corpus
+    }
+
+    #[test]
+    fn test_template_result() {
+        let corpus = corpus();
+        let result = template_result(&corpus.templates()[0]).unwrap();
+        assert_eq!(result["status"], "success");
+        assert_eq!(result["schema_version"], SCHEMA_VERSION);
+        assert_eq!(result["external_vars"], json!(["messages"]));
+        let failure = template_result(&corpus.templates()[2]).unwrap();
+        assert_eq!(failure["status"], "error");
+        assert!(failure["error"].as_str().unwrap().contains("syntax error"));
+
+        let mut pending = TemplateCorpus::new();
+        pending.insert("x", "{{ x }}", ["m"]);
+        assert_eq!(template_result(&pending.templates()[0]), None);
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let mut out = Vec::new();
+        write_csv(&mut out, &corpus()).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "template_id,model_count,external_vars,shape_hash,status"
+        );
+        assert!(lines[1].starts_with("chat,2,messages,"));
+        // Several variables are one quoted field
+        assert!(lines[2].starts_with("tools,1,prompt;tools,"));
+        assert_eq!(lines[3], "broken,1,,,error");
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+
+    #[test]
+    fn test_html_report() {
+        let corpus = corpus();
+        let mut template = TemplateCorpus::new();
+        template.insert("<b>", "{{ x }}", ["m&m"]);
+        template.analyze(&Default::default());
+        let html = html_report(&template, &[], &[]);
+        assert!(html.contains("<code>&lt;b&gt;</code>"));
+        assert!(html.contains("m&amp;m"));
+
+        let shapes = corpus
+            .report()
+            .shape_frequencies(BucketOrder::ModelCount, false);
+        let html = html_report(&corpus, &shapes, &shape_families(&corpus, 0.8));
+        assert!(html.contains("<p>3 templates, 2 analyzed, 1 failed, 2 unique shapes"));
+        assert!(html.contains("4 model ids."));
+        assert_eq!(html.matches("<td>error</td>").count(), 1);
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("cleanplate-checkpoint-{}", std::process::id()));
+        assert!(read_checkpoint(&path).unwrap().is_empty());
+
+        let corpus = corpus();
+        let mut out = Vec::new();
+        for template in corpus.templates() {
+            write_checkpoint(&mut out, template).unwrap();
+        }
+        // A line cut short by an interruption is dropped and truncated away
+        out.extend_from_slice(b"[\"partial\", {\"Ok\"");
+        fs::write(&path, &out).unwrap();
+        let restored = read_checkpoint(&path).unwrap();
+        assert_eq!(restored.len(), 3);
+        assert_eq!(
+            restored["chat"],
+            corpus.templates()[0].outcome.clone().unwrap()
+        );
+        assert!(restored["broken"].is_err());
+        assert!(fs::read_to_string(&path).unwrap().ends_with('\n'));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_coverage_table() {
+        let corpus = corpus();
+        let report = corpus.report();
+        let table = coverage_table(&report, BucketOrder::ModelCount, 50.0, None);
+        // Header, separator and the one shape covering two of the four models
+        assert_eq!(table.lines().count(), 3);
+        assert!(table.contains("50.00%"));
+        let table = coverage_table(&report, BucketOrder::ModelCount, 100.0, Some(1));
+        assert_eq!(table.lines().count(), 3);
+        assert_eq!(
+            coverage_table(
+                &CorpusReport::default(),
+                BucketOrder::ModelCount,
+                95.0,
+                None
+            ),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_list_templates() {
+        let corpus = corpus();
+        let mut report = corpus.report();
+        list_templates(&mut report, &corpus, TemplateListing::FirstN(0));
+        assert!(report
+            .buckets
+            .values()
+            .all(|bucket| bucket.templates.is_empty()));
+        list_templates(&mut report, &corpus, TemplateListing::Ids);
+        let ids = report
+            .buckets
+            .values()
+            .flat_map(|bucket| bucket.templates.clone())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            ids,
+            BTreeSet::from(["chat".to_string(), "tools".to_string()])
+        );
+    }
+}
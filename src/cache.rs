@@ -0,0 +1,244 @@
+use crate::corpus::template_id;
+use crate::{
+    analyze_with, fnv1a, AnalyzeOptions, TemplateAnalysis, TemplateLoader, SCHEMA_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// On-disk cache of analysis outcomes: a directory with one JSON file per
+/// template, named after a hash of the template source, the analysis options,
+/// the crate version and the analysis layout version, so only new or changed
+/// templates are re-analyzed. Entries also hold a hash of every template the
+/// options' loader resolved for the analysis (included, extended or imported
+/// templates) and are stale once one of those changes. Lookups count hits and
+/// misses and can run from several threads
+///
+/// ```no_run
+/// use cleanplate::cache::AnalysisCache;
+///
+/// let options = Default::default();
+/// let cache = AnalysisCache::open(".cleanplate-cache", &options).unwrap();
+/// let analysis = cache.get_or_analyze("{{ prompt }}", &options);
+/// assert!(analysis.is_ok());
+/// println!("{} hits, {} misses", cache.stats().hits, cache.stats().misses);
+/// ```
+#[derive(Debug)]
+pub struct AnalysisCache {
+    dir: PathBuf,
+    // Hash of the options and versions, mixed into every key
+    salt: String,
+    // Loader of the options, to check the templates an entry depends on
+    loader: Option<TemplateLoader>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// Hit and miss counts of an `AnalysisCache`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl AnalysisCache {
+    /// Opens (and creates) the cache directory for analyses run with `options`
+    pub fn open(dir: impl Into<PathBuf>, options: &AnalyzeOptions) -> io::Result<AnalysisCache> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        // Any option can change the outcome, so entries of other options or
+        // versions get different keys
+        let salt = format!(
+            "{}\n{SCHEMA_VERSION}\n{options:?}",
+            env!("CARGO_PKG_VERSION")
+        );
+        Ok(AnalysisCache {
+            dir,
+            salt,
+            loader: options.loader.clone(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Cache key of a template: its FNV-1a hash, with the options and version
+    pub fn key(&self, template: &str) -> String {
+        let hash = fnv1a(self.salt.bytes().chain([0]).chain(template.bytes()));
+        format!("{hash:016x}")
+    }
+
+    /// The cached outcome of a template (an analysis or its error message),
+    /// counted as a hit or a miss; unreadable entries and entries whose loaded
+    /// templates changed count as misses
+    pub fn get(&self, template: &str) -> Option<Result<TemplateAnalysis, String>> {
+        let outcome = fs::read_to_string(self.entry_path(template))
+            .ok()
+            .and_then(|content| serde_json::from_str::<Entry<Value>>(&content).ok())
+            .filter(|entry| self.is_fresh(entry))
+            .and_then(|entry| match entry.outcome {
+                Ok(analysis) => Some(Ok(TemplateAnalysis::from_value(analysis).ok()?)),
+                Err(err) => Some(Err(err)),
+            });
+        let counter = match outcome {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        outcome
+    }
+
+    /// Stores the outcome of a template, without the templates its analysis
+    /// loaded (`get_or_analyze` records those)
+    pub fn insert(
+        &self,
+        template: &str,
+        outcome: &Result<TemplateAnalysis, String>,
+    ) -> io::Result<()> {
+        self.store(template, outcome, BTreeMap::new())
+    }
+
+    fn store(
+        &self,
+        template: &str,
+        outcome: &Result<TemplateAnalysis, String>,
+        loaded: BTreeMap<String, Option<String>>,
+    ) -> io::Result<()> {
+        let entry = Entry {
+            outcome: outcome.as_ref().map_err(Clone::clone),
+            loaded,
+        };
+        let path = self.entry_path(template);
+        // Written aside and renamed, so readers never see a partial entry
+        let partial = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&partial, serde_json::to_vec(&entry)?)?;
+        fs::rename(partial, path)
+    }
+
+    /// The cached outcome of a template, or its fresh analysis stored for the
+    /// next run; a failure to store it only costs the next run a re-analysis
+    pub fn get_or_analyze(
+        &self,
+        template: &str,
+        options: &AnalyzeOptions,
+    ) -> Result<TemplateAnalysis, String> {
+        if let Some(outcome) = self.get(template) {
+            return outcome;
+        }
+        // The loader is wrapped to record the hash of every template it resolves
+        let loaded = Arc::new(Mutex::new(BTreeMap::new()));
+        let options = match &options.loader {
+            Some(loader) => {
+                let (loader, loaded) = (loader.clone(), Arc::clone(&loaded));
+                AnalyzeOptions {
+                    loader: Some(TemplateLoader::new(move |name| {
+                        let source = loader.load(name);
+                        let hash = source.as_deref().map(template_id);
+                        loaded.lock().unwrap().insert(name.to_string(), hash);
+                        source
+                    })),
+                    ..options.clone()
+                }
+            }
+            None => options.clone(),
+        };
+        let outcome = analyze_with(template, &options).map_err(|err| err.to_string());
+        let loaded = loaded.lock().unwrap().clone();
+        let _ = self.store(template, &outcome, loaded);
+        outcome
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn entry_path(&self, template: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", self.key(template)))
+    }
+
+    // Whether every template the entry's analysis loaded is still the same
+    fn is_fresh(&self, entry: &Entry<Value>) -> bool {
+        entry.loaded.iter().all(|(name, hash)| {
+            let source = self.loader.as_ref().and_then(|loader| loader.load(name));
+            source.as_deref().map(template_id) == *hash
+        })
+    }
+}
+
+// Cache file: the outcome and the hashes of the templates the loader resolved
+// for it (`None` for names it could not find)
+#[derive(Serialize, Deserialize)]
+struct Entry<T> {
+    outcome: Result<T, String>,
+    #[serde(default)]
+    loaded: BTreeMap<String, Option<String>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analysis_cache() {
+        let dir = std::env::temp_dir().join(format!("cleanplate-cache-{}", std::process::id()));
+        let options = AnalyzeOptions::default();
+        let cache = AnalysisCache::open(&dir, &options).unwrap();
+
+        let template = "{% for m in messages %}{{ m.content }}{% endfor %}";
+        let fresh = cache.get_or_analyze(template, &options).unwrap();
+        assert!(cache.get_or_analyze("{% if %}", &options).is_err());
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 2 });
+
+        // A new handle (the next run) reads the stored outcomes
+        let cache = AnalysisCache::open(&dir, &options).unwrap();
+        assert_eq!(cache.get_or_analyze(template, &options), Ok(fresh));
+        assert!(cache.get_or_analyze("{% if %}", &options).is_err());
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 0 });
+
+        // Other options don't reuse the entries
+        let strict = AnalyzeOptions::builder().strict(true).build();
+        let cache = AnalysisCache::open(&dir, &strict).unwrap();
+        assert_eq!(cache.get(template), None);
+        assert_eq!(cache.stats().misses, 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_loaded_templates_invalidate_entries() {
+        let dir =
+            std::env::temp_dir().join(format!("cleanplate-cache-loader-{}", std::process::id()));
+        let templates = dir.join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("header.jinja"), "{{ bos_token }}").unwrap();
+        let loader_dir = templates.clone();
+        let options = AnalyzeOptions::builder()
+            .loader(move |name| fs::read_to_string(loader_dir.join(name)).ok())
+            .build();
+        let template = "{% include 'header.jinja' %}{{ prompt }}";
+
+        let cache = AnalysisCache::open(dir.join("cache"), &options).unwrap();
+        let fresh = cache.get_or_analyze(template, &options).unwrap();
+        assert!(fresh.external_vars.contains("bos_token"));
+        assert_eq!(cache.get_or_analyze(template, &options), Ok(fresh));
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+
+        // Editing the included template makes the entry stale
+        fs::write(templates.join("header.jinja"), "{{ eos_token }}").unwrap();
+        let analysis = cache.get_or_analyze(template, &options).unwrap();
+        assert!(analysis.external_vars.contains("eos_token"));
+        assert!(!analysis.external_vars.contains("bos_token"));
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2 });
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
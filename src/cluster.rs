@@ -0,0 +1,101 @@
+use crate::merge::merge_analyses;
+use crate::shape::Shape;
+use crate::TemplateAnalysis;
+use serde_json::Value;
+
+/// Similarity at which `cluster_analyses` puts two shapes in the same family
+pub const DEFAULT_CLUSTER_THRESHOLD: f64 = 0.8;
+
+/// Family of analyses whose shapes are similar enough to share one schema
+#[derive(Debug, Clone)]
+pub struct ShapeCluster {
+    /// Indices of the member analyses in the clustered slice
+    pub members: Vec<usize>,
+    /// Merged analysis of the members; its `presence` tells how many of
+    /// them use each path
+    pub representative: TemplateAnalysis,
+}
+
+impl ShapeCluster {
+    /// JSON Schema that every member of the family satisfies
+    pub fn schema(&self) -> Value {
+        self.representative.to_json_schema()
+    }
+}
+
+/// Groups analyses into families of compatible shapes: each analysis joins
+/// the family whose merged shape is most similar to its own (at least
+/// `threshold`, see `Shape::similarity`) or starts a new one. Families are
+/// returned largest first.
+pub fn cluster_analyses(analyses: &[TemplateAnalysis], threshold: f64) -> Vec<ShapeCluster> {
+    let mut families: Vec<(Vec<usize>, Shape)> = Vec::new();
+
+    for (idx, analysis) in analyses.iter().enumerate() {
+        let best = families
+            .iter()
+            .enumerate()
+            .map(|(family, (_, shape))| (family, shape.similarity(&analysis.shape)))
+            .filter(|(_, score)| *score >= threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)));
+
+        match best {
+            Some((family, _)) => {
+                let (members, shape) = &mut families[family];
+                members.push(idx);
+                *shape = std::mem::replace(shape, Shape::Unknown).merge(analysis.shape.clone());
+            }
+            None => families.push((vec![idx], analysis.shape.clone())),
+        }
+    }
+
+    let mut clusters = families
+        .into_iter()
+        .map(|(members, _)| {
+            let member_analyses = members
+                .iter()
+                .map(|&idx| analyses[idx].clone())
+                .collect::<Vec<_>>();
+            ShapeCluster {
+                representative: merge_analyses(&member_analyses),
+                members,
+            }
+        })
+        .collect::<Vec<_>>();
+    // Stable, so equally sized families keep their first-seen order
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.members.len()));
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze;
+
+    #[test]
+    fn test_cluster_analyses() {
+        let templates = [
+            "{% for message in messages %}{{ message.role }}{{ message.content }}{% endfor %}",
+            "{% for m in messages %}{{ m.role }}: {{ m.content }}{% endfor %}{{ eos_token }}",
+            "{{ prompt }}{{ suffix }}",
+            "{% for message in messages %}{{ message.role }}{{ message.content }}{% endfor %}{% if add_generation_prompt %}x{% endif %}",
+        ];
+        let analyses = templates
+            .iter()
+            .map(|template| analyze(template, false).unwrap())
+            .collect::<Vec<_>>();
+
+        let clusters = cluster_analyses(&analyses, 0.6);
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].members, vec![0, 1, 3]);
+        assert_eq!(clusters[1].members, vec![2]);
+        assert_eq!(clusters[0].representative.presence["messages[].role"], 1.0);
+        assert!(clusters[0]
+            .representative
+            .optional_paths
+            .contains("eos_token"));
+        assert!(clusters[0].schema()["properties"]["messages"].is_object());
+
+        // A threshold of 1.0 only groups identical shapes
+        assert_eq!(cluster_analyses(&analyses, 1.0).len(), 4);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::shape::{child_path, Shape};
+use crate::TemplateAnalysis;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+mod avro;
+mod go;
+mod graphql;
+mod mermaid;
+mod python;
+mod rust;
+mod typescript;
+mod zod;
+
+pub use avro::to_avro;
+pub use go::to_go;
+pub use graphql::to_graphql;
+pub use mermaid::to_mermaid;
+pub use python::{to_python, PythonStyle};
+pub use rust::to_rust;
+pub use typescript::to_typescript;
+pub use zod::to_zod;
+
+/// Options shared by the code generators
+#[derive(Debug, Clone)]
+pub struct CodegenOptions {
+    /// Name of the type describing the whole render context
+    pub root_name: String,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            root_name: "Context".to_string(),
+        }
+    }
+}
+
+// Named object type found in the shape, e.g. `Message` for `messages[]`
+#[derive(Debug, Clone)]
+pub(crate) struct TypeDef {
+    pub name: String,
+    pub fields: Vec<FieldDef>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FieldDef {
+    /// Property name as it appears in the context
+    pub key: String,
+    pub ty: TypeRef,
+    pub optional: bool,
+    /// Description from a `@param` annotation
+    pub description: Option<String>,
+}
+
+// Language-neutral type of a field
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TypeRef {
+    Named(String),
+    Array(Box<TypeRef>),
+    Map(Box<TypeRef>),
+    Union(Vec<TypeRef>),
+    /// String (or other scalar) restricted to the listed constants
+    Enum(Vec<Value>),
+    String,
+    Number,
+    Integer,
+    Bool,
+    Null,
+    /// Value with no type evidence, or arbitrary JSON
+    Any,
+}
+
+impl TypeRef {
+    // The type without its `null` variant, and whether it had one
+    pub fn split_null(&self) -> (TypeRef, bool) {
+        match self {
+            TypeRef::Null => (TypeRef::Any, true),
+            TypeRef::Union(variants) if variants.contains(&TypeRef::Null) => {
+                let mut rest = variants
+                    .iter()
+                    .filter(|variant| **variant != TypeRef::Null)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let ty = match rest.len() {
+                    1 => rest.remove(0),
+                    _ => TypeRef::Union(rest),
+                };
+                (ty, true)
+            }
+            ty => (ty.clone(), false),
+        }
+    }
+}
+
+// Collects the named object types of an analysis, the root type first and
+// nested types in the order they are reached
+pub(crate) fn collect_types(analysis: &TemplateAnalysis, options: &CodegenOptions) -> Vec<TypeDef> {
+    let mut collector = Collector {
+        analysis,
+        types: Vec::new(),
+        names: BTreeMap::new(),
+    };
+    let root = match &analysis.shape {
+        Shape::Object(properties) => properties.clone(),
+        _ => BTreeMap::new(),
+    };
+    collector.object_type(&root, "", &options.root_name);
+    collector.types
+}
+
+struct Collector<'a> {
+    analysis: &'a TemplateAnalysis,
+    types: Vec<TypeDef>,
+    // Type name -> shape it was generated for
+    names: BTreeMap<String, Shape>,
+}
+
+impl Collector<'_> {
+    fn type_ref(&mut self, shape: &Shape, path: &str, hint: &str) -> TypeRef {
+        match shape {
+            Shape::Object(properties) => TypeRef::Named(self.object_type(properties, path, hint)),
+            Shape::Array(item) => {
+                let item = self.type_ref(item, &format!("{path}[]"), &item_name(hint));
+                TypeRef::Array(Box::new(item))
+            }
+            Shape::Map(value) => {
+                let value = self.type_ref(value, &format!("{path}{{}}"), &format!("{hint}Value"));
+                TypeRef::Map(Box::new(value))
+            }
+            Shape::Union(variants) => TypeRef::Union(
+                variants
+                    .iter()
+                    .map(|variant| self.type_ref(variant, path, hint))
+                    .collect(),
+            ),
+            Shape::Ref(name) => self.definition_type(name),
+            Shape::String | Shape::Unknown
+                if self.analysis.closed_enums.contains(path)
+                    && self.analysis.enum_values.contains_key(path) =>
+            {
+                TypeRef::Enum(self.analysis.enum_values[path].clone())
+            }
+            Shape::String => TypeRef::String,
+            Shape::Number => TypeRef::Number,
+            Shape::Integer => TypeRef::Integer,
+            Shape::Bool => TypeRef::Bool,
+            Shape::Null => TypeRef::Null,
+            Shape::Unknown | Shape::Any => TypeRef::Any,
+        }
+    }
+
+    // Names (and collects, the first time) the type of an object
+    fn object_type(
+        &mut self,
+        properties: &BTreeMap<String, Shape>,
+        path: &str,
+        hint: &str,
+    ) -> String {
+        let shape = Shape::Object(properties.clone());
+        let base = pascal_case(hint);
+        let mut name = base.clone();
+        let mut suffix = 1;
+        while let Some(existing) = self.names.get(&name) {
+            // The same object reached again (e.g. through a definition) reuses its type
+            if *existing == shape {
+                return name;
+            }
+            suffix += 1;
+            name = format!("{base}{suffix}");
+        }
+        self.names.insert(name.clone(), shape);
+
+        // Reserve the slot so the type comes before the types it uses
+        let idx = self.types.len();
+        self.types.push(TypeDef {
+            name: name.clone(),
+            fields: Vec::new(),
+        });
+        let mut fields = Vec::new();
+        for (key, property) in properties {
+            let property_path = child_path(path, key);
+            let ty = self.type_ref(property, &property_path, key);
+            fields.push(FieldDef {
+                key: key.clone(),
+                optional: self.analysis.optional_paths.contains(&property_path),
+                description: self
+                    .analysis
+                    .annotations
+                    .get(&property_path)
+                    .and_then(|annotation| annotation.description.clone()),
+                ty,
+            });
+        }
+        self.types[idx].fields = fields;
+        name
+    }
+
+    fn definition_type(&mut self, name: &str) -> TypeRef {
+        let type_name = pascal_case(name);
+        if self.names.contains_key(&type_name) {
+            return TypeRef::Named(type_name);
+        }
+        match self.analysis.definitions.get(name) {
+            Some(definition) => match &definition.shape {
+                Shape::Object(properties) => {
+                    TypeRef::Named(self.object_type(properties, &definition.path, name))
+                }
+                _ => TypeRef::Any,
+            },
+            None => TypeRef::Any,
+        }
+    }
+}
+
+// Name of the items of a list named `hint` (`messages` -> `message`,
+// `content` -> `content_item`)
+fn item_name(hint: &str) -> String {
+    if let Some(stem) = hint.strip_suffix("ies") {
+        format!("{stem}y")
+    } else if hint.ends_with('s') && !hint.ends_with("ss") && hint.len() > 1 {
+        hint[..hint.len() - 1].to_string()
+    } else {
+        format!("{hint}_item")
+    }
+}
+
+// `tool_calls` -> `ToolCalls`, `chat-template` -> `ChatTemplate`
+pub(crate) fn pascal_case(name: &str) -> String {
+    let mut out = String::new();
+    for word in name.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            out.push(first.to_ascii_uppercase());
+            out.extend(chars);
+        }
+    }
+    if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, 'T');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_names() {
+        assert_eq!(pascal_case("tool_calls"), "ToolCalls");
+        assert_eq!(pascal_case(&item_name("tool_calls")), "ToolCall");
+        assert_eq!(pascal_case(&item_name("properties")), "Property");
+        assert_eq!(pascal_case(&item_name("content")), "ContentItem");
+        assert_eq!(pascal_case("3d"), "T3d");
+    }
+}
@@ -0,0 +1,177 @@
+use super::{collect_types, CodegenOptions, TypeDef, TypeRef};
+use crate::TemplateAnalysis;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Avro record schema for the render context; optional and nullable fields
+/// become `["null", T]` unions defaulting to `null`, and free-form values
+/// are carried as JSON-encoded strings
+pub fn to_avro(analysis: &TemplateAnalysis, options: &CodegenOptions) -> Value {
+    let types = collect_types(analysis, options)
+        .into_iter()
+        .map(|def| (def.name.clone(), def))
+        .collect::<BTreeMap<_, _>>();
+    let mut defined = BTreeSet::new();
+    let mut root = record(&types, &options.root_name, &mut defined);
+    if let Value::Object(root) = &mut root {
+        root.insert("namespace".to_string(), json!("cleanplate"));
+    }
+    root
+}
+
+// Full record definition the first time a type is reached, its name after
+fn record(types: &BTreeMap<String, TypeDef>, name: &str, defined: &mut BTreeSet<String>) -> Value {
+    if !defined.insert(name.to_string()) {
+        return json!(name);
+    }
+    let Some(def) = types.get(name) else {
+        return json!("string");
+    };
+
+    let mut fields = Vec::new();
+    for field in &def.fields {
+        let (ty, nullable) = field.ty.split_null();
+        let mut avro = Map::new();
+        let field_name = field_name(&field.key);
+        avro.insert("name".to_string(), json!(field_name));
+
+        let mut doc = Vec::new();
+        if let Some(description) = &field.description {
+            doc.push(description.clone());
+        }
+        if field_name != field.key {
+            doc.push(format!("Context key {}", Value::from(field.key.as_str())));
+        }
+        if let TypeRef::Enum(values) = &ty {
+            let values = values.iter().map(ToString::to_string).collect::<Vec<_>>();
+            doc.push(format!("One of {}", values.join(", ")));
+        }
+        if !doc.is_empty() {
+            avro.insert("doc".to_string(), json!(doc.join(". ")));
+        }
+
+        let avro_ty = avro_type(&ty, types, defined);
+        if field.optional || nullable {
+            let mut variants = vec![json!("null")];
+            match avro_ty {
+                Value::Array(rest) => variants.extend(rest),
+                ty => variants.push(ty),
+            }
+            avro.insert("type".to_string(), Value::Array(variants));
+            avro.insert("default".to_string(), Value::Null);
+        } else {
+            avro.insert("type".to_string(), avro_ty);
+        }
+        fields.push(Value::Object(avro));
+    }
+    json!({"type": "record", "name": name, "fields": fields})
+}
+
+fn avro_type(
+    ty: &TypeRef,
+    types: &BTreeMap<String, TypeDef>,
+    defined: &mut BTreeSet<String>,
+) -> Value {
+    match ty {
+        TypeRef::Named(name) => record(types, name, defined),
+        TypeRef::Array(item) => json!({"type": "array", "items": avro_type(item, types, defined)}),
+        TypeRef::Map(value) => json!({"type": "map", "values": avro_type(value, types, defined)}),
+        TypeRef::Union(variants) if variants.contains(&TypeRef::Any) => json!("string"),
+        TypeRef::Union(variants) => {
+            // Avro unions may hold one branch per unnamed type and can't nest
+            let mut kinds = BTreeSet::new();
+            let mut branches = Vec::new();
+            for variant in variants {
+                let branch = avro_type(variant, types, defined);
+                let kind = match &branch {
+                    Value::Object(schema) if schema["type"] != "record" => schema["type"].clone(),
+                    Value::Object(schema) => schema["name"].clone(),
+                    branch => branch.clone(),
+                };
+                if kinds.insert(kind.to_string()) {
+                    branches.push(branch);
+                }
+            }
+            Value::Array(branches)
+        }
+        TypeRef::Enum(_) | TypeRef::String | TypeRef::Any => json!("string"),
+        TypeRef::Number => json!("double"),
+        TypeRef::Integer => json!("long"),
+        TypeRef::Bool => json!("boolean"),
+        TypeRef::Null => json!("null"),
+    }
+}
+
+// Avro names are `[A-Za-z_][A-Za-z0-9_]*`
+fn field_name(key: &str) -> String {
+    let mut name = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+    use crate::codegen::{to_avro, CodegenOptions};
+    use serde_json::json;
+
+    fn avro(template: &str) -> serde_json::Value {
+        to_avro(
+            &analyze(template, false).unwrap(),
+            &CodegenOptions::default(),
+        )
+    }
+
+    #[test]
+    fn test_avro_record() {
+        let template = "{% for message in messages %}{% if message.content is string %}{{ message.content }}{% else %}{% for part in message.content %}{{ part.text ~ '' }}{% endfor %}{% endif %}{% endfor %}";
+
+        assert_eq!(
+            avro(template),
+            json!({
+                "type": "record",
+                "name": "Context",
+                "namespace": "cleanplate",
+                "fields": [
+                    {"name": "messages", "type": {"type": "array", "items": {
+                        "type": "record",
+                        "name": "Message",
+                        "fields": [
+                            {"name": "content", "type": ["string", {"type": "array", "items": {
+                                "type": "record",
+                                "name": "ContentItem",
+                                "fields": [{"name": "text", "type": "string"}]
+                            }}]}
+                        ]
+                    }}}
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_avro_nullable_fields() {
+        let template = "{% if weight is not none %}{{ weight | int }}{% endif %}{% if tools is defined %}{{ tools | tojson }}{% endif %}";
+
+        assert_eq!(
+            avro(template)["fields"],
+            json!([
+                {"name": "tools", "type": ["null", "string"], "default": null},
+                {"name": "weight", "type": ["null", "long"], "default": null}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_avro_renamed_key() {
+        assert_eq!(
+            avro("{{ meta['chat-id'] ~ '' }}")["fields"][0]["type"]["fields"],
+            json!([{"name": "chat_id", "doc": "Context key \"chat-id\"", "type": "string"}])
+        );
+    }
+}
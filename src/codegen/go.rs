@@ -0,0 +1,133 @@
+use super::{collect_types, pascal_case, CodegenOptions, TypeRef};
+use crate::TemplateAnalysis;
+
+/// Go structs with JSON tags for the render context; optional fields get
+/// `omitempty`, optional and nullable scalars and structs become pointers,
+/// and unions and free-form values use `any`
+pub fn to_go(analysis: &TemplateAnalysis, options: &CodegenOptions) -> String {
+    let mut out =
+        String::from("// Code generated by cleanplate. DO NOT EDIT.\n\npackage cleanplate\n");
+
+    for def in collect_types(analysis, options) {
+        // (comments, name, type, tag) per field, aligned like gofmt does
+        let mut fields = Vec::new();
+        for field in &def.fields {
+            let (ty, nullable) = field.ty.split_null();
+            let mut comments = Vec::new();
+            if let Some(description) = &field.description {
+                comments.push(description.clone());
+            }
+            if let TypeRef::Enum(values) = &ty {
+                let values = values.iter().map(ToString::to_string).collect::<Vec<_>>();
+                comments.push(format!("One of {}", values.join(", ")));
+            }
+
+            let mut go_ty = go_type(&ty);
+            // Slices, maps and `any` already have a nil value
+            let nilable = matches!(ty, TypeRef::Array(_) | TypeRef::Map(_)) || go_ty == "any";
+            if (field.optional || nullable) && !nilable {
+                go_ty = format!("*{go_ty}");
+            }
+            let tag = if field.optional {
+                format!("`json:\"{},omitempty\"`", field.key)
+            } else {
+                format!("`json:\"{}\"`", field.key)
+            };
+            fields.push((comments, pascal_case(&field.key), go_ty, tag));
+        }
+
+        let name_width = fields.iter().map(|field| field.1.len()).max().unwrap_or(0);
+        let type_width = fields.iter().map(|field| field.2.len()).max().unwrap_or(0);
+        out.push_str(&format!("\ntype {} struct {{\n", def.name));
+        for (comments, name, ty, tag) in fields {
+            for comment in comments {
+                out.push_str(&format!("\t// {comment}\n"));
+            }
+            out.push_str(&format!("\t{name:name_width$} {ty:type_width$} {tag}\n"));
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+fn go_type(ty: &TypeRef) -> String {
+    match ty {
+        TypeRef::Named(name) => name.clone(),
+        TypeRef::Array(item) => format!("[]{}", element_type(item)),
+        TypeRef::Map(value) => format!("map[string]{}", element_type(value)),
+        TypeRef::Enum(_) | TypeRef::String => "string".to_string(),
+        TypeRef::Number => "float64".to_string(),
+        TypeRef::Integer => "int64".to_string(),
+        TypeRef::Bool => "bool".to_string(),
+        // Go has no sum types
+        TypeRef::Union(_) | TypeRef::Null | TypeRef::Any => "any".to_string(),
+    }
+}
+
+// Type of a slice element or map value; nullable ones become pointers
+fn element_type(ty: &TypeRef) -> String {
+    let (inner, nullable) = ty.split_null();
+    let go_ty = go_type(&inner);
+    if nullable && go_ty != "any" && !matches!(inner, TypeRef::Array(_) | TypeRef::Map(_)) {
+        format!("*{go_ty}")
+    } else {
+        go_ty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+    use crate::codegen::{to_go, CodegenOptions};
+
+    fn go(template: &str) -> String {
+        to_go(
+            &analyze(template, false).unwrap(),
+            &CodegenOptions::default(),
+        )
+    }
+
+    #[test]
+    fn test_go_structs() {
+        let template = "{% for message in messages %}{{ message['chat-id'] ~ '' }}{% for key, value in message.metadata.items() %}{{ key }}{% endfor %}{% endfor %}";
+
+        assert_eq!(
+            go(template),
+            "// Code generated by cleanplate. DO NOT EDIT.
+
+package cleanplate
+
+type Context struct {
+\tMessages []Message `json:\"messages\"`
+}
+
+type Message struct {
+\tChatId   string         `json:\"chat-id\"`
+\tMetadata map[string]any `json:\"metadata\"`
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_go_pointer_fields() {
+        let template = "{% if weight is not none %}{{ weight | int }}{% endif %}{% if add_generation_prompt is defined and add_generation_prompt %}{% endif %}{% if tools is defined %}{{ tools | tojson }}{% endif %}";
+
+        assert!(go(template).contains(
+            "type Context struct {
+\tAddGenerationPrompt *bool  `json:\"add_generation_prompt,omitempty\"`
+\tTools               any    `json:\"tools,omitempty\"`
+\tWeight              *int64 `json:\"weight\"`
+}
+"
+        ));
+    }
+
+    #[test]
+    fn test_go_enum_comment() {
+        let template = "{% if role == 'user' %}{% elif role == 'assistant' %}{% else %}{{ raise_exception('unknown role') }}{% endif %}";
+
+        assert!(go(template)
+            .contains("\t// One of \"user\", \"assistant\"\n\tRole string `json:\"role\"`\n"));
+    }
+}
@@ -0,0 +1,135 @@
+use super::{collect_types, CodegenOptions, TypeRef};
+use crate::TemplateAnalysis;
+use serde_json::Value;
+
+/// GraphQL SDL input types for the render context; required, non-null
+/// fields get `!`, arrays become lists, and unions, maps and untyped
+/// values use a `JSON` scalar
+pub fn to_graphql(analysis: &TemplateAnalysis, options: &CodegenOptions) -> String {
+    let mut uses_json = false;
+    let mut body = String::new();
+
+    for def in collect_types(analysis, options) {
+        body.push_str(&format!("\ninput {} {{\n", def.name));
+        for field in &def.fields {
+            let (ty, nullable) = field.ty.split_null();
+            let name = field_name(&field.key);
+
+            let mut description = Vec::new();
+            if let Some(text) = &field.description {
+                description.push(text.clone());
+            }
+            if name != field.key {
+                description.push(format!("Context key {}", Value::from(field.key.as_str())));
+            }
+            if let TypeRef::Enum(values) = &ty {
+                let values = values.iter().map(ToString::to_string).collect::<Vec<_>>();
+                description.push(format!("One of {}", values.join(", ")));
+            }
+            if !description.is_empty() {
+                let text = description.join(". ").replace('"', "\\\"");
+                body.push_str(&format!("  \"{text}\"\n"));
+            }
+
+            let mut graphql_ty = graphql_type(&ty, &mut uses_json);
+            if !field.optional && !nullable {
+                graphql_ty.push('!');
+            }
+            body.push_str(&format!("  {name}: {graphql_ty}\n"));
+        }
+        body.push_str("}\n");
+    }
+
+    let mut out = String::from("# Generated by cleanplate\n");
+    if uses_json {
+        out.push_str("\nscalar JSON\n");
+    }
+    out.push_str(&body);
+    out
+}
+
+fn graphql_type(ty: &TypeRef, uses_json: &mut bool) -> String {
+    match ty {
+        TypeRef::Named(name) => name.clone(),
+        TypeRef::Array(item) => {
+            let (item, nullable) = item.split_null();
+            let item = graphql_type(&item, uses_json);
+            if nullable {
+                format!("[{item}]")
+            } else {
+                format!("[{item}!]")
+            }
+        }
+        TypeRef::Enum(_) | TypeRef::String => "String".to_string(),
+        TypeRef::Number => "Float".to_string(),
+        TypeRef::Integer => "Int".to_string(),
+        TypeRef::Bool => "Boolean".to_string(),
+        TypeRef::Union(_) if ty.split_null().1 => {
+            let (inner, _) = ty.split_null();
+            graphql_type(&inner, uses_json)
+        }
+        // Input types have no unions, and maps and untyped values have no fields
+        TypeRef::Map(_) | TypeRef::Union(_) | TypeRef::Null | TypeRef::Any => {
+            *uses_json = true;
+            "JSON".to_string()
+        }
+    }
+}
+
+// GraphQL names are `[_A-Za-z][_0-9A-Za-z]*`
+fn field_name(key: &str) -> String {
+    let mut name = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+    use crate::codegen::{to_graphql, CodegenOptions};
+
+    fn graphql(template: &str) -> String {
+        to_graphql(
+            &analyze(template, false).unwrap(),
+            &CodegenOptions::default(),
+        )
+    }
+
+    #[test]
+    fn test_graphql_inputs() {
+        let template = "{% for message in messages %}{{ message['chat-id'] ~ '' }}{% if message.weight is not none %}{{ message.weight | int }}{% endif %}{% endfor %}{% if tools is defined %}{{ tools | tojson }}{% endif %}{{ scores[0] + 1 }}";
+
+        assert_eq!(
+            graphql(template),
+            r#"# Generated by cleanplate
+
+scalar JSON
+
+input Context {
+  messages: [Message!]!
+  scores: [JSON!]!
+  tools: JSON
+}
+
+input Message {
+  "Context key \"chat-id\""
+  chat_id: String!
+  weight: Int
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_graphql_enum_description() {
+        let template = "{% if role == 'user' %}{% elif role == 'assistant' %}{% else %}{{ raise_exception('unknown role') }}{% endif %}";
+
+        assert!(graphql(template)
+            .contains("  \"One of \\\"user\\\", \\\"assistant\\\"\"\n  role: String!\n"));
+    }
+}
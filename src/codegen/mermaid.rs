@@ -0,0 +1,114 @@
+use super::{collect_types, CodegenOptions, TypeRef};
+use crate::TemplateAnalysis;
+
+/// Mermaid class diagram of the object types in the render context, with a
+/// composition edge (and its multiplicity) from each object to the types of
+/// its fields
+pub fn to_mermaid(analysis: &TemplateAnalysis, options: &CodegenOptions) -> String {
+    let mut out = String::from("classDiagram\n");
+    let mut relations = Vec::new();
+
+    for def in collect_types(analysis, options) {
+        out.push_str(&format!("    class {} {{\n", def.name));
+        for field in &def.fields {
+            let optional = if field.optional { "?" } else { "" };
+            out.push_str(&format!(
+                "        +{} {}{optional}\n",
+                mermaid_type(&field.ty),
+                field.key
+            ));
+
+            let (ty, nullable) = field.ty.split_null();
+            let single = if field.optional || nullable {
+                "0..1"
+            } else {
+                "1"
+            };
+            let mut targets = Vec::new();
+            named_types(&ty, single, &mut targets);
+            for (target, multiplicity) in targets {
+                relations.push(format!(
+                    "    {} \"1\" *-- \"{multiplicity}\" {target} : {}\n",
+                    def.name, field.key
+                ));
+            }
+        }
+        out.push_str("    }\n");
+    }
+
+    for relation in relations {
+        out.push_str(&relation);
+    }
+    out
+}
+
+// `Message[]`, `Map~string~`, `string | null`; `~` is Mermaid's generic marker
+fn mermaid_type(ty: &TypeRef) -> String {
+    match ty {
+        TypeRef::Named(name) => name.clone(),
+        TypeRef::Array(item) => format!("{}[]", mermaid_type(item)),
+        TypeRef::Map(value) => format!("Map~{}~", mermaid_type(value)),
+        TypeRef::Union(variants) => variants
+            .iter()
+            .map(mermaid_type)
+            .collect::<Vec<_>>()
+            .join(" | "),
+        TypeRef::Enum(_) | TypeRef::String => "string".to_string(),
+        TypeRef::Number => "number".to_string(),
+        TypeRef::Integer => "integer".to_string(),
+        TypeRef::Bool => "boolean".to_string(),
+        TypeRef::Null => "null".to_string(),
+        TypeRef::Any => "any".to_string(),
+    }
+}
+
+// Named types used by a field and how many of them one object holds
+fn named_types(ty: &TypeRef, multiplicity: &'static str, out: &mut Vec<(String, &'static str)>) {
+    match ty {
+        TypeRef::Named(name) if !out.iter().any(|(target, _)| target == name) => {
+            out.push((name.clone(), multiplicity));
+        }
+        TypeRef::Array(inner) | TypeRef::Map(inner) => named_types(inner, "*", out),
+        TypeRef::Union(variants) => {
+            for variant in variants {
+                named_types(variant, multiplicity, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+    use crate::codegen::{to_mermaid, CodegenOptions};
+
+    #[test]
+    fn test_mermaid_diagram() {
+        let template = "{% for message in messages %}{{ message.role ~ '' }}{% if message.tool_call is defined %}{{ message.tool_call.name ~ '' }}{% endif %}{% endfor %}{% set key = 'bleu' %}{{ scores[key].value + 1 }}";
+        let analysis = analyze(template, false).unwrap();
+
+        assert_eq!(
+            to_mermaid(&analysis, &CodegenOptions::default()),
+            r#"classDiagram
+    class Context {
+        +Message[] messages
+        +Map~ScoresValue~ scores
+    }
+    class Message {
+        +string role
+        +ToolCall tool_call?
+    }
+    class ToolCall {
+        +string name
+    }
+    class ScoresValue {
+        +number value
+    }
+    Context "1" *-- "*" Message : messages
+    Context "1" *-- "*" ScoresValue : scores
+    Message "1" *-- "0..1" ToolCall : tool_call
+"#
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use super::{collect_types, CodegenOptions, TypeRef};
+use crate::TemplateAnalysis;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+const KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+    "with", "yield",
+];
+
+/// Flavor of the generated Python definitions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PythonStyle {
+    /// `class Message(TypedDict)`, matching the dicts passed to the renderer
+    #[default]
+    TypedDict,
+    /// `@dataclass class Message`
+    Dataclass,
+}
+
+/// Dependency-free Python definitions (`TypedDict` or `@dataclass`) for the
+/// render context
+pub fn to_python(
+    analysis: &TemplateAnalysis,
+    options: &CodegenOptions,
+    style: PythonStyle,
+) -> String {
+    let mut typing = BTreeSet::new();
+    let mut body = String::new();
+
+    for def in collect_types(analysis, options) {
+        let mut fields = def.fields.clone();
+        if style == PythonStyle::Dataclass {
+            // Fields with a default must follow the ones without
+            fields.sort_by_key(|field| field.optional);
+        }
+        let functional = style == PythonStyle::TypedDict
+            && fields.iter().any(|field| !is_identifier(&field.key));
+
+        body.push_str("\n\n");
+        if functional {
+            // Keys such as `chat-id` need the functional syntax
+            typing.insert("TypedDict");
+            body.push_str(&format!("{} = TypedDict(\"{}\", {{\n", def.name, def.name));
+            for field in &fields {
+                let ty = field_type(&field.ty, field.optional, style, &mut typing);
+                body.push_str(&format!("    {}: {ty},\n", Value::from(field.key.as_str())));
+            }
+            body.push_str("})\n");
+            continue;
+        }
+
+        match style {
+            PythonStyle::TypedDict => {
+                typing.insert("TypedDict");
+                body.push_str(&format!("class {}(TypedDict):\n", def.name));
+            }
+            PythonStyle::Dataclass => {
+                body.push_str(&format!("@dataclass\nclass {}:\n", def.name));
+            }
+        }
+        if fields.is_empty() {
+            body.push_str("    pass\n");
+        }
+        for field in &fields {
+            if let Some(description) = &field.description {
+                body.push_str(&format!("    # {description}\n"));
+            }
+            let name = match style {
+                PythonStyle::TypedDict => field.key.clone(),
+                PythonStyle::Dataclass => attribute_name(&field.key),
+            };
+            if name != field.key {
+                body.push_str(&format!("    # key: {}\n", Value::from(field.key.as_str())));
+            }
+            let ty = field_type(&field.ty, field.optional, style, &mut typing);
+            let default = if style == PythonStyle::Dataclass && field.optional {
+                " = None"
+            } else {
+                ""
+            };
+            body.push_str(&format!("    {name}: {ty}{default}\n"));
+        }
+    }
+
+    let mut out = String::from("# Generated by cleanplate\nfrom __future__ import annotations\n\n");
+    if style == PythonStyle::Dataclass {
+        out.push_str("from dataclasses import dataclass\n");
+    }
+    if !typing.is_empty() {
+        let names = typing.into_iter().collect::<Vec<_>>();
+        out.push_str(&format!("from typing import {}\n", names.join(", ")));
+    }
+    out.push_str(&body);
+    out
+}
+
+// Annotation of a field, wrapping optional fields in `NotRequired` (dicts) or
+// `Optional` (dataclasses, which default them to `None`)
+fn field_type(
+    ty: &TypeRef,
+    optional: bool,
+    style: PythonStyle,
+    typing: &mut BTreeSet<&'static str>,
+) -> String {
+    let ty = python_type(ty, typing);
+    match (optional, style) {
+        (false, _) => ty,
+        (true, PythonStyle::TypedDict) => {
+            typing.insert("NotRequired");
+            format!("NotRequired[{ty}]")
+        }
+        (true, PythonStyle::Dataclass) if ty.starts_with("Optional[") || ty == "Any" => ty,
+        (true, PythonStyle::Dataclass) => {
+            typing.insert("Optional");
+            format!("Optional[{ty}]")
+        }
+    }
+}
+
+fn python_type(ty: &TypeRef, typing: &mut BTreeSet<&'static str>) -> String {
+    match ty {
+        TypeRef::Named(name) => name.clone(),
+        TypeRef::Array(item) => {
+            typing.insert("List");
+            format!("List[{}]", python_type(item, typing))
+        }
+        TypeRef::Map(value) => {
+            typing.insert("Dict");
+            format!("Dict[str, {}]", python_type(value, typing))
+        }
+        TypeRef::Union(variants) if variants.contains(&TypeRef::Any) => {
+            typing.insert("Any");
+            "Any".to_string()
+        }
+        TypeRef::Union(variants) => {
+            let (inner, nullable) = ty.split_null();
+            if nullable {
+                typing.insert("Optional");
+                return format!("Optional[{}]", python_type(&inner, typing));
+            }
+            typing.insert("Union");
+            let variants = variants
+                .iter()
+                .map(|variant| python_type(variant, typing))
+                .collect::<Vec<_>>();
+            format!("Union[{}]", variants.join(", "))
+        }
+        TypeRef::Enum(values) => {
+            typing.insert("Literal");
+            let values = values.iter().map(python_literal).collect::<Vec<_>>();
+            format!("Literal[{}]", values.join(", "))
+        }
+        TypeRef::String => "str".to_string(),
+        TypeRef::Number => "float".to_string(),
+        TypeRef::Integer => "int".to_string(),
+        TypeRef::Bool => "bool".to_string(),
+        TypeRef::Null => "None".to_string(),
+        TypeRef::Any => {
+            typing.insert("Any");
+            "Any".to_string()
+        }
+    }
+}
+
+fn python_literal(value: &Value) -> String {
+    match value {
+        Value::Bool(true) => "True".to_string(),
+        Value::Bool(false) => "False".to_string(),
+        Value::Null => "None".to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&key)
+}
+
+// Identifier for a key in a dataclass (`chat-id` -> `chat_id`, `from` -> `from_`)
+fn attribute_name(key: &str) -> String {
+    let mut name = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    if KEYWORDS.contains(&name.as_str()) {
+        name.push('_');
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+    use crate::codegen::{to_python, CodegenOptions, PythonStyle};
+
+    #[test]
+    fn test_python_definitions() {
+        let template = "{% for message in messages %}{{ message['chat-id'] ~ '' }}{% if message.name is defined %}{{ message.name ~ '' }}{% endif %}{% endfor %}{% if tools is not none %}{{ tools | length }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        let options = CodegenOptions::default();
+
+        assert_eq!(
+            to_python(&analysis, &options, PythonStyle::TypedDict),
+            r#"# Generated by cleanplate
+from __future__ import annotations
+
+from typing import Any, List, NotRequired, Optional, TypedDict
+
+
+class Context(TypedDict):
+    messages: List[Message]
+    tools: Optional[List[Any]]
+
+
+Message = TypedDict("Message", {
+    "chat-id": str,
+    "name": NotRequired[str],
+})
+"#
+        );
+        assert_eq!(
+            to_python(&analysis, &options, PythonStyle::Dataclass),
+            r#"# Generated by cleanplate
+from __future__ import annotations
+
+from dataclasses import dataclass
+from typing import Any, List, Optional
+
+
+@dataclass
+class Context:
+    messages: List[Message]
+    tools: Optional[List[Any]]
+
+
+@dataclass
+class Message:
+    # key: "chat-id"
+    chat_id: str
+    name: Optional[str] = None
+"#
+        );
+    }
+
+    #[test]
+    fn test_python_literal() {
+        let template = "{% if role == 'user' %}{% elif role == 'assistant' %}{% else %}{{ raise_exception('unknown role') }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+
+        for style in [PythonStyle::TypedDict, PythonStyle::Dataclass] {
+            let output = to_python(&analysis, &CodegenOptions::default(), style);
+            assert!(output.contains("    role: Literal[\"user\", \"assistant\"]\n"));
+        }
+    }
+}
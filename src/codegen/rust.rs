@@ -0,0 +1,244 @@
+use super::{collect_types, pascal_case, CodegenOptions, TypeRef};
+use crate::TemplateAnalysis;
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "do", "dyn", "else", "enum",
+    "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "macro",
+    "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static", "struct",
+    "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual", "where",
+    "while", "yield",
+];
+
+/// Rust structs with serde derives for the render context; optional and
+/// nullable fields become `Option<T>`, arrays `Vec<T>` and unions untagged
+/// enums
+pub fn to_rust(analysis: &TemplateAnalysis, options: &CodegenOptions) -> String {
+    let mut out =
+        String::from("// Generated by cleanplate\nuse serde::{Deserialize, Serialize};\n");
+    // Untagged enums for unions, emitted after the structs
+    let mut enums = Vec::new();
+
+    for def in collect_types(analysis, options) {
+        out.push_str("\n#[derive(Debug, Clone, Serialize, Deserialize)]\n");
+        out.push_str(&format!("pub struct {} {{\n", def.name));
+        for field in &def.fields {
+            if let Some(description) = &field.description {
+                out.push_str(&format!("    /// {description}\n"));
+            }
+            let (ty, nullable) = field.ty.split_null();
+            if let TypeRef::Enum(values) = &ty {
+                let values = values.iter().map(ToString::to_string).collect::<Vec<_>>();
+                out.push_str(&format!("    /// One of {}\n", values.join(", ")));
+            }
+
+            let (name, renamed) = field_name(&field.key);
+            let mut serde = Vec::new();
+            if renamed {
+                serde.push(format!("rename = {:?}", field.key));
+            }
+            if field.optional {
+                serde.push("default".to_string());
+                serde.push("skip_serializing_if = \"Option::is_none\"".to_string());
+            }
+            if !serde.is_empty() {
+                out.push_str(&format!("    #[serde({})]\n", serde.join(", ")));
+            }
+
+            let hint = format!("{}{}", def.name, pascal_case(&field.key));
+            let mut rust_ty = rust_type(&ty, &hint, &mut enums);
+            if field.optional || nullable {
+                rust_ty = format!("Option<{rust_ty}>");
+            }
+            out.push_str(&format!("    pub {name}: {rust_ty},\n"));
+        }
+        out.push_str("}\n");
+    }
+
+    for (name, variants) in enums {
+        out.push_str("\n#[derive(Debug, Clone, Serialize, Deserialize)]\n#[serde(untagged)]\n");
+        out.push_str(&format!("pub enum {name} {{\n"));
+        for (variant, ty) in variants {
+            out.push_str(&format!("    {variant}({ty}),\n"));
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+// Rust type of a field; unions are added to `enums` under the `hint` name
+fn rust_type(ty: &TypeRef, hint: &str, enums: &mut Vec<(String, Vec<(String, String)>)>) -> String {
+    match ty {
+        TypeRef::Named(name) => name.clone(),
+        TypeRef::Array(item) => format!("Vec<{}>", rust_type(item, &format!("{hint}Item"), enums)),
+        TypeRef::Map(value) => format!(
+            "std::collections::BTreeMap<String, {}>",
+            rust_type(value, &format!("{hint}Value"), enums)
+        ),
+        TypeRef::Union(variants) if variants.contains(&TypeRef::Any) => {
+            "serde_json::Value".to_string()
+        }
+        TypeRef::Union(variants) => {
+            let (inner, nullable) = ty.split_null();
+            if nullable {
+                return format!("Option<{}>", rust_type(&inner, hint, enums));
+            }
+            let variants = variants
+                .iter()
+                .map(|variant| {
+                    let ty = rust_type(variant, hint, enums);
+                    (variant_name(variant), ty)
+                })
+                .collect();
+            enums.push((hint.to_string(), variants));
+            hint.to_string()
+        }
+        TypeRef::Enum(_) | TypeRef::String => "String".to_string(),
+        TypeRef::Number => "f64".to_string(),
+        TypeRef::Integer => "i64".to_string(),
+        TypeRef::Bool => "bool".to_string(),
+        TypeRef::Null => "Option<serde_json::Value>".to_string(),
+        TypeRef::Any => "serde_json::Value".to_string(),
+    }
+}
+
+fn variant_name(ty: &TypeRef) -> String {
+    match ty {
+        TypeRef::Named(name) => name.clone(),
+        TypeRef::Array(_) => "List".to_string(),
+        TypeRef::Map(_) => "Map".to_string(),
+        TypeRef::Union(_) | TypeRef::Null | TypeRef::Any => "Value".to_string(),
+        TypeRef::Enum(_) | TypeRef::String => "String".to_string(),
+        TypeRef::Number => "Number".to_string(),
+        TypeRef::Integer => "Integer".to_string(),
+        TypeRef::Bool => "Bool".to_string(),
+    }
+}
+
+// Snake case field name for a key (`chat-id` -> `chat_id`, `toolCalls` ->
+// `tool_calls`), and whether it differs from the key
+fn field_name(key: &str) -> (String, bool) {
+    let chars = key.chars().collect::<Vec<_>>();
+    let mut name = String::with_capacity(key.len());
+    for (idx, &c) in chars.iter().enumerate() {
+        if !c.is_ascii_alphanumeric() {
+            name.push('_');
+            continue;
+        }
+        // A word starts at `aB`, `1B` and the last capital of `HTTPServer`
+        if c.is_ascii_uppercase() && idx > 0 {
+            let prev = chars[idx - 1];
+            let next_lower = chars.get(idx + 1).is_some_and(char::is_ascii_lowercase);
+            if prev.is_ascii_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_ascii_uppercase() && next_lower)
+            {
+                name.push('_');
+            }
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    // These keywords can't be raw identifiers
+    if matches!(name.as_str(), "self" | "super" | "crate") {
+        name.push('_');
+    }
+    let renamed = name != key;
+    if KEYWORDS.contains(&name.as_str()) {
+        name = format!("r#{name}");
+    }
+    (name, renamed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::field_name;
+    use crate::analyze;
+    use crate::codegen::{to_rust, CodegenOptions};
+
+    fn rust(template: &str) -> String {
+        to_rust(
+            &analyze(template, false).unwrap(),
+            &CodegenOptions::default(),
+        )
+    }
+
+    #[test]
+    fn test_rust_structs() {
+        let template = "{% for message in messages %}{% if message.content is string %}{{ message.content }}{% else %}{% for part in message.content %}{{ part.text }}{% endfor %}{% endif %}{% endfor %}{% if tools is defined %}{{ tools | length }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        let options = CodegenOptions {
+            root_name: "ChatContext".to_string(),
+        };
+
+        assert_eq!(
+            to_rust(&analysis, &options),
+            r#"// Generated by cleanplate
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatContext {
+    pub messages: Vec<Message>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<serde_json::Value>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub content: MessageContent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentItem {
+    pub text: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    String(String),
+    List(Vec<ContentItem>),
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_rust_field_names() {
+        let output = rust(
+            "{{ type ~ meta['chat-id'] }}{% if weight is not none %}{{ weight + 1 }}{% endif %}",
+        );
+
+        assert!(output.contains("    pub r#type: String,\n"));
+        assert!(output.contains("    #[serde(rename = \"chat-id\")]\n    pub chat_id: String,\n"));
+        assert!(output.contains("    pub weight: Option<f64>,\n"));
+    }
+
+    #[test]
+    fn test_rust_enum_comment() {
+        let template = "{% if role == 'user' %}{% elif role == 'assistant' %}{% else %}{{ raise_exception('unknown role') }}{% endif %}";
+
+        assert!(rust(template)
+            .contains("    /// One of \"user\", \"assistant\"\n    pub role: String,\n"));
+    }
+
+    #[test]
+    fn test_field_names() {
+        for (key, expected) in [
+            ("role", ("role", false)),
+            ("chat-id", ("chat_id", true)),
+            ("toolCalls", ("tool_calls", true)),
+            ("HTTPServer", ("http_server", true)),
+            ("image2Url", ("image2_url", true)),
+            ("type", ("r#type", false)),
+            ("self", ("self_", true)),
+            ("Self", ("self_", true)),
+            ("crate", ("crate_", true)),
+            ("3d", ("_3d", true)),
+        ] {
+            let (name, renamed) = field_name(key);
+            assert_eq!((name.as_str(), renamed), expected, "{key}");
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use super::{collect_types, CodegenOptions, TypeRef};
+use crate::TemplateAnalysis;
+
+/// TypeScript interfaces for the render context, the root interface first
+/// (`export interface Context { messages: Message[]; ... }`)
+pub fn to_typescript(analysis: &TemplateAnalysis, options: &CodegenOptions) -> String {
+    let mut out = String::from("// Generated by cleanplate\n");
+    for def in collect_types(analysis, options) {
+        out.push_str(&format!("\nexport interface {} {{\n", def.name));
+        for field in &def.fields {
+            if let Some(description) = &field.description {
+                out.push_str(&format!("  /** {description} */\n"));
+            }
+            let optional = if field.optional { "?" } else { "" };
+            out.push_str(&format!(
+                "  {}{optional}: {};\n",
+                property_name(&field.key),
+                ts_type(&field.ty)
+            ));
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+fn ts_type(ty: &TypeRef) -> String {
+    match ty {
+        TypeRef::Named(name) => name.clone(),
+        TypeRef::Array(item) => match **item {
+            TypeRef::Union(_) | TypeRef::Enum(_) => format!("({})[]", ts_type(item)),
+            _ => format!("{}[]", ts_type(item)),
+        },
+        TypeRef::Map(value) => format!("Record<string, {}>", ts_type(value)),
+        // `unknown` already admits everything else
+        TypeRef::Union(variants) if variants.contains(&TypeRef::Any) => "unknown".to_string(),
+        TypeRef::Union(variants) => variants.iter().map(ts_type).collect::<Vec<_>>().join(" | "),
+        TypeRef::Enum(values) => values
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" | "),
+        TypeRef::String => "string".to_string(),
+        TypeRef::Number | TypeRef::Integer => "number".to_string(),
+        TypeRef::Bool => "boolean".to_string(),
+        TypeRef::Null => "null".to_string(),
+        TypeRef::Any => "unknown".to_string(),
+    }
+}
+
+// Keys that aren't identifiers are quoted
+fn property_name(key: &str) -> String {
+    let mut chars = key.chars();
+    let identifier = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if identifier {
+        key.to_string()
+    } else {
+        serde_json::Value::from(key).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+    use crate::codegen::{to_typescript, CodegenOptions};
+
+    fn typescript(template: &str) -> String {
+        to_typescript(
+            &analyze(template, false).unwrap(),
+            &CodegenOptions::default(),
+        )
+    }
+
+    #[test]
+    fn test_typescript() {
+        let template = "{# @param messages[].content: text of the message #}{% for message in messages %}{% if message.content is not none %}{{ message.content ~ '' }}{% endif %}{% for call in message.tool_calls %}{{ call.name ~ '' }}{% endfor %}{% endfor %}{% if tools is defined %}{{ tools | tojson }}{% endif %}";
+
+        assert_eq!(
+            typescript(template),
+            r#"// Generated by cleanplate
+
+export interface Context {
+  messages: Message[];
+  tools?: unknown;
+}
+
+export interface Message {
+  /** text of the message */
+  content: string | null;
+  tool_calls: ToolCall[];
+}
+
+export interface ToolCall {
+  name: string;
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_typescript_literal_union() {
+        let template = "{% if role == 'user' %}{% elif role == 'assistant' %}{% else %}{{ raise_exception('unknown role') }}{% endif %}";
+
+        assert!(typescript(template).contains("  role: \"user\" | \"assistant\";\n"));
+    }
+
+    #[test]
+    fn test_typescript_quoted_key() {
+        assert!(typescript("{{ meta['chat-id'] ~ '' }}").contains("  \"chat-id\": string;\n"));
+    }
+}
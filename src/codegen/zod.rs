@@ -0,0 +1,162 @@
+use super::{collect_types, CodegenOptions, TypeRef};
+use crate::TemplateAnalysis;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// Zod schemas (`z.object({...})`) validating the render context at runtime,
+/// with an inferred TypeScript type next to each schema
+pub fn to_zod(analysis: &TemplateAnalysis, options: &CodegenOptions) -> String {
+    let mut out = String::from("// Generated by cleanplate\nimport { z } from \"zod\";\n");
+
+    // Schemas are constants, so nested types are declared before the types using them
+    let types = collect_types(analysis, options);
+    let mut declared = BTreeSet::new();
+    for def in types.iter().rev() {
+        declared.insert(def.name.clone());
+        let mut recursive = false;
+        let mut fields = String::new();
+        for field in &def.fields {
+            let mut schema = zod_type(&field.ty, &declared, &def.name, &mut recursive);
+            if field.optional {
+                schema.push_str(".optional()");
+            }
+            if let Some(description) = &field.description {
+                schema.push_str(&format!(".describe({})", Value::from(description.as_str())));
+            }
+            fields.push_str(&format!(
+                "  {}: {schema},\n",
+                Value::from(field.key.as_str())
+            ));
+        }
+
+        // Self-references go through `z.lazy`, which needs an explicit type
+        let annotation = if recursive { ": z.ZodTypeAny" } else { "" };
+        out.push_str(&format!(
+            "\nexport const {}{annotation} = z.object({{\n{fields}}});\n",
+            def.name
+        ));
+        out.push_str(&format!(
+            "export type {0} = z.infer<typeof {0}>;\n",
+            def.name
+        ));
+    }
+    out
+}
+
+fn zod_type(
+    ty: &TypeRef,
+    declared: &BTreeSet<String>,
+    current: &str,
+    recursive: &mut bool,
+) -> String {
+    match ty {
+        TypeRef::Named(name) if name == current || !declared.contains(name) => {
+            *recursive = true;
+            format!("z.lazy(() => {name})")
+        }
+        TypeRef::Named(name) => name.clone(),
+        TypeRef::Array(item) => {
+            format!("z.array({})", zod_type(item, declared, current, recursive))
+        }
+        TypeRef::Map(value) => format!(
+            "z.record(z.string(), {})",
+            zod_type(value, declared, current, recursive)
+        ),
+        TypeRef::Union(variants) if variants.contains(&TypeRef::Any) => "z.unknown()".to_string(),
+        TypeRef::Union(variants) => {
+            let (inner, nullable) = ty.split_null();
+            if nullable {
+                return format!(
+                    "{}.nullable()",
+                    zod_type(&inner, declared, current, recursive)
+                );
+            }
+            let variants = variants
+                .iter()
+                .map(|variant| zod_type(variant, declared, current, recursive))
+                .collect::<Vec<_>>();
+            format!("z.union([{}])", variants.join(", "))
+        }
+        TypeRef::Enum(values) if values.iter().all(Value::is_string) => {
+            let values = values.iter().map(ToString::to_string).collect::<Vec<_>>();
+            format!("z.enum([{}])", values.join(", "))
+        }
+        TypeRef::Enum(values) => {
+            let literals = values
+                .iter()
+                .map(|value| format!("z.literal({value})"))
+                .collect::<Vec<_>>();
+            match literals.len() {
+                1 => literals[0].clone(),
+                _ => format!("z.union([{}])", literals.join(", ")),
+            }
+        }
+        TypeRef::String => "z.string()".to_string(),
+        TypeRef::Number => "z.number()".to_string(),
+        TypeRef::Integer => "z.number().int()".to_string(),
+        TypeRef::Bool => "z.boolean()".to_string(),
+        TypeRef::Null => "z.null()".to_string(),
+        TypeRef::Any => "z.unknown()".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+    use crate::codegen::{to_zod, CodegenOptions};
+
+    fn zod(template: &str) -> String {
+        to_zod(
+            &analyze(template, false).unwrap(),
+            &CodegenOptions::default(),
+        )
+    }
+
+    #[test]
+    fn test_zod_schemas() {
+        let template = "{# @param tools: functions the model may call #}{% if weight is not none %}{{ weight | int }}{% endif %}{% if tools is defined %}{{ tools | length }}{% endif %}";
+
+        assert_eq!(
+            zod(template),
+            r#"// Generated by cleanplate
+import { z } from "zod";
+
+export const Context = z.object({
+  "tools": z.array(z.unknown()).optional().describe("functions the model may call"),
+  "weight": z.number().int().nullable(),
+});
+export type Context = z.infer<typeof Context>;
+"#
+        );
+    }
+
+    #[test]
+    fn test_zod_recursive_schema() {
+        let template = "{% for item in items recursive %}{{ item.name ~ '' }}{{ loop(item.children) }}{% endfor %}";
+
+        assert_eq!(
+            zod(template),
+            r#"// Generated by cleanplate
+import { z } from "zod";
+
+export const Item: z.ZodTypeAny = z.object({
+  "children": z.array(z.lazy(() => Item)),
+  "name": z.string(),
+});
+export type Item = z.infer<typeof Item>;
+
+export const Context = z.object({
+  "items": z.array(Item),
+});
+export type Context = z.infer<typeof Context>;
+"#
+        );
+    }
+
+    #[test]
+    fn test_zod_enum() {
+        let template = "{% if role == 'user' %}{% elif role == 'assistant' %}{% else %}{{ raise_exception('unknown role') }}{% endif %}";
+
+        assert!(zod(template).contains("  \"role\": z.enum([\"user\", \"assistant\"]),\n"));
+    }
+}
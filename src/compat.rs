@@ -0,0 +1,33 @@
+use crate::{TemplateAnalysis, SCHEMA_VERSION};
+use serde::de::Error;
+use serde_json::Value;
+
+// Reads a serialized analysis of the current layout or the one before it,
+// upgrading the older layout in place first
+pub(crate) fn analysis_from_value(mut value: Value) -> Result<TemplateAnalysis, serde_json::Error> {
+    let Some(fields) = value.as_object_mut() else {
+        return Err(serde_json::Error::custom("expected a JSON object"));
+    };
+    // Version 1 layouts predate the version key
+    let version = match fields.get("schema_version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| serde_json::Error::custom("invalid schema_version"))?,
+    };
+    match version {
+        1 => {
+            // Version 2 added the analysis warnings
+            fields.insert("warnings".to_string(), Value::Array(Vec::new()));
+            fields.insert("schema_version".to_string(), SCHEMA_VERSION.into());
+        }
+        2 => {}
+        other => {
+            return Err(serde_json::Error::custom(format!(
+                "unsupported schema_version {other} (this version reads {} and {SCHEMA_VERSION})",
+                SCHEMA_VERSION - 1
+            )))
+        }
+    }
+    serde_json::from_value(value)
+}
@@ -0,0 +1,88 @@
+use crate::rules::Rule;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the configuration file looked up from the working directory upward
+pub const CONFIG_FILE_NAME: &str = ".cleanplate.toml";
+
+/// Project defaults read from a `.cleanplate.toml`; the keys mirror the command
+/// line flags (`trim-blocks = true`, `custom-filters = ["shout"]`, ...)
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub trim_blocks: bool,
+    pub lstrip_blocks: bool,
+    pub keep_trailing_newline: bool,
+    /// Filters registered in the rendering environment, added to `--custom-filter`
+    pub custom_filters: Vec<String>,
+    /// Refine well-known chat fields with `knowledge::chat_rules`
+    pub chat_knowledge: bool,
+    /// Decode JSON string escaping before parsing, like `--unescape`
+    pub unescape: bool,
+    pub max_depth: Option<usize>,
+    pub max_properties: Option<usize>,
+    /// Variables left out of the context, added to `--ignore-var`
+    pub ignore_vars: Vec<String>,
+    /// Variables the context is limited to, added to `--only-var`
+    pub only_vars: Vec<String>,
+    /// Classification and typing rules (`"*_json: string"`), added to `--rule`
+    pub rules: Vec<Rule>,
+    /// Schema overrides file, relative to the directory of the config file
+    pub overrides: Option<PathBuf>,
+    /// Output format of `analyze` (`report`, `json`, `schema`, ...)
+    pub format: Option<String>,
+}
+
+impl Config {
+    /// Parses a configuration; relative paths are resolved against `base_dir`
+    pub fn parse(content: &str, base_dir: &Path) -> Result<Config, toml::de::Error> {
+        let mut config = toml::from_str::<Config>(content)?;
+        config.overrides = config.overrides.map(|path| base_dir.join(path));
+        Ok(config)
+    }
+
+    /// Reads the configuration file at `path`
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let content = fs::read_to_string(path)?;
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        Config::parse(&content, base_dir)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.message().to_string()))
+    }
+
+    /// The nearest `.cleanplate.toml` in `dir` or one of its ancestors
+    pub fn discover(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = Config::parse(
+            "trim-blocks = true\ncustom-filters = [\"shout\"]\nmax-depth = 8\nignore-vars = [\"bos_token\"]\nrules = [\"*_json: string\"]\noverrides = \"schema/overrides.json\"\nformat = \"json\"\n",
+            Path::new("/project"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.overrides.as_deref(),
+            Some(Path::new("/project/schema/overrides.json"))
+        );
+        assert_eq!(config.format.as_deref(), Some("json"));
+
+        assert!(config.trim_blocks);
+        assert!(!config.lstrip_blocks);
+        assert_eq!(config.custom_filters, vec!["shout"]);
+        assert_eq!(config.max_depth, Some(8));
+        assert_eq!(config.ignore_vars, vec!["bos_token"]);
+        assert_eq!(config.rules, vec!["*_json: string".parse().unwrap()]);
+
+        assert!(Config::parse("trim_blocks = true", Path::new("")).is_err());
+    }
+}
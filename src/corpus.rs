@@ -0,0 +1,546 @@
+use crate::cache::AnalysisCache;
+use crate::{
+    analyze_with, cluster_analyses, fnv1a, merge_analyses, AnalyzeOptions, ShapeCluster,
+    TemplateAnalysis,
+};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+use tracing::{debug, info_span, warn};
+
+/// Analysis of every distinct template of a corpus, with its shape frequencies
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CorpusAnalysis {
+    /// One outcome per distinct template, in first-seen order
+    pub templates: Vec<TemplateOutcome>,
+    pub report: CorpusReport,
+}
+
+/// Outcome of one distinct template of a corpus
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TemplateOutcome {
+    /// `template_id` of the template
+    pub name: String,
+    pub template: String,
+    /// Models sharing the template, in corpus order
+    pub model_ids: Vec<String>,
+    /// The analysis, or the error message when the template failed
+    pub result: Result<TemplateAnalysis, String>,
+}
+
+impl CorpusAnalysis {
+    /// Outcomes of the templates that analyzed successfully
+    pub fn analyses(&self) -> impl Iterator<Item = (&TemplateOutcome, &TemplateAnalysis)> {
+        self.templates
+            .iter()
+            .filter_map(|outcome| Some((outcome, outcome.result.as_ref().ok()?)))
+    }
+
+    /// Outcomes of the templates that failed, with their errors
+    pub fn failures(&self) -> impl Iterator<Item = (&TemplateOutcome, &str)> {
+        self.templates
+            .iter()
+            .filter_map(|outcome| Some((outcome, outcome.result.as_ref().err()?.as_str())))
+    }
+}
+
+/// Analyzes a corpus of `(model_id, template)` pairs: byte-identical templates
+/// are analyzed once for all their models, in parallel, and a template that
+/// fails to parse is recorded with its error instead of stopping the run
+///
+/// ```
+/// let corpus = [
+///     ("model-a".to_string(), "{{ prompt }}".to_string()),
+///     ("model-b".to_string(), "{{ prompt }}".to_string()),
+///     ("model-c".to_string(), "{% if %}".to_string()),
+/// ];
+/// let analysis = cleanplate::analyze_many(corpus, &Default::default());
+/// assert_eq!(analysis.templates.len(), 2);
+/// assert_eq!(analysis.templates[0].model_ids, ["model-a", "model-b"]);
+/// assert_eq!(analysis.report.failed_count, 1);
+/// ```
+pub fn analyze_many(
+    templates: impl IntoIterator<Item = (String, String)>,
+    options: &AnalyzeOptions,
+) -> CorpusAnalysis {
+    let mut corpus = TemplateCorpus::from_sources(
+        templates
+            .into_iter()
+            .map(|(model_id, template)| (template, vec![model_id])),
+    );
+    corpus.analyze(options);
+    let report = corpus.report();
+    let templates = corpus
+        .templates
+        .into_iter()
+        .filter_map(|template| {
+            Some(TemplateOutcome {
+                result: template.outcome?,
+                name: template.name,
+                template: template.source,
+                model_ids: template.model_ids,
+            })
+        })
+        .collect();
+    CorpusAnalysis { templates, report }
+}
+
+/// Stable id of a template source (its FNV-1a hash in hex), naming the
+/// templates of a corpus built from sources
+pub fn template_id(source: &str) -> String {
+    format!("{:016x}", fnv1a(source.bytes()))
+}
+
+/// Named templates with the models using them, and their analyses once
+/// `analyze` has run, for corpus-wide operations: shape frequencies, a merged
+/// analysis, shape families and lookups by variable
+///
+/// ```
+/// use cleanplate::TemplateCorpus;
+///
+/// let mut corpus = TemplateCorpus::new();
+/// corpus.insert("llama", "{% for m in messages %}{{ m.content }}{% endfor %}", ["llama-3"]);
+/// corpus.insert("tools", "{{ messages | length }}{{ tools }}", ["hermes", "qwen"]);
+/// corpus.analyze(&Default::default());
+/// assert_eq!(corpus.requiring("tools").len(), 1);
+/// assert_eq!(corpus.report().model_ids.len(), 3);
+/// assert!(corpus.merged().unwrap().optional_paths.contains("tools"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TemplateCorpus {
+    templates: Vec<CorpusTemplate>,
+    // Position of each template by name
+    index: HashMap<String, usize>,
+}
+
+/// Template of a `TemplateCorpus`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorpusTemplate {
+    pub name: String,
+    pub source: String,
+    /// Models using the template, in the order they were added
+    pub model_ids: Vec<String>,
+    /// The analysis or its error message; `None` until analyzed
+    pub outcome: Option<Result<TemplateAnalysis, String>>,
+}
+
+impl TemplateCorpus {
+    pub fn new() -> TemplateCorpus {
+        TemplateCorpus::default()
+    }
+
+    /// Corpus of template sources and their models, each named by its
+    /// `template_id` so that byte-identical sources are merged
+    pub fn from_sources(
+        sources: impl IntoIterator<Item = (String, Vec<String>)>,
+    ) -> TemplateCorpus {
+        let mut corpus = TemplateCorpus::new();
+        for (source, model_ids) in sources {
+            corpus.insert(template_id(&source), source, model_ids);
+        }
+        corpus
+    }
+
+    /// Adds a template; adding a name again only adds its model ids
+    pub fn insert(
+        &mut self,
+        name: impl Into<String>,
+        source: impl Into<String>,
+        model_ids: impl IntoIterator<Item = impl Into<String>>,
+    ) {
+        let name = name.into();
+        let model_ids = model_ids.into_iter().map(Into::into);
+        match self.index.get(&name) {
+            Some(&idx) => self.templates[idx].model_ids.extend(model_ids),
+            None => {
+                self.index.insert(name.clone(), self.templates.len());
+                self.templates.push(CorpusTemplate {
+                    name,
+                    source: source.into(),
+                    model_ids: model_ids.collect(),
+                    outcome: None,
+                });
+            }
+        }
+    }
+
+    /// Templates in the order they were added
+    pub fn templates(&self) -> &[CorpusTemplate] {
+        &self.templates
+    }
+
+    /// Templates to record outcomes analyzed elsewhere (e.g. restored from a
+    /// cache) in
+    pub fn templates_mut(&mut self) -> &mut [CorpusTemplate] {
+        &mut self.templates
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CorpusTemplate> {
+        self.index.get(name).map(|&idx| &self.templates[idx])
+    }
+
+    pub fn len(&self) -> usize {
+        self.templates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    /// Analyzes the templates without an outcome yet, in parallel
+    pub fn analyze(&mut self, options: &AnalyzeOptions) {
+        self.analyze_range(0..self.templates.len(), options, None);
+    }
+
+    /// Analyzes the templates at `range` without an outcome yet, in parallel,
+    /// looking them up in and storing them to `cache` when there is one
+    pub fn analyze_range(
+        &mut self,
+        range: Range<usize>,
+        options: &AnalyzeOptions,
+        cache: Option<&AnalysisCache>,
+    ) {
+        self.templates[range]
+            .par_iter_mut()
+            .filter(|template| template.outcome.is_none())
+            .for_each(|template| {
+                let _span = info_span!("template", id = %template.name).entered();
+                let outcome = match cache {
+                    Some(cache) => cache.get_or_analyze(&template.source, options),
+                    None => analyze_with(&template.source, options).map_err(|err| err.to_string()),
+                };
+                match &outcome {
+                    Ok(analysis) => debug!(
+                        shape_hash = %format!("{:016x}", analysis.shape_hash()),
+                        "template analyzed"
+                    ),
+                    Err(err) => warn!(error = %err, "template failed to analyze"),
+                }
+                template.outcome = Some(outcome);
+            });
+    }
+
+    /// Templates that analyzed successfully, with their analyses
+    pub fn analyses(&self) -> impl Iterator<Item = (&CorpusTemplate, &TemplateAnalysis)> {
+        self.templates
+            .iter()
+            .filter_map(|template| match &template.outcome {
+                Some(Ok(analysis)) => Some((template, analysis)),
+                _ => None,
+            })
+    }
+
+    /// Templates that failed to analyze, with their errors
+    pub fn failures(&self) -> impl Iterator<Item = (&CorpusTemplate, &str)> {
+        self.templates
+            .iter()
+            .filter_map(|template| match &template.outcome {
+                Some(Err(err)) => Some((template, err.as_str())),
+                _ => None,
+            })
+    }
+
+    /// Shape frequencies and counts of the analyzed templates
+    pub fn report(&self) -> CorpusReport {
+        let mut report = CorpusReport::default();
+        for template in &self.templates {
+            match &template.outcome {
+                Some(Ok(analysis)) => {
+                    report.add(analysis, &template.model_ids);
+                }
+                Some(Err(_)) => report.add_failure(&template.model_ids),
+                None => {}
+            }
+        }
+        report
+    }
+
+    /// One analysis whose shape satisfies every analyzed template (see
+    /// `merge_analyses`); `None` when no template analyzed successfully
+    pub fn merged(&self) -> Option<TemplateAnalysis> {
+        let analyses = self
+            .analyses()
+            .map(|(_, analysis)| analysis.clone())
+            .collect::<Vec<_>>();
+        (!analyses.is_empty()).then(|| merge_analyses(&analyses))
+    }
+
+    /// Families of similar shapes (see `cluster_analyses`), with `members`
+    /// indexing `templates()`
+    pub fn clusters(&self, threshold: f64) -> Vec<ShapeCluster> {
+        let (positions, analyses): (Vec<usize>, Vec<TemplateAnalysis>) = self
+            .templates
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, template)| match &template.outcome {
+                Some(Ok(analysis)) => Some((idx, analysis.clone())),
+                _ => None,
+            })
+            .unzip();
+        let mut clusters = cluster_analyses(&analyses, threshold);
+        for cluster in &mut clusters {
+            for member in &mut cluster.members {
+                *member = positions[*member];
+            }
+        }
+        clusters
+    }
+
+    /// Analyzed templates that require a variable or schema path
+    /// (`tools`, `messages[].role`)
+    pub fn requiring(&self, path: &str) -> Vec<&CorpusTemplate> {
+        self.analyses()
+            .filter(|(_, analysis)| analysis.is_required(path))
+            .map(|(template, _)| template)
+            .collect()
+    }
+}
+
+/// Templates of a corpus that share one shape, with the models using them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShapeBucket {
+    /// `TemplateAnalysis::shape_hash` of the member templates
+    pub shape_hash: u64,
+    /// Example-shaped JSON skeleton of the shape
+    pub object_shapes_json: Value,
+    pub template_count: usize,
+    pub model_ids: BTreeSet<String>,
+    /// Member templates (sources or ids) the caller chose to list
+    pub templates: Vec<String>,
+}
+
+/// Order of the buckets of a `CorpusReport`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BucketOrder {
+    /// Most templates first, then most models
+    TemplateCount,
+    /// Most models first
+    #[default]
+    ModelCount,
+    /// Alphabetically by the shape's JSON
+    Shape,
+}
+
+/// Bucket of a coverage table with the share of the corpus's models it covers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoverageRow<'a> {
+    pub bucket: &'a ShapeBucket,
+    /// Percentage of the models using the bucket's shape
+    pub percent: f64,
+    /// Percentage covered by this bucket and the ones before it
+    pub cumulative: f64,
+}
+
+/// Shape frequencies of a template corpus: the analyzed templates bucketed by
+/// shape hash, with the template and model counts for a summary
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CorpusReport {
+    pub buckets: BTreeMap<u64, ShapeBucket>,
+    pub analyzed_count: usize,
+    pub failed_count: usize,
+    /// Model ids of the analyzed templates, counted once per template
+    pub analyzed_model_count: usize,
+    /// Model ids of the failed templates, counted once per template
+    pub failed_model_count: usize,
+    /// Every model id of the corpus
+    pub model_ids: BTreeSet<String>,
+}
+
+impl CorpusReport {
+    /// Adds an analyzed template used by `model_ids` and returns its bucket
+    pub fn add(&mut self, analysis: &TemplateAnalysis, model_ids: &[String]) -> &mut ShapeBucket {
+        self.analyzed_count += 1;
+        self.analyzed_model_count += model_ids.len();
+        self.model_ids.extend(model_ids.iter().cloned());
+
+        // The canonical shape hash is the frequency key
+        let shape_hash = analysis.shape_hash();
+        let bucket = self
+            .buckets
+            .entry(shape_hash)
+            .or_insert_with(|| ShapeBucket {
+                shape_hash,
+                object_shapes_json: analysis.object_shapes_json(),
+                template_count: 0,
+                model_ids: BTreeSet::new(),
+                templates: Vec::new(),
+            });
+        bucket.template_count += 1;
+        bucket.model_ids.extend(model_ids.iter().cloned());
+        bucket
+    }
+
+    /// Records a template used by `model_ids` that failed to analyze
+    pub fn add_failure(&mut self, model_ids: &[String]) {
+        self.failed_count += 1;
+        self.failed_model_count += model_ids.len();
+        self.model_ids.extend(model_ids.iter().cloned());
+    }
+
+    /// Buckets in the given order, ties in shape hash order
+    pub fn sorted_buckets(&self, order: BucketOrder) -> Vec<&ShapeBucket> {
+        let mut buckets = self.buckets.values().collect::<Vec<_>>();
+        match order {
+            BucketOrder::TemplateCount => buckets
+                .sort_by_key(|bucket| Reverse((bucket.template_count, bucket.model_ids.len()))),
+            BucketOrder::ModelCount => {
+                buckets.sort_by_key(|bucket| Reverse(bucket.model_ids.len()))
+            }
+            BucketOrder::Shape => {
+                buckets.sort_by_cached_key(|bucket| bucket.object_shapes_json.to_string())
+            }
+        }
+        buckets
+    }
+
+    /// The shape frequencies as JSON, one object per bucket in the given order
+    /// with its hex `shape_hash`, `object_shapes_json`, `template_count` and
+    /// `model_id_count`, and the listed `templates` when `with_templates` is set
+    pub fn shape_frequencies(&self, order: BucketOrder, with_templates: bool) -> Vec<Value> {
+        self.sorted_buckets(order)
+            .into_iter()
+            .map(|bucket| {
+                let mut result = json!({
+                    "shape_hash": format!("{:016x}", bucket.shape_hash),
+                    "object_shapes_json": bucket.object_shapes_json,
+                    "template_count": bucket.template_count,
+                    "model_id_count": bucket.model_ids.len(),
+                });
+                if with_templates {
+                    result["templates"] = json!(bucket.templates);
+                }
+                result
+            })
+            .collect()
+    }
+
+    /// Buckets in the given order with the share of the corpus's models each
+    /// covers, up to the first at which the running total reaches `target`
+    /// percent
+    pub fn coverage(&self, order: BucketOrder, target: f64) -> Vec<CoverageRow<'_>> {
+        let total = self.model_ids.len().max(1) as f64;
+        let mut cumulative = 0.0;
+        let mut rows = Vec::new();
+        for bucket in self.sorted_buckets(order) {
+            let percent = bucket.model_ids.len() as f64 / total * 100.0;
+            cumulative += percent;
+            rows.push(CoverageRow {
+                bucket,
+                percent,
+                cumulative,
+            });
+            if cumulative >= target {
+                break;
+            }
+        }
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze;
+
+    #[test]
+    fn test_corpus_report() {
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let chat = analyze("{% for m in messages %}{{ m.content }}{% endfor %}", false).unwrap();
+        let plain = analyze("{{ prompt }}", false).unwrap();
+
+        let mut report = CorpusReport::default();
+        report
+            .add(&chat, &ids(&["a", "b"]))
+            .templates
+            .push("chat".to_string());
+        report.add(&chat, &ids(&["c"]));
+        report.add(&plain, &ids(&["d"]));
+        report.add(&plain, &ids(&["e"]));
+        report.add(&plain, &ids(&["f"]));
+        report.add_failure(&ids(&["g", "h"]));
+
+        assert_eq!(report.analyzed_count, 5);
+        assert_eq!(report.failed_model_count, 2);
+        assert_eq!(report.model_ids.len(), 8);
+        let bucket = &report.buckets[&chat.shape_hash()];
+        assert_eq!(bucket.template_count, 2);
+        assert_eq!(bucket.templates, vec!["chat"]);
+
+        let by_templates = report.sorted_buckets(BucketOrder::TemplateCount);
+        assert_eq!(by_templates[0].shape_hash, plain.shape_hash());
+        let coverage = report.coverage(BucketOrder::ModelCount, 30.0);
+        assert_eq!(coverage.len(), 1);
+        assert_eq!(coverage[0].percent, 37.5);
+        assert_eq!(report.coverage(BucketOrder::ModelCount, 100.0).len(), 2);
+    }
+
+    #[test]
+    fn test_analyze_many() {
+        let corpus = [
+            ("a", "{{ prompt }}"),
+            ("b", "{% for m in messages %}{{ m.content }}{% endfor %}"),
+            ("c", "{% if %}"),
+            ("d", "{{ prompt }}"),
+        ]
+        .map(|(model_id, template)| (model_id.to_string(), template.to_string()));
+        let analysis = analyze_many(corpus, &Default::default());
+
+        assert_eq!(analysis.templates.len(), 3);
+        assert_eq!(analysis.templates[0].model_ids, vec!["a", "d"]);
+        let (outcome, error) = analysis.failures().next().unwrap();
+        assert_eq!(outcome.model_ids, vec!["c"]);
+        assert!(error.contains("syntax error"));
+        assert_eq!(analysis.analyses().count(), 2);
+        assert_eq!(analysis.report.analyzed_model_count, 3);
+        assert_eq!(analysis.report.model_ids.len(), 4);
+        assert_eq!(analysis.report.buckets.len(), 2);
+    }
+
+    #[test]
+    fn test_template_corpus() {
+        let chat = "{% for m in messages %}{{ m.role }}{{ m.content }}{% endfor %}";
+        let mut corpus = TemplateCorpus::from_sources([
+            ("{% if %}".to_string(), vec!["broken".to_string()]),
+            (chat.to_string(), vec!["a".to_string()]),
+            (chat.to_string(), vec!["b".to_string()]),
+        ]);
+        corpus.insert(
+            "tools",
+            "{% for m in messages %}{{ m.role }}{% endfor %}{{ tools }}",
+            ["c"],
+        );
+        assert_eq!(corpus.len(), 3);
+        assert_eq!(
+            corpus.get(&template_id(chat)).unwrap().model_ids,
+            ["a", "b"]
+        );
+
+        corpus.analyze(&Default::default());
+        assert_eq!(corpus.failures().count(), 1);
+        let names = |templates: Vec<&CorpusTemplate>| {
+            templates
+                .into_iter()
+                .map(|template| template.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(corpus.requiring("tools")), vec!["tools"]);
+        assert_eq!(corpus.requiring("messages[].role").len(), 2);
+
+        // Cluster members index the corpus, skipping the failed template
+        let clusters = corpus.clusters(0.5);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].members, vec![1, 2]);
+        let merged = corpus.merged().unwrap();
+        assert!(merged.optional_paths.contains("tools"));
+
+        let frequencies = corpus
+            .report()
+            .shape_frequencies(BucketOrder::ModelCount, false);
+        assert_eq!(frequencies[0]["model_id_count"], 2);
+        assert_eq!(frequencies[0]["template_count"], 1);
+        assert!(frequencies[0].get("templates").is_none());
+    }
+}
@@ -0,0 +1,598 @@
+use crate::lint::target_names;
+use crate::{AnalyzeOptions, SourceSpan};
+use minijinja::machinery::ast::{CallArg, Expr, Stmt};
+use minijinja::machinery::{self, Span};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Scope tree, definitions and reads of a template, with the definitions that
+/// can reach every read (def-use chains) and the paths local names alias
+///
+/// Scopes, definitions and reads are numbered by their index in `scopes`,
+/// `defs` and `uses`, in template order; scope 0 is the template itself.
+///
+/// ```
+/// let template = "{% set who = 'you' %}{% if user %}{% set who = user.name %}{% endif %}{{ who }}";
+/// let flow = cleanplate::dataflow(template, &Default::default()).unwrap();
+/// let read = flow.uses.iter().position(|read| read.name == "who").unwrap();
+/// // Both writes can reach the read, so `who` always has a value
+/// assert_eq!(flow.reaching_defs(read).count(), 2);
+/// assert!(!flow.uses[read].from_context);
+/// assert_eq!(flow.context_paths(1), ["user.name"].map(String::from).into());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DataFlow {
+    pub scopes: Vec<Scope>,
+    pub defs: Vec<Def>,
+    pub uses: Vec<Use>,
+}
+
+/// Region of the template whose assignments are discarded when it ends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub kind: ScopeKind,
+    /// Enclosing scope; `None` for the template
+    pub parent: Option<usize>,
+    pub span: SourceSpan,
+}
+
+/// Construct opening a scope
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeKind {
+    Template,
+    /// Body of a `for` loop
+    Loop,
+    /// Body of a `with` block
+    With,
+    /// Body of a macro or of a `call` block's caller
+    Macro,
+    /// `block` of template inheritance
+    Block,
+}
+
+/// Write of a template-local name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Def {
+    pub name: String,
+    pub kind: DefKind,
+    /// Scope the name is bound in
+    pub scope: usize,
+    pub span: SourceSpan,
+    /// Reads of the assigned value (the iterable of a loop variable, the
+    /// parameter default, the body of a `set` block)
+    pub reads: Vec<usize>,
+    /// Read of the path the name is bound to when the value is a plain
+    /// variable or attribute path (`set m = messages[0]`, `for m in messages`)
+    pub alias_of: Option<usize>,
+}
+
+/// How a definition binds its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefKind {
+    /// `set` statement
+    Set,
+    /// `set` block capturing its body
+    SetBlock,
+    /// Loop variable, bound to each item of the iterable
+    LoopVar,
+    /// Assignment of a `with` block
+    With,
+    /// Macro parameter, with its default as the value
+    MacroParam,
+    /// Macro declaration
+    Macro,
+    /// Name bound by `import` or `from ... import`
+    Import,
+    /// Name the runtime binds: `loop` in a loop, `caller` in a macro
+    Implicit,
+}
+
+/// Read of a variable or attribute path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Use {
+    /// Variable the path starts from
+    pub name: String,
+    /// The path as the template writes it, with constant subscripts as
+    /// attributes or indexes (`message.content`, `messages[0].role`)
+    pub path: String,
+    /// Innermost scope around the read
+    pub scope: usize,
+    pub span: SourceSpan,
+    /// Definitions of `name` that can reach the read
+    pub reaching: Vec<usize>,
+    /// Whether the read can also fall through to the render context, because
+    /// no definition reaches it on some path
+    pub from_context: bool,
+}
+
+impl DataFlow {
+    /// Definitions that can reach a read
+    pub fn reaching_defs(&self, read: usize) -> impl Iterator<Item = &Def> {
+        self.uses[read].reaching.iter().map(|&def| &self.defs[def])
+    }
+
+    /// Reads a definition can reach (its def-use chain)
+    pub fn def_uses(&self, def: usize) -> Vec<usize> {
+        (0..self.uses.len())
+            .filter(|&read| self.uses[read].reaching.contains(&def))
+            .collect()
+    }
+
+    /// Scopes directly nested in a scope
+    pub fn children(&self, scope: usize) -> Vec<usize> {
+        (0..self.scopes.len())
+            .filter(|&child| self.scopes[child].parent == Some(scope))
+            .collect()
+    }
+
+    /// Render context paths a definition aliases, following the alias graph
+    /// through intermediate local names (`messages[]` for `m` in `for m in
+    /// messages`, `messages[].content` for a `set text = m.content` in that
+    /// loop); empty when the value is computed
+    pub fn context_paths(&self, def: usize) -> BTreeSet<String> {
+        let mut paths = BTreeSet::new();
+        self.collect_context_paths(def, "", &mut BTreeSet::new(), &mut paths);
+        paths
+    }
+
+    // Resolves the aliased read of `def` with `suffix` appended, to the paths
+    // it reads from the context
+    fn collect_context_paths(
+        &self,
+        def: usize,
+        suffix: &str,
+        visited: &mut BTreeSet<usize>,
+        paths: &mut BTreeSet<String>,
+    ) {
+        let Some(read) = self.defs[def].alias_of else {
+            return;
+        };
+        if !visited.insert(def) {
+            return;
+        }
+        let items = match self.defs[def].kind {
+            DefKind::LoopVar => "[]",
+            _ => "",
+        };
+        let read = &self.uses[read];
+        let rest = format!("{}{items}{suffix}", &read.path[read.name.len()..]);
+        if read.from_context {
+            paths.insert(format!("{}{rest}", read.name));
+        }
+        for &source in &read.reaching {
+            self.collect_context_paths(source, &rest, visited, paths);
+        }
+        visited.remove(&def);
+    }
+}
+
+/// Builds the data-flow model of a template source string
+///
+/// Each loop iteration is assumed to start from the names visible when the
+/// loop is entered, as assignments in a loop body are discarded with the loop
+/// scope; macros see the names visible where they are declared.
+pub fn dataflow(
+    template_content: &str,
+    options: &AnalyzeOptions,
+) -> Result<DataFlow, Box<dyn std::error::Error>> {
+    let ast = machinery::parse(
+        template_content,
+        "<string>",
+        Default::default(),
+        options.whitespace_config(),
+    )?;
+    let mut builder = Builder {
+        flow: DataFlow::default(),
+        env: BTreeMap::new(),
+        scope: 0,
+    };
+    builder.flow.scopes.push(Scope {
+        kind: ScopeKind::Template,
+        parent: None,
+        span: match &ast {
+            Stmt::Template(template) => template.span().into(),
+            _ => SourceSpan::default(),
+        },
+    });
+    builder.stmt(&ast);
+    Ok(builder.flow)
+}
+
+// Definitions that can provide a name's value, and whether it can come from
+// the context instead; names without an entry come from the context
+#[derive(Debug, Clone, Default)]
+struct Reach {
+    defs: BTreeSet<usize>,
+    from_context: bool,
+}
+
+struct Builder {
+    flow: DataFlow,
+    env: BTreeMap<String, Reach>,
+    scope: usize,
+}
+
+impl Builder {
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Template(template) => self.stmts(&template.children),
+            Stmt::EmitExpr(emit) => self.expr(&emit.expr),
+            Stmt::EmitRaw(_) => {}
+            Stmt::Extends(extends) => self.expr(&extends.name),
+            Stmt::Include(include) => self.expr(&include.name),
+            Stmt::ForLoop(for_loop) => {
+                let start = self.flow.uses.len();
+                self.expr(&for_loop.iter);
+                let reads = (start..self.flow.uses.len()).collect::<Vec<_>>();
+                let alias_of = match (&for_loop.target, path_of(&for_loop.iter)) {
+                    (Expr::Var(_), Some(_)) => Some(start),
+                    _ => None,
+                };
+
+                let (outer, parent) = self.enter(ScopeKind::Loop, for_loop.span());
+                for (name, span) in target_names(&for_loop.target) {
+                    self.define(name, DefKind::LoopVar, span, reads.clone(), alias_of);
+                }
+                self.define("loop", DefKind::Implicit, for_loop.span(), Vec::new(), None);
+                if let Some(filter_expr) = &for_loop.filter_expr {
+                    self.expr(filter_expr);
+                }
+                self.stmts(&for_loop.body);
+                self.leave(outer, parent);
+                self.stmts(&for_loop.else_body);
+            }
+            Stmt::IfCond(if_cond) => {
+                self.expr(&if_cond.expr);
+                let before = self.env.clone();
+                self.stmts(&if_cond.true_body);
+                let taken = std::mem::replace(&mut self.env, before);
+                self.stmts(&if_cond.false_body);
+                let skipped = std::mem::take(&mut self.env);
+                self.env = merge(taken, skipped);
+            }
+            Stmt::WithBlock(with_block) => {
+                let (outer, parent) = self.enter(ScopeKind::With, with_block.span());
+                for (target, value) in &with_block.assignments {
+                    self.assign(target, value, DefKind::With);
+                }
+                self.stmts(&with_block.body);
+                self.leave(outer, parent);
+            }
+            Stmt::Set(set) => self.assign(&set.target, &set.expr, DefKind::Set),
+            Stmt::SetBlock(set_block) => {
+                let start = self.flow.uses.len();
+                if let Some(filter) = &set_block.filter {
+                    self.expr(filter);
+                }
+                self.stmts(&set_block.body);
+                let reads = (start..self.flow.uses.len()).collect::<Vec<_>>();
+                for (name, span) in target_names(&set_block.target) {
+                    self.define(name, DefKind::SetBlock, span, reads.clone(), None);
+                }
+            }
+            Stmt::AutoEscape(auto_escape) => {
+                self.expr(&auto_escape.enabled);
+                self.stmts(&auto_escape.body);
+            }
+            Stmt::FilterBlock(filter_block) => {
+                self.expr(&filter_block.filter);
+                self.stmts(&filter_block.body);
+            }
+            Stmt::Block(block) => {
+                let (outer, parent) = self.enter(ScopeKind::Block, block.span());
+                self.stmts(&block.body);
+                self.leave(outer, parent);
+            }
+            Stmt::Import(import) => {
+                self.expr(&import.expr);
+                for (name, span) in target_names(&import.name) {
+                    self.define(name, DefKind::Import, span, Vec::new(), None);
+                }
+            }
+            Stmt::FromImport(from_import) => {
+                self.expr(&from_import.expr);
+                for (name, alias) in &from_import.names {
+                    for (name, span) in target_names(alias.as_ref().unwrap_or(name)) {
+                        self.define(name, DefKind::Import, span, Vec::new(), None);
+                    }
+                }
+            }
+            Stmt::Macro(macro_decl) => {
+                // Declared first, so the body can call the macro recursively
+                self.define(
+                    macro_decl.name,
+                    DefKind::Macro,
+                    macro_decl.span(),
+                    Vec::new(),
+                    None,
+                );
+                self.macro_body(
+                    &macro_decl.args,
+                    &macro_decl.defaults,
+                    &macro_decl.body,
+                    macro_decl.span(),
+                );
+            }
+            Stmt::CallBlock(call_block) => {
+                self.call(&call_block.call.expr, &call_block.call.args);
+                let decl = &call_block.macro_decl;
+                self.macro_body(&decl.args, &decl.defaults, &decl.body, call_block.span());
+            }
+            Stmt::Do(do_stmt) => self.call(&do_stmt.call.expr, &do_stmt.call.args),
+        }
+    }
+
+    // Opens a child scope, returning the names and scope to restore when it ends
+    fn enter(&mut self, kind: ScopeKind, span: Span) -> (BTreeMap<String, Reach>, usize) {
+        self.flow.scopes.push(Scope {
+            kind,
+            parent: Some(self.scope),
+            span: span.into(),
+        });
+        let parent = std::mem::replace(&mut self.scope, self.flow.scopes.len() - 1);
+        (self.env.clone(), parent)
+    }
+
+    fn leave(&mut self, outer: BTreeMap<String, Reach>, parent: usize) {
+        self.env = outer;
+        self.scope = parent;
+    }
+
+    fn define(
+        &mut self,
+        name: &str,
+        kind: DefKind,
+        span: Span,
+        reads: Vec<usize>,
+        alias_of: Option<usize>,
+    ) {
+        self.flow.defs.push(Def {
+            name: name.to_string(),
+            kind,
+            scope: self.scope,
+            span: span.into(),
+            reads,
+            alias_of,
+        });
+        let reach = Reach {
+            defs: BTreeSet::from([self.flow.defs.len() - 1]),
+            from_context: false,
+        };
+        self.env.insert(name.to_string(), reach);
+    }
+
+    fn assign(&mut self, target: &Expr, value: &Expr, kind: DefKind) {
+        let start = self.flow.uses.len();
+        self.expr(value);
+        let reads = (start..self.flow.uses.len()).collect::<Vec<_>>();
+        let alias_of = match (target, path_of(value)) {
+            (Expr::Var(_), Some(_)) => Some(start),
+            _ => None,
+        };
+        for (name, span) in target_names(target) {
+            self.define(name, kind, span, reads.clone(), alias_of);
+        }
+    }
+
+    // Macro bodies run in their own scope with their parameters and `caller`
+    fn macro_body(&mut self, args: &[Expr], defaults: &[Expr], body: &[Stmt], span: Span) {
+        // Defaults belong to the last parameters and are evaluated at the call
+        let mut default_reads = Vec::new();
+        for default in defaults {
+            let start = self.flow.uses.len();
+            self.expr(default);
+            default_reads.push((start..self.flow.uses.len()).collect::<Vec<_>>());
+        }
+        let (outer, parent) = self.enter(ScopeKind::Macro, span);
+        let first_default = args.len().saturating_sub(defaults.len());
+        for (idx, arg) in args.iter().enumerate() {
+            let reads = match idx.checked_sub(first_default) {
+                Some(default) => default_reads[default].clone(),
+                None => Vec::new(),
+            };
+            for (name, span) in target_names(arg) {
+                self.define(name, DefKind::MacroParam, span, reads.clone(), None);
+            }
+        }
+        self.define("caller", DefKind::Implicit, span, Vec::new(), None);
+        self.stmts(body);
+        self.leave(outer, parent);
+    }
+
+    fn call(&mut self, callee: &Expr, args: &[CallArg]) {
+        match callee {
+            // The object of a method call is read, not the method
+            Expr::GetAttr(get_attr) => self.expr(&get_attr.expr),
+            callee => self.expr(callee),
+        }
+        self.args(args);
+    }
+
+    fn args(&mut self, args: &[CallArg]) {
+        for arg in args {
+            match arg {
+                CallArg::Pos(expr)
+                | CallArg::Kwarg(_, expr)
+                | CallArg::PosSplat(expr)
+                | CallArg::KwargSplat(expr) => self.expr(expr),
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        if let Some((name, path)) = path_of(expr) {
+            let reach = self.env.get(name).cloned().unwrap_or(Reach {
+                defs: BTreeSet::new(),
+                from_context: true,
+            });
+            self.flow.uses.push(Use {
+                name: name.to_string(),
+                path,
+                scope: self.scope,
+                span: expr.span().into(),
+                reaching: reach.defs.into_iter().collect(),
+                from_context: reach.from_context,
+            });
+            return;
+        }
+        match expr {
+            Expr::Var(_) | Expr::Const(_) => {}
+            Expr::Slice(slice) => {
+                self.expr(&slice.expr);
+                for bound in [&slice.start, &slice.stop, &slice.step]
+                    .into_iter()
+                    .flatten()
+                {
+                    self.expr(bound);
+                }
+            }
+            Expr::UnaryOp(unary_op) => self.expr(&unary_op.expr),
+            Expr::BinOp(bin_op) => {
+                self.expr(&bin_op.left);
+                self.expr(&bin_op.right);
+            }
+            Expr::IfExpr(if_expr) => {
+                self.expr(&if_expr.test_expr);
+                self.expr(&if_expr.true_expr);
+                if let Some(false_expr) = &if_expr.false_expr {
+                    self.expr(false_expr);
+                }
+            }
+            Expr::Filter(filter) => {
+                if let Some(expr) = &filter.expr {
+                    self.expr(expr);
+                }
+                self.args(&filter.args);
+            }
+            Expr::Test(test) => {
+                self.expr(&test.expr);
+                self.args(&test.args);
+            }
+            Expr::GetAttr(get_attr) => self.expr(&get_attr.expr),
+            Expr::GetItem(get_item) => {
+                self.expr(&get_item.expr);
+                self.expr(&get_item.subscript_expr);
+            }
+            Expr::Call(call) => self.call(&call.expr, &call.args),
+            Expr::List(list) => {
+                for item in &list.items {
+                    self.expr(item);
+                }
+            }
+            Expr::Map(map) => {
+                for (key, value) in map.keys.iter().zip(&map.values) {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+        }
+    }
+}
+
+// Names visible after an `if`: set by either branch, and also from the
+// context when a branch can leave them unset
+fn merge(
+    taken: BTreeMap<String, Reach>,
+    skipped: BTreeMap<String, Reach>,
+) -> BTreeMap<String, Reach> {
+    let unset = Reach {
+        defs: BTreeSet::new(),
+        from_context: true,
+    };
+    let names = taken
+        .keys()
+        .chain(skipped.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    names
+        .into_iter()
+        .map(|name| {
+            let taken = taken.get(&name).unwrap_or(&unset);
+            let skipped = skipped.get(&name).unwrap_or(&unset);
+            let reach = Reach {
+                defs: taken.defs.union(&skipped.defs).copied().collect(),
+                from_context: taken.from_context || skipped.from_context,
+            };
+            (name, reach)
+        })
+        .collect()
+}
+
+// Root variable and path of a variable read through constant attributes and
+// subscripts (`message.content`, `messages[0]`)
+fn path_of<'a>(expr: &Expr<'a>) -> Option<(&'a str, String)> {
+    match expr {
+        Expr::Var(var) => Some((var.id, var.id.to_string())),
+        Expr::GetAttr(get_attr) => {
+            let (name, path) = path_of(&get_attr.expr)?;
+            Some((name, format!("{path}.{}", get_attr.name)))
+        }
+        Expr::GetItem(get_item) => {
+            let (name, path) = path_of(&get_item.expr)?;
+            let Expr::Const(constant) = &get_item.subscript_expr else {
+                return None;
+            };
+            match (constant.value.as_str(), constant.value.as_i64()) {
+                (Some(key), _) => Some((name, format!("{path}.{key}"))),
+                (None, Some(idx)) => Some((name, format!("{path}[{idx}]"))),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dataflow() {
+        let template = "{% set greeting = 'hi' %}{% for m in messages %}{% set text = m.content %}{{ greeting }}{{ text }}{% endfor %}{{ text }}{% macro row(item, sep=joiner) %}{{ item }}{{ sep }}{% endmacro %}";
+        let flow = dataflow(template, &Default::default()).unwrap();
+        let kinds = flow
+            .scopes
+            .iter()
+            .map(|scope| scope.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![ScopeKind::Template, ScopeKind::Loop, ScopeKind::Macro]
+        );
+        assert_eq!(flow.children(0), vec![1, 2]);
+
+        let def = |name: &str| flow.defs.iter().position(|def| def.name == name).unwrap();
+        let reads = |name: &str| {
+            (0..flow.uses.len())
+                .filter(|&read| flow.uses[read].name == name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(flow.def_uses(def("greeting")), reads("greeting"));
+        assert_eq!(
+            flow.context_paths(def("m")),
+            BTreeSet::from(["messages[]".to_string()])
+        );
+        assert_eq!(
+            flow.context_paths(def("text")),
+            BTreeSet::from(["messages[].content".to_string()])
+        );
+
+        // The loop scope discards `text`, so the read after it hits the context
+        let [inside, after] = reads("text")[..] else {
+            panic!("expected two reads of `text`");
+        };
+        assert_eq!(flow.uses[inside].reaching, vec![def("text")]);
+        assert!(!flow.uses[inside].from_context);
+        assert!(flow.uses[after].reaching.is_empty());
+        assert!(flow.uses[after].from_context);
+
+        let sep = &flow.defs[def("sep")];
+        assert_eq!((sep.kind, sep.scope), (DefKind::MacroParam, 2));
+        assert_eq!(flow.uses[sep.reads[0]].path, "joiner");
+    }
+}
@@ -0,0 +1,397 @@
+use crate::{AnalyzeOptions, SourceSpan};
+use minijinja::machinery::ast::{Expr, Stmt};
+use minijinja::machinery::{self, Span};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Name a template is loaded by through `AnalyzeOptions::loader`
+pub type TemplateId = String;
+
+/// Templates reachable from a root template through `include`, `extends`,
+/// `import` and `from ... import`, loaded with `AnalyzeOptions::loader`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DependencyGraph {
+    pub root: TemplateId,
+    /// Every template reached, including the ones that could not be loaded
+    pub templates: BTreeMap<TemplateId, TemplateNode>,
+    /// Edges in the order the templates reference each other
+    pub edges: Vec<Dependency>,
+    /// References whose template name is computed, so they can't be followed
+    pub dynamic: Vec<(TemplateId, SourceSpan)>,
+}
+
+/// Template of a dependency graph
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TemplateNode {
+    /// Whether the loader found the template
+    pub found: bool,
+    /// Parse error of a template that was found but does not parse
+    pub error: Option<String>,
+}
+
+/// Reference from one template to another
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Dependency {
+    pub from: TemplateId,
+    pub to: TemplateId,
+    pub kind: DependencyKind,
+    /// Location of the tag in the `from` template
+    pub span: SourceSpan,
+}
+
+/// Tag creating a dependency
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyKind {
+    Include,
+    Extends,
+    Import,
+    FromImport,
+}
+
+impl DependencyKind {
+    /// Name of the tag (`include`, `from-import`, ...)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DependencyKind::Include => "include",
+            DependencyKind::Extends => "extends",
+            DependencyKind::Import => "import",
+            DependencyKind::FromImport => "from-import",
+        }
+    }
+}
+
+impl DependencyGraph {
+    /// Templates a template references directly, in template order
+    pub fn dependencies_of(&self, template: &str) -> Vec<&Dependency> {
+        self.edges
+            .iter()
+            .filter(|edge| edge.from == template)
+            .collect()
+    }
+
+    /// Templates a template is referenced by directly
+    pub fn dependents_of(&self, template: &str) -> Vec<&Dependency> {
+        self.edges
+            .iter()
+            .filter(|edge| edge.to == template)
+            .collect()
+    }
+
+    /// Required templates the loader could not find
+    pub fn missing(&self) -> Vec<&str> {
+        self.templates
+            .iter()
+            .filter(|(_, node)| !node.found)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Reference cycles, each as the templates along it (the last one
+    /// references the first); empty when the graph is acyclic
+    pub fn cycles(&self) -> Vec<Vec<TemplateId>> {
+        let mut targets = BTreeMap::<&str, BTreeSet<&str>>::new();
+        for edge in &self.edges {
+            targets.entry(&edge.from).or_default().insert(&edge.to);
+        }
+        let mut cycles = BTreeSet::new();
+        let mut done = BTreeSet::new();
+        let mut path = Vec::new();
+        for template in self.templates.keys() {
+            find_cycles(template, &targets, &mut path, &mut done, &mut cycles);
+        }
+        cycles.into_iter().collect()
+    }
+
+    /// Graphviz DOT digraph of the templates, with missing templates dashed,
+    /// templates that don't parse red and edges labeled with their tag
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from(
+            "digraph templates {\n    rankdir=LR;\n    node [shape=box, fontname=\"monospace\"];\n\n",
+        );
+        for (name, node) in &self.templates {
+            let style = match (node.found, &node.error) {
+                (false, _) => " [style=dashed]",
+                (true, Some(_)) => " [color=red]",
+                (true, None) => "",
+            };
+            out.push_str(&format!("    {}{style};\n", id(name)));
+        }
+        if !self.edges.is_empty() {
+            out.push('\n');
+        }
+        for edge in &self.edges {
+            out.push_str(&format!(
+                "    {} -> {} [label={}];\n",
+                id(&edge.from),
+                id(&edge.to),
+                id(edge.kind.as_str())
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Loads a template and every template it references, transitively, with
+/// `options.loader`; fails when there is no loader or it can't load the root
+///
+/// ```
+/// use cleanplate::AnalyzeOptions;
+///
+/// let options = AnalyzeOptions::builder()
+///     .loader(|name| match name {
+///         "chat.jinja" => Some("{% include 'header.jinja' %}{{ messages }}".to_string()),
+///         "header.jinja" => Some("{% include 'chat.jinja' %}".to_string()),
+///         _ => None,
+///     })
+///     .build();
+/// let graph = cleanplate::dependency_graph("chat.jinja", &options).unwrap();
+/// assert_eq!(graph.edges.len(), 2);
+/// assert_eq!(graph.cycles(), vec![vec!["chat.jinja", "header.jinja"]]);
+/// ```
+pub fn dependency_graph(
+    root: &str,
+    options: &AnalyzeOptions,
+) -> Result<DependencyGraph, Box<dyn std::error::Error>> {
+    let Some(loader) = &options.loader else {
+        return Err("a dependency graph needs a template loader".into());
+    };
+    if loader.load(root).is_none() {
+        return Err(format!("template `{root}` not found").into());
+    }
+
+    let mut graph = DependencyGraph {
+        root: root.to_string(),
+        templates: BTreeMap::new(),
+        edges: Vec::new(),
+        dynamic: Vec::new(),
+    };
+    let mut pending = vec![root.to_string()];
+    while let Some(name) = pending.pop() {
+        if graph.templates.contains_key(&name) {
+            continue;
+        }
+        let Some(source) = loader.load(&name) else {
+            let node = TemplateNode {
+                found: false,
+                error: None,
+            };
+            graph.templates.insert(name, node);
+            continue;
+        };
+        let ast = match machinery::parse(
+            &source,
+            &name,
+            Default::default(),
+            options.whitespace_config(),
+        ) {
+            Ok(ast) => ast,
+            Err(err) => {
+                let node = TemplateNode {
+                    found: true,
+                    error: Some(err.to_string()),
+                };
+                graph.templates.insert(name, node);
+                continue;
+            }
+        };
+
+        let mut references = Vec::new();
+        collect_references(&ast, &mut references);
+        let mut targets = Vec::new();
+        for (kind, expr, span, ignore_missing) in references {
+            let Some(candidates) = template_names(expr) else {
+                graph.dynamic.push((name.clone(), span.into()));
+                continue;
+            };
+            // A list of names renders the first template that exists
+            let target = candidates
+                .iter()
+                .find(|candidate| loader.load(candidate).is_some())
+                .or(candidates.first().filter(|_| !ignore_missing));
+            if let Some(target) = target {
+                graph.edges.push(Dependency {
+                    from: name.clone(),
+                    to: target.to_string(),
+                    kind,
+                    span: span.into(),
+                });
+                targets.push(target.to_string());
+            }
+        }
+        graph.templates.insert(
+            name,
+            TemplateNode {
+                found: true,
+                error: None,
+            },
+        );
+        // Visited depth-first in template order
+        pending.extend(targets.into_iter().rev());
+    }
+    Ok(graph)
+}
+
+// Template references in a statement and the statements nested in it, as
+// (tag, name expression, span, whether a missing template is ignored)
+fn collect_references<'a>(
+    stmt: &'a Stmt<'a>,
+    references: &mut Vec<(DependencyKind, &'a Expr<'a>, Span, bool)>,
+) {
+    let bodies: Vec<&[Stmt]> = match stmt {
+        Stmt::Template(template) => vec![&template.children],
+        Stmt::Include(include) => {
+            references.push((
+                DependencyKind::Include,
+                &include.name,
+                include.span(),
+                include.ignore_missing,
+            ));
+            Vec::new()
+        }
+        Stmt::Extends(extends) => {
+            references.push((
+                DependencyKind::Extends,
+                &extends.name,
+                extends.span(),
+                false,
+            ));
+            Vec::new()
+        }
+        Stmt::Import(import) => {
+            references.push((DependencyKind::Import, &import.expr, import.span(), false));
+            Vec::new()
+        }
+        Stmt::FromImport(from_import) => {
+            references.push((
+                DependencyKind::FromImport,
+                &from_import.expr,
+                from_import.span(),
+                false,
+            ));
+            Vec::new()
+        }
+        Stmt::ForLoop(for_loop) => vec![&for_loop.body, &for_loop.else_body],
+        Stmt::IfCond(if_cond) => vec![&if_cond.true_body, &if_cond.false_body],
+        Stmt::WithBlock(with_block) => vec![&with_block.body],
+        Stmt::SetBlock(set_block) => vec![&set_block.body],
+        Stmt::AutoEscape(auto_escape) => vec![&auto_escape.body],
+        Stmt::FilterBlock(filter_block) => vec![&filter_block.body],
+        Stmt::Block(block) => vec![&block.body],
+        Stmt::Macro(macro_decl) => vec![&macro_decl.body],
+        Stmt::CallBlock(call_block) => vec![&call_block.macro_decl.body],
+        Stmt::EmitExpr(_) | Stmt::EmitRaw(_) | Stmt::Set(_) | Stmt::Do(_) => Vec::new(),
+    };
+    for stmt in bodies.into_iter().flatten() {
+        collect_references(stmt, references);
+    }
+}
+
+// Constant template name, or the names of a constant list of candidates
+fn template_names(expr: &Expr) -> Option<Vec<String>> {
+    match expr {
+        Expr::List(list) => list
+            .items
+            .iter()
+            .map(|item| crate::template_name(item).map(str::to_string))
+            .collect(),
+        expr => Some(vec![crate::template_name(expr)?.to_string()]),
+    }
+}
+
+// Depth-first search recording the path at every edge back into it
+fn find_cycles<'a>(
+    template: &'a str,
+    targets: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+    path: &mut Vec<&'a str>,
+    done: &mut BTreeSet<&'a str>,
+    cycles: &mut BTreeSet<Vec<TemplateId>>,
+) {
+    if done.contains(template) {
+        return;
+    }
+    if let Some(start) = path.iter().position(|&on_path| on_path == template) {
+        // Rotated to start at the smallest name, so each cycle is kept once
+        let mut cycle = path[start..]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let smallest = (0..cycle.len()).min_by_key(|&idx| &cycle[idx]).unwrap_or(0);
+        cycle.rotate_left(smallest);
+        cycles.insert(cycle);
+        return;
+    }
+    path.push(template);
+    for target in targets.get(template).into_iter().flatten() {
+        find_cycles(target, targets, path, done, cycles);
+    }
+    path.pop();
+    done.insert(template);
+}
+
+// Quoted DOT identifier
+fn id(name: &str) -> String {
+    Value::from(name).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dependency_graph() {
+        let options = AnalyzeOptions::builder()
+            .loader(|name| {
+                let source = match name {
+                    "chat.jinja" => "{% extends 'base.jinja' %}{% block body %}{% include ['custom.jinja', 'message.jinja'] %}{% include 'optional.jinja' ignore missing %}{% endblock %}",
+                    "base.jinja" => "{% import 'macros.jinja' as m %}{% include template_name %}{% block body %}{% endblock %}{% include 'footer.jinja' %}",
+                    "message.jinja" => "{% from 'macros.jinja' import row %}{{ row(message) }}",
+                    "macros.jinja" => "{% macro row(x) %}{% include 'message.jinja' %}{% endmacro %}",
+                    "broken.jinja" => "{% if %}",
+                    _ => return None,
+                };
+                Some(source.to_string())
+            })
+            .build();
+        let graph = dependency_graph("chat.jinja", &options).unwrap();
+
+        let edges = graph
+            .edges
+            .iter()
+            .map(|edge| format!("{} -{}-> {}", edge.from, edge.kind.as_str(), edge.to))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            edges,
+            vec![
+                "chat.jinja -extends-> base.jinja",
+                "chat.jinja -include-> message.jinja",
+                "base.jinja -import-> macros.jinja",
+                "base.jinja -include-> footer.jinja",
+                "macros.jinja -include-> message.jinja",
+                "message.jinja -from-import-> macros.jinja",
+            ]
+        );
+        assert_eq!(graph.missing(), vec!["footer.jinja"]);
+        assert_eq!(graph.dynamic.len(), 1);
+        assert_eq!(graph.dynamic[0].0, "base.jinja");
+        assert_eq!(graph.dependents_of("macros.jinja").len(), 2);
+        assert_eq!(
+            graph.cycles(),
+            vec![vec![
+                "macros.jinja".to_string(),
+                "message.jinja".to_string()
+            ]]
+        );
+        assert!(graph
+            .to_dot()
+            .contains("\"chat.jinja\" -> \"base.jinja\" [label=\"extends\"];"));
+
+        let broken = dependency_graph("broken.jinja", &options).unwrap();
+        assert!(broken.templates["broken.jinja"].error.is_some());
+        assert!(broken.cycles().is_empty());
+        assert!(dependency_graph("nope.jinja", &options).is_err());
+        assert!(dependency_graph("chat.jinja", &Default::default()).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::TemplateAnalysis;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Field-level differences between the context shapes of two analyses
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SchemaDiff {
+    /// Schema paths only in the newer analysis, with their type
+    pub added: BTreeMap<String, String>,
+    /// Schema paths only in the older analysis, with their type
+    pub removed: BTreeMap<String, String>,
+    /// Schema paths in both analyses whose type changed
+    pub retyped: BTreeMap<String, TypeChange>,
+}
+
+/// Type of a field before and after a change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeChange {
+    pub before: String,
+    pub after: String,
+}
+
+impl SchemaDiff {
+    /// Whether the two analyses describe the same fields and types
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.retyped.is_empty()
+    }
+}
+
+/// One line per change, in path order: `+ path: type` for added fields,
+/// `- path: type` for removed ones and `~ path: before -> after` for retyped ones
+impl fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = BTreeMap::new();
+        for (path, type_name) in &self.added {
+            lines.insert(path, format!("+ {path}: {type_name}"));
+        }
+        for (path, type_name) in &self.removed {
+            lines.insert(path, format!("- {path}: {type_name}"));
+        }
+        for (path, change) in &self.retyped {
+            lines.insert(
+                path,
+                format!("~ {path}: {} -> {}", change.before, change.after),
+            );
+        }
+        for line in lines.values() {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+// Compares the shape of `before` with the shape of `after`
+pub(crate) fn diff(before: &TemplateAnalysis, after: &TemplateAnalysis) -> SchemaDiff {
+    let old_fields = before.shape.fields();
+    let new_fields = after.shape.fields();
+    let mut diff = SchemaDiff::default();
+
+    for (path, old) in &old_fields {
+        match new_fields.get(path) {
+            None => {
+                diff.removed.insert(path.clone(), old.type_name());
+            }
+            Some(new) if old.type_name() != new.type_name() => {
+                let change = TypeChange {
+                    before: old.type_name(),
+                    after: new.type_name(),
+                };
+                diff.retyped.insert(path.clone(), change);
+            }
+            Some(_) => {}
+        }
+    }
+    for (path, new) in &new_fields {
+        if !old_fields.contains_key(path) {
+            diff.added.insert(path.clone(), new.type_name());
+        }
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TypeChange;
+    use crate::analyze;
+
+    #[test]
+    fn test_schema_diff() {
+        let before = analyze(
+            "{% for message in messages %}{{ message.role }}{{ message.name }}{% endfor %}{{ count }}",
+            false,
+        )
+        .unwrap();
+        let after = analyze(
+            "{% for message in messages %}{{ message.role }}{{ message.weight }}{% endfor %}{{ count + 1 }}",
+            false,
+        )
+        .unwrap();
+
+        assert!(before.diff(&before).is_empty());
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.added.keys().collect::<Vec<_>>(), ["messages[].weight"]);
+        assert_eq!(diff.removed.keys().collect::<Vec<_>>(), ["messages[].name"]);
+        assert_eq!(
+            diff.retyped["count"],
+            TypeChange {
+                before: "unknown".to_string(),
+                after: "number".to_string()
+            }
+        );
+        assert_eq!(
+            diff.to_string(),
+            "~ count: unknown -> number\n- messages[].name: unknown\n+ messages[].weight: unknown\n"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use crate::shape::{is_descendant, Shape};
+use crate::{default_type_hint, TemplateAnalysis, TypeHint};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// Most nested fields listed in an "elements need" reason
+const MAX_LISTED_FIELDS: usize = 5;
+
+/// Human-readable rationale for the inferred shape of one schema path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub path: String,
+    pub required: bool,
+    /// Inferred type (`array`, `string | null`, ...)
+    pub type_name: String,
+    /// Evidence behind the type and requiredness, most important first
+    pub reasons: Vec<String>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let requiredness = if self.required {
+            "required"
+        } else {
+            "optional"
+        };
+        write!(f, "`{}` is {requiredness} ({})", self.path, self.type_name)?;
+        if !self.reasons.is_empty() {
+            write!(f, ": {}", self.reasons.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+// Explains every field of the shape except array items and map values, whose
+// fields are summarized by their container
+pub(crate) fn explain(analysis: &TemplateAnalysis) -> Vec<Explanation> {
+    let fields = analysis.shape.fields();
+    fields
+        .iter()
+        .filter(|(path, _)| !path.ends_with("[]") && !path.ends_with("{}"))
+        .map(|(path, shape)| explain_path(analysis, &fields, path, shape))
+        .collect()
+}
+
+fn explain_path(
+    analysis: &TemplateAnalysis,
+    fields: &BTreeMap<String, &Shape>,
+    path: &str,
+    shape: &Shape,
+) -> Explanation {
+    let mut reasons = Vec::new();
+    let tests = analysis.tests.get(path);
+    let filters = analysis.filters.get(path);
+
+    let lines = origin_lines(analysis, path);
+    let hints = analysis.type_hints.get(path);
+    let iterated = matches!(shape, Shape::Array(_))
+        && !hints.is_some_and(|hints| {
+            hints.contains(&TypeHint::Container) || hints.contains(&TypeHint::Indexed)
+        });
+    match (lines.is_empty(), iterated) {
+        (true, true) => reasons.push("iterated".to_string()),
+        (false, true) => reasons.push(format!("iterated at {}", describe_lines(&lines))),
+        (false, false) => reasons.push(format!("used at {}", describe_lines(&lines))),
+        (true, false) => {}
+    }
+
+    // A hint implied by the default is explained by "defaults to ..." below
+    let default_hint = analysis.defaults.get(path).and_then(default_type_hint);
+    for hint in hints.into_iter().flatten() {
+        if Some(*hint) == default_hint {
+            continue;
+        }
+        reasons.push(
+            match hint {
+                TypeHint::String => "used as a string",
+                TypeHint::Boolean => "only tested for truthiness",
+                TypeHint::Number => "used in arithmetic, `round` or numeric comparisons",
+                TypeHint::Integer => "passed through `int`",
+                TypeHint::Container => "measured with `length`",
+                TypeHint::Indexed => "indexed at a constant position",
+                TypeHint::Json => "serialized with `tojson`",
+            }
+            .to_string(),
+        );
+    }
+    if let Some(values) = analysis.enum_values.get(path) {
+        let values = values.iter().map(ToString::to_string).collect::<Vec<_>>();
+        reasons.push(format!("compared against {}", values.join(", ")));
+    }
+    if analysis.nullable_paths.contains(path) {
+        reasons.push("compared against `none`".to_string());
+    }
+    if analysis.optional_paths.contains(path) {
+        let guard = if tests.is_some_and(|tests| tests.contains("defined")) {
+            "guarded by `is defined`"
+        } else if filters.is_some_and(|filters| filters.contains("default")) {
+            "falls back with `default`"
+        } else {
+            "has an `or` fallback"
+        };
+        reasons.push(guard.to_string());
+    }
+    if let Some(default) = analysis.defaults.get(path) {
+        reasons.push(format!("defaults to {default}"));
+    }
+    if let Some(filters) = filters.filter(|filters| !filters.is_empty()) {
+        let filters = filters.iter().map(|f| format!("`{f}`")).collect::<Vec<_>>();
+        reasons.push(format!("filtered with {}", filters.join(", ")));
+    }
+
+    let (nested_prefix, noun) = match shape {
+        Shape::Array(_) => (format!("{path}[]"), "elements need"),
+        Shape::Map(_) => (format!("{path}{{}}"), "values need"),
+        Shape::Object(_) => (path.to_string(), "needs"),
+        _ => (String::new(), ""),
+    };
+    if !nested_prefix.is_empty() {
+        let nested = nested_leaves(fields, &nested_prefix)
+            .into_iter()
+            .map(|leaf| {
+                let relative = &leaf[nested_prefix.len()..];
+                match origin_lines(analysis, leaf).first() {
+                    Some(line) => format!("`{relative}` (line {line})"),
+                    None => format!("`{relative}`"),
+                }
+            })
+            .collect::<Vec<_>>();
+        if !nested.is_empty() {
+            let mut listed = nested
+                .iter()
+                .take(MAX_LISTED_FIELDS)
+                .cloned()
+                .collect::<Vec<_>>();
+            if nested.len() > MAX_LISTED_FIELDS {
+                listed.push(format!("{} more", nested.len() - MAX_LISTED_FIELDS));
+            }
+            reasons.push(format!("{noun} {}", listed.join(", ")));
+        }
+    }
+
+    for constraint in &analysis.constraints {
+        if constraint.paths.contains(path) {
+            reasons.push(format!("must satisfy `{}`", constraint.condition));
+        }
+    }
+    if let Some(description) = analysis
+        .annotations
+        .get(path)
+        .and_then(|annotation| annotation.description.as_ref())
+    {
+        reasons.push(format!("documented as \"{description}\""));
+    }
+    if analysis.truncated_paths.contains(path) {
+        reasons.push("truncated by the depth or property limit".to_string());
+    }
+
+    Explanation {
+        path: path.to_string(),
+        required: !analysis.optional_paths.contains(path),
+        type_name: shape.type_name(),
+        reasons,
+    }
+}
+
+// Lines the path is read at, when provenance was recorded
+fn origin_lines(analysis: &TemplateAnalysis, path: &str) -> Vec<u32> {
+    let lines = analysis
+        .provenance
+        .as_ref()
+        .and_then(|provenance| provenance.get(path))
+        .into_iter()
+        .flatten()
+        .map(|origin| origin.span.start_line)
+        .collect::<BTreeSet<_>>();
+    lines.into_iter().collect()
+}
+
+fn describe_lines(lines: &[u32]) -> String {
+    let lines = lines.iter().map(ToString::to_string).collect::<Vec<_>>();
+    match lines.len() {
+        1 => format!("line {}", lines[0]),
+        _ => format!("lines {}", lines.join(", ")),
+    }
+}
+
+// Fields below `prefix` that have no fields of their own
+fn nested_leaves<'a>(fields: &'a BTreeMap<String, &Shape>, prefix: &str) -> Vec<&'a String> {
+    fields
+        .keys()
+        .filter(|path| is_descendant(path, prefix))
+        .filter(|path| !fields.keys().any(|other| is_descendant(other, path)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{analyze_with, AnalyzeOptions};
+
+    #[test]
+    fn test_explain() {
+        let template = "{% if tools is defined %}\n{% for tool in tools %}\n{{ tool.function.name }}\n{{ tool.type }}\n{% endfor %}\n{% endif %}\n{% for message in messages %}{% if message.role == 'user' %}{{ message.content | trim }}{% endif %}{% endfor %}";
+        let options = AnalyzeOptions {
+            provenance: true,
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+        let explanations = analysis
+            .explain()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            explanations,
+            vec![
+                "`messages` is required (array): iterated at line 7; elements need `.content` (line 7), `.role` (line 7)",
+                "`messages[].content` is required (unknown): used at line 7; filtered with `trim`",
+                "`messages[].role` is required (string): used at line 7; used as a string; compared against \"user\"",
+                "`tools` is optional (array): iterated at lines 1, 2; guarded by `is defined`; elements need `.function.name` (line 3), `.type` (line 4)",
+                "`tools[].function` is required (object): used at line 3; needs `.name` (line 3)",
+                "`tools[].function.name` is required (unknown): used at line 3",
+                "`tools[].type` is required (unknown): used at line 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explain_indexed() {
+        let template = "{{ messages[0].content }}\n{{ scores | length }}";
+        let options = AnalyzeOptions {
+            provenance: true,
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+        let explanations = analysis
+            .explain()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            explanations[..2],
+            [
+                "`messages` is required (array): used at line 1; indexed at a constant position; elements need `.content` (line 1)",
+                "`messages[].content` is required (unknown): used at line 1",
+            ]
+        );
+        assert!(explanations[2].contains("measured with `length`"));
+    }
+}
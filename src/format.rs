@@ -0,0 +1,131 @@
+// Date and time formats of values, from the strftime patterns and date-shaped
+// constants they are used with
+
+const MONTHS: &[&str] = &[
+    "january",
+    "february",
+    "march",
+    "april",
+    "may",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+];
+
+// strftime directives that print a part of a date or a time of day
+const DATE_DIRECTIVES: &[char] = &[
+    'a', 'A', 'b', 'B', 'c', 'd', 'D', 'e', 'F', 'h', 'j', 'm', 'u', 'U', 'w', 'W', 'x', 'y', 'Y',
+];
+const TIME_DIRECTIVES: &[char] = &['c', 'H', 'I', 'M', 'p', 'r', 'R', 'S', 'T', 'X', 'z', 'Z'];
+
+/// JSON Schema `format` (`date`, `time` or `date-time`) of the values a
+/// strftime pattern such as `%d %b %Y` produces
+pub fn strftime_format(pattern: &str) -> Option<&'static str> {
+    let directives = pattern
+        .split('%')
+        .skip(1)
+        .filter_map(|rest| rest.chars().next());
+    let (mut date, mut time) = (false, false);
+    for directive in directives {
+        date |= DATE_DIRECTIVES.contains(&directive);
+        time |= TIME_DIRECTIVES.contains(&directive);
+    }
+    match (date, time) {
+        (true, true) => Some("date-time"),
+        (true, false) => Some("date"),
+        (false, true) => Some("time"),
+        (false, false) => None,
+    }
+}
+
+// JSON Schema `format` of a date-shaped constant: ISO 8601 dates and
+// timestamps, or written dates such as `26 Jul 2024` and `July 26, 2024`
+pub(crate) fn date_format_of(text: &str) -> Option<&'static str> {
+    let bytes = text.as_bytes();
+    let digits = |start: usize, end: usize| {
+        bytes
+            .get(start..end)
+            .is_some_and(|part| part.iter().all(u8::is_ascii_digit))
+    };
+    let iso_date = digits(0, 4)
+        && bytes.get(4) == Some(&b'-')
+        && digits(5, 7)
+        && bytes.get(7) == Some(&b'-')
+        && digits(8, 10);
+    if iso_date && bytes.len() == 10 {
+        return Some("date");
+    }
+    if iso_date
+        && matches!(bytes.get(10), Some(b'T' | b' '))
+        && digits(11, 13)
+        && bytes.get(13) == Some(&b':')
+        && digits(14, 16)
+    {
+        return Some("date-time");
+    }
+
+    let words = text
+        .split([' ', ','])
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let is_month = |word: &str| {
+        let word = word.to_lowercase();
+        word.len() >= 3 && MONTHS.iter().any(|month| month.starts_with(&word))
+    };
+    let is_year = |word: &str| word.len() == 4 && word.bytes().all(|b| b.is_ascii_digit());
+    let written = (2..=4).contains(&words.len())
+        && words.iter().any(|word| is_month(word))
+        && words.iter().any(|word| is_year(word));
+    written.then_some("date")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze;
+    use serde_json::json;
+
+    #[test]
+    fn test_date_formats() {
+        assert_eq!(strftime_format("%d %b %Y"), Some("date"));
+        assert_eq!(strftime_format("%Y-%m-%d %H:%M"), Some("date-time"));
+        assert_eq!(strftime_format("%H:%M"), Some("time"));
+        assert_eq!(strftime_format("100%%"), None);
+        assert_eq!(date_format_of("2024-07-26"), Some("date"));
+        assert_eq!(date_format_of("2024-07-26T10:00:00Z"), Some("date-time"));
+        assert_eq!(date_format_of("26 Jul 2024"), Some("date"));
+        assert_eq!(date_format_of("July 26, 2024"), Some("date"));
+        assert_eq!(date_format_of("may the force"), None);
+        // Too short for an ISO date
+        assert_eq!(date_format_of("2024"), None);
+        assert_eq!(date_format_of("2024-07"), None);
+        let analysis = analyze(
+            "{% set year = '2024' %}{% if v == '2024' %}{{ year }}{% endif %}{% if d > '2024-07' %}new{% endif %}",
+            false,
+        )
+        .unwrap();
+        assert!(analysis.formats.is_empty());
+
+        let template = "{% if date_string is not defined %}{% set date_string = strftime_now('%d %b %Y') %}{% endif %}{{ date_string }}{% for message in messages %}{{ message.sent.strftime('%H:%M') }}{% if message.created > '2024-01-01T00:00:00Z' %}new{% endif %}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(analysis.formats["date_string"], "%d %b %Y");
+        assert_eq!(analysis.formats["messages[].created"], "date-time");
+
+        let schema = analysis.to_json_schema();
+        assert_eq!(
+            schema["properties"]["date_string"],
+            json!({"type": "string", "format": "date", "x-strftime": "%d %b %Y"})
+        );
+        let message = &schema["properties"]["messages"]["items"]["properties"];
+        // Formatted with `strftime`, so the context passes a timestamp
+        assert_eq!(
+            message["sent"],
+            json!({"type": "string", "format": "date-time"})
+        );
+        assert_eq!(message["created"]["format"], json!("date-time"));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::TemplateAnalysis;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+// Renders the variables and the attributes of the shape as a DOT digraph:
+// external variables are blue boxes, locals grey ellipses and loop variables
+// yellow ellipses, and optional paths are dashed; edges point from an object to its attributes, items and
+// values, from an alias to its source and from a loop variable to its iterable
+pub(crate) fn to_dot(analysis: &TemplateAnalysis) -> String {
+    let mut out =
+        String::from("digraph context {\n    rankdir=LR;\n    node [fontname=\"monospace\"];\n\n");
+
+    for var in &analysis.external_vars {
+        let style = if analysis.optional_paths.contains(var) {
+            "\"filled,dashed\""
+        } else {
+            "filled"
+        };
+        out.push_str(&format!(
+            "    {} [shape=box, style={style}, fillcolor=lightblue];\n",
+            id(var)
+        ));
+    }
+    for var in &analysis.internal_vars {
+        let color = if analysis.loop_vars.contains_key(var) {
+            "lightyellow"
+        } else {
+            "lightgrey"
+        };
+        out.push_str(&format!(
+            "    {} [shape=ellipse, style=filled, fillcolor={color}];\n",
+            id(var)
+        ));
+    }
+
+    // Attribute nodes and edges, from the schema paths of the shape
+    let fields = analysis.shape.fields();
+    let mut edges = BTreeSet::new();
+    for path in fields.keys() {
+        let Some((parent, label)) = parent_of(path) else {
+            continue;
+        };
+        if !analysis.external_vars.contains(path) {
+            let optional = if analysis.optional_paths.contains(path) {
+                ", style=dashed"
+            } else {
+                ""
+            };
+            out.push_str(&format!("    {} [shape=box{optional}];\n", id(path)));
+        }
+        edges.insert((parent, path.clone(), label));
+    }
+
+    if !edges.is_empty() {
+        out.push('\n');
+    }
+    for (parent, path, label) in &edges {
+        out.push_str(&format!(
+            "    {} -> {} [label={}];\n",
+            id(parent),
+            id(path),
+            id(label)
+        ));
+    }
+
+    // Locals that forward to another variable
+    let mut loops = analysis.loop_vars.iter().collect::<Vec<_>>();
+    loops.sort();
+    if !analysis.aliases.is_empty() || !loops.is_empty() {
+        out.push('\n');
+    }
+    for (alias, source) in &analysis.aliases {
+        out.push_str(&format!(
+            "    {} -> {} [label=\"alias\", style=dashed];\n",
+            id(alias),
+            id(source)
+        ));
+    }
+    for (var, iterable) in loops {
+        out.push_str(&format!(
+            "    {} -> {} [label=\"iterates\", style=dotted];\n",
+            id(var),
+            id(iterable)
+        ));
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+// Parent of a schema path and the edge label (`messages[].role` ->
+// (`messages[]`, `role`), `messages[]` -> (`messages`, `[]`))
+pub(crate) fn parent_of(path: &str) -> Option<(String, String)> {
+    if let Some(parent) = path.strip_suffix("[]") {
+        return Some((parent.to_string(), "[]".to_string()));
+    }
+    if let Some(parent) = path.strip_suffix("{}") {
+        return Some((parent.to_string(), "{}".to_string()));
+    }
+    let idx = path.rfind('.')?;
+    Some((path[..idx].to_string(), path[idx + 1..].to_string()))
+}
+
+// Quoted DOT identifier
+fn id(name: &str) -> String {
+    Value::from(name).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+
+    #[test]
+    fn test_dot_graph() {
+        let template = "{% set loop_messages = messages %}{% for message in loop_messages %}{{ message.role }}{% endfor %}{% if tools is defined %}{{ tools | length }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+
+        assert_eq!(
+            analysis.to_dot(),
+            r#"digraph context {
+    rankdir=LR;
+    node [fontname="monospace"];
+
+    "messages" [shape=box, style=filled, fillcolor=lightblue];
+    "tools" [shape=box, style="filled,dashed", fillcolor=lightblue];
+    "loop_messages" [shape=ellipse, style=filled, fillcolor=lightgrey];
+    "message" [shape=ellipse, style=filled, fillcolor=lightyellow];
+    "messages[]" [shape=box];
+    "messages[].role" [shape=box];
+    "tools[]" [shape=box];
+
+    "messages" -> "messages[]" [label="[]"];
+    "messages[]" -> "messages[].role" [label="role"];
+    "tools" -> "tools[]" [label="[]"];
+
+    "loop_messages" -> "messages" [label="alias", style=dashed];
+    "message" -> "loop_messages" [label="iterates", style=dotted];
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Hub the models are fetched from unless `HF_ENDPOINT` names another one
+pub const DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+
+// How long a download may stall before it fails
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Local path of the `tokenizer_config.json` of a model on the Hugging Face
+/// Hub, downloaded on first use (or again with `refresh`) and cached below
+/// the user cache directory
+pub fn tokenizer_config(model_id: &str, revision: &str, refresh: bool) -> io::Result<PathBuf> {
+    let cache_dir = dirs::cache_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no cache directory"))?
+        .join("cleanplate")
+        .join("hub");
+    let path = cache_path(&cache_dir, model_id, revision, "tokenizer_config.json")?;
+    if refresh || !path.is_file() {
+        let content = download(model_id, revision, "tokenizer_config.json")?;
+        fs::create_dir_all(path.parent().unwrap_or(&cache_dir))?;
+        // Write next to the target first so an interrupted download is never cached
+        let partial = path.with_extension("json.part");
+        fs::write(&partial, content)?;
+        fs::rename(&partial, &path)?;
+    }
+    Ok(path)
+}
+
+/// Where a file of a model revision is cached: `<cache_dir>/<model_id>/<revision>/<file>`;
+/// fails for ids and revisions that would leave the cache directory
+pub fn cache_path(
+    cache_dir: &Path,
+    model_id: &str,
+    revision: &str,
+    file: &str,
+) -> io::Result<PathBuf> {
+    let mut path = cache_dir.to_path_buf();
+    for part in model_id.split('/').chain(revision.split('/')) {
+        if part.is_empty() || part == "." || part == ".." || part.contains('\\') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid model id or revision `{model_id}@{revision}`"),
+            ));
+        }
+        path.push(part);
+    }
+    path.push(file);
+    Ok(path)
+}
+
+/// The Hub access token for gated and private models: `HF_TOKEN`, else the
+/// token saved by `huggingface-cli login`
+pub fn token() -> Option<String> {
+    let token = match env::var("HF_TOKEN") {
+        Ok(token) => token,
+        Err(_) => {
+            let hf_home = env::var_os("HF_HOME")
+                .map(PathBuf::from)
+                .or_else(|| dirs::cache_dir().map(|dir| dir.join("huggingface")))?;
+            fs::read_to_string(hf_home.join("token")).ok()?
+        }
+    };
+    let token = token.trim();
+    (!token.is_empty()).then(|| token.to_string())
+}
+
+// Fetches a file of a model revision from the Hub
+fn download(model_id: &str, revision: &str, file: &str) -> io::Result<String> {
+    let endpoint = env::var("HF_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string());
+    let url = format!(
+        "{}/{model_id}/resolve/{}/{file}",
+        endpoint.trim_end_matches('/'),
+        revision.replace('/', "%2F")
+    );
+    let mut request = ureq::AgentBuilder::new()
+        .timeout_read(TIMEOUT)
+        .timeout_connect(TIMEOUT)
+        .build()
+        .get(&url);
+    if let Some(token) = token() {
+        request = request.set("Authorization", &format!("Bearer {token}"));
+    }
+
+    let response = request.call().map_err(|err| match err {
+        ureq::Error::Status(401 | 403, _) => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("access to `{model_id}` was denied; gated and private models need HF_TOKEN or `huggingface-cli login`"),
+        ),
+        ureq::Error::Status(404, _) => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("`{model_id}` has no {file} at revision `{revision}`"),
+        ),
+        ureq::Error::Status(code, _) => {
+            io::Error::other(format!("the Hub answered {code} for {url}"))
+        }
+        ureq::Error::Transport(transport) => io::Error::other(transport.to_string()),
+    })?;
+    let mut content = String::new();
+    response.into_reader().read_to_string(&mut content)?;
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_path() {
+        let cache_dir = Path::new("/cache");
+        assert_eq!(
+            cache_path(
+                cache_dir,
+                "meta-llama/Llama-3.1-8B-Instruct",
+                "main",
+                "tokenizer_config.json"
+            )
+            .unwrap(),
+            Path::new("/cache/meta-llama/Llama-3.1-8B-Instruct/main/tokenizer_config.json")
+        );
+        assert_eq!(
+            cache_path(cache_dir, "gpt2", "refs/pr/1", "tokenizer_config.json").unwrap(),
+            Path::new("/cache/gpt2/refs/pr/1/tokenizer_config.json")
+        );
+        assert!(cache_path(cache_dir, "../etc", "main", "passwd").is_err());
+        assert!(cache_path(cache_dir, "org//model", "main", "x").is_err());
+        assert!(cache_path(cache_dir, "org/model", "..", "x").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::shape::Shape;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Known shape of a well-known chat template field
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownField {
+    /// Schema path the rule applies to (e.g. `messages[].role`)
+    pub path: String,
+    /// Shape merged into the inferred shape of the path
+    pub shape: Shape,
+    /// Allowed values added to the enum candidates of the path
+    pub enum_values: Vec<Value>,
+}
+
+fn object<const N: usize>(properties: [(&str, Shape); N]) -> Shape {
+    Shape::Object(
+        properties
+            .into_iter()
+            .map(|(key, shape)| (key.to_string(), shape))
+            .collect(),
+    )
+}
+
+fn field(path: &str, shape: Shape) -> KnownField {
+    KnownField {
+        path: path.to_string(),
+        shape,
+        enum_values: Vec::new(),
+    }
+}
+
+/// Rules for the fields of the common chat template context (`messages`,
+/// `tools`, special tokens), applied with `AnalyzeOptions::chat_knowledge`
+pub fn chat_rules() -> Vec<KnownField> {
+    let function_call = object([
+        ("name", Shape::String),
+        // Arguments are a JSON-encoded string or an already decoded object
+        (
+            "arguments",
+            Shape::Union(vec![Shape::String, Shape::Map(Box::new(Shape::Any))]),
+        ),
+    ]);
+    let json_schema = object([
+        ("type", Shape::String),
+        ("properties", Shape::Map(Box::new(Shape::Any))),
+        ("required", Shape::Array(Box::new(Shape::String))),
+    ]);
+
+    vec![
+        KnownField {
+            path: "messages[].role".to_string(),
+            shape: Shape::String,
+            enum_values: vec![
+                json!("system"),
+                json!("user"),
+                json!("assistant"),
+                json!("tool"),
+            ],
+        },
+        field("messages[].name", Shape::String),
+        field("messages[].tool_call_id", Shape::String),
+        field(
+            "messages[].tool_calls",
+            Shape::Array(Box::new(object([
+                ("id", Shape::String),
+                ("type", Shape::String),
+                ("function", function_call),
+            ]))),
+        ),
+        field(
+            "tools[].function",
+            object([
+                ("name", Shape::String),
+                ("description", Shape::String),
+                ("parameters", json_schema.clone()),
+            ]),
+        ),
+        field("tools[].function.parameters", json_schema),
+        field("tools[].type", Shape::String),
+        field("add_generation_prompt", Shape::Bool),
+        field("bos_token", Shape::String),
+        field("eos_token", Shape::String),
+    ]
+}
+
+// Chat rules keyed by schema path
+pub(crate) fn chat_rules_by_path() -> BTreeMap<String, KnownField> {
+    chat_rules()
+        .into_iter()
+        .map(|rule| (rule.path.clone(), rule))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{analyze_with, AnalyzeOptions, Shape};
+    use serde_json::json;
+
+    #[test]
+    fn test_chat_knowledge() {
+        let template = "{{ bos_token }}{% for message in messages %}{% if message.role == 'user' %}{{ message.content }}{% endif %}{% for tool_call in message.tool_calls %}{{ tool_call.function.name }}{% endfor %}{% endfor %}";
+
+        let plain = analyze_with(template, &AnalyzeOptions::default()).unwrap();
+        assert_eq!(plain.enum_values["messages[].role"], vec![json!("user")]);
+
+        let options = AnalyzeOptions {
+            chat_knowledge: true,
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+        assert_eq!(
+            analysis.enum_values["messages[].role"],
+            vec![
+                json!("user"),
+                json!("system"),
+                json!("assistant"),
+                json!("tool")
+            ]
+        );
+
+        let schema = analysis.to_json_schema();
+        assert_eq!(schema["properties"]["bos_token"]["type"], json!("string"));
+        let tool_call =
+            &schema["properties"]["messages"]["items"]["properties"]["tool_calls"]["items"];
+        assert_eq!(tool_call["properties"]["id"], json!({"type": "string"}));
+        assert_eq!(
+            tool_call["properties"]["function"]["properties"]["name"],
+            json!({"type": "string"})
+        );
+        // Rules only refine paths the template uses
+        assert!(!matches!(&analysis.shape, Shape::Object(p) if p.contains_key("tools")));
+    }
+}
@@ -1,15 +1,695 @@
 use minijinja::machinery;
-use minijinja::machinery::ast::Const;
-use serde_json::{json, Map, Value};
-use std::collections::{BTreeSet, HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use tracing::{debug, debug_span, trace};
+
+mod annotated;
+pub mod annotations;
+pub mod batch;
+pub mod cache;
+pub mod cluster;
+pub mod codegen;
+mod compat;
+pub mod config;
+pub mod corpus;
+pub mod dataflow;
+pub mod dependencies;
+pub mod diff;
+pub mod explain;
+pub mod format;
+mod graph;
+pub mod hub;
+pub mod knowledge;
+pub mod lint;
+mod markdown;
+pub mod merge;
+pub mod pointer;
+pub mod rules;
+pub mod sample;
+pub mod schema;
+pub mod shape;
+pub mod source;
+pub mod stats;
+mod tree;
+pub mod validate;
+pub mod visitor;
+
+pub use annotations::Annotation;
+pub use cluster::{cluster_analyses, ShapeCluster};
+pub use corpus::{analyze_many, CorpusAnalysis, CorpusReport, ShapeBucket, TemplateCorpus};
+pub use dataflow::{dataflow, DataFlow};
+pub use dependencies::{dependency_graph, DependencyGraph};
+pub use diff::SchemaDiff;
+pub use explain::Explanation;
+pub use lint::{lint, Lint};
+pub use merge::merge_analyses;
+pub use pointer::FieldPointer;
+pub use sample::ContextFuzzer;
+pub use schema::SchemaOptions;
+pub use shape::{Definition, Shape};
+pub use stats::{template_stats, TemplateStats};
+pub use validate::{ContextViolation, ViolationKind};
+pub use visitor::AnalysisVisitor;
+
+/// Version of the serialized `TemplateAnalysis` layout, bumped whenever a
+/// change to it would break readers of stored analyses
+pub const SCHEMA_VERSION: u32 = 2;
 
 /// Core structure to represent template analysis results
-#[derive(Debug, Clone)]
+///
+/// The serde layout is stable, so analyses can be cached, snapshot-tested and
+/// compared across runs: a JSON object with one key per field (named as the
+/// field, every key always present), where sets are sorted arrays, maps are
+/// objects sorted by key, `Option`s are `null` when unset and enums use serde's
+/// externally tagged form (`"String"`, `{"Array": "Number"}`,
+/// `{"Object": {"role": "String"}}`). Changes to the layout bump
+/// `SCHEMA_VERSION`; `from_value` also reads the previous version.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TemplateAnalysis {
+    /// Layout version of the analysis, `SCHEMA_VERSION` when it is produced
+    pub schema_version: u32,
     pub external_vars: BTreeSet<String>,
     pub internal_vars: BTreeSet<String>,
-    pub loop_vars: HashMap<String, String>,
-    pub object_shapes_json: Value,
+    /// Loop variables and the schema path they iterate
+    pub loop_vars: BTreeMap<String, String>,
+    /// Template-local names bound to another variable (`loop_messages` ->
+    /// `messages`, `value` -> `data{}` in an `items()` loop)
+    pub aliases: BTreeMap<String, String>,
+    /// Schema paths (e.g. `messages[].content`) that are compared against `none`
+    pub nullable_paths: BTreeSet<String>,
+    /// Schema paths guarded by `is defined`, `default()` or an `or` fallback
+    pub optional_paths: BTreeSet<String>,
+    /// Constants each schema path is compared against (`role == 'user'`)
+    pub enum_values: BTreeMap<String, Vec<Value>>,
+    /// Schema paths whose `enum_values` are exhaustive: an `if`/`elif` chain
+    /// compares them against two or more constants and its `else` raises
+    pub closed_enums: BTreeSet<String>,
+    /// Default values for schema paths, taken from macro parameter defaults
+    pub defaults: BTreeMap<String, Value>,
+    /// Filters applied to each schema path (e.g. `messages[].content` -> `trim`)
+    pub filters: BTreeMap<String, BTreeSet<String>>,
+    /// Tests applied to each schema path (e.g. `tools` -> `defined`)
+    pub tests: BTreeMap<String, BTreeSet<String>>,
+    /// Global functions called by the template (e.g. `raise_exception`)
+    pub globals_used: BTreeMap<String, GlobalUsage>,
+    /// Filters that are neither builtins nor registered custom filters
+    pub unknown_filters: BTreeSet<String>,
+    /// Kinds of values each schema path is used as
+    pub type_hints: BTreeMap<String, BTreeSet<TypeHint>>,
+    /// Typed shape of the render context
+    pub shape: Shape,
+    /// Named shapes referenced by `Shape::Ref`, e.g. the items of a recursive loop
+    pub definitions: BTreeMap<String, Definition>,
+    /// Confidence in the inferred type of each schema path in `shape`
+    pub confidence: BTreeMap<String, Confidence>,
+    /// Expressions that made each schema path exist, when requested with
+    /// `AnalyzeOptions::provenance`
+    pub provenance: Option<BTreeMap<String, Vec<FieldOrigin>>>,
+    /// Requirements the template enforces with `raise_exception`
+    pub constraints: Vec<Constraint>,
+    /// Descriptions and type overrides from `{# @param ... #}` comments
+    pub annotations: BTreeMap<String, Annotation>,
+    /// Schema paths cut short by the depth or property limits
+    pub truncated_paths: BTreeSet<String>,
+    /// Number of reads of each schema path in `shape`
+    pub usage_counts: BTreeMap<String, usize>,
+    /// Fraction of templates using each schema path, set by `merge_analyses`
+    pub presence: BTreeMap<String, f64>,
+    /// Item count bounds of arrays, from constant indexes (`messages[1]`) and
+    /// `length` checks guarding `raise_exception`
+    pub cardinality: BTreeMap<String, Cardinality>,
+    /// Date/time format of each schema path: a JSON Schema `format` (`date`,
+    /// `date-time`) or the strftime pattern the value is produced with
+    pub formats: BTreeMap<String, String>,
+    /// Non-fatal problems met while analyzing, in the order they were found
+    pub warnings: Vec<AnalysisWarning>,
+    /// Template name or model id given in `AnalyzeOptions::name`
+    pub name: Option<String>,
+}
+
+impl TemplateAnalysis {
+    /// Reads a serialized analysis of the current layout or of the previous
+    /// `SCHEMA_VERSION` (upgraded on the way), so stored analyses survive a
+    /// crate upgrade; `serde` deserialization reads the current layout only
+    pub fn from_value(value: Value) -> Result<TemplateAnalysis, serde_json::Error> {
+        compat::analysis_from_value(value)
+    }
+
+    /// `from_value` for a JSON document
+    pub fn from_json(json: &str) -> Result<TemplateAnalysis, serde_json::Error> {
+        compat::analysis_from_value(serde_json::from_str(json)?)
+    }
+
+    /// Example-shaped JSON skeleton of the render context
+    pub fn object_shapes_json(&self) -> Value {
+        self.shape.to_json_with_defaults(&self.defaults)
+    }
+
+    /// Stable hash of the inferred shape (see `Shape::shape_hash`), for
+    /// deduplicating and joining analyses across runs
+    pub fn shape_hash(&self) -> u64 {
+        self.shape.shape_hash()
+    }
+
+    /// Checks a concrete render context for missing required variables and
+    /// attributes and for values of the wrong container type
+    pub fn validate_context(&self, ctx: &Value) -> Vec<ContextViolation> {
+        validate::validate_context(self, ctx)
+    }
+
+    /// Keys of a concrete render context the template never reads (e.g.
+    /// `messages[0].name`), such as dead payload fields shipped to the renderer
+    pub fn unused_keys(&self, ctx: &Value) -> Vec<String> {
+        validate::unused_keys(self, ctx)
+    }
+
+    /// Smallest render context satisfying the inferred shape (required paths
+    /// only, one item per array, empty strings, `false`, defaults and enum
+    /// values where known), suitable for smoke-rendering the template
+    pub fn sample_context(&self) -> Value {
+        sample::sample_context(self)
+    }
+
+    /// Endless, seeded stream of random contexts conforming to the inferred
+    /// shape, for fuzz-rendering the template
+    pub fn fuzz_contexts(&self, seed: u64) -> ContextFuzzer<'_> {
+        ContextFuzzer::new(self, seed)
+    }
+
+    /// Whether the context must provide `path` whenever its parent is
+    /// present (`false` for optional and unknown paths)
+    pub fn is_required(&self, path: &str) -> bool {
+        self.type_of(path).is_some() && !self.optional_paths.contains(path)
+    }
+
+    /// Inferred shape at a schema path such as `messages[].content`
+    pub fn type_of(&self, path: &str) -> Option<&Shape> {
+        self.shape.fields().get(path).copied()
+    }
+
+    /// Schema paths below `prefix` (`messages[]` -> `messages[].content`,
+    /// `messages[].role`, ...); an empty prefix lists every path
+    pub fn fields_under(&self, prefix: &str) -> Vec<String> {
+        self.shape
+            .fields()
+            .into_keys()
+            .filter(|path| shape::is_descendant(path, prefix))
+            .collect()
+    }
+
+    /// Every schema path with its RFC 6901 JSON Pointer into
+    /// `to_json_schema()` and its type, requiredness, nullability and confidence
+    pub fn pointer_index(&self) -> Vec<FieldPointer> {
+        pointer::pointer_index(self)
+    }
+
+    /// Rationale for the type and requiredness of every field; line numbers
+    /// are included when the analysis recorded provenance
+    pub fn explain(&self) -> Vec<Explanation> {
+        explain::explain(self)
+    }
+
+    /// Fields added, removed or retyped in `other` compared to this analysis
+    pub fn diff(&self, other: &TemplateAnalysis) -> SchemaDiff {
+        diff::diff(self, other)
+    }
+
+    /// Schema paths ordered by how often the template reads them, most used first
+    pub fn fields_by_usage(&self) -> Vec<(&str, usize)> {
+        let mut fields = self
+            .usage_counts
+            .iter()
+            .map(|(path, count)| (path.as_str(), *count))
+            .collect::<Vec<_>>();
+        fields.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        fields
+    }
+
+    /// Human-readable Markdown docs: a table of the variables with their
+    /// types and annotation descriptions, plus an example context
+    pub fn to_markdown(&self) -> String {
+        markdown::to_markdown(self)
+    }
+
+    /// Tree view of the shape for terminals; `color` adds ANSI colors for
+    /// external, loop-derived and local variables
+    pub fn to_tree(&self, color: bool) -> String {
+        tree::render_tree(self, color)
+    }
+
+    /// Template source with each variable occurrence annotated with its kind
+    /// (external, loop, internal), schema path and type; needs the analysis
+    /// to have recorded provenance, and `color` highlights with ANSI colors
+    pub fn annotate_source(&self, source: &str, color: bool) -> String {
+        annotated::annotate_source(self, source, color)
+    }
+
+    /// Graphviz DOT graph of the variables and attributes, with alias,
+    /// loop-iteration and attribute edges
+    pub fn to_dot(&self) -> String {
+        graph::to_dot(self)
+    }
+
+    /// JSON Schema (Draft 2020-12) describing the render context
+    pub fn to_json_schema(&self) -> Value {
+        self.to_json_schema_with(&SchemaOptions::default())
+    }
+
+    /// JSON Schema of the render context, emitted with the given options
+    pub fn to_json_schema_with(&self, options: &SchemaOptions) -> Value {
+        schema::json_schema(self, options)
+    }
+
+    /// OpenAPI 3.1 `components.schemas` fragment with the context schema as
+    /// component `name` (definitions become `{name}{Definition}` components)
+    pub fn to_openapi_component(&self, name: &str) -> Value {
+        schema::openapi_components(self, name, &SchemaOptions::default())
+    }
+}
+
+/// Location of a construct in the template source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct SourceSpan {
+    pub start_line: u32,
+    pub start_col: u32,
+    pub start_offset: u32,
+    pub end_line: u32,
+    pub end_col: u32,
+    pub end_offset: u32,
+}
+
+impl From<machinery::Span> for SourceSpan {
+    fn from(span: machinery::Span) -> Self {
+        Self {
+            start_line: span.start_line.into(),
+            start_col: span.start_col.into(),
+            start_offset: span.start_offset,
+            end_line: span.end_line.into(),
+            end_col: span.end_col.into(),
+            end_offset: span.end_offset,
+        }
+    }
+}
+
+/// Evidence about the type of a value, gathered from how the template uses it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TypeHint {
+    /// Concatenated with strings or used with string methods
+    String,
+    /// Only ever tested for truthiness in conditions
+    Boolean,
+    /// Used in arithmetic, rounded or compared against numbers
+    Number,
+    /// Passed through `int` or compared against integer literals
+    Integer,
+    /// Measured with `length`/`count`, so a sized collection
+    Container,
+    /// Read at a constant position (`xs[0]`), so a sequence
+    Indexed,
+    /// Serialized with `tojson`, so arbitrary JSON
+    Json,
+}
+
+/// How certain the analysis is about the type of a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Confidence {
+    /// Inferred from a heuristic (`tool_calls` rule, a single `int`/`length`
+    /// filter, truthiness-only use) or no type evidence at all
+    Low,
+    /// Backed by a single piece of direct evidence, or by conflicting evidence
+    Medium,
+    /// Backed by structure (iteration, attribute reads), type tests or
+    /// repeated direct evidence
+    High,
+}
+
+impl Confidence {
+    /// Lowercase name, as emitted in `x-confidence`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Confidence::Low => "low",
+            Confidence::Medium => "medium",
+            Confidence::High => "high",
+        }
+    }
+}
+
+/// Requirement on the render context, derived from a `raise_exception` guard
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Constraint {
+    /// Condition the context must satisfy, in template syntax
+    /// (e.g. `messages[0].role == 'system'`)
+    pub condition: String,
+    /// Message passed to `raise_exception`, when it is a constant
+    pub message: Option<String>,
+    /// Schema paths the condition refers to
+    pub paths: BTreeSet<String>,
+    /// Location of the `raise_exception` call
+    pub span: SourceSpan,
+}
+
+/// Bounds on the number of items of an array
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Cardinality {
+    pub min_items: usize,
+    pub max_items: Option<usize>,
+}
+
+impl Cardinality {
+    /// Bounds satisfying both `self` and `other`
+    pub fn intersect(self, other: Cardinality) -> Cardinality {
+        let max_items = match (self.max_items, other.max_items) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Cardinality {
+            min_items: self.min_items.max(other.min_items),
+            max_items,
+        }
+    }
+    /// Tightest bounds that both `self` and `other` satisfy
+    pub fn widen(self, other: Cardinality) -> Cardinality {
+        Cardinality {
+            min_items: self.min_items.min(other.min_items),
+            max_items: self.max_items.zip(other.max_items).map(|(a, b)| a.max(b)),
+        }
+    }
+}
+
+/// Template expression that caused a field to exist
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldOrigin {
+    /// Source text of the expression (e.g. `message.weight`)
+    pub expression: String,
+    pub span: SourceSpan,
+}
+
+/// Non-fatal problem met while analyzing a template
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalysisWarning {
+    pub code: WarningCode,
+    pub message: String,
+    /// Variable or schema path the warning is about
+    pub path: Option<String>,
+    /// Where in the template, when known
+    pub span: Option<SourceSpan>,
+}
+
+impl AnalysisWarning {
+    /// The warning as a JSON object with its kebab-case `code`, `message` and
+    /// `path`, the form the CLI prints
+    pub fn summary_json(&self) -> Value {
+        serde_json::json!({
+            "code": self.code.as_str(),
+            "message": self.message,
+            "path": self.path,
+        })
+    }
+}
+
+/// Kind of an `AnalysisWarning`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum WarningCode {
+    /// A template-local name hides a context variable the template also reads
+    Shadowing,
+    /// A filter that is neither a builtin nor a registered custom filter
+    UnknownFilter,
+    /// A schema path cut short by the depth or property limits
+    Truncated,
+    /// A statement whose variables the analysis doesn't follow (`import`,
+    /// `do`, `include` without a loader, ...)
+    UnhandledNode,
+}
+
+impl WarningCode {
+    /// Stable kebab-case name of the code, e.g. `unknown-filter`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WarningCode::Shadowing => "shadowing",
+            WarningCode::UnknownFilter => "unknown-filter",
+            WarningCode::Truncated => "truncated",
+            WarningCode::UnhandledNode => "unhandled-node",
+        }
+    }
+}
+
+/// Call count and call sites of a global function
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct GlobalUsage {
+    pub count: usize,
+    pub spans: Vec<SourceSpan>,
+}
+
+/// Global functions shipped with minijinja
+pub const BUILTIN_GLOBALS: &[&str] = &["cycler", "debug", "dict", "joiner", "namespace", "range"];
+
+/// Filters shipped with minijinja (including the `json` and `urlencode` features)
+pub const BUILTIN_FILTERS: &[&str] = &[
+    "abs",
+    "attr",
+    "batch",
+    "bool",
+    "capitalize",
+    "count",
+    "d",
+    "default",
+    "dictsort",
+    "e",
+    "escape",
+    "first",
+    "float",
+    "groupby",
+    "indent",
+    "int",
+    "items",
+    "join",
+    "last",
+    "length",
+    "lines",
+    "list",
+    "lower",
+    "map",
+    "max",
+    "min",
+    "pprint",
+    "reject",
+    "rejectattr",
+    "replace",
+    "reverse",
+    "round",
+    "safe",
+    "select",
+    "selectattr",
+    "slice",
+    "sort",
+    "split",
+    "string",
+    "sum",
+    "title",
+    "tojson",
+    "trim",
+    "unique",
+    "upper",
+    "urlencode",
+];
+
+/// Deepest schema path built when `AnalyzeOptions::max_depth` is not set
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// Most properties per object when `AnalyzeOptions::max_properties` is not set
+pub const DEFAULT_MAX_PROPERTIES: usize = 256;
+
+/// Options controlling template analysis
+#[derive(Debug, Clone, Default)]
+pub struct AnalyzeOptions {
+    /// Emit a `trace` event (through `tracing`) for every variable access and
+    /// inference
+    pub verbose: bool,
+    /// Filters registered in the rendering environment on top of the builtins
+    pub custom_filters: BTreeSet<String>,
+    /// Remove the first newline after a block tag
+    pub trim_blocks: bool,
+    /// Strip leading whitespace from the start of a line up to a block tag
+    pub lstrip_blocks: bool,
+    /// Keep the trailing newline at the end of the template
+    pub keep_trailing_newline: bool,
+    /// Record the expressions behind every schema path in `provenance`
+    pub provenance: bool,
+    /// Refine well-known chat fields (`messages[].role`, `tool_calls`, ...)
+    /// with the rules in `knowledge::chat_rules`
+    pub chat_knowledge: bool,
+    /// Deepest schema path (in segments) to build, defaults to `DEFAULT_MAX_DEPTH`
+    pub max_depth: Option<usize>,
+    /// Most properties kept per object, defaults to `DEFAULT_MAX_PROPERTIES`
+    pub max_properties: Option<usize>,
+    /// Template name or model id, used as the schema `title` and `$id`
+    pub name: Option<String>,
+    /// Variables left out of `external_vars` and the shape, e.g. helpers the
+    /// rendering environment injects
+    pub ignore_vars: BTreeSet<String>,
+    /// When not empty, the only variables kept in `external_vars` and the shape
+    pub only_vars: BTreeSet<String>,
+    /// Fail on filters the environment doesn't provide and on templates the
+    /// loader can't find
+    pub strict: bool,
+    /// Classification and typing rules by name or path pattern (see
+    /// `rules::Rule`), applied after the chat rules
+    pub rules: Vec<rules::Rule>,
+    /// Resolves the templates named by `{% include %}` and `{% extends %}`,
+    /// whose variables become part of the analysis
+    pub loader: Option<TemplateLoader>,
+}
+
+/// Source of the templates a template includes or extends, by name
+#[derive(Clone)]
+pub struct TemplateLoader(Arc<LoadFn>);
+
+type LoadFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+impl TemplateLoader {
+    pub fn new(load: impl Fn(&str) -> Option<String> + Send + Sync + 'static) -> Self {
+        TemplateLoader(Arc::new(load))
+    }
+
+    /// Source of the template called `name`, if there is one
+    pub fn load(&self, name: &str) -> Option<String> {
+        (self.0)(name)
+    }
+}
+
+impl fmt::Debug for TemplateLoader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TemplateLoader")
+    }
+}
+
+impl AnalyzeOptions {
+    /// Builder starting from the default options
+    pub fn builder() -> AnalyzeOptionsBuilder {
+        AnalyzeOptionsBuilder::default()
+    }
+
+    fn keeps_var(&self, var: &str) -> bool {
+        !self.ignore_vars.contains(var)
+            && (self.only_vars.is_empty() || self.only_vars.contains(var))
+            && !self
+                .rules
+                .iter()
+                .any(|rule| rule.action == rules::RuleAction::Constant && rule.matches(var))
+    }
+
+    fn whitespace_config(&self) -> machinery::WhitespaceConfig {
+        machinery::WhitespaceConfig {
+            keep_trailing_newline: self.keep_trailing_newline,
+            lstrip_blocks: self.lstrip_blocks,
+            trim_blocks: self.trim_blocks,
+        }
+    }
+}
+
+/// Builds `AnalyzeOptions` one setting at a time
+///
+/// ```
+/// let options = cleanplate::AnalyzeOptions::builder()
+///     .trim_blocks(true)
+///     .custom_filter("shout")
+///     .max_depth(8)
+///     .build();
+/// assert_eq!(options.max_depth, Some(8));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AnalyzeOptionsBuilder {
+    options: AnalyzeOptions,
+}
+
+impl AnalyzeOptionsBuilder {
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.options.verbose = verbose;
+        self
+    }
+
+    /// Registers a filter of the rendering environment
+    pub fn custom_filter(mut self, name: impl Into<String>) -> Self {
+        self.options.custom_filters.insert(name.into());
+        self
+    }
+
+    pub fn trim_blocks(mut self, trim_blocks: bool) -> Self {
+        self.options.trim_blocks = trim_blocks;
+        self
+    }
+
+    pub fn lstrip_blocks(mut self, lstrip_blocks: bool) -> Self {
+        self.options.lstrip_blocks = lstrip_blocks;
+        self
+    }
+
+    pub fn keep_trailing_newline(mut self, keep_trailing_newline: bool) -> Self {
+        self.options.keep_trailing_newline = keep_trailing_newline;
+        self
+    }
+
+    pub fn provenance(mut self, provenance: bool) -> Self {
+        self.options.provenance = provenance;
+        self
+    }
+
+    pub fn chat_knowledge(mut self, chat_knowledge: bool) -> Self {
+        self.options.chat_knowledge = chat_knowledge;
+        self
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.options.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn max_properties(mut self, max_properties: usize) -> Self {
+        self.options.max_properties = Some(max_properties);
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.options.name = Some(name.into());
+        self
+    }
+
+    /// Leaves a variable out of the context
+    pub fn ignore_var(mut self, var: impl Into<String>) -> Self {
+        self.options.ignore_vars.insert(var.into());
+        self
+    }
+
+    /// Keeps a variable, and only the variables kept this way, in the context
+    pub fn only_var(mut self, var: impl Into<String>) -> Self {
+        self.options.only_vars.insert(var.into());
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        self
+    }
+
+    /// Adds a classification or typing rule
+    pub fn rule(mut self, rule: rules::Rule) -> Self {
+        self.options.rules.push(rule);
+        self
+    }
+
+    /// Resolves included and extended templates with `load`
+    pub fn loader(mut self, load: impl Fn(&str) -> Option<String> + Send + Sync + 'static) -> Self {
+        self.options.loader = Some(TemplateLoader::new(load));
+        self
+    }
+
+    pub fn build(self) -> AnalyzeOptions {
+        self.options
+    }
 }
 
 /// Analyzes a template source string and returns structured analysis data
@@ -17,39 +697,159 @@ pub fn analyze(
     template_content: &str,
     verbose: bool,
 ) -> Result<TemplateAnalysis, Box<dyn std::error::Error>> {
-    if verbose {
-        eprintln!("TEMPLATE ANALYSIS: Starting template analysis with verbose tracing");
-    }
+    analyze_with(
+        template_content,
+        &AnalyzeOptions::builder().verbose(verbose).build(),
+    )
+}
+
+/// Analyzes a template source string with the given options
+pub fn analyze_with(
+    template_content: &str,
+    options: &AnalyzeOptions,
+) -> Result<TemplateAnalysis, Box<dyn std::error::Error>> {
+    run_analysis(template_content, options, None)
+}
+
+/// Analyzes a template source string, calling back `visitor` for every
+/// variable access, loop, `set` and `if` condition along the way
+pub fn analyze_with_visitor(
+    template_content: &str,
+    options: &AnalyzeOptions,
+    visitor: &mut dyn AnalysisVisitor,
+) -> Result<TemplateAnalysis, Box<dyn std::error::Error>> {
+    run_analysis(template_content, options, Some(visitor))
+}
+
+fn run_analysis(
+    template_content: &str,
+    options: &AnalyzeOptions,
+    visitor: Option<&mut dyn AnalysisVisitor>,
+) -> Result<TemplateAnalysis, Box<dyn std::error::Error>> {
+    let _span = debug_span!("analyze", template = options.name.as_deref()).entered();
+    debug!("starting template analysis");
 
     // Parse the template content to get the AST
     let ast = machinery::parse(
         template_content,
         "<string>",
         Default::default(),
-        Default::default(),
+        options.whitespace_config(),
     )?;
 
     // Initialize variable tracker
     let mut variable_tracker = VariableTracker::new();
-    variable_tracker.verbose = verbose;
+    variable_tracker.verbose = options.verbose;
+    variable_tracker.visitor = visitor;
+    variable_tracker.custom_filters = options.custom_filters.clone();
+    variable_tracker.source = template_content.to_string();
+    variable_tracker.chat_knowledge = options.chat_knowledge;
+    variable_tracker.rules = options.rules.clone();
+    variable_tracker.loader = options.loader.clone();
+    variable_tracker.whitespace = options.whitespace_config();
+    variable_tracker.max_depth = options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+    variable_tracker.max_properties = options.max_properties.unwrap_or(DEFAULT_MAX_PROPERTIES);
+    if options.provenance {
+        variable_tracker.origins = Some(HashMap::new());
+    }
 
     // Collect all variables and track their reads/sets
     collect_variables(&ast, &mut variable_tracker);
+    if options.strict && !variable_tracker.missing_templates.is_empty() {
+        let missing = Vec::from_iter(variable_tracker.missing_templates);
+        return Err(format!("templates not found: {}", missing.join(", ")).into());
+    }
 
-    // Convert to neat analysis result
-    let analysis = variable_tracker.to_analysis();
+    // Variables the options leave out and functions of the environment
+    // (called by the template or minijinja builtins) don't become part of the context
+    let dropped = variable_tracker
+        .external_vars
+        .iter()
+        .filter(|var| {
+            !options.keeps_var(var)
+                || variable_tracker.globals_used.contains_key(*var)
+                || BUILTIN_GLOBALS.contains(&var.as_str())
+        })
+        .cloned()
+        .collect::<HashSet<_>>();
+    variable_tracker
+        .external_vars
+        .retain(|var| !dropped.contains(var));
 
-    if verbose {
-        eprintln!("TEMPLATE ANALYSIS: Completed template analysis with {} external variables, {} internal variables, and {} loop variables",
-            analysis.external_vars.len(),
-            analysis.internal_vars.len(),
-            analysis.loop_vars.len()
-        );
+    // Convert to neat analysis result
+    let mut analysis = variable_tracker.to_analysis();
+    analysis.name = options.name.clone();
+    if options.strict && !analysis.unknown_filters.is_empty() {
+        let unknown = Vec::from_iter(analysis.unknown_filters);
+        return Err(format!("unknown filters: {}", unknown.join(", ")).into());
+    }
+    if !dropped.is_empty() {
+        drop_var_paths(&mut analysis, &dropped);
+    }
+    let optional = options
+        .rules
+        .iter()
+        .filter(|rule| rule.action == rules::RuleAction::Optional)
+        .collect::<Vec<_>>();
+    if !optional.is_empty() {
+        let paths = analysis
+            .shape
+            .fields()
+            .into_keys()
+            .filter(|path| optional.iter().any(|rule| rule.matches(path)));
+        analysis.optional_paths.extend(paths);
     }
 
+    debug!(
+        external_vars = analysis.external_vars.len(),
+        internal_vars = analysis.internal_vars.len(),
+        loop_vars = analysis.loop_vars.len(),
+        "completed template analysis"
+    );
+
     Ok(analysis)
 }
 
+// FNV-1a hash of a byte sequence, stable across runs, platforms and Rust
+// versions (unlike the std hasher), for ids and cache keys
+pub(crate) fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Removes everything recorded for the schema paths below the dropped variables
+fn drop_var_paths(analysis: &mut TemplateAnalysis, dropped: &HashSet<String>) {
+    let kept =
+        |path: &String| !dropped.contains(path.split(['.', '[', '{']).next().unwrap_or(path));
+    analysis.nullable_paths.retain(kept);
+    analysis.optional_paths.retain(kept);
+    analysis.truncated_paths.retain(kept);
+    analysis.enum_values.retain(|path, _| kept(path));
+    analysis.closed_enums.retain(kept);
+    analysis.defaults.retain(|path, _| kept(path));
+    analysis.filters.retain(|path, _| kept(path));
+    analysis.tests.retain(|path, _| kept(path));
+    analysis.type_hints.retain(|path, _| kept(path));
+    analysis.confidence.retain(|path, _| kept(path));
+    analysis.annotations.retain(|path, _| kept(path));
+    analysis.usage_counts.retain(|path, _| kept(path));
+    analysis.cardinality.retain(|path, _| kept(path));
+    analysis.formats.retain(|path, _| kept(path));
+    analysis
+        .definitions
+        .retain(|_, definition| kept(&definition.path));
+    if let Some(provenance) = &mut analysis.provenance {
+        provenance.retain(|path, _| kept(path));
+    }
+    analysis
+        .constraints
+        .retain(|constraint| constraint.paths.iter().all(kept));
+    analysis
+        .warnings
+        .retain(|warning| warning.path.as_ref().is_none_or(kept));
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum VarAccess {
     Read,
@@ -58,7 +858,7 @@ enum VarAccess {
     LoopVar(String),  // Loop variable with the iterable name
 }
 
-struct VariableTracker {
+struct VariableTracker<'v> {
     // Track variable accesses in order
     access_log: Vec<(String, VarAccess)>,
 
@@ -67,9 +867,27 @@ struct VariableTracker {
     external_vars: HashSet<String>,
     loop_vars: HashMap<String, String>, // loop_var -> iterable
 
-    // Track attributes of objects and their hierarchical relationships
+    // Attributes read on each schema path
     object_attrs: HashMap<String, BTreeSet<String>>,
 
+    // Schema paths iterated by a `for` loop
+    iterated_paths: HashSet<String>,
+
+    // Schema paths indexed by computed keys or iterated with `items()`
+    mapped_paths: HashSet<String>,
+
+    // Enclosing recursive loops as (item schema path, loop variable)
+    recursive_loops: Vec<(String, String)>,
+
+    // Targets of the enclosing `range(...)` loops, which index arrays
+    index_vars: Vec<String>,
+
+    // Schema paths that repeat the item shape of a recursive loop (path -> item path)
+    recursive_paths: HashMap<String, String>,
+
+    // Item paths of recursive loops and the definition name they are emitted under
+    recursive_defs: BTreeMap<String, String>,
+
     // Track aliases of objects
     object_aliases: HashMap<String, String>,
 
@@ -79,11 +897,99 @@ struct VariableTracker {
     // To track first access of each variable
     first_access: HashMap<String, VarAccess>,
 
-    // Flag to enable verbose debug output
+    // Schema paths that are tested against `none`
+    nullable_paths: HashSet<String>,
+
+    // Schema paths the template can render without
+    optional_paths: HashSet<String>,
+
+    // Constants compared against each schema path
+    enum_values: HashMap<String, Vec<Value>>,
+
+    // Schema paths an `if`/`elif` chain only admits its constants for
+    closed_enums: HashSet<String>,
+
+    // Macro signatures (macro -> [(param, default)])
+    macros: HashMap<String, Vec<(String, Option<Value>)>>,
+
+    // Parameters of the enclosing macro bodies (innermost last), with the
+    // loop variables and aliases derived from them; they shadow the context
+    local_scopes: Vec<HashSet<String>>,
+
+    // Default values for schema paths inferred from macro parameters
+    defaults: HashMap<String, Value>,
+
+    // Filters applied to each schema path
+    filters: HashMap<String, BTreeSet<String>>,
+
+    // Tests applied to each schema path
+    tests: HashMap<String, BTreeSet<String>>,
+
+    // Calls to functions that are not defined in the template
+    globals_used: BTreeMap<String, GlobalUsage>,
+
+    // Every filter name used in the template
+    filter_names: BTreeSet<String>,
+
+    // Filters registered in the rendering environment
+    custom_filters: BTreeSet<String>,
+
+    // Type evidence for each schema path
+    type_hints: HashMap<String, BTreeSet<TypeHint>>,
+
+    // Reads of each schema path
+    read_counts: HashMap<String, usize>,
+
+    // Reads of each schema path that are bare truthiness tests
+    truthiness_counts: HashMap<String, usize>,
+
+    // Reads of each schema path that are type-neutral `is defined` checks
+    neutral_counts: HashMap<String, usize>,
+
+    // Pieces of direct (non-heuristic) type evidence for each schema path
+    evidence_counts: HashMap<String, usize>,
+
+    // Expressions read for each schema path, only collected when requested
+    origins: Option<HashMap<String, Vec<FieldOrigin>>>,
+    source: String,
+
+    // Conditions of the enclosing `if` branches, and the constraints derived
+    // from the `raise_exception` calls they guard
+    guards: Vec<Guard>,
+    constraints: Vec<Constraint>,
+
+    // Item count bounds of arrays, keyed by schema path
+    cardinality: HashMap<String, Cardinality>,
+
+    // Date/time formats (JSON Schema names or strftime patterns), keyed by schema path
+    formats: HashMap<String, String>,
+
+    // Apply the well-known chat field rules, then the rules of the options
+    chat_knowledge: bool,
+    rules: Vec<rules::Rule>,
+
+    // Included and extended templates: how they are found and parsed, the
+    // ones being collected (to stop at cycles) and the ones not found
+    loader: Option<TemplateLoader>,
+    whitespace: machinery::WhitespaceConfig,
+    including: Vec<String>,
+    missing_templates: BTreeSet<String>,
+
+    // Limits on the shape built from the collected paths
+    max_depth: usize,
+    max_properties: usize,
+
+    // Emit trace events for every access and inference
     verbose: bool,
+
+    // Custom analysis pass called back during the traversal
+    visitor: Option<&'v mut dyn AnalysisVisitor>,
+
+    // Shadowed variables and unhandled statements met during the traversal
+    warnings: Vec<AnalysisWarning>,
 }
 
-impl VariableTracker {
+impl VariableTracker<'_> {
     fn new() -> Self {
         Self {
             access_log: Vec::new(),
@@ -91,10 +997,49 @@ impl VariableTracker {
             external_vars: HashSet::new(),
             loop_vars: HashMap::new(),
             object_attrs: HashMap::new(),
+            iterated_paths: HashSet::new(),
+            mapped_paths: HashSet::new(),
+            recursive_loops: Vec::new(),
+            index_vars: Vec::new(),
+            recursive_paths: HashMap::new(),
+            recursive_defs: BTreeMap::new(),
             object_aliases: HashMap::new(),
             var_hierarchy: HashMap::new(),
             first_access: HashMap::new(),
+            nullable_paths: HashSet::new(),
+            optional_paths: HashSet::new(),
+            enum_values: HashMap::new(),
+            closed_enums: HashSet::new(),
+            macros: HashMap::new(),
+            local_scopes: Vec::new(),
+            defaults: HashMap::new(),
+            filters: HashMap::new(),
+            tests: HashMap::new(),
+            globals_used: BTreeMap::new(),
+            filter_names: BTreeSet::new(),
+            custom_filters: BTreeSet::new(),
+            type_hints: HashMap::new(),
+            read_counts: HashMap::new(),
+            truthiness_counts: HashMap::new(),
+            neutral_counts: HashMap::new(),
+            evidence_counts: HashMap::new(),
+            origins: None,
+            source: String::new(),
+            guards: Vec::new(),
+            constraints: Vec::new(),
+            cardinality: HashMap::new(),
+            formats: HashMap::new(),
+            chat_knowledge: false,
+            rules: Vec::new(),
+            loader: None,
+            whitespace: Default::default(),
+            including: Vec::new(),
+            missing_templates: BTreeSet::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_properties: DEFAULT_MAX_PROPERTIES,
             verbose: false,
+            visitor: None,
+            warnings: Vec::new(),
         }
     }
 
@@ -111,19 +1056,47 @@ impl VariableTracker {
 
         // TODO: handle other special cases
 
-        // Debug logging when verbose mode is enabled
+        // Macro parameters and names bound from them are local to the macro
+        // body and never describe the context
+        if self.is_local(var_name) {
+            return;
+        }
+        if let VarAccess::LoopVar(target) | VarAccess::SetAlias(target) = &access {
+            if self.is_local(target) {
+                if let Some(scope) = self.local_scopes.last_mut() {
+                    scope.insert(var_name.to_string());
+                }
+                return;
+            }
+        }
+
         if self.verbose {
-            let access_desc = match &access {
-                VarAccess::Read => "READ".to_string(),
-                VarAccess::Set => "SET".to_string(),
-                VarAccess::SetAlias(alias) => format!("SET ALIAS to {alias}"),
-                VarAccess::LoopVar(iterable) => format!("LOOP VAR from {iterable}"),
-            };
-            eprintln!("VARIABLE TRACKER: {var_name} => {access_desc}");
+            trace!(var = var_name, ?access, "access");
         }
 
         // Add to access log
         self.access_log.push((var_name.to_string(), access.clone()));
+        if self.visitor.is_some() {
+            let event = visitor::VariableAccess {
+                name: var_name.to_string(),
+                schema_path: self.tracked_schema_path(var_name),
+                kind: match &access {
+                    VarAccess::Read => visitor::AccessKind::Read,
+                    VarAccess::Set => visitor::AccessKind::Set,
+                    VarAccess::SetAlias(target) => visitor::AccessKind::Alias(target.clone()),
+                    VarAccess::LoopVar(iterable) => visitor::AccessKind::LoopVar(iterable.clone()),
+                },
+            };
+            if let Some(visitor) = self.visitor.as_deref_mut() {
+                visitor.visit_access(&event);
+            }
+        }
+
+        if access == VarAccess::Read {
+            if let Some(path) = self.tracked_schema_path(var_name) {
+                *self.read_counts.entry(path).or_default() += 1;
+            }
+        }
 
         // Process attribute access and build hierarchy
         if let Some(idx) = var_name.rfind('.') {
@@ -145,21 +1118,26 @@ impl VariableTracker {
                     .insert(parent[base_idx + 1..].to_string());
             }
 
-            // If the parent is a loop variable, associate the attribute with the iterable
-            if let Some(iterable) = self.loop_vars.get(parent) {
-                self.object_attrs
-                    .entry(iterable.clone())
-                    .or_default()
-                    .insert(attr.to_string());
-            } else {
-                // Track attribute for regular objects too
+            // Attach the attribute to the schema path of its parent, so reads
+            // through loop variables and aliases land on the same object
+            if let Some(parent_path) = self.tracked_schema_path(parent) {
                 self.object_attrs
-                    .entry(parent.to_string())
+                    .entry(parent_path)
                     .or_default()
                     .insert(attr.to_string());
             }
         }
 
+        // Binding a name the template already reads from the context hides it
+        if access != VarAccess::Read && self.external_vars.contains(var_name) {
+            self.warn(
+                WarningCode::Shadowing,
+                format!("`{var_name}` is read from the context and also bound in the template"),
+                Some(var_name.to_string()),
+                None,
+            );
+        }
+
         // Track first access for classification
         if !self.first_access.contains_key(var_name) {
             self.first_access
@@ -169,7 +1147,7 @@ impl VariableTracker {
             match access {
                 VarAccess::Read => {
                     // Only add base variable name to external vars
-                    let base_name = var_name.split('.').next().unwrap_or(var_name);
+                    let base_name = var_name.split(['.', '[', '{']).next().unwrap_or(var_name);
 
                     let is_a_loop_var = self.loop_vars.contains_key(base_name);
                     if is_a_loop_var {
@@ -187,6 +1165,9 @@ impl VariableTracker {
                     self.internal_vars.insert(var_name.to_string());
                 }
                 VarAccess::LoopVar(iterable) => {
+                    if let Some(path) = self.tracked_schema_path(&iterable) {
+                        self.iterated_paths.insert(path);
+                    }
                     self.internal_vars.insert(var_name.to_string());
                     self.loop_vars.insert(var_name.to_string(), iterable);
                 }
@@ -194,161 +1175,799 @@ impl VariableTracker {
         }
     }
 
-    fn to_analysis(&self) -> TemplateAnalysis {
-        // Convert to BTreeSet for deterministic ordering
-        let external_vars = BTreeSet::from_iter(self.external_vars.iter().cloned());
-        let internal_vars = BTreeSet::from_iter(self.internal_vars.iter().cloned());
-
-        // Create a TemplateData struct to use with build_nested_object
-        let data = TemplateData {
-            internal_vars: self.internal_vars.clone(),
-            external_vars: self.external_vars.clone(),
-            loop_vars: self.loop_vars.clone(),
-            object_attrs: self.object_attrs.clone(),
-            object_aliases: self.object_aliases.clone(),
-        };
+    // Tracker path of an expression, reading subscripts by the targets of
+    // the enclosing `range(...)` loops as array indexes
+    fn attribute_path(&self, expr: &machinery::ast::Expr) -> String {
+        attribute_path(expr, &self.index_vars)
+    }
 
-        // Build the object shapes JSON representation
-        let object_shapes_json = build_nested_object(&data);
+    // Whether the base variable of a path is bound by an enclosing macro
+    fn is_local(&self, var_name: &str) -> bool {
+        let base = var_name.split(['.', '[', '{']).next().unwrap_or(var_name);
+        self.local_scopes.iter().any(|scope| scope.contains(base))
+    }
 
-        TemplateAnalysis {
-            external_vars,
-            internal_vars,
-            loop_vars: self.loop_vars.clone(),
-            object_shapes_json,
+    // Records a warning once per code and path (or span, without a path)
+    fn warn(
+        &mut self,
+        code: WarningCode,
+        message: String,
+        path: Option<String>,
+        span: Option<SourceSpan>,
+    ) {
+        let seen = self.warnings.iter().any(|warning| {
+            warning.code == code && warning.path == path && (path.is_some() || warning.span == span)
+        });
+        if !seen {
+            self.warnings.push(AnalysisWarning {
+                code,
+                message,
+                path,
+                span,
+            });
         }
     }
-}
-
-#[derive(Debug, Clone)]
-struct TemplateData {
-    #[allow(dead_code)]
-    internal_vars: HashSet<String>,
-    external_vars: HashSet<String>,
-    loop_vars: HashMap<String, String>,
-    object_attrs: HashMap<String, BTreeSet<String>>,
-    object_aliases: HashMap<String, String>,
-}
 
-fn build_nested_object(data: &TemplateData) -> Value {
-    let mut result = Map::new();
-
-    // Process all external_vars as top-level keys
-    for var in &data.external_vars {
-        // Resolve the actual variable name through aliases (possibly multiple levels)
-        let resolved_var = resolve_alias_chain(var, &data.object_aliases);
-
-        // Check if this variable (or its resolved alias) is an iterated variable
-        let iterated_var = find_iterated_var(&resolved_var, data);
-
-        if let Some(iterated) = iterated_var {
-            // This is an iterated variable or aliases to one
-            if data.object_attrs.contains_key(&iterated) {
-                let item_obj = build_object_from_attrs(&iterated, data);
-                result.insert(var.clone(), json!([item_obj]));
-            } else {
-                result.insert(var.clone(), json!([]));
-            }
-        } else if data.object_attrs.contains_key(&resolved_var) {
-            // This is a non-iterated object
-            result.insert(var.clone(), build_object_from_attrs(&resolved_var, data));
-        } else {
-            // This is a simple value
-            result.insert(var.clone(), json!(""));
+    fn mark_nullable(&mut self, var_name: &str) {
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, "nullable");
         }
+        self.nullable_paths.insert(path);
     }
 
-    Value::Object(result)
-}
-
-// Recursively resolves aliases until reaching a non-aliased variable
-fn resolve_alias_chain(var: &str, aliases: &HashMap<String, String>) -> String {
-    let mut current = var;
-    let mut visited = HashSet::new();
+    fn mark_mapped(&mut self, var_name: &str) {
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, "map");
+        }
+        self.mapped_paths.insert(path);
+    }
 
-    while let Some(alias) = aliases.get(current) {
-        if visited.contains(alias) {
-            // Detected a cycle, break out
-            break;
+    // `loop(xs)` inside a recursive loop renders the items of `xs` with the loop body again
+    fn record_recursion(&mut self, var_name: &str) {
+        let Some((item_path, name)) = self.recursive_loops.last().cloned() else {
+            return;
+        };
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, %item_path, "recursion");
         }
-        visited.insert(alias);
-        current = alias;
+        self.iterated_paths.insert(path.clone());
+        self.recursive_paths
+            .insert(format!("{path}[]"), item_path.clone());
+        self.recursive_defs.insert(item_path, name);
     }
 
-    current.to_string()
-}
+    fn mark_optional(&mut self, var_name: &str) {
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, "optional");
+        }
+        self.optional_paths.insert(path);
+    }
 
-// Find if a variable is iterated or aliases to an iterated variable
-fn find_iterated_var(var: &str, data: &TemplateData) -> Option<String> {
-    // Direct check if this var is being iterated
-    if data.loop_vars.values().any(|v| v == var) {
-        return Some(var.to_string());
+    fn record_enum_value(&mut self, var_name: &str, value: Value) {
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, %value, "enum candidate");
+        }
+        let hint = match value {
+            Value::String(_) => Some(TypeHint::String),
+            Value::Number(ref number) if number.is_f64() => Some(TypeHint::Number),
+            Value::Number(_) => Some(TypeHint::Integer),
+            _ => None,
+        };
+        if let Some(hint) = hint {
+            self.type_hints
+                .entry(path.clone())
+                .or_default()
+                .insert(hint);
+            *self.evidence_counts.entry(path.clone()).or_default() += 1;
+        }
+        let values = self.enum_values.entry(path).or_default();
+        if !values.contains(&value) {
+            values.push(value);
+        }
     }
 
-    // Check if this var is an alias of an iterated var
-    for iterable in data.loop_vars.values() {
-        let resolved_iterable = resolve_alias_chain(iterable, &data.object_aliases);
-        if &resolved_iterable == var {
-            return Some(resolved_iterable);
+    fn record_default(&mut self, var_name: &str, value: Value) {
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, %value, "default");
+        }
+        // `show_role=true` passes a boolean, `n=3` an integer
+        if let Some(hint) = default_type_hint(&value) {
+            self.record_weak_type(var_name, hint);
         }
+        self.defaults.entry(path).or_insert(value);
     }
 
-    None
-}
+    fn record_filter(&mut self, var_name: &str, filter: &str) {
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, filter, "filter");
+        }
+        self.filters
+            .entry(path)
+            .or_default()
+            .insert(filter.to_string());
+    }
+
+    fn record_test(&mut self, var_name: &str, test: &str) {
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, test, "test");
+        }
+        self.tests.entry(path).or_default().insert(test.to_string());
+    }
+
+    fn record_global_call(&mut self, name: &str, span: SourceSpan) {
+        if self.verbose {
+            trace!(name, "global call");
+        }
+        let usage = self.globals_used.entry(name.to_string()).or_default();
+        usage.count += 1;
+        usage.spans.push(span);
+    }
+
+    fn record_type(&mut self, var_name: &str, hint: TypeHint) {
+        if let Some(path) = self.record_weak_type(var_name, hint) {
+            *self.evidence_counts.entry(path).or_default() += 1;
+        }
+    }
+
+    // Records a hint that comes from a heuristic rather than direct use,
+    // returning the schema path it was recorded for
+    fn record_weak_type(&mut self, var_name: &str, hint: TypeHint) -> Option<String> {
+        let path = self.tracked_schema_path(var_name)?;
+        if self.verbose {
+            trace!(var = var_name, %path, ?hint, "type hint");
+        }
+        self.type_hints
+            .entry(path.clone())
+            .or_default()
+            .insert(hint);
+        Some(path)
+    }
+
+    fn record_origin(&mut self, var_name: &str, expr: &machinery::ast::Expr) {
+        if self.origins.is_none() {
+            return;
+        }
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        let span = SourceSpan::from(expr_span(expr));
+        let expression = self.source_text(expr).to_string();
+        if let Some(origins) = &mut self.origins {
+            let entries = origins.entry(path).or_default();
+            if !entries.iter().any(|origin| origin.span == span) {
+                entries.push(FieldOrigin { expression, span });
+            }
+        }
+    }
+
+    // Source text of an expression
+    fn source_text(&self, expr: &machinery::ast::Expr) -> &str {
+        let span = expr_span(expr);
+        self.source
+            .get(span.start_offset as usize..span.end_offset as usize)
+            .unwrap_or_default()
+    }
+
+    // `raise_exception(...)` under the current guards: the guards must not all hold
+    fn record_constraint(&mut self, call: &machinery::ast::Spanned<machinery::ast::Call>) {
+        let condition = match self.guards.as_slice() {
+            // An unconditional raise makes the template unrenderable, not a constraint
+            [] => return,
+            [guard] => guard.negated.clone(),
+            guards => format!(
+                "not ({})",
+                guards
+                    .iter()
+                    .map(|guard| guard.holds.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" and ")
+            ),
+        };
+        let message = match call.args.first() {
+            Some(machinery::ast::CallArg::Pos(machinery::ast::Expr::Const(constant))) => {
+                constant.value.as_str().map(str::to_string)
+            }
+            _ => None,
+        };
+        if self.verbose {
+            trace!(%condition, "constraint");
+        }
+        if let [Guard {
+            escape_bounds: Some((var_name, bounds)),
+            ..
+        }] = self.guards.as_slice()
+        {
+            let (var_name, bounds) = (var_name.clone(), *bounds);
+            self.record_cardinality(&var_name, bounds);
+        }
+        self.constraints.push(Constraint {
+            condition,
+            message,
+            paths: self
+                .guards
+                .iter()
+                .flat_map(|guard| guard.paths.iter().cloned())
+                .collect(),
+            span: call.span().into(),
+        });
+    }
+
+    fn record_cardinality(&mut self, var_name: &str, bounds: Cardinality) {
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, ?bounds, "cardinality");
+        }
+        let entry = self.cardinality.entry(path).or_default();
+        *entry = entry.intersect(bounds);
+    }
+
+    // `xs[n]` outside any condition on `xs` requires `xs` to have the item
+    fn record_index(&mut self, var_name: &str, index: i64) {
+        let Some(path) = self.tracked_schema_path(var_name) else {
+            return;
+        };
+        if self.guards.iter().any(|guard| guard.paths.contains(&path)) {
+            return;
+        }
+        let min_items = if index < 0 {
+            index.unsigned_abs()
+        } else {
+            index.unsigned_abs() + 1
+        };
+        self.record_weak_type(var_name, TypeHint::Indexed);
+        self.record_cardinality(
+            var_name,
+            Cardinality {
+                min_items: min_items as usize,
+                max_items: None,
+            },
+        );
+    }
+
+    // A strftime pattern is more specific than a format name and wins over it
+    fn record_format(&mut self, var_name: &str, format: &str) {
+        let Some(path) = self.record_weak_type(var_name, TypeHint::String) else {
+            return;
+        };
+        if self.verbose {
+            trace!(var = var_name, %path, format, "format");
+        }
+        let entry = self.formats.entry(path).or_default();
+        if entry.is_empty() || (format.contains('%') && !entry.contains('%')) {
+            *entry = format.to_string();
+        }
+    }
+
+    fn record_truthiness_use(&mut self, var_name: &str) {
+        if let Some(path) = self.tracked_schema_path(var_name) {
+            *self.truthiness_counts.entry(path).or_default() += 1;
+        }
+    }
+
+    fn record_neutral_use(&mut self, var_name: &str) {
+        if let Some(path) = self.tracked_schema_path(var_name) {
+            *self.neutral_counts.entry(path).or_default() += 1;
+        }
+    }
 
-// Function to build an object from its attributes
-fn build_object_from_attrs(obj_key: &str, data: &TemplateData) -> Value {
-    let mut obj = Map::new();
+    // Type hints including `Boolean` for paths whose every read is a truthiness test
+    fn resolved_type_hints(&self) -> HashMap<String, BTreeSet<TypeHint>> {
+        let mut type_hints = self.type_hints.clone();
+        for (path, truthiness) in &self.truthiness_counts {
+            let neutral = self.neutral_counts.get(path).copied().unwrap_or(0);
+            let reads = self.read_counts.get(path).copied().unwrap_or(0);
+            if truthiness + neutral == reads {
+                type_hints
+                    .entry(path.clone())
+                    .or_default()
+                    .insert(TypeHint::Boolean);
+            }
+        }
+        type_hints
+    }
 
-    if let Some(attrs) = data.object_attrs.get(obj_key) {
-        for attr in attrs {
-            // Build the potential nested key
-            let nested_key = format!("{obj_key}.{attr}");
+    // Schema path for paths that describe context data, `None` for `loop.*`,
+    // empty paths and items of iterables that have no path themselves
+    fn tracked_schema_path(&self, var_name: &str) -> Option<String> {
+        if var_name.is_empty()
+            || var_name.starts_with("loop.")
+            || var_name == "loop"
+            || self.is_local(var_name)
+        {
+            return None;
+        }
+        let path = self.schema_path(var_name);
+        if path.starts_with("[]") {
+            return None;
+        }
+        Some(path)
+    }
 
-            // Find corresponding loop variable
-            let corresponding_loop_var = find_corresponding_loop_var(&nested_key, data);
+    // Rewrites a tracker path (`message.content`) into the schema path it
+    // describes (`messages[].content`) by following loop variables and aliases
+    fn schema_path(&self, var_name: &str) -> String {
+        let mut current = var_name.to_string();
+        let mut visited = HashSet::new();
 
-            // Check if there are attributes for either form of the path
-            let has_nested_attrs = data.object_attrs.contains_key(&nested_key);
+        loop {
+            let (base, rest) = match current.find(['.', '[', '{']) {
+                Some(idx) => current.split_at(idx),
+                None => (current.as_str(), ""),
+            };
+            if !visited.insert(base.to_string()) {
+                // Detected a cycle, break out
+                break;
+            }
 
-            // Determine which key to use for attributes
-            let key_to_use = if has_nested_attrs {
-                Some(nested_key.clone())
+            let next = if let Some(iterable) = self.loop_vars.get(base) {
+                Some(format!("{iterable}[]{rest}"))
             } else {
-                None
+                self.object_aliases
+                    .iter()
+                    .find(|(_, alias)| alias.as_str() == base)
+                    .map(|(source, _)| format!("{source}{rest}"))
             };
 
-            // Determine if this should be an array
-            let should_be_array = corresponding_loop_var.is_some() || attr == "tool_calls";
+            match next {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+
+        current
+    }
 
-            if let Some(key) = key_to_use {
-                // Has nested attributes
-                if should_be_array {
-                    let nested_obj = build_object_from_attrs(&key, data);
-                    obj.insert(attr.clone(), json!([nested_obj]));
-                } else {
-                    obj.insert(attr.clone(), build_object_from_attrs(&key, data));
-                }
+    fn to_analysis(&self) -> TemplateAnalysis {
+        // Convert to BTreeSet for deterministic ordering
+        let external_vars = BTreeSet::from_iter(self.external_vars.iter().cloned());
+        let internal_vars = BTreeSet::from_iter(self.internal_vars.iter().cloned());
+
+        // Create a TemplateData struct to use with build_nested_object
+        let data = TemplateData {
+            external_vars: self.external_vars.clone(),
+            object_attrs: self.object_attrs.clone(),
+            iterated_paths: self.iterated_paths.clone(),
+            mapped_paths: self.mapped_paths.clone(),
+            recursive_paths: self.recursive_paths.clone(),
+            recursive_defs: self.recursive_defs.clone(),
+            evidence_counts: self.evidence_counts.clone(),
+            tests: self.tests.clone(),
+            annotations: annotations::parse_annotations(&self.source),
+            max_depth: self.max_depth,
+            max_properties: self.max_properties,
+            truncated: RefCell::new(BTreeSet::new()),
+            knowledge: if self.chat_knowledge {
+                knowledge::chat_rules_by_path()
             } else {
-                // No nested attributes
-                obj.insert(attr.clone(), json!(""));
+                BTreeMap::new()
+            },
+            rules: self.rules.clone(),
+            nullable_paths: self.nullable_paths.clone(),
+            type_hints: self.resolved_type_hints(),
+        };
+
+        // Build the typed shape of the context
+        let shape = build_nested_object(&data);
+        let definitions = self
+            .recursive_defs
+            .iter()
+            .map(|(path, name)| {
+                let definition = Definition {
+                    path: path.clone(),
+                    shape: build_value_shape(path, &data),
+                };
+                (name.clone(), definition)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let mut confidence = BTreeMap::new();
+        collect_confidence(&shape, "", &data, &mut confidence);
+        for definition in definitions.values() {
+            collect_confidence(&definition.shape, &definition.path, &data, &mut confidence);
+        }
+
+        // Reads of the paths that made it into the shape
+        let usage_counts = self
+            .read_counts
+            .iter()
+            .filter(|(path, _)| confidence.contains_key(*path))
+            .map(|(path, count)| (path.clone(), *count))
+            .collect();
+
+        // Allowed values of well-known fields the template uses
+        let mut enum_values = BTreeMap::from_iter(self.enum_values.clone());
+        for (path, rule) in &data.knowledge {
+            if rule.enum_values.is_empty() || !confidence.contains_key(path) {
+                continue;
+            }
+            let values = enum_values.entry(path.clone()).or_default();
+            for value in &rule.enum_values {
+                if !values.contains(value) {
+                    values.push(value.clone());
+                }
             }
         }
+
+        // Item count bounds of the paths that made it into the shape
+        let cardinality = self
+            .cardinality
+            .iter()
+            .filter(|(path, _)| confidence.contains_key(*path))
+            .map(|(path, bounds)| (path.clone(), *bounds))
+            .collect();
+
+        // Date/time formats of the paths that made it into the shape
+        let formats = self
+            .formats
+            .iter()
+            .filter(|(path, _)| confidence.contains_key(*path))
+            .map(|(path, format)| (path.clone(), format.clone()))
+            .collect();
+
+        // Provenance for the paths that made it into the shape
+        let provenance = self.origins.as_ref().map(|origins| {
+            origins
+                .iter()
+                .filter(|(path, _)| confidence.contains_key(*path))
+                .map(|(path, entries)| (path.clone(), entries.clone()))
+                .collect()
+        });
+
+        let unknown_filters = self
+            .filter_names
+            .iter()
+            .filter(|name| {
+                !BUILTIN_FILTERS.contains(&name.as_str()) && !self.custom_filters.contains(*name)
+            })
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        let truncated_paths = data.truncated.take();
+        let mut warnings = self.warnings.clone();
+        warnings.extend(unknown_filters.iter().map(|filter| AnalysisWarning {
+            code: WarningCode::UnknownFilter,
+            message: format!("unknown filter `{filter}` must be registered before rendering"),
+            path: None,
+            span: None,
+        }));
+        warnings.extend(truncated_paths.iter().map(|path| AnalysisWarning {
+            code: WarningCode::Truncated,
+            message: format!("`{path}` was cut short by the depth or property limits"),
+            path: Some(path.clone()),
+            span: None,
+        }));
+
+        TemplateAnalysis {
+            schema_version: SCHEMA_VERSION,
+            external_vars,
+            internal_vars,
+            loop_vars: BTreeMap::from_iter(self.loop_vars.clone()),
+            aliases: self
+                .object_aliases
+                .iter()
+                .map(|(source, alias)| (alias.clone(), source.clone()))
+                .collect(),
+            nullable_paths: BTreeSet::from_iter(self.nullable_paths.iter().cloned()),
+            optional_paths: BTreeSet::from_iter(self.optional_paths.iter().cloned()),
+            closed_enums: self
+                .closed_enums
+                .iter()
+                .filter(|path| enum_values.contains_key(*path))
+                .cloned()
+                .collect(),
+            enum_values,
+            defaults: BTreeMap::from_iter(self.defaults.clone()),
+            filters: BTreeMap::from_iter(self.filters.clone()),
+            tests: BTreeMap::from_iter(self.tests.clone()),
+            globals_used: self.globals_used.clone(),
+            unknown_filters: unknown_filters.clone(),
+            type_hints: BTreeMap::from_iter(self.resolved_type_hints()),
+            shape,
+            definitions,
+            confidence,
+            provenance,
+            constraints: self.constraints.clone(),
+            annotations: data.annotations.clone(),
+            truncated_paths,
+            usage_counts,
+            presence: BTreeMap::new(),
+            cardinality,
+            formats,
+            warnings,
+            name: None,
+        }
+    }
+}
+
+// Condition of an enclosing `if` branch, as it holds inside the branch and negated
+#[derive(Debug, Clone)]
+struct Guard {
+    holds: String,
+    negated: String,
+    paths: BTreeSet<String>,
+    // Item count bounds on a variable that hold when the branch is not taken
+    escape_bounds: Option<(String, Cardinality)>,
+}
+
+#[derive(Debug, Clone)]
+struct TemplateData {
+    external_vars: HashSet<String>,
+    object_attrs: HashMap<String, BTreeSet<String>>,
+    iterated_paths: HashSet<String>,
+    mapped_paths: HashSet<String>,
+    recursive_paths: HashMap<String, String>,
+    recursive_defs: BTreeMap<String, String>,
+    evidence_counts: HashMap<String, usize>,
+    tests: HashMap<String, BTreeSet<String>>,
+    annotations: BTreeMap<String, Annotation>,
+    knowledge: BTreeMap<String, knowledge::KnownField>,
+    rules: Vec<rules::Rule>,
+    max_depth: usize,
+    max_properties: usize,
+    // Paths cut short while building, filled in by `build_value_shape`
+    truncated: RefCell<BTreeSet<String>>,
+    nullable_paths: HashSet<String>,
+    type_hints: HashMap<String, BTreeSet<TypeHint>>,
+}
+
+// Shape of a value without attributes, typed from the collected evidence
+fn leaf_shape(path: &str, data: &TemplateData) -> Shape {
+    let Some(hints) = data.type_hints.get(path) else {
+        return Shape::Unknown;
+    };
+
+    let mut shape = Shape::Unknown;
+    if hints.contains(&TypeHint::String) {
+        shape = shape.merge(Shape::String);
+    }
+    if hints.contains(&TypeHint::Integer) {
+        // Integer evidence is more specific than generic numeric use
+        shape = shape.merge(Shape::Integer);
+    } else if hints.contains(&TypeHint::Number) {
+        shape = shape.merge(Shape::Number);
+    }
+    if hints.contains(&TypeHint::Boolean) {
+        shape = shape.merge(Shape::Bool);
+    }
+    shape
+}
+
+// Applies path-level modifiers (nullability) to a built shape
+fn finish_shape(shape: Shape, path: &str, data: &TemplateData) -> Shape {
+    if data.nullable_paths.contains(path) {
+        shape.nullable()
+    } else {
+        shape
+    }
+}
+
+fn build_nested_object(data: &TemplateData) -> Shape {
+    // Process all external_vars as top-level keys
+    Shape::Object(
+        data.external_vars
+            .iter()
+            .map(|var| (var.clone(), build_value_shape(var, data)))
+            .collect(),
+    )
+}
+
+// Shape of the value at a schema path, merging the evidence of every use:
+// scalar hints, iteration (array) and attribute reads (object)
+fn build_value_shape(path: &str, data: &TemplateData) -> Shape {
+    // Recursive data refers back to its definition instead of nesting forever
+    if let Some(name) = data
+        .recursive_paths
+        .get(path)
+        .and_then(|item_path| data.recursive_defs.get(item_path))
+    {
+        return finish_shape(Shape::Ref(name.clone()), path, data);
+    }
+
+    // Stop at the depth limit, marking the path so the output shows the cut
+    if path_depth(path) > data.max_depth {
+        data.truncated.borrow_mut().insert(path.to_string());
+        return Shape::Unknown;
     }
 
-    Value::Object(obj)
+    let mut shape = leaf_shape(path, data);
+
+    let item_path = format!("{path}[]");
+    if data.iterated_paths.contains(path) || data.object_attrs.contains_key(&item_path) {
+        let item = build_value_shape(&item_path, data);
+        shape = shape.merge(Shape::Array(Box::new(item)));
+    }
+
+    // Values under computed keys live at `path{}`
+    let value_path = format!("{path}{{}}");
+    if data.mapped_paths.contains(path) || data.object_attrs.contains_key(&value_path) {
+        let value = build_value_shape(&value_path, data);
+        shape = shape.merge(Shape::Map(Box::new(value)));
+    }
+
+    if data.object_attrs.contains_key(path) {
+        let object = build_object_from_attrs(path, data);
+        // `tool_calls` holds a list of calls even when only read through attributes
+        let object = if path.ends_with(".tool_calls") {
+            Shape::Array(Box::new(object))
+        } else {
+            object
+        };
+        shape = shape.merge(object);
+    }
+
+    // A measured or indexed value with no other evidence is a collection of
+    // unknown items; strings have a length too, so any other evidence takes
+    // precedence
+    let has_hint = |hint| {
+        data.type_hints
+            .get(path)
+            .is_some_and(|hints| hints.contains(&hint))
+    };
+    if (has_hint(TypeHint::Container) || has_hint(TypeHint::Indexed)) && shape == Shape::Unknown {
+        shape = Shape::Array(Box::new(Shape::Unknown));
+    }
+
+    // A value serialized with `tojson` and otherwise unconstrained is free-form JSON
+    if has_hint(TypeHint::Json) && shape == Shape::Unknown {
+        shape = Shape::Any;
+    }
+
+    // Well-known fields fill in what the template doesn't show
+    if let Some(rule) = data.knowledge.get(path) {
+        shape = shape.merge(rule.shape.clone());
+    }
+
+    // Types given by rules, then by the template author, win over inferred ones
+    for rule in &data.rules {
+        if let rules::RuleAction::Type(ty) = &rule.action {
+            if rule.matches(path) {
+                shape = annotations::refine(shape, ty);
+            }
+        }
+    }
+    if let Some(annotated) = data.annotations.get(path).and_then(|a| a.shape.as_ref()) {
+        shape = annotations::refine(shape, annotated);
+    }
+
+    finish_shape(shape, path, data)
 }
 
-// Function to find corresponding loop variable
-fn find_corresponding_loop_var<'a>(path: &str, data: &'a TemplateData) -> Option<&'a String> {
-    // Direct match in loop_vars values
-    for (loop_var, iterable) in &data.loop_vars {
-        if iterable == path {
-            return Some(loop_var);
+// Records the confidence of every path below `path` in `shape`
+fn collect_confidence(
+    shape: &Shape,
+    path: &str,
+    data: &TemplateData,
+    out: &mut BTreeMap<String, Confidence>,
+) {
+    match shape {
+        Shape::Object(properties) => {
+            for (key, property) in properties {
+                let property_path = shape::child_path(path, key);
+                out.insert(
+                    property_path.clone(),
+                    shape_confidence(property, &property_path, data),
+                );
+                collect_confidence(property, &property_path, data, out);
+            }
+        }
+        Shape::Array(item) => {
+            let item_path = format!("{path}[]");
+            out.insert(item_path.clone(), shape_confidence(item, &item_path, data));
+            collect_confidence(item, &item_path, data, out);
+        }
+        Shape::Map(value) => {
+            let value_path = format!("{path}{{}}");
+            out.insert(
+                value_path.clone(),
+                shape_confidence(value, &value_path, data),
+            );
+            collect_confidence(value, &value_path, data, out);
+        }
+        Shape::Union(variants) => {
+            for variant in variants {
+                collect_confidence(variant, path, data, out);
+            }
         }
+        _ => {}
     }
+}
 
-    None
+// How well the evidence for `path` supports its shape
+fn shape_confidence(shape: &Shape, path: &str, data: &TemplateData) -> Confidence {
+    if data
+        .annotations
+        .get(path)
+        .is_some_and(|annotation| annotation.shape.is_some())
+    {
+        return Confidence::High;
+    }
+    match shape {
+        Shape::Union(variants) => {
+            let typed = variants
+                .iter()
+                .filter(|variant| **variant != Shape::Null)
+                .collect::<Vec<_>>();
+            match typed.as_slice() {
+                [variant] => shape_confidence(variant, path, data),
+                // Conflicting direct evidence, each variant was observed
+                _ => Confidence::Medium,
+            }
+        }
+        Shape::Object(_) | Shape::Map(_) | Shape::Ref(_) => Confidence::High,
+        Shape::Array(_) => {
+            let item_path = format!("{path}[]");
+            if data.iterated_paths.contains(path) || data.object_attrs.contains_key(&item_path) {
+                Confidence::High
+            } else {
+                // From the `tool_calls` rule or a `length` filter
+                Confidence::Low
+            }
+        }
+        Shape::Unknown | Shape::Any => Confidence::Low,
+        Shape::String | Shape::Number | Shape::Integer | Shape::Bool | Shape::Null => {
+            let type_tested = data.tests.get(path).is_some_and(|tests| {
+                tests.iter().any(|test| {
+                    matches!(
+                        test.as_str(),
+                        "string" | "number" | "float" | "integer" | "boolean"
+                    )
+                })
+            });
+            match data.evidence_counts.get(path).copied().unwrap_or(0) {
+                _ if type_tested => Confidence::High,
+                0 => Confidence::Low,
+                1 => Confidence::Medium,
+                _ => Confidence::High,
+            }
+        }
+    }
+}
+
+// Function to build an object from its attributes, `path` is the schema path of the object
+fn build_object_from_attrs(path: &str, data: &TemplateData) -> Shape {
+    let mut obj = BTreeMap::new();
+
+    if let Some(attrs) = data.object_attrs.get(path) {
+        if attrs.len() > data.max_properties {
+            data.truncated.borrow_mut().insert(path.to_string());
+        }
+        for attr in attrs.iter().take(data.max_properties) {
+            let nested_path = format!("{path}.{attr}");
+            obj.insert(attr.clone(), build_value_shape(&nested_path, data));
+        }
+    }
+
+    Shape::Object(obj)
+}
+
+// Number of segments in a schema path (`messages[].role` has three)
+fn path_depth(path: &str) -> usize {
+    path.matches(['.', '[', '{']).count() + 1
 }
 
 fn collect_variables(node: &machinery::ast::Stmt, tracker: &mut VariableTracker) {
@@ -365,40 +1984,127 @@ fn collect_variables(node: &machinery::ast::Stmt, tracker: &mut VariableTracker)
         }
         machinery::ast::Stmt::EmitExpr(expr) => {
             collect_var_reads(&expr.expr, tracker);
+
+            if let machinery::ast::Expr::Call(call) = &expr.expr {
+                if matches!(&call.expr, machinery::ast::Expr::Var(callee) if callee.id == "raise_exception")
+                {
+                    tracker.record_constraint(call);
+                }
+            }
         }
         machinery::ast::Stmt::ForLoop(for_loop) => {
             // Track reads in the iterable expression
             collect_var_reads(&for_loop.iter, tracker);
 
-            // Get the loop variable name
-            let loop_var = match extract_var_name(&format!("{:?}", for_loop.target)) {
-                Some(name) => name,
-                None => "loop_var".to_string(), // Fallback
-            };
+            let recursion_depth = tracker.recursive_loops.len();
+            let index_depth = tracker.index_vars.len();
+            let targets;
+            if let Some((key_var, value_var, mapping)) = items_loop(for_loop) {
+                // `for key, value in data.items()`: keys are local, values forward to `data{}`
+                tracker.mark_mapped(&mapping);
+                tracker.track_access(key_var, VarAccess::Set);
+                tracker.track_access(value_var, VarAccess::SetAlias(format!("{mapping}{{}}")));
+                targets = vec![key_var.to_string(), value_var.to_string()];
+            } else {
+                // Get the loop variable name
+                let loop_var = match extract_var_name(&format!("{:?}", for_loop.target)) {
+                    Some(name) => name,
+                    None => "loop_var".to_string(), // Fallback
+                };
+
+                // Get what we're iterating over
+                let iter_expr = tracker.attribute_path(iterated_expr(&for_loop.iter));
+
+                // Track as loop variable
+                tracker.track_access(&loop_var, VarAccess::LoopVar(iter_expr));
+
+                // `for i in range(xs | length)` counts positions of `xs`
+                if matches!(&for_loop.iter, machinery::ast::Expr::Call(call)
+                    if matches!(&call.expr, machinery::ast::Expr::Var(callee) if callee.id == "range"))
+                {
+                    tracker.index_vars.push(loop_var.clone());
+                }
 
-            // Get what we're iterating over
-            let iter_expr = get_attribute_path(&for_loop.iter);
+                // `loop(...)` in the body recurses into the items of this loop
+                if for_loop.recursive {
+                    if let Some(item_path) = tracker.tracked_schema_path(&loop_var) {
+                        tracker.recursive_loops.push((item_path, loop_var.clone()));
+                    }
+                }
+                targets = vec![loop_var];
+            }
+            if tracker.visitor.is_some() {
+                let entry = visitor::LoopEntry {
+                    item_path: tracker.tracked_schema_path(&targets[targets.len() - 1]),
+                    targets,
+                    iterable: tracker.source_text(&for_loop.iter).to_string(),
+                    recursive: for_loop.recursive,
+                    span: SourceSpan::from(for_loop.span()),
+                };
+                if let Some(visitor) = tracker.visitor.as_deref_mut() {
+                    visitor.enter_loop(&entry);
+                }
+            }
 
-            // Track as loop variable
-            tracker.track_access(&loop_var, VarAccess::LoopVar(iter_expr));
+            // Track reads in the loop filter (`for x in xs if x.visible`)
+            if let Some(filter_expr) = &for_loop.filter_expr {
+                collect_var_reads(filter_expr, tracker);
+                collect_truthiness_uses(filter_expr, tracker);
+            }
 
             // Process the loop body
             for child in &for_loop.body {
                 collect_variables(child, tracker);
             }
+            tracker.recursive_loops.truncate(recursion_depth);
+            tracker.index_vars.truncate(index_depth);
         }
         machinery::ast::Stmt::IfCond(if_cond) => {
             // Track reads in condition
             collect_var_reads(&if_cond.expr, tracker);
+            collect_truthiness_uses(&if_cond.expr, tracker);
+            if let Some(path) = closed_enum_path(if_cond, tracker) {
+                tracker.closed_enums.insert(path);
+            }
+
+            let text = tracker.source_text(&if_cond.expr).to_string();
+            let negated = negate_condition(&if_cond.expr, tracker);
+            let mut paths = BTreeSet::new();
+            collect_condition_paths(&if_cond.expr, tracker, &mut paths);
+            if tracker.visitor.is_some() {
+                let condition = visitor::Condition {
+                    expression: text.clone(),
+                    paths: paths.clone(),
+                    span: SourceSpan::from(expr_span(&if_cond.expr)),
+                };
+                if let Some(visitor) = tracker.visitor.as_deref_mut() {
+                    visitor.visit_condition(&condition);
+                }
+            }
 
             // Process true body
+            tracker.guards.push(Guard {
+                holds: text.clone(),
+                negated: negated.clone(),
+                paths: paths.clone(),
+                escape_bounds: length_bounds(&if_cond.expr, false),
+            });
             for child in &if_cond.true_body {
                 collect_variables(child, tracker);
             }
+            tracker.guards.pop();
+
             // Process false body if it exists
+            tracker.guards.push(Guard {
+                holds: negated,
+                negated: text,
+                paths,
+                escape_bounds: length_bounds(&if_cond.expr, true),
+            });
             for child in &if_cond.false_body {
                 collect_variables(child, tracker);
             }
+            tracker.guards.pop();
         }
         machinery::ast::Stmt::WithBlock(with_block) => {
             // Process all assignments
@@ -423,6 +2129,10 @@ fn collect_variables(node: &machinery::ast::Stmt, tracker: &mut VariableTracker)
 
             // Track setting of the target
             if let Some(var_name) = extract_var_name(&format!("{:?}", set.target)) {
+                // `set date = strftime_now('%d %b %Y')` or a date-shaped fallback
+                if let Some(format) = produced_date_format(&set.expr) {
+                    tracker.record_format(&var_name, &format);
+                }
                 match &set.expr {
                     machinery::ast::Expr::Var(var) => {
                         tracker.track_access(&var_name, VarAccess::SetAlias(var.id.to_string()));
@@ -431,12 +2141,15 @@ fn collect_variables(node: &machinery::ast::Stmt, tracker: &mut VariableTracker)
                         tracker.track_access(&var_name, VarAccess::Set);
                     }
                 }
+                let value = tracker.source_text(&set.expr).to_string();
+                visit_set(tracker, var_name, Some(value), set.span());
             }
         }
         machinery::ast::Stmt::SetBlock(set_block) => {
             // Track setting of the target
             if let Some(var_name) = extract_var_name(&format!("{:?}", set_block.target)) {
                 tracker.track_access(&var_name, VarAccess::Set);
+                visit_set(tracker, var_name, None, set_block.span());
             }
 
             // Process the body
@@ -458,81 +2171,192 @@ fn collect_variables(node: &machinery::ast::Stmt, tracker: &mut VariableTracker)
                 collect_variables(child, tracker);
             }
         }
+        machinery::ast::Stmt::Macro(macro_decl) => {
+            // The macro itself is template-local, so calls to it are not external reads
+            tracker.track_access(macro_decl.name, VarAccess::Set);
+            let params = collect_macro_params(macro_decl, tracker);
+            tracker
+                .macros
+                .insert(macro_decl.name.to_string(), params.clone());
+
+            collect_macro_body(macro_decl, params, tracker);
+        }
+        machinery::ast::Stmt::CallBlock(call_block) => {
+            collect_call_reads(&call_block.call, tracker);
+
+            // The caller body is an anonymous macro
+            let params = collect_macro_params(&call_block.macro_decl, tracker);
+            collect_macro_body(&call_block.macro_decl, params, tracker);
+        }
+        machinery::ast::Stmt::Include(include) => match template_name(&include.name) {
+            Some(name) if tracker.loader.is_some() => {
+                include_template(name, !include.ignore_missing, tracker)
+            }
+            _ => unhandled_node("include", include.span(), tracker),
+        },
+        machinery::ast::Stmt::Extends(extends) => match template_name(&extends.name) {
+            Some(name) if tracker.loader.is_some() => include_template(name, true, tracker),
+            _ => unhandled_node("extends", extends.span(), tracker),
+        },
+        machinery::ast::Stmt::Import(import) => unhandled_node("import", import.span(), tracker),
+        machinery::ast::Stmt::FromImport(from_import) => {
+            unhandled_node("from ... import", from_import.span(), tracker)
+        }
+        machinery::ast::Stmt::Do(do_tag) => unhandled_node("do", do_tag.span(), tracker),
         _ => {}
     }
 }
 
+fn visit_set(
+    tracker: &mut VariableTracker,
+    target: String,
+    value: Option<String>,
+    span: machinery::Span,
+) {
+    if let Some(visitor) = tracker.visitor.as_deref_mut() {
+        visitor.visit_set(&visitor::SetStatement {
+            target,
+            value,
+            span: SourceSpan::from(span),
+        });
+    }
+}
+
+fn unhandled_node(tag: &str, span: machinery::Span, tracker: &mut VariableTracker) {
+    tracker.warn(
+        WarningCode::UnhandledNode,
+        format!("the variables of `{tag}` statements are not analyzed"),
+        None,
+        Some(SourceSpan::from(span)),
+    );
+}
+
+// Constant template name of an `include` or `extends`
+fn template_name<'a>(expr: &'a machinery::ast::Expr) -> Option<&'a str> {
+    match expr {
+        machinery::ast::Expr::Const(constant) => constant.value.as_str(),
+        _ => None,
+    }
+}
+
+// Collects the variables of a template pulled in with `include` or `extends`,
+// which renders with the same context; cycles are collected once
+fn include_template(name: &str, required: bool, tracker: &mut VariableTracker) {
+    let Some(loader) = tracker.loader.clone() else {
+        return;
+    };
+    if tracker.including.iter().any(|including| including == name) {
+        return;
+    }
+    let Some(source) = loader.load(name) else {
+        if required {
+            tracker.missing_templates.insert(name.to_string());
+        }
+        return;
+    };
+    let ast = match machinery::parse(&source, name, Default::default(), tracker.whitespace) {
+        Ok(ast) => ast,
+        Err(err) => {
+            debug!(template = name, error = %err, "included template does not parse");
+            return;
+        }
+    };
+
+    // Spans of the included expressions point into the included source
+    let outer_source = std::mem::replace(&mut tracker.source, source.clone());
+    tracker.including.push(name.to_string());
+    collect_variables(&ast, tracker);
+    tracker.including.pop();
+    tracker.source = outer_source;
+}
+
 // Track variable reads in expressions
 fn collect_var_reads(expr: &machinery::ast::Expr, tracker: &mut VariableTracker) {
     match expr {
         machinery::ast::Expr::Var(var) => {
             // Track variable read
             tracker.track_access(var.id, VarAccess::Read);
+            tracker.record_origin(var.id, expr);
         }
         machinery::ast::Expr::GetAttr(get_attr) => {
             // Get the full attribute path
-            let attr_path = get_attribute_path(expr);
+            let attr_path = tracker.attribute_path(expr);
 
             // Track read of the full path
             tracker.track_access(&attr_path, VarAccess::Read);
+            tracker.record_origin(&attr_path, expr);
 
             // Also track read of base expression (needed for attribute tracking)
             collect_var_reads(&get_attr.expr, tracker);
         }
         machinery::ast::Expr::GetItem(get_item) => {
-            let access_in_get = {
-                let mut left = String::new();
+            // `obj['key']` reads `obj.key`, however deep `obj` is (`messages[0]['role']`)
+            let is_key = matches!(
+                &get_item.subscript_expr,
+                machinery::ast::Expr::Const(constant) if constant.value.as_str().is_some()
+            );
+            let path = tracker.attribute_path(expr);
+            if is_key && !path.is_empty() {
+                tracker.track_access(&path, VarAccess::Read);
+            }
+            tracker.record_origin(&tracker.attribute_path(expr), expr);
 
-                // First check if we have a variable expression
-                let has_var = match &get_item.expr {
-                    machinery::ast::Expr::Var(var) => {
-                        left.push_str(var.id);
-                        left.push('.');
-                        true
-                    }
-                    _ => false, // Skip if not a variable
-                };
+            // `xs[1]` needs `xs` to hold at least two items
+            if let Some(index) = integer_value(&get_item.subscript_expr) {
+                tracker.record_index(&tracker.attribute_path(&get_item.expr), index);
+            }
 
-                // Only continue if we found a variable
-                if has_var {
-                    match &get_item.subscript_expr {
-                        machinery::ast::Expr::Const(constant) => {
-                            let Const { value } = &**constant;
-                            if value.is_number() {
-                                None
-                            } else {
-                                left.push_str(&format!("{value}"));
-                                Some(left)
-                            }
-                        }
-                        _ => None, // Skip if not a constant
-                    }
-                } else {
-                    None
-                }
-            };
-
-            if let Some(access_in_get) = access_in_get {
-                // Track read of the full path
-                tracker.track_access(&access_in_get, VarAccess::Read);
+            // `data[field_name]` looks up a computed key, so `data` is a map
+            if is_computed_key(&get_item.subscript_expr, &tracker.index_vars) {
+                tracker.mark_mapped(&tracker.attribute_path(&get_item.expr));
+                tracker.track_access(&tracker.attribute_path(expr), VarAccess::Read);
             }
 
             collect_var_reads(&get_item.expr, tracker);
             collect_var_reads(&get_item.subscript_expr, tracker);
         }
         machinery::ast::Expr::Call(call) => {
-            collect_var_reads(&call.expr, tracker);
-
-            // Process call arguments
-            for arg in &call.args {
-                // Use extract_vars_from_debug_str instead of direct call to handle CallArg type
-                let arg_str = format!("{arg:?}");
-                extract_vars_from_debug_str(&arg_str, tracker);
-            }
+            collect_call_reads(call, tracker);
         }
         machinery::ast::Expr::Filter(filter) => {
+            tracker.filter_names.insert(filter.name.to_string());
+
             if let Some(expr) = &filter.expr {
                 collect_var_reads(expr, tracker);
+
+                // Attribute the whole filter chain (`x | trim | tojson`) to `x`
+                tracker.record_filter(&tracker.attribute_path(unwrap_filters(expr)), filter.name);
+
+                // `x | int` expects an integer, `x | round` any number
+                let hint = match filter.name {
+                    "int" => Some(TypeHint::Integer),
+                    "round" => Some(TypeHint::Number),
+                    _ => None,
+                };
+                if let Some(hint) = hint {
+                    tracker.record_weak_type(&tracker.attribute_path(unwrap_filters(expr)), hint);
+                }
+
+                // `x | tojson` serializes `x` wholesale, whatever its structure
+                if filter.name == "tojson" {
+                    tracker.record_weak_type(
+                        &tracker.attribute_path(unwrap_filters(expr)),
+                        TypeHint::Json,
+                    );
+                }
+
+                // `xs | length` measures a collection
+                if matches!(filter.name, "length" | "count") {
+                    tracker.record_weak_type(
+                        &tracker.attribute_path(iterated_expr(expr)),
+                        TypeHint::Container,
+                    );
+                }
+
+                // `x | default(...)` renders fine without `x`
+                if matches!(filter.name, "default" | "d") {
+                    tracker.mark_optional(&tracker.attribute_path(unwrap_filters(expr)));
+                }
             }
 
             // Process filter arguments
@@ -544,6 +2368,30 @@ fn collect_var_reads(expr: &machinery::ast::Expr, tracker: &mut VariableTracker)
         }
         machinery::ast::Expr::Test(test) => {
             collect_var_reads(&test.expr, tracker);
+            tracker.record_test(&tracker.attribute_path(&test.expr), test.name);
+
+            // `x is defined` / `x is undefined` guards against a missing `x`
+            if matches!(test.name, "defined" | "undefined") {
+                tracker.mark_optional(&tracker.attribute_path(&test.expr));
+                tracker.record_neutral_use(&tracker.attribute_path(&test.expr));
+            }
+
+            // `x is none` / `x is not none` means `x` may be null
+            if test.name == "none" {
+                tracker.mark_nullable(&tracker.attribute_path(&test.expr));
+            }
+
+            // Type tests (`x is string`) are direct evidence for that type
+            let hint = match test.name {
+                "string" => Some(TypeHint::String),
+                "number" | "float" => Some(TypeHint::Number),
+                "integer" => Some(TypeHint::Integer),
+                "boolean" => Some(TypeHint::Boolean),
+                _ => None,
+            };
+            if let Some(hint) = hint {
+                tracker.record_type(&tracker.attribute_path(&test.expr), hint);
+            }
 
             // Process test arguments
             for arg in &test.args {
@@ -555,9 +2403,34 @@ fn collect_var_reads(expr: &machinery::ast::Expr, tracker: &mut VariableTracker)
         machinery::ast::Expr::BinOp(bin_op) => {
             collect_var_reads(&bin_op.left, tracker);
             collect_var_reads(&bin_op.right, tracker);
+            collect_operator_hints(bin_op, tracker);
+
+            collect_comparison_values(bin_op, tracker);
+
+            // `x or fallback` renders fine without `x`
+            if matches!(bin_op.op, machinery::ast::BinOpKind::ScOr) {
+                tracker.mark_optional(&tracker.attribute_path(&bin_op.left));
+            }
+
+            // `x == none` / `x != none` means `x` may be null
+            if matches!(
+                bin_op.op,
+                machinery::ast::BinOpKind::Eq | machinery::ast::BinOpKind::Ne
+            ) {
+                if is_none_const(&bin_op.right) {
+                    tracker.mark_nullable(&tracker.attribute_path(&bin_op.left));
+                } else if is_none_const(&bin_op.left) {
+                    tracker.mark_nullable(&tracker.attribute_path(&bin_op.right));
+                }
+            }
         }
         machinery::ast::Expr::UnaryOp(unary_op) => {
             collect_var_reads(&unary_op.expr, tracker);
+
+            // `-x` makes `x` a number
+            if matches!(unary_op.op, machinery::ast::UnaryOpKind::Neg) {
+                tracker.record_type(&tracker.attribute_path(&unary_op.expr), TypeHint::Number);
+            }
         }
         machinery::ast::Expr::List(list) => {
             for item in &list.items {
@@ -572,27 +2445,759 @@ fn collect_var_reads(expr: &machinery::ast::Expr, tracker: &mut VariableTracker)
                 collect_var_reads(value, tracker);
             }
         }
+        machinery::ast::Expr::IfExpr(if_expr) => {
+            collect_var_reads(&if_expr.test_expr, tracker);
+            collect_truthiness_uses(&if_expr.test_expr, tracker);
+            collect_var_reads(&if_expr.true_expr, tracker);
+            if let Some(false_expr) = &if_expr.false_expr {
+                collect_var_reads(false_expr, tracker);
+            }
+        }
+        machinery::ast::Expr::Slice(slice) => {
+            collect_var_reads(&slice.expr, tracker);
+            for bound in [&slice.start, &slice.stop, &slice.step]
+                .into_iter()
+                .flatten()
+            {
+                collect_var_reads(bound, tracker);
+            }
+        }
         machinery::ast::Expr::Const(_) => {}
+    }
+}
+
+// Track paths used as bare truthiness tests in a condition (`x`, `not x`, `x and y`)
+fn collect_truthiness_uses(expr: &machinery::ast::Expr, tracker: &mut VariableTracker) {
+    match expr {
+        machinery::ast::Expr::UnaryOp(unary_op) => {
+            if matches!(unary_op.op, machinery::ast::UnaryOpKind::Not) {
+                collect_truthiness_uses(&unary_op.expr, tracker);
+            }
+        }
+        machinery::ast::Expr::BinOp(bin_op) => {
+            if matches!(
+                bin_op.op,
+                machinery::ast::BinOpKind::ScAnd | machinery::ast::BinOpKind::ScOr
+            ) {
+                collect_truthiness_uses(&bin_op.left, tracker);
+                collect_truthiness_uses(&bin_op.right, tracker);
+            }
+        }
+        machinery::ast::Expr::Var(_)
+        | machinery::ast::Expr::GetAttr(_)
+        | machinery::ast::Expr::GetItem(_) => {
+            tracker.record_truthiness_use(&tracker.attribute_path(expr));
+        }
         _ => {}
     }
 }
 
+// Methods that only exist on strings
+const STRING_METHODS: &[&str] = &[
+    "capitalize",
+    "endswith",
+    "lower",
+    "lstrip",
+    "replace",
+    "rstrip",
+    "split",
+    "splitlines",
+    "startswith",
+    "strip",
+    "title",
+    "upper",
+];
+
+// Filters that always produce a string
+const STRING_FILTERS: &[&str] = &[
+    "capitalize",
+    "escape",
+    "e",
+    "indent",
+    "join",
+    "lower",
+    "replace",
+    "string",
+    "title",
+    "tojson",
+    "trim",
+    "upper",
+];
+
+// Type evidence from operators, `a ~ b` and `'text' + b` make both sides strings,
+// `a - b`, `n + 1` and `count > 3` make both sides numbers
+fn collect_operator_hints(bin_op: &machinery::ast::BinOp, tracker: &mut VariableTracker) {
+    use machinery::ast::BinOpKind;
+
+    let operands = [&bin_op.left, &bin_op.right];
+    let has_string = operands.iter().any(|expr| is_string_expr(expr));
+    let has_number = operands.iter().any(|expr| is_number_expr(expr));
+    let hint = match bin_op.op {
+        BinOpKind::Concat => Some(TypeHint::String),
+        BinOpKind::Add if has_string => Some(TypeHint::String),
+        BinOpKind::Add if has_number => Some(TypeHint::Number),
+        // `'-' * 3` repeats a string
+        BinOpKind::Mul if has_string => None,
+        BinOpKind::Sub
+        | BinOpKind::Mul
+        | BinOpKind::Div
+        | BinOpKind::FloorDiv
+        | BinOpKind::Rem
+        | BinOpKind::Pow => Some(TypeHint::Number),
+        BinOpKind::Lt | BinOpKind::Lte | BinOpKind::Gt | BinOpKind::Gte if has_number => {
+            if operands.iter().any(|expr| is_integer_const(expr)) {
+                Some(TypeHint::Integer)
+            } else {
+                Some(TypeHint::Number)
+            }
+        }
+        _ => None,
+    };
+
+    if let Some(hint) = hint {
+        for operand in operands {
+            tracker.record_type(&tracker.attribute_path(operand), hint);
+        }
+    }
+}
+
+// Whether an expression is a bare integer literal
+fn is_integer_const(expr: &machinery::ast::Expr) -> bool {
+    match expr {
+        machinery::ast::Expr::Const(constant) => constant.value.is_integer(),
+        _ => false,
+    }
+}
+
+// Whether an expression is known to evaluate to a number
+fn is_number_expr(expr: &machinery::ast::Expr) -> bool {
+    use machinery::ast::BinOpKind;
+
+    match expr {
+        machinery::ast::Expr::Const(constant) => constant.value.is_number(),
+        machinery::ast::Expr::UnaryOp(unary_op) => {
+            matches!(unary_op.op, machinery::ast::UnaryOpKind::Neg)
+        }
+        machinery::ast::Expr::BinOp(bin_op) => match bin_op.op {
+            BinOpKind::Sub
+            | BinOpKind::Div
+            | BinOpKind::FloorDiv
+            | BinOpKind::Rem
+            | BinOpKind::Pow => true,
+            BinOpKind::Add | BinOpKind::Mul => {
+                is_number_expr(&bin_op.left) || is_number_expr(&bin_op.right)
+            }
+            _ => false,
+        },
+        // `loop.index`, `loop.length`, ...
+        machinery::ast::Expr::GetAttr(get_attr) => {
+            matches!(&get_attr.expr, machinery::ast::Expr::Var(var) if var.id == "loop")
+                && matches!(
+                    get_attr.name,
+                    "index" | "index0" | "revindex" | "revindex0" | "length" | "depth" | "depth0"
+                )
+        }
+        machinery::ast::Expr::Filter(filter) => matches!(
+            filter.name,
+            "length" | "count" | "int" | "float" | "round" | "abs" | "sum"
+        ),
+        _ => false,
+    }
+}
+
+// Enum candidates from `x == 'a'`, `x != 'a'` and `x in ['a', 'b']`
+fn collect_comparison_values(bin_op: &machinery::ast::BinOp, tracker: &mut VariableTracker) {
+    // Compared against a date-shaped constant, so a date or timestamp
+    if comparison(&bin_op.op).is_some() {
+        let format = |expr: &machinery::ast::Expr| {
+            const_value(expr).and_then(|value| format::date_format_of(value.as_str()?))
+        };
+        match (format(&bin_op.left), format(&bin_op.right)) {
+            (None, Some(format)) => {
+                tracker.record_format(&tracker.attribute_path(&bin_op.left), format)
+            }
+            (Some(format), None) => {
+                tracker.record_format(&tracker.attribute_path(&bin_op.right), format)
+            }
+            _ => {}
+        }
+    }
+
+    match bin_op.op {
+        machinery::ast::BinOpKind::Eq | machinery::ast::BinOpKind::Ne => {
+            match (const_value(&bin_op.left), const_value(&bin_op.right)) {
+                (None, Some(value)) => {
+                    tracker.record_enum_value(&tracker.attribute_path(&bin_op.left), value)
+                }
+                (Some(value), None) => {
+                    tracker.record_enum_value(&tracker.attribute_path(&bin_op.right), value)
+                }
+                _ => {}
+            }
+        }
+        machinery::ast::BinOpKind::In => {
+            let path = tracker.attribute_path(&bin_op.left);
+            let candidates = bin_op
+                .right
+                .as_const()
+                .and_then(|value| serde_json::to_value(value).ok());
+            if let Some(Value::Array(candidates)) = candidates {
+                for value in candidates {
+                    tracker.record_enum_value(&path, value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// Schema path an `if`/`elif` chain tests against two or more constants with
+// no fallthrough: every branch compares the same path and the `else` raises
+fn closed_enum_path(if_cond: &machinery::ast::IfCond, tracker: &VariableTracker) -> Option<String> {
+    let mut chain_path = None;
+    let mut constants = 0;
+    let mut branch = if_cond;
+    loop {
+        let (path, count) = constant_test(&branch.expr)?;
+        if chain_path.get_or_insert_with(|| path.clone()) != &path {
+            return None;
+        }
+        constants += count;
+        match branch.false_body.as_slice() {
+            [machinery::ast::Stmt::IfCond(next)] => branch = next,
+            body if raises(body) => break,
+            _ => return None,
+        }
+    }
+    if constants < 2 {
+        return None;
+    }
+    tracker.tracked_schema_path(&chain_path?)
+}
+
+// Tracker path and number of constants of `x == 'a'`, `'a' == x` or `x in ['a', 'b']`
+fn constant_test(expr: &machinery::ast::Expr) -> Option<(String, usize)> {
+    let machinery::ast::Expr::BinOp(bin_op) = expr else {
+        return None;
+    };
+    let (path, count) = match bin_op.op {
+        machinery::ast::BinOpKind::Eq => {
+            match (const_value(&bin_op.left), const_value(&bin_op.right)) {
+                (None, Some(_)) => (get_attribute_path(&bin_op.left), 1),
+                (Some(_), None) => (get_attribute_path(&bin_op.right), 1),
+                _ => return None,
+            }
+        }
+        machinery::ast::BinOpKind::In => {
+            match serde_json::to_value(bin_op.right.as_const()?).ok()? {
+                Value::Array(candidates) => (get_attribute_path(&bin_op.left), candidates.len()),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    (!path.is_empty()).then_some((path, count))
+}
+
+// Whether a branch body calls `raise_exception` at its top level
+fn raises(body: &[machinery::ast::Stmt]) -> bool {
+    body.iter().any(|stmt| match stmt {
+        machinery::ast::Stmt::EmitExpr(emit) => matches!(
+            &emit.expr,
+            machinery::ast::Expr::Call(call)
+                if matches!(&call.expr, machinery::ast::Expr::Var(callee) if callee.id == "raise_exception")
+        ),
+        _ => false,
+    })
+}
+
+// Constant (other than `none`) as a JSON value
+fn const_value(expr: &machinery::ast::Expr) -> Option<Value> {
+    match expr {
+        machinery::ast::Expr::Const(constant) if !constant.value.is_none() => {
+            serde_json::to_value(&constant.value).ok()
+        }
+        _ => None,
+    }
+}
+
+// Whether an expression is known to evaluate to a string
+fn is_string_expr(expr: &machinery::ast::Expr) -> bool {
+    match expr {
+        machinery::ast::Expr::Const(constant) => constant.value.as_str().is_some(),
+        machinery::ast::Expr::BinOp(bin_op) => match bin_op.op {
+            machinery::ast::BinOpKind::Concat => true,
+            machinery::ast::BinOpKind::Add => {
+                is_string_expr(&bin_op.left) || is_string_expr(&bin_op.right)
+            }
+            _ => false,
+        },
+        machinery::ast::Expr::Filter(filter) => STRING_FILTERS.contains(&filter.name),
+        machinery::ast::Expr::Call(call) => match &call.expr {
+            machinery::ast::Expr::GetAttr(method) => {
+                STRING_METHODS.contains(&method.name)
+                    && !matches!(
+                        method.name,
+                        "split" | "splitlines" | "startswith" | "endswith"
+                    )
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Track reads in a call and feed macro parameter defaults into the passed values
+fn collect_call_reads(
+    call: &machinery::ast::Spanned<machinery::ast::Call>,
+    tracker: &mut VariableTracker,
+) {
+    // Anything called by name that the template did not define must come from the environment
+    if let machinery::ast::Expr::Var(callee) = &call.expr {
+        if callee.id == "loop" {
+            // `loop(xs)` recurses in a recursive for loop
+            if let Some(machinery::ast::CallArg::Pos(arg)) = call.args.first() {
+                tracker.record_recursion(&tracker.attribute_path(iterated_expr(arg)));
+            }
+        } else if !tracker.internal_vars.contains(callee.id) && !tracker.is_local(callee.id) {
+            tracker.record_global_call(callee.id, call.span().into());
+        }
+    }
+
+    match &call.expr {
+        machinery::ast::Expr::GetAttr(method) => {
+            // Method call, the receiver is read but the method is not one of its attributes
+            collect_var_reads(&method.expr, tracker);
+            if STRING_METHODS.contains(&method.name) {
+                tracker.record_type(&tracker.attribute_path(&method.expr), TypeHint::String);
+            }
+            // `x.strftime(...)` / `x.isoformat()` format a timestamp
+            if matches!(method.name, "strftime" | "isoformat") {
+                tracker.record_format(&tracker.attribute_path(&method.expr), "date-time");
+            }
+        }
+        _ => collect_var_reads(&call.expr, tracker),
+    }
+
+    // Process call arguments
+    for arg in &call.args {
+        // Use extract_vars_from_debug_str instead of direct call to handle CallArg type
+        let arg_str = format!("{arg:?}");
+        extract_vars_from_debug_str(&arg_str, tracker);
+    }
+
+    let machinery::ast::Expr::Var(callee) = &call.expr else {
+        return;
+    };
+    let Some(params) = tracker.macros.get(callee.id).cloned() else {
+        return;
+    };
+
+    for (idx, arg) in call.args.iter().enumerate() {
+        let (param, expr) = match arg {
+            machinery::ast::CallArg::Pos(expr) => (params.get(idx), expr),
+            machinery::ast::CallArg::Kwarg(name, expr) => {
+                (params.iter().find(|(param, _)| param == name), expr)
+            }
+            _ => continue,
+        };
+        if let Some((_, Some(default))) = param {
+            tracker.record_default(&tracker.attribute_path(expr), default.clone());
+        }
+    }
+}
+
+// Walks a macro body with its parameters in a scope of their own, so they
+// shadow context variables of the same name only inside the body
+fn collect_macro_body(
+    macro_decl: &machinery::ast::Macro,
+    params: Vec<(String, Option<Value>)>,
+    tracker: &mut VariableTracker,
+) {
+    let mut scope = params
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<HashSet<_>>();
+    scope.extend(["caller", "varargs", "kwargs"].map(String::from));
+    tracker.local_scopes.push(scope);
+    for child in &macro_decl.body {
+        collect_variables(child, tracker);
+    }
+    tracker.local_scopes.pop();
+}
+
+// Returns the macro parameters with their constant defaults (defaults belong
+// to the trailing parameters); defaults are read outside the macro scope
+fn collect_macro_params(
+    macro_decl: &machinery::ast::Macro,
+    tracker: &mut VariableTracker,
+) -> Vec<(String, Option<Value>)> {
+    let first_default = macro_decl.args.len() - macro_decl.defaults.len();
+    let mut params = Vec::new();
+
+    for (idx, arg) in macro_decl.args.iter().enumerate() {
+        let name = get_attribute_path(arg);
+        let default_expr = idx
+            .checked_sub(first_default)
+            .and_then(|default_idx| macro_decl.defaults.get(default_idx));
+
+        // Non-constant defaults can still read from the context
+        if let Some(expr) = default_expr {
+            collect_var_reads(expr, tracker);
+        }
+        let default = default_expr
+            .and_then(|expr| expr.as_const())
+            .and_then(|value| serde_json::to_value(value).ok());
+
+        params.push((name, default));
+    }
+
+    params
+}
+
 // Helper function to recursively build the full attribute path
 fn get_attribute_path(expr: &machinery::ast::Expr) -> String {
+    attribute_path(expr, &[])
+}
+
+// Full attribute path of an expression, with `index_vars` subscripting arrays
+fn attribute_path(expr: &machinery::ast::Expr, index_vars: &[String]) -> String {
     match expr {
         machinery::ast::Expr::Var(var) => var.id.to_string(),
         machinery::ast::Expr::GetAttr(get_attr) => {
-            let base_path = get_attribute_path(&get_attr.expr);
+            let base_path = attribute_path(&get_attr.expr, index_vars);
             if !base_path.is_empty() {
                 format!("{}.{}", base_path, get_attr.name)
             } else {
                 String::new()
             }
         }
+        machinery::ast::Expr::GetItem(get_item) => {
+            // Normalise `obj['key']` into `obj.key`, `xs[0]` and `xs[i]` into
+            // `xs[]` and `obj[key]` into `obj{}`
+            let base_path = attribute_path(&get_item.expr, index_vars);
+            match (&get_item.subscript_expr, base_path.is_empty()) {
+                (_, true) => String::new(),
+                (subscript, false) if is_index(subscript, index_vars) => format!("{base_path}[]"),
+                (machinery::ast::Expr::Const(constant), false) => match constant.value.as_str() {
+                    Some(key) => format!("{base_path}.{key}"),
+                    None => String::new(),
+                },
+                (_, false) => format!("{base_path}{{}}"),
+            }
+        }
         _ => String::new(),
     }
 }
 
+// Helper to check whether a subscript is a computed, non-numeric key (`data[field]`)
+fn is_computed_key(subscript: &machinery::ast::Expr, index_vars: &[String]) -> bool {
+    !matches!(subscript, machinery::ast::Expr::Const(_)) && !is_index(subscript, index_vars)
+}
+
+// Whether a subscript is a position (`xs[0]`, `xs[loop.index0]`, `xs[i]` with
+// `i` from `range(...)`)
+fn is_index(subscript: &machinery::ast::Expr, index_vars: &[String]) -> bool {
+    match subscript {
+        machinery::ast::Expr::Var(var) => index_vars.iter().any(|name| name == var.id),
+        _ => is_number_expr(subscript),
+    }
+}
+
+// Type hint implied by a constant default value, if it is a scalar
+pub(crate) fn default_type_hint(value: &Value) -> Option<TypeHint> {
+    match value {
+        Value::Bool(_) => Some(TypeHint::Boolean),
+        Value::Number(number) if number.is_f64() => Some(TypeHint::Number),
+        Value::Number(_) => Some(TypeHint::Integer),
+        Value::String(_) => Some(TypeHint::String),
+        _ => None,
+    }
+}
+
+// Helper to split `for key, value in data.items()` (or `data | dictsort`)
+// into its key variable, value variable and mapping path
+fn items_loop<'a>(for_loop: &'a machinery::ast::ForLoop) -> Option<(&'a str, &'a str, String)> {
+    let machinery::ast::Expr::List(target) = &for_loop.target else {
+        return None;
+    };
+    let [machinery::ast::Expr::Var(key), machinery::ast::Expr::Var(value)] =
+        target.items.as_slice()
+    else {
+        return None;
+    };
+
+    let mapping = match &for_loop.iter {
+        machinery::ast::Expr::Call(call) => match &call.expr {
+            machinery::ast::Expr::GetAttr(get_attr) if get_attr.name == "items" => {
+                get_attribute_path(&get_attr.expr)
+            }
+            _ => return None,
+        },
+        machinery::ast::Expr::Filter(filter) if filter.name == "dictsort" => {
+            get_attribute_path(filter.expr.as_ref()?)
+        }
+        _ => return None,
+    };
+    if mapping.is_empty() {
+        return None;
+    }
+    Some((key.id, value.id, mapping))
+}
+
+// Helper to get the span of an expression from its leftmost operand; the
+// parser may start postfix and binary expressions at the operator or a
+// preceding token (`messages[0].role`, `if a in b`)
+fn expr_span(expr: &machinery::ast::Expr) -> machinery::Span {
+    let base = match expr {
+        machinery::ast::Expr::GetAttr(get_attr) => Some(&get_attr.expr),
+        machinery::ast::Expr::GetItem(get_item) => Some(&get_item.expr),
+        machinery::ast::Expr::Slice(slice) => Some(&slice.expr),
+        machinery::ast::Expr::Call(call) => Some(&call.expr),
+        machinery::ast::Expr::Filter(filter) => filter.expr.as_ref(),
+        machinery::ast::Expr::Test(test) => Some(&test.expr),
+        machinery::ast::Expr::BinOp(bin_op) => Some(&bin_op.left),
+        _ => None,
+    };
+    let mut span = expr.span();
+    if let Some(base) = base {
+        let base_span = expr_span(base);
+        span.start_line = base_span.start_line;
+        span.start_col = base_span.start_col;
+        span.start_offset = base_span.start_offset;
+    }
+    span
+}
+
+// Helper to write the negation of a condition in template syntax,
+// flipping comparisons and tests where possible
+fn negate_condition(expr: &machinery::ast::Expr, tracker: &VariableTracker) -> String {
+    let text = |expr: &machinery::ast::Expr| tracker.source_text(expr).to_string();
+    match expr {
+        machinery::ast::Expr::UnaryOp(unary_op)
+            if matches!(unary_op.op, machinery::ast::UnaryOpKind::Not) =>
+        {
+            text(&unary_op.expr)
+        }
+        machinery::ast::Expr::BinOp(bin_op) => {
+            let op = match bin_op.op {
+                machinery::ast::BinOpKind::Eq => "!=",
+                machinery::ast::BinOpKind::Ne => "==",
+                machinery::ast::BinOpKind::Lt => ">=",
+                machinery::ast::BinOpKind::Lte => ">",
+                machinery::ast::BinOpKind::Gt => "<=",
+                machinery::ast::BinOpKind::Gte => "<",
+                machinery::ast::BinOpKind::In => "not in",
+                _ => return format!("not ({})", text(expr)),
+            };
+            format!("{} {op} {}", text(&bin_op.left), text(&bin_op.right))
+        }
+        machinery::ast::Expr::Test(test) if test.args.is_empty() => {
+            format!("{} is not {}", text(&test.expr), test.name)
+        }
+        machinery::ast::Expr::Var(_) | machinery::ast::Expr::GetAttr(_) => {
+            format!("not {}", text(expr))
+        }
+        _ => format!("not ({})", text(expr)),
+    }
+}
+
+// Date format of the value an expression produces: the pattern passed to
+// `strftime_now`, or the format of a date-shaped string constant
+fn produced_date_format(expr: &machinery::ast::Expr) -> Option<String> {
+    match expr {
+        machinery::ast::Expr::Call(call) => {
+            let machinery::ast::Expr::Var(callee) = &call.expr else {
+                return None;
+            };
+            match (callee.id, call.args.first()) {
+                ("strftime_now", Some(machinery::ast::CallArg::Pos(pattern))) => {
+                    Some(const_value(pattern)?.as_str()?.to_string())
+                }
+                _ => None,
+            }
+        }
+        machinery::ast::Expr::Const(constant) => {
+            format::date_format_of(constant.value.as_str()?).map(str::to_string)
+        }
+        _ => None,
+    }
+}
+
+// Item count bounds implied by a `xs | length <op> n` condition when it is
+// true (`holds`) or false, with the variable path of `xs`
+fn length_bounds(expr: &machinery::ast::Expr, holds: bool) -> Option<(String, Cardinality)> {
+    let machinery::ast::Expr::BinOp(bin_op) = expr else {
+        return None;
+    };
+    let length_of = |expr: &machinery::ast::Expr| match expr {
+        machinery::ast::Expr::Filter(filter)
+            if matches!(filter.name, "length" | "count") && filter.args.is_empty() =>
+        {
+            filter.expr.as_ref().map(get_attribute_path)
+        }
+        _ => None,
+    };
+    // Normalized to `length <op> n`, as (less, equal, greater) admitted
+    let (var_name, n, mut admits) = match (
+        length_of(&bin_op.left),
+        integer_value(&bin_op.right),
+        length_of(&bin_op.right),
+        integer_value(&bin_op.left),
+    ) {
+        (Some(var_name), Some(n), _, _) => (var_name, n, comparison(&bin_op.op)?),
+        (_, _, Some(var_name), Some(n)) => {
+            let (less, equal, greater) = comparison(&bin_op.op)?;
+            (var_name, n, (greater, equal, less))
+        }
+        _ => return None,
+    };
+    if !holds {
+        admits = (!admits.0, !admits.1, !admits.2);
+    }
+    if var_name.is_empty() || n < 0 {
+        return None;
+    }
+
+    let n = n as usize;
+    let bounds = match admits {
+        (false, true, true) => Cardinality {
+            min_items: n,
+            max_items: None,
+        },
+        (false, false, true) => Cardinality {
+            min_items: n + 1,
+            max_items: None,
+        },
+        (true, true, false) => Cardinality {
+            min_items: 0,
+            max_items: Some(n),
+        },
+        (true, false, false) if n > 0 => Cardinality {
+            min_items: 0,
+            max_items: Some(n - 1),
+        },
+        (false, true, false) => Cardinality {
+            min_items: n,
+            max_items: Some(n),
+        },
+        _ => return None,
+    };
+    Some((var_name, bounds))
+}
+
+// Which of `<`, `==`, `>` a comparison operator admits
+fn comparison(op: &machinery::ast::BinOpKind) -> Option<(bool, bool, bool)> {
+    use machinery::ast::BinOpKind;
+
+    match op {
+        BinOpKind::Lt => Some((true, false, false)),
+        BinOpKind::Lte => Some((true, true, false)),
+        BinOpKind::Gt => Some((false, false, true)),
+        BinOpKind::Gte => Some((false, true, true)),
+        BinOpKind::Eq => Some((false, true, false)),
+        BinOpKind::Ne => Some((true, false, true)),
+        _ => None,
+    }
+}
+
+// Value of an integer literal, including negated ones (`-1`)
+fn integer_value(expr: &machinery::ast::Expr) -> Option<i64> {
+    match expr {
+        machinery::ast::Expr::Const(constant) if constant.value.is_integer() => {
+            i64::try_from(constant.value.clone()).ok()
+        }
+        machinery::ast::Expr::UnaryOp(unary_op)
+            if matches!(unary_op.op, machinery::ast::UnaryOpKind::Neg) =>
+        {
+            integer_value(&unary_op.expr).map(|n| -n)
+        }
+        _ => None,
+    }
+}
+
+// Helper to collect the schema paths a condition refers to
+fn collect_condition_paths(
+    expr: &machinery::ast::Expr,
+    tracker: &VariableTracker,
+    paths: &mut BTreeSet<String>,
+) {
+    if let Some(path) = tracker.tracked_schema_path(&tracker.attribute_path(expr)) {
+        if !tracker.internal_vars.contains(&path) {
+            paths.insert(path);
+        }
+        return;
+    }
+    match expr {
+        machinery::ast::Expr::GetAttr(get_attr) => {
+            collect_condition_paths(&get_attr.expr, tracker, paths)
+        }
+        machinery::ast::Expr::GetItem(get_item) => {
+            collect_condition_paths(&get_item.expr, tracker, paths)
+        }
+        machinery::ast::Expr::UnaryOp(unary_op) => {
+            collect_condition_paths(&unary_op.expr, tracker, paths)
+        }
+        machinery::ast::Expr::BinOp(bin_op) => {
+            collect_condition_paths(&bin_op.left, tracker, paths);
+            collect_condition_paths(&bin_op.right, tracker, paths);
+        }
+        machinery::ast::Expr::Test(test) => collect_condition_paths(&test.expr, tracker, paths),
+        machinery::ast::Expr::Filter(filter) => {
+            if let Some(inner) = &filter.expr {
+                collect_condition_paths(inner, tracker, paths);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Helper to find the collection a loop iterates, looking through slices and
+// filters that keep the items unchanged (`messages[1:]`, `messages|reverse`)
+fn iterated_expr<'a, 'b>(expr: &'a machinery::ast::Expr<'b>) -> &'a machinery::ast::Expr<'b> {
+    match expr {
+        machinery::ast::Expr::Slice(slice) => iterated_expr(&slice.expr),
+        machinery::ast::Expr::Filter(filter)
+            if matches!(
+                filter.name,
+                "reverse"
+                    | "sort"
+                    | "list"
+                    | "unique"
+                    | "select"
+                    | "reject"
+                    | "selectattr"
+                    | "rejectattr"
+            ) =>
+        {
+            match &filter.expr {
+                Some(inner) => iterated_expr(inner),
+                None => expr,
+            }
+        }
+        _ => expr,
+    }
+}
+
+// Helper to strip a filter chain down to the filtered expression
+fn unwrap_filters<'a, 'b>(expr: &'a machinery::ast::Expr<'b>) -> &'a machinery::ast::Expr<'b> {
+    match expr {
+        machinery::ast::Expr::Filter(filter) => match &filter.expr {
+            Some(inner) => unwrap_filters(inner),
+            None => expr,
+        },
+        _ => expr,
+    }
+}
+
+// Helper to check whether an expression is the `none` literal
+fn is_none_const(expr: &machinery::ast::Expr) -> bool {
+    match expr {
+        machinery::ast::Expr::Const(constant) => constant.value.is_none(),
+        _ => false,
+    }
+}
+
 // Helper to extract a clean variable name from a debug string
 fn extract_var_name(debug_str: &str) -> Option<String> {
     if let Some(start) = debug_str.find("id: \"") {
@@ -638,8 +3243,8 @@ fn extract_vars_from_debug_str(debug_str: &str, tracker: &mut VariableTracker) {
                     let mut full_path = path_parts[0].clone();
                     tracker.track_access(&full_path, VarAccess::Read);
 
-                    for i in 1..path_parts.len() {
-                        full_path = format!("{}.{}", full_path, path_parts[i]);
+                    for part in path_parts.iter().skip(1) {
+                        full_path = format!("{full_path}.{part}");
                         tracker.track_access(&full_path, VarAccess::Read);
                     }
                 }
@@ -651,6 +3256,7 @@ fn extract_vars_from_debug_str(debug_str: &str, tracker: &mut VariableTracker) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
 
     #[test]
     fn test_external_variable_detection() {
@@ -681,7 +3287,8 @@ mod tests {
         let template = "{% for item in items %}{{ item.name }}{% endfor %}";
         // need to ensure that name is in the object shapes
         let analysis = analyze(template, false).unwrap();
-        let object_shapes = analysis.object_shapes_json.as_object().unwrap();
+        let object_shapes_json = analysis.object_shapes_json();
+        let object_shapes = object_shapes_json.as_object().unwrap();
         assert!(object_shapes.contains_key("items"));
         assert!(!object_shapes["items"].as_array().unwrap().is_empty());
         assert!(object_shapes["items"][0]
@@ -689,4 +3296,647 @@ mod tests {
             .unwrap()
             .contains_key("name"));
     }
+
+    #[test]
+    fn test_nullable_from_none_checks() {
+        let template = "{% for message in messages %}{% if message.content is not none %}{{ message.content }}{% endif %}{% endfor %}{% if tools == none %}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        assert!(analysis.nullable_paths.contains("messages[].content"));
+        assert!(analysis.nullable_paths.contains("tools"));
+        let object_shapes_json = analysis.object_shapes_json();
+        let object_shapes = object_shapes_json.as_object().unwrap();
+        assert!(object_shapes["messages"][0]["content"].is_null());
+        assert!(object_shapes["tools"].is_null());
+    }
+
+    #[test]
+    fn test_macro_defaults_as_hints() {
+        let template = "{% macro render(msg, show_role=true) %}{{ msg }}{% endmacro %}{{ render(message, show_role=add_role) }}";
+        let analysis = analyze(template, false).unwrap();
+        assert!(analysis.internal_vars.contains("render"));
+        assert!(analysis.external_vars.contains("add_role"));
+        assert!(!analysis.external_vars.contains("show_role"));
+        assert_eq!(analysis.defaults.get("add_role"), Some(&json!(true)));
+        assert_eq!(analysis.object_shapes_json()["add_role"], json!(true));
+
+        let template = "{% macro render(msg, show_role=true, n=3, ratio=0.5) %}{{ msg }}{% endmacro %}{{ render(message, show_role=add_role, n=count, ratio=temperature) }}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(
+            analysis.type_hints["add_role"],
+            BTreeSet::from([TypeHint::Boolean])
+        );
+        assert_eq!(
+            analysis.type_hints["count"],
+            BTreeSet::from([TypeHint::Integer])
+        );
+        assert_eq!(
+            analysis.type_hints["temperature"],
+            BTreeSet::from([TypeHint::Number])
+        );
+        assert_eq!(analysis.shape.fields()["count"], &Shape::Integer);
+    }
+
+    #[test]
+    fn test_macro_params_are_scoped() {
+        // The parameter shares its name with the context variable passed to it
+        let template = "{% macro render_tools(tools) %}{% for tool in tools %}{{ tool.name }}{% endfor %}{{ tools.extra }}{% endmacro %}{% if tools %}{{ render_tools(tools) }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        assert!(analysis.external_vars.contains("tools"));
+        assert!(!analysis.internal_vars.contains("tools"));
+        assert!(!analysis.external_vars.contains("tool"));
+        assert_eq!(analysis.object_shapes_json()["tools"], json!(""));
+    }
+
+    #[test]
+    fn test_filter_inventory() {
+        let template = "{% for message in messages %}{{ message.content | trim | tojson }}{% endfor %}{{ tools | length }}";
+        let analysis = analyze(template, false).unwrap();
+        let content_filters = &analysis.filters["messages[].content"];
+        assert!(content_filters.contains("trim"));
+        assert!(content_filters.contains("tojson"));
+        assert!(analysis.filters["tools"].contains("length"));
+    }
+
+    #[test]
+    fn test_test_inventory() {
+        let template = "{% if tools is defined and tools is iterable %}{% endif %}{% for message in messages %}{% if message.content is string %}{% endif %}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+        assert!(analysis.tests["tools"].contains("defined"));
+        assert!(analysis.tests["tools"].contains("iterable"));
+        assert!(analysis.tests["messages[].content"].contains("string"));
+    }
+
+    #[test]
+    fn test_globals_used() {
+        let template = "{% macro m() %}{% endmacro %}{{ m() }}{% for i in range(3) %}{% endfor %}{% if x %}{{ raise_exception('a') }}{% else %}{{ raise_exception('b') }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        assert!(!analysis.globals_used.contains_key("m"));
+        assert_eq!(analysis.globals_used["range"].count, 1);
+        let raise = &analysis.globals_used["raise_exception"];
+        assert_eq!(raise.count, 2);
+        assert_eq!(raise.spans.len(), 2);
+        assert_eq!(raise.spans[0].start_line, 1);
+    }
+
+    #[test]
+    fn test_globals_left_out_of_context() {
+        let template = "{% set ns = namespace(found=false) %}{% for i in range(messages | length) %}{{ messages[i].content }}{% endfor %}{{ strftime_now('%Y') }}{{ raise_exception('x') }}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(Vec::from_iter(&analysis.external_vars), vec!["messages"]);
+        let schema = analysis.to_json_schema();
+        assert_eq!(schema["required"], json!(["messages"]));
+        assert_eq!(
+            Vec::from_iter(schema["properties"].as_object().unwrap().keys()),
+            vec!["messages"]
+        );
+        assert_eq!(analysis.globals_used.len(), 4);
+    }
+
+    #[test]
+    fn test_unknown_filters() {
+        let template = "{{ messages | tojson }}{{ x | from_json }}{{ y | pretty }}{% filter shout %}hi{% endfilter %}";
+        let options = AnalyzeOptions {
+            custom_filters: BTreeSet::from(["pretty".to_string()]),
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+        assert_eq!(
+            analysis.unknown_filters,
+            BTreeSet::from(["from_json".to_string(), "shout".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_warnings() {
+        let template = "{{ name }}{% set name = name | upper %}{{ x | shout }}{% import 'macros.jinja' as m %}{% do items.append(1) %}{{ a.b.c }}";
+        let options = AnalyzeOptions::builder().max_depth(2).build();
+        let analysis = analyze_with(template, &options).unwrap();
+        let codes = analysis
+            .warnings
+            .iter()
+            .map(|warning| (warning.code, warning.path.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            vec![
+                (WarningCode::Shadowing, Some("name")),
+                (WarningCode::UnhandledNode, None),
+                (WarningCode::UnhandledNode, None),
+                (WarningCode::UnknownFilter, None),
+                (WarningCode::Truncated, Some("a.b.c")),
+            ]
+        );
+        assert_eq!(analysis.warnings[1].span.unwrap().start_col, 57);
+        assert_eq!(WarningCode::UnknownFilter.as_str(), "unknown-filter");
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let template = "{# @param messages[].content: the text #}{% for m in messages %}{% if m.role == 'user' %}{{ m.content | trim }}{% endif %}{% endfor %}{% if messages | length < 1 %}{{ raise_exception('empty') }}{% endif %}";
+        let options = AnalyzeOptions::builder()
+            .provenance(true)
+            .name("chat")
+            .build();
+        let analysis = analyze_with(template, &options).unwrap();
+        let json = serde_json::to_value(&analysis).unwrap();
+        assert_eq!(json["loop_vars"], serde_json::json!({"m": "messages"}));
+        assert_eq!(
+            json["shape"]["Object"]["messages"]["Array"]["Object"]["role"],
+            "String"
+        );
+        let restored = serde_json::from_value::<TemplateAnalysis>(json).unwrap();
+        assert_eq!(restored, analysis);
+        assert_ne!(restored, analyze(template, false).unwrap());
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let analysis = analyze("{{ user.name }}", false).unwrap();
+        let mut json = serde_json::to_value(&analysis).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(
+            TemplateAnalysis::from_value(json.clone()).unwrap(),
+            analysis
+        );
+
+        // Version 1 had neither the version key nor the warnings
+        let fields = json.as_object_mut().unwrap();
+        fields.remove("schema_version");
+        fields.remove("warnings");
+        assert!(serde_json::from_value::<TemplateAnalysis>(json.clone()).is_err());
+        let upgraded = TemplateAnalysis::from_json(&json.to_string()).unwrap();
+        assert_eq!(upgraded, analysis);
+
+        json["schema_version"] = (SCHEMA_VERSION + 1).into();
+        let err = TemplateAnalysis::from_value(json).unwrap_err();
+        assert!(err.to_string().contains("unsupported schema_version 3"));
+    }
+
+    #[test]
+    fn test_options_builder() {
+        let options = AnalyzeOptions::builder()
+            .custom_filter("pretty")
+            .rule("settings_json: string".parse().unwrap())
+            .loader(|name| match name {
+                "header.jinja" => Some("{{ bos_token }}{% include 'header.jinja' %}".to_string()),
+                _ => None,
+            })
+            .build();
+        let template = "{% include 'header.jinja' %}{{ messages | pretty }}{{ settings_json }}";
+        let analysis = analyze_with(template, &options).unwrap();
+        assert_eq!(
+            analysis.external_vars,
+            BTreeSet::from(["bos_token", "messages", "settings_json"].map(String::from))
+        );
+        assert_eq!(analysis.shape.fields()["settings_json"], &Shape::String);
+
+        let strict = AnalyzeOptions {
+            strict: true,
+            ..options
+        };
+        assert!(analyze_with(template, &strict).is_ok());
+        assert!(analyze_with("{{ x | shout }}", &strict).is_err());
+        assert!(analyze_with("{% include 'footer.jinja' %}", &strict).is_err());
+        assert!(analyze_with("{% include 'footer.jinja' ignore missing %}", &strict).is_ok());
+    }
+
+    #[test]
+    fn test_string_hints_from_operators_and_methods() {
+        let template = "{% for message in messages %}{{ bos_token ~ message.content }}{{ '<|' + message['role'] + '|>' }}{% if message.name.startswith('x') %}{% endif %}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+        for path in [
+            "bos_token",
+            "messages[].content",
+            "messages[].role",
+            "messages[].name",
+        ] {
+            assert!(
+                analysis.type_hints[path].contains(&TypeHint::String),
+                "{path}"
+            );
+        }
+        // Methods are not attributes of the receiver
+        assert_eq!(
+            analysis.object_shapes_json()["messages"][0]["name"],
+            json!("")
+        );
+    }
+
+    #[test]
+    fn test_typed_shape() {
+        let template = "{% for message in messages %}{{ message.role ~ message.content }}{% endfor %}{{ name }}";
+        let analysis = analyze(template, false).unwrap();
+        let message = Shape::Object(BTreeMap::from([
+            ("content".to_string(), Shape::String),
+            ("role".to_string(), Shape::String),
+        ]));
+        assert_eq!(
+            analysis.shape,
+            Shape::Object(BTreeMap::from([
+                ("messages".to_string(), Shape::Array(Box::new(message))),
+                ("name".to_string(), Shape::Unknown),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_optional_paths() {
+        let template = "{% if tools is defined %}{% endif %}{{ system | default('') }}{{ name or 'x' }}{{ bos_token }}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(
+            analysis.optional_paths,
+            BTreeSet::from([
+                "name".to_string(),
+                "system".to_string(),
+                "tools".to_string()
+            ])
+        );
+        let schema = analysis.to_json_schema();
+        assert_eq!(schema["required"], json!(["bos_token"]));
+    }
+
+    #[test]
+    fn test_enum_candidates() {
+        let template = "{% for message in messages %}{% if message.role == 'user' %}{% elif message.role == 'assistant' %}{% elif 'user' == message.role %}{% endif %}{% if message.type in ['text', 'image'] %}{% endif %}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(
+            analysis.enum_values["messages[].role"],
+            vec![json!("user"), json!("assistant")]
+        );
+        assert_eq!(
+            analysis.enum_values["messages[].type"],
+            vec![json!("text"), json!("image")]
+        );
+        // Other roles fall through the chain, so the constants are only candidates
+        assert!(analysis.closed_enums.is_empty());
+        let schema = analysis.to_json_schema();
+        let role = &schema["properties"]["messages"]["items"]["properties"]["role"];
+        assert_eq!(role["enum"], Value::Null);
+        assert_eq!(role["x-enum-candidates"], json!(["user", "assistant"]));
+    }
+
+    #[test]
+    fn test_closed_enums() {
+        let template = "{% for message in messages %}{% if message.role == 'user' %}{% elif message.role in ['assistant', 'tool'] %}{% else %}{{ raise_exception('unknown role') }}{% endif %}{% endfor %}{% if k == 2 %}{% else %}{{ raise_exception('k') }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(
+            analysis.closed_enums,
+            BTreeSet::from(["messages[].role".to_string()])
+        );
+        let schema = analysis.to_json_schema();
+        let role = &schema["properties"]["messages"]["items"]["properties"]["role"];
+        assert_eq!(role["enum"], json!(["user", "assistant", "tool"]));
+        // A single constant doesn't close the values of `k`
+        assert_eq!(schema["properties"]["k"]["enum"], Value::Null);
+        assert_eq!(schema["properties"]["k"]["x-enum-candidates"], json!([2]));
+    }
+
+    #[test]
+    fn test_condition_only_booleans() {
+        let template = "{% if add_generation_prompt is defined and add_generation_prompt %}{% endif %}{% if not flag %}{% endif %}{% if name %}{{ name }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        let shapes = analysis.object_shapes_json();
+        assert_eq!(shapes["add_generation_prompt"], json!(false));
+        assert_eq!(shapes["flag"], json!(false));
+        assert_eq!(shapes["name"], json!(""));
+        let schema = analysis.to_json_schema();
+        assert_eq!(schema["properties"]["flag"], json!({"type": "boolean"}));
+    }
+
+    #[test]
+    fn test_number_hints_from_arithmetic() {
+        let template = "{% for m in messages %}{{ loop.index0 + offset }}{% endfor %}{% if count > 3 %}{% endif %}{{ total / parts }}{{ '-' * width }}";
+        let analysis = analyze(template, false).unwrap();
+        for path in ["offset", "count", "total", "parts"] {
+            assert_eq!(analysis.shape.to_json()[path], json!(0), "{path}");
+        }
+        assert!(!analysis.type_hints.contains_key("width"));
+        let schema = analysis.to_json_schema();
+        assert_eq!(schema["properties"]["total"], json!({"type": "number"}));
+    }
+
+    #[test]
+    fn test_integer_hints() {
+        let template = "{{ limit | int }}{% if count > 3 %}{% endif %}{% if ratio < 0.5 %}{% endif %}{% if depth == 2 %}{% endif %}{{ score | round }}";
+        let analysis = analyze(template, false).unwrap();
+        let schema = analysis.to_json_schema();
+        for path in ["limit", "count", "depth"] {
+            assert_eq!(
+                schema["properties"][path]["type"],
+                json!("integer"),
+                "{path}"
+            );
+        }
+        // `round` takes floats as well
+        for path in ["ratio", "score"] {
+            assert_eq!(
+                schema["properties"][path]["type"],
+                json!("number"),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_union_shapes() {
+        let template = "{% for message in messages %}{% if message.content is string %}{{ message.content }}{% else %}{% for part in message.content %}{{ part.text }}{% endfor %}{% endif %}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+        let Shape::Object(properties) = &analysis.shape else {
+            panic!("context should be an object");
+        };
+        let Some(Shape::Array(message)) = properties.get("messages") else {
+            panic!("messages should be an array");
+        };
+        let Shape::Object(message) = &**message else {
+            panic!("messages should hold objects");
+        };
+        assert_eq!(
+            message["content"],
+            Shape::Union(vec![
+                Shape::String,
+                Shape::Array(Box::new(Shape::Object(BTreeMap::from([(
+                    "text".to_string(),
+                    Shape::Unknown
+                )])))),
+            ])
+        );
+
+        let schema = analysis.to_json_schema();
+        let content = &schema["properties"]["messages"]["items"]["properties"]["content"];
+        assert_eq!(content["anyOf"][0], json!({"type": "string"}));
+        assert_eq!(content["anyOf"][1]["type"], json!("array"));
+    }
+
+    #[test]
+    fn test_length_container_evidence() {
+        let template = "{% if messages | length > 0 %}{% endif %}{{ tools | selectattr('type') | count }}{{ name ~ '!' }}{{ name | length }}";
+        let schema = analyze(template, false).unwrap().to_json_schema();
+        assert_eq!(schema["properties"]["messages"], json!({"type": "array"}));
+        assert_eq!(schema["properties"]["tools"], json!({"type": "array"}));
+        assert_eq!(schema["properties"]["name"], json!({"type": "string"}));
+    }
+
+    #[test]
+    fn test_tojson_opaque() {
+        let template = "{% for tool in tools %}{{ tool.function.name ~ ':' }}{{ tool.function.parameters | tojson }}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(
+            analysis.object_shapes_json(),
+            json!({"tools": [{"function": {"name": "", "parameters": {}}}]})
+        );
+        let schema = analysis.to_json_schema();
+        let function = &schema["properties"]["tools"]["items"]["properties"]["function"];
+        assert_eq!(function["properties"]["parameters"], json!({}));
+    }
+
+    #[test]
+    fn test_map_shapes() {
+        let template = "{% for name, tool in tools.items() %}{{ name ~ tool.description }}{% endfor %}{{ labels[key] ~ '!' }}";
+        let analysis = analyze(template, false).unwrap();
+        assert!(analysis.external_vars.contains("key"));
+        assert!(!analysis.external_vars.contains("name"));
+
+        let schema = analysis.to_json_schema();
+        assert_eq!(
+            schema["properties"]["tools"]["additionalProperties"]["properties"]["description"],
+            json!({"type": "string"})
+        );
+        assert_eq!(
+            schema["properties"]["labels"],
+            json!({"type": "object", "additionalProperties": {"type": "string"}})
+        );
+
+        // Positions index arrays, not maps
+        let template = "{% for i in range(messages | length) %}{{ messages[i].content }}{% endfor %}{{ turns[0].role }}{{ turns[loop_count - 1].name }}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(
+            analysis.object_shapes_json(),
+            json!({"loop_count": 0, "messages": [{"content": ""}], "turns": [{"name": "", "role": ""}]})
+        );
+    }
+
+    #[test]
+    fn test_recursive_shapes() {
+        let template = "{% for item in items recursive %}{{ item.name ~ '/' }}{% if item.children %}{{ loop(item.children) }}{% endif %}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+        assert!(analysis.globals_used.is_empty());
+        assert_eq!(analysis.definitions["item"].path, "items[]");
+        assert_eq!(
+            analysis.object_shapes_json(),
+            json!({"items": [{"children": [], "name": ""}]})
+        );
+
+        let schema = analysis.to_json_schema();
+        let item = &schema["$defs"]["item"];
+        assert_eq!(item["properties"]["name"], json!({"type": "string"}));
+        assert_eq!(
+            item["properties"]["children"],
+            json!({"type": "array", "items": {"$ref": "#/$defs/item"}})
+        );
+        assert_eq!(
+            schema["properties"]["items"]["items"],
+            json!({"$ref": "#/$defs/item"})
+        );
+    }
+
+    #[test]
+    fn test_provenance() {
+        let template = "{% for message in messages %}\n{{ message.weight }}{% endfor %}";
+        assert!(analyze(template, false).unwrap().provenance.is_none());
+
+        let options = AnalyzeOptions {
+            provenance: true,
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+        let provenance = analysis.provenance.unwrap();
+        let origins = &provenance["messages[].weight"];
+        assert_eq!(origins.len(), 1);
+        assert_eq!(origins[0].expression, "message.weight");
+        assert_eq!(origins[0].span.start_line, 2);
+        assert_eq!(provenance["messages"][0].expression, "messages");
+    }
+
+    #[test]
+    fn test_raise_exception_constraints() {
+        let template = "{% if messages[0].role != 'system' %}{{ raise_exception('System message required') }}{% endif %}{% for message in messages %}{% if message.role in ['user', 'assistant'] %}{{ message.content }}{% else %}{{ raise_exception('Unknown role') }}{% endif %}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+        let constraints = &analysis.constraints;
+        assert_eq!(constraints.len(), 2);
+        assert_eq!(constraints[0].condition, "messages[0].role == 'system'");
+        assert_eq!(
+            constraints[0].message.as_deref(),
+            Some("System message required")
+        );
+        assert_eq!(
+            constraints[0].paths,
+            BTreeSet::from(["messages[].role".to_string()])
+        );
+        assert_eq!(
+            constraints[1].condition,
+            "message.role in ['user', 'assistant']"
+        );
+        assert_eq!(
+            constraints[1].paths,
+            BTreeSet::from(["messages[].role".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_shape_limits() {
+        let template = "{{ a.b.c.d.e }}{{ wide.x ~ wide.y ~ wide.z }}";
+        let options = AnalyzeOptions {
+            max_depth: Some(3),
+            max_properties: Some(2),
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+        assert_eq!(
+            analysis.truncated_paths,
+            BTreeSet::from(["a.b.c.d".to_string(), "wide".to_string()])
+        );
+        assert_eq!(
+            analysis.object_shapes_json(),
+            json!({"a": {"b": {"c": {"d": ""}}}, "wide": {"x": "", "y": ""}})
+        );
+        let schema = analysis.to_json_schema();
+        assert_eq!(schema["properties"]["wide"]["x-truncated"], json!(true));
+    }
+
+    #[test]
+    fn test_path_queries() {
+        let template = "{% for message in messages %}{{ message.role }}{% if message.content is not none %}{{ message.content.text }}{% endif %}{% endfor %}{{ tools | default([]) | length }}";
+        let analysis = analyze(template, false).unwrap();
+
+        assert!(analysis.is_required("messages"));
+        assert!(analysis.is_required("messages[].role"));
+        assert!(!analysis.is_required("tools"));
+        assert!(!analysis.is_required("missing"));
+
+        assert_eq!(
+            analysis.type_of("messages[].content").map(Shape::type_name),
+            Some("object | null".to_string())
+        );
+        assert_eq!(analysis.type_of("messages[].name"), None);
+        assert_eq!(
+            analysis.fields_under("messages[]"),
+            vec![
+                "messages[].content",
+                "messages[].content.text",
+                "messages[].role"
+            ]
+        );
+        assert_eq!(analysis.fields_under("tools"), Vec::<String>::new());
+        assert_eq!(analysis.fields_under("").len(), 6);
+    }
+
+    #[test]
+    fn test_array_cardinality() {
+        let template = "{% if messages | length < 2 %}{{ raise_exception('need two messages') }}{% endif %}{% if tools | length > 8 %}{{ raise_exception('too many tools') }}{% endif %}{{ messages[0].content }}{{ messages[1].content }}{% if history | length > 2 %}{{ history[2] }}{% endif %}{{ turns[-1] }}";
+        let analysis = analyze(template, false).unwrap();
+
+        assert_eq!(
+            analysis.cardinality,
+            BTreeMap::from([
+                (
+                    "messages".to_string(),
+                    Cardinality {
+                        min_items: 2,
+                        max_items: None
+                    }
+                ),
+                (
+                    "tools".to_string(),
+                    Cardinality {
+                        min_items: 0,
+                        max_items: Some(8)
+                    }
+                ),
+                (
+                    "turns".to_string(),
+                    Cardinality {
+                        min_items: 1,
+                        max_items: None
+                    }
+                ),
+            ])
+        );
+
+        let schema = analysis.to_json_schema();
+        assert_eq!(schema["properties"]["messages"]["minItems"], json!(2));
+        assert_eq!(schema["properties"]["tools"]["maxItems"], json!(8));
+        assert_eq!(
+            analysis.sample_context()["messages"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_schema_identity() {
+        let template = "{% for message in messages %}{{ message.content }}{% endfor %}";
+        let anonymous = analyze(template, false).unwrap().to_json_schema();
+        assert!(anonymous.get("title").is_none() && anonymous.get("$id").is_none());
+
+        let options = AnalyzeOptions {
+            name: Some("meta-llama/Llama-3.1-8B-Instruct".to_string()),
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+        let hash = format!("{:016x}", analysis.shape_hash());
+        let schema = analysis.to_json_schema();
+        assert_eq!(schema["title"], json!("meta-llama/Llama-3.1-8B-Instruct"));
+        assert_eq!(
+            schema["$id"],
+            json!(format!(
+                "urn:cleanplate:meta-llama/Llama-3.1-8B-Instruct:{hash}"
+            ))
+        );
+        assert!(schema["description"].as_str().unwrap().contains(&hash));
+    }
+
+    #[test]
+    fn test_usage_counts() {
+        let template = "{% for message in messages %}{{ message.role }}{% if message.role == 'user' %}{{ message.content }}{% endif %}{% endfor %}{{ messages | length }}";
+        let analysis = analyze(template, false).unwrap();
+        assert_eq!(analysis.usage_counts["messages[].role"], 2);
+        assert_eq!(analysis.usage_counts["messages[].content"], 1);
+        assert!(!analysis.usage_counts.contains_key("message"));
+        assert_eq!(
+            analysis.fields_by_usage()[..2],
+            [("messages[]", 3), ("messages", 2)]
+        );
+    }
+
+    #[test]
+    fn test_ignore_and_only_vars() {
+        let template = "{% if messages | length < 1 %}{{ raise_exception('empty') }}{% endif %}{% for message in messages %}{{ message.content }}{% endfor %}{{ bos_token }}{% if tools is defined %}{{ tools | tojson }}{% endif %}";
+        let options = AnalyzeOptions {
+            ignore_vars: BTreeSet::from(["bos_token".to_string(), "messages".to_string()]),
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+        assert!(!analysis.external_vars.contains("messages"));
+        assert!(!analysis.external_vars.contains("bos_token"));
+        assert!(analysis.external_vars.contains("tools"));
+        assert!(analysis.constraints.is_empty());
+        assert!(analysis.cardinality.is_empty());
+        assert!(analysis.optional_paths.contains("tools"));
+
+        let options = AnalyzeOptions {
+            only_vars: BTreeSet::from(["messages".to_string()]),
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+        assert_eq!(
+            analysis.external_vars,
+            BTreeSet::from(["messages".to_string()])
+        );
+        assert_eq!(
+            analysis.object_shapes_json(),
+            json!({"messages": [{"content": ""}]})
+        );
+    }
 }
@@ -0,0 +1,492 @@
+use crate::{AnalyzeOptions, SourceSpan, BUILTIN_FILTERS};
+use minijinja::machinery::ast::{BinOpKind, CallArg, Expr, Stmt};
+use minijinja::machinery::{self, Span};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Template hygiene problem found by `lint`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// Stable rule code (e.g. `L003`), one of `RULES`
+    pub code: &'static str,
+    pub message: String,
+    pub span: SourceSpan,
+}
+
+/// Every lint rule as `(code, name, summary)`
+pub const RULES: &[(&str, &str, &str)] = &[
+    (
+        "L001",
+        "shadowed-loop-var",
+        "a loop variable hides an enclosing loop variable or a set variable",
+    ),
+    (
+        "L002",
+        "possibly-undefined",
+        "a variable is read after being set in only some branches",
+    ),
+    (
+        "L003",
+        "unknown-filter",
+        "a filter is neither builtin nor registered",
+    ),
+    (
+        "L004",
+        "unreachable-branch",
+        "a branch can never run because its condition is constant",
+    ),
+    (
+        "L005",
+        "loop-scoped-set",
+        "a variable set inside a loop is read after it, where the loop scope discarded it",
+    ),
+    (
+        "L006",
+        "none-comparison",
+        "a value is compared to `none` with `==` or `!=` instead of `is none`",
+    ),
+    (
+        "L007",
+        "loop-outside-loop",
+        "`loop` is used outside of a `for` loop",
+    ),
+];
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {} {}",
+            self.span.start_line, self.span.start_col, self.code, self.message
+        )
+    }
+}
+
+/// Lints a template source string, returning the problems in source order
+pub fn lint(
+    template_content: &str,
+    options: &AnalyzeOptions,
+) -> Result<Vec<Lint>, Box<dyn std::error::Error>> {
+    let ast = machinery::parse(
+        template_content,
+        "<string>",
+        Default::default(),
+        options.whitespace_config(),
+    )?;
+
+    let mut linter = Linter {
+        custom_filters: &options.custom_filters,
+        lints: Vec::new(),
+        loops: Vec::new(),
+        scope: Scope::default(),
+        reported: BTreeSet::new(),
+    };
+    linter.stmt(&ast);
+
+    let mut lints = linter.lints;
+    lints.sort_by_key(|lint| (lint.span.start_offset, lint.code));
+    Ok(lints)
+}
+
+// Names set so far on every path, and the ones set on some paths only
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    assigned: BTreeSet<String>,
+    // Set in some branches of an `if` (name -> where)
+    maybe: BTreeMap<String, SourceSpan>,
+    // Set in a loop body and discarded with the loop scope (name -> where)
+    loop_scoped: BTreeMap<String, SourceSpan>,
+    // Where each name was last set
+    set_spans: BTreeMap<String, SourceSpan>,
+}
+
+impl Scope {
+    fn assign(&mut self, name: &str) {
+        self.assigned.insert(name.to_string());
+        self.maybe.remove(name);
+        self.loop_scoped.remove(name);
+    }
+}
+
+struct Linter<'a> {
+    custom_filters: &'a BTreeSet<String>,
+    lints: Vec<Lint>,
+    // Variables of the enclosing loops, innermost last
+    loops: Vec<String>,
+    scope: Scope,
+    // (code, name) pairs already reported, so each variable is reported once per rule
+    reported: BTreeSet<(&'static str, String)>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, code: &'static str, message: String, span: Span) {
+        self.lints.push(Lint {
+            code,
+            message,
+            span: span.into(),
+        });
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Template(template) => self.stmts(&template.children),
+            Stmt::EmitExpr(emit) => self.expr(&emit.expr),
+            Stmt::EmitRaw(_) | Stmt::Extends(_) | Stmt::Include(_) => {}
+            Stmt::ForLoop(for_loop) => {
+                self.expr(&for_loop.iter);
+                let outer = self.scope.clone();
+                let targets = target_names(&for_loop.target);
+                for (name, span) in &targets {
+                    if self.loops.iter().any(|var| var == name) {
+                        self.report(
+                            "L001",
+                            format!(
+                                "loop variable `{name}` shadows the variable of an enclosing loop"
+                            ),
+                            *span,
+                        );
+                    } else if outer.assigned.contains(*name) {
+                        self.report(
+                            "L001",
+                            format!(
+                                "loop variable `{name}` shadows a variable set before the loop"
+                            ),
+                            *span,
+                        );
+                    }
+                    self.scope.assign(name);
+                }
+                self.scope.assign("loop");
+
+                self.loops
+                    .extend(targets.iter().map(|(name, _)| name.to_string()));
+                if let Some(filter_expr) = &for_loop.filter_expr {
+                    self.expr(filter_expr);
+                }
+                self.stmts(&for_loop.body);
+                self.loops.truncate(self.loops.len() - targets.len());
+
+                // Sets in the body do not outlive the loop
+                let body = std::mem::replace(&mut self.scope, outer);
+                for (name, span) in body
+                    .set_spans
+                    .iter()
+                    .chain(&body.maybe)
+                    .chain(&body.loop_scoped)
+                {
+                    if !self.scope.assigned.contains(name) {
+                        self.scope.loop_scoped.entry(name.clone()).or_insert(*span);
+                    }
+                }
+                self.stmts(&for_loop.else_body);
+            }
+            Stmt::IfCond(if_cond) => {
+                self.expr(&if_cond.expr);
+                if let Expr::Const(constant) = &if_cond.expr {
+                    let dead = match constant.value.is_true() {
+                        true => &if_cond.false_body,
+                        false => &if_cond.true_body,
+                    };
+                    if !dead.is_empty() {
+                        self.report(
+                            "L004",
+                            format!(
+                                "branch never runs because the condition is always {}",
+                                constant.value.is_true()
+                            ),
+                            constant.span(),
+                        );
+                    }
+                }
+
+                let before = self.scope.clone();
+                self.stmts(&if_cond.true_body);
+                let taken = std::mem::replace(&mut self.scope, before.clone());
+                self.stmts(&if_cond.false_body);
+                let skipped = std::mem::replace(&mut self.scope, before);
+                self.merge_branches(taken, skipped);
+            }
+            Stmt::WithBlock(with_block) => {
+                let outer = self.scope.clone();
+                for (target, value) in &with_block.assignments {
+                    self.expr(value);
+                    for (name, _) in target_names(target) {
+                        self.scope.assign(name);
+                    }
+                }
+                self.stmts(&with_block.body);
+                self.scope = outer;
+            }
+            Stmt::Set(set) => {
+                self.expr(&set.expr);
+                self.set_targets(&set.target, set.span());
+            }
+            Stmt::SetBlock(set_block) => {
+                if let Some(filter) = &set_block.filter {
+                    self.expr(filter);
+                }
+                self.stmts(&set_block.body);
+                self.set_targets(&set_block.target, set_block.span());
+            }
+            Stmt::AutoEscape(auto_escape) => {
+                self.expr(&auto_escape.enabled);
+                self.stmts(&auto_escape.body);
+            }
+            Stmt::FilterBlock(filter_block) => {
+                self.expr(&filter_block.filter);
+                self.stmts(&filter_block.body);
+            }
+            Stmt::Block(block) => self.stmts(&block.body),
+            Stmt::Import(import) => {
+                self.expr(&import.expr);
+                for (name, _) in target_names(&import.name) {
+                    self.scope.assign(name);
+                }
+            }
+            Stmt::FromImport(from_import) => {
+                self.expr(&from_import.expr);
+                for (name, alias) in &from_import.names {
+                    for (name, _) in target_names(alias.as_ref().unwrap_or(name)) {
+                        self.scope.assign(name);
+                    }
+                }
+            }
+            Stmt::Macro(macro_decl) => {
+                self.scope.assign(macro_decl.name);
+                self.macro_body(&macro_decl.args, &macro_decl.defaults, &macro_decl.body);
+            }
+            Stmt::CallBlock(call_block) => {
+                self.call_args(&call_block.call.expr, &call_block.call.args);
+                let decl = &call_block.macro_decl;
+                self.macro_body(&decl.args, &decl.defaults, &decl.body);
+            }
+            Stmt::Do(do_stmt) => self.call_args(&do_stmt.call.expr, &do_stmt.call.args),
+        }
+    }
+
+    // Macros run in their own scope with only their parameters (and `caller`) set
+    fn macro_body(&mut self, args: &[Expr], defaults: &[Expr], body: &[Stmt]) {
+        for default in defaults {
+            self.expr(default);
+        }
+        let outer = std::mem::take(&mut self.scope);
+        let loops = std::mem::take(&mut self.loops);
+        self.scope.assigned = outer.assigned.clone();
+        for arg in args {
+            for (name, _) in target_names(arg) {
+                self.scope.assign(name);
+            }
+        }
+        self.scope.assign("caller");
+        self.stmts(body);
+        self.scope = outer;
+        self.loops = loops;
+    }
+
+    // Names set on both branches stay assigned; names set on one become possibly undefined
+    fn merge_branches(&mut self, taken: Scope, skipped: Scope) {
+        for name in taken.assigned.intersection(&skipped.assigned) {
+            self.scope.assign(name);
+        }
+        for (branch, other) in [(&taken, &skipped), (&skipped, &taken)] {
+            for (name, span) in &branch.set_spans {
+                if !other.assigned.contains(name) && !self.scope.assigned.contains(name) {
+                    self.scope.maybe.entry(name.clone()).or_insert(*span);
+                }
+            }
+            for (name, span) in branch.maybe.iter().chain(&branch.loop_scoped) {
+                if !self.scope.assigned.contains(name) {
+                    self.scope.maybe.entry(name.clone()).or_insert(*span);
+                }
+            }
+            for (name, span) in &branch.set_spans {
+                self.scope.set_spans.insert(name.clone(), *span);
+            }
+        }
+    }
+
+    fn set_targets(&mut self, target: &Expr, span: Span) {
+        for (name, _) in target_names(target) {
+            self.scope.assign(name);
+            self.scope.set_spans.insert(name.to_string(), span.into());
+        }
+    }
+
+    fn call_args(&mut self, callee: &Expr, args: &[CallArg]) {
+        self.expr(callee);
+        self.args(args);
+    }
+
+    fn args(&mut self, args: &[CallArg]) {
+        for arg in args {
+            match arg {
+                CallArg::Pos(expr)
+                | CallArg::Kwarg(_, expr)
+                | CallArg::PosSplat(expr)
+                | CallArg::KwargSplat(expr) => self.expr(expr),
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Var(var) => self.read(var.id, var.span()),
+            Expr::Const(_) => {}
+            Expr::Slice(slice) => {
+                self.expr(&slice.expr);
+                for bound in [&slice.start, &slice.stop, &slice.step]
+                    .into_iter()
+                    .flatten()
+                {
+                    self.expr(bound);
+                }
+            }
+            Expr::UnaryOp(unary_op) => self.expr(&unary_op.expr),
+            Expr::BinOp(bin_op) => {
+                if matches!(bin_op.op, BinOpKind::Eq | BinOpKind::Ne)
+                    && [&bin_op.left, &bin_op.right]
+                        .iter()
+                        .any(|side| is_none(side))
+                {
+                    let test = match bin_op.op {
+                        BinOpKind::Eq => "is none",
+                        _ => "is not none",
+                    };
+                    self.report(
+                        "L006",
+                        format!("comparison with `none`, use `{test}`"),
+                        bin_op.span(),
+                    );
+                }
+                self.expr(&bin_op.left);
+                self.expr(&bin_op.right);
+            }
+            Expr::IfExpr(if_expr) => {
+                self.expr(&if_expr.test_expr);
+                self.expr(&if_expr.true_expr);
+                if let Some(false_expr) = &if_expr.false_expr {
+                    self.expr(false_expr);
+                }
+            }
+            Expr::Filter(filter) => {
+                if !BUILTIN_FILTERS.contains(&filter.name)
+                    && !self.custom_filters.contains(filter.name)
+                {
+                    self.report(
+                        "L003",
+                        format!("unknown filter `{}`", filter.name),
+                        filter.span(),
+                    );
+                }
+                if let Some(expr) = &filter.expr {
+                    self.expr(expr);
+                }
+                self.args(&filter.args);
+            }
+            Expr::Test(test) => {
+                // `is defined` is how templates guard possibly undefined names
+                let guarded = matches!(test.name, "defined" | "undefined")
+                    && matches!(test.expr, Expr::Var(_));
+                if !guarded {
+                    self.expr(&test.expr);
+                }
+                self.args(&test.args);
+            }
+            Expr::GetAttr(get_attr) => self.expr(&get_attr.expr),
+            Expr::GetItem(get_item) => {
+                self.expr(&get_item.expr);
+                self.expr(&get_item.subscript_expr);
+            }
+            Expr::Call(call) => self.call_args(&call.expr, &call.args),
+            Expr::List(list) => {
+                for item in &list.items {
+                    self.expr(item);
+                }
+            }
+            Expr::Map(map) => {
+                for (key, value) in map.keys.iter().zip(&map.values) {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+        }
+    }
+
+    fn read(&mut self, name: &str, span: Span) {
+        if self.scope.assigned.contains(name) {
+            return;
+        }
+        if name == "loop" && self.loops.is_empty() {
+            self.report("L007", "`loop` used outside of a loop".to_string(), span);
+        } else if let Some(set_at) = self.scope.maybe.get(name) {
+            if self.reported.insert(("L002", name.to_string())) {
+                let message = format!(
+                    "`{name}` may be undefined: it is only set in some branches (line {})",
+                    set_at.start_line
+                );
+                self.report("L002", message, span);
+            }
+        } else if let Some(set_at) = self.scope.loop_scoped.get(name) {
+            if self.reported.insert(("L005", name.to_string())) {
+                let message = format!(
+                    "`{name}` is set inside a loop (line {}) but the loop scope discards it",
+                    set_at.start_line
+                );
+                self.report("L005", message, span);
+            }
+        }
+    }
+}
+
+// Variable names bound by an assignment target (`x`, `key, value`)
+pub(crate) fn target_names<'a>(target: &'a Expr) -> Vec<(&'a str, Span)> {
+    match target {
+        Expr::Var(var) => vec![(var.id, var.span())],
+        Expr::List(list) => list.items.iter().flat_map(target_names).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn is_none(expr: &Expr) -> bool {
+    matches!(expr, Expr::Const(constant) if constant.value.is_none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_rules() {
+        let template = "{% for message in messages %}{% for message in message.parts %}{% endfor %}{% set last = message %}{% endfor %}{{ last }}{% if add_generation_prompt %}{% set prompt = 'x' %}{% endif %}{{ prompt | shout }}{% if false %}dead{% endif %}{% if tools == none %}{{ loop.index }}{% endif %}";
+        let lints = lint(template, &AnalyzeOptions::default())
+            .unwrap()
+            .iter()
+            .map(|lint| format!("{} {}", lint.code, lint.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lints,
+            vec![
+                "L001 loop variable `message` shadows the variable of an enclosing loop",
+                "L005 `last` is set inside a loop (line 1) but the loop scope discards it",
+                "L002 `prompt` may be undefined: it is only set in some branches (line 1)",
+                "L003 unknown filter `shout`",
+                "L004 branch never runs because the condition is always false",
+                "L006 comparison with `none`, use `is none`",
+                "L007 `loop` used outside of a loop",
+            ]
+        );
+
+        let options = AnalyzeOptions {
+            custom_filters: BTreeSet::from(["shout".to_string()]),
+            ..Default::default()
+        };
+        let clean = "{% set prompt = '' %}{% if x is defined %}{% set prompt = x %}{% endif %}{{ prompt | shout }}{% for m in messages %}{{ loop.index }}{% endfor %}";
+        assert_eq!(lint(clean, &options).unwrap(), vec![]);
+    }
+}
@@ -1,65 +1,1908 @@
-use clap::Parser;
-use cleanplate::analyze;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use cleanplate::batch::{self, TemplateListing};
+use cleanplate::cache::AnalysisCache;
+use cleanplate::cluster::DEFAULT_CLUSTER_THRESHOLD;
+use cleanplate::codegen::{to_mermaid, CodegenOptions};
+use cleanplate::config::Config;
+use cleanplate::corpus::BucketOrder;
+use cleanplate::hub;
+use cleanplate::lint;
+use cleanplate::rules::Rule;
+use cleanplate::schema::parse_overrides;
+use cleanplate::source::{
+    detect_escaped_template, gguf_templates, tokenizer_config_templates, unescape_template,
+    ChatTemplate,
+};
+use cleanplate::{
+    analyze_with, dependency_graph, template_stats, AnalysisWarning, AnalyzeOptions, SchemaDiff,
+    SchemaOptions, Shape, TemplateAnalysis, TemplateCorpus,
+};
+use minijinja::value::{Enumerator, Object, ObjectRepr, Rest};
+use minijinja::{Environment, Error, ErrorKind, State, UndefinedBehavior};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
 
 /// A tool for generating JSON Schema from `MiniJinja` templates
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration file with project defaults (default: the nearest
+    /// `.cleanplate.toml` from the working directory upward)
+    #[clap(long, value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+
+    /// Format of the log events on stderr (`RUST_LOG` sets the level)
+    #[clap(long, value_enum, global = true, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Without a subcommand, the arguments of `analyze`
+    #[clap(flatten)]
+    analyze: AnalyzeArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Analyze a template and print the inferred context
+    Analyze(AnalyzeArgs),
+    /// Check a render context against the context the template expects
+    Validate(ValidateArgs),
+    /// Render the template with a sample context built from the analysis, or
+    /// with a given context
+    Render(RenderArgs),
+    /// Check a template for shadowing, undefined names, unknown filters and
+    /// other suspicious constructs
+    Lint(LintArgs),
+    /// Compare the inferred contexts of two templates
+    Diff(DiffArgs),
+    /// Fail if the inferred JSON Schema differs from a committed snapshot
+    Check(CheckArgs),
+    /// Print complexity metrics of templates
+    Stats(StatsArgs),
+    /// Print what the template expects of one variable or field
+    Query(QueryArgs),
+    /// Print the templates a template includes, extends or imports, and fail
+    /// on reference cycles and missing templates
+    Deps(DepsArgs),
+    /// Analyze a corpus of templates and report how often each context shape
+    /// occurs across models
+    Batch(BatchArgs),
+    /// Print a shell completion script
+    Completions(CompletionsArgs),
+}
+
+/// Where the template comes from and how it is parsed and analyzed
+#[derive(Args, Debug)]
+struct TemplateArgs {
+    /// The template file to analyze (`-` reads the template from stdin)
+    #[clap(value_name = "TEMPLATE", conflicts_with = "file")]
+    template: Option<PathBuf>,
+
     /// The template file to analyze
     #[clap(short, long, value_parser)]
     file: Option<PathBuf>,
 
-    /// Enable verbose output with debug tracing
-    #[clap(short, long)]
-    verbose: bool,
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+}
+
+/// How templates are parsed and analyzed
+#[derive(Args, Debug)]
+struct AnalysisArgs {
+    /// Log every variable access and inference of the analysis
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// Custom filter registered in the rendering environment (repeatable)
+    #[clap(long = "custom-filter", value_name = "NAME")]
+    custom_filters: Vec<String>,
+
+    /// Remove the first newline after a block tag
+    #[clap(long)]
+    trim_blocks: bool,
+
+    /// Strip leading whitespace from the start of a line up to a block tag
+    #[clap(long)]
+    lstrip_blocks: bool,
+
+    /// Keep the trailing newline at the end of the template
+    #[clap(long)]
+    keep_trailing_newline: bool,
+
+    /// Refine well-known chat fields (roles, tool calls, tools)
+    #[clap(long)]
+    chat_knowledge: bool,
+
+    /// Deepest schema path to build before truncating
+    #[clap(long, value_name = "SEGMENTS")]
+    max_depth: Option<usize>,
+
+    /// Most properties kept per object before truncating
+    #[clap(long, value_name = "COUNT")]
+    max_properties: Option<usize>,
+
+    /// Template name or model id used as the schema title and $id (defaults to the file name)
+    #[clap(long)]
+    name: Option<String>,
+
+    /// Leave a variable out of the context, e.g. a helper the environment injects (repeatable)
+    #[clap(long = "ignore-var", value_name = "NAME")]
+    ignore_vars: Vec<String>,
+
+    /// Keep only these variables in the context (repeatable)
+    #[clap(long = "only-var", value_name = "NAME")]
+    only_vars: Vec<String>,
+
+    /// Classification or typing rule `PATTERN: ACTION`, e.g. `bos_token: constant`
+    /// or `*_json: string` (repeatable)
+    #[clap(long = "rule", id = "rule", value_name = "RULE")]
+    rules: Vec<Rule>,
+
+    /// Decode JSON string escaping (`\n`, `\"`) before parsing; escaped
+    /// templates that do not parse as they are are decoded without it
+    #[clap(long)]
+    unescape: bool,
+}
+
+impl AnalysisArgs {
+    // Turns on the flags the configuration sets and fills in the limits not given
+    fn apply_config(&mut self, config: &Config) {
+        self.trim_blocks |= config.trim_blocks;
+        self.lstrip_blocks |= config.lstrip_blocks;
+        self.keep_trailing_newline |= config.keep_trailing_newline;
+        self.custom_filters
+            .extend(config.custom_filters.iter().cloned());
+        self.chat_knowledge |= config.chat_knowledge;
+        self.unescape |= config.unescape;
+        self.ignore_vars.extend(config.ignore_vars.iter().cloned());
+        self.only_vars.extend(config.only_vars.iter().cloned());
+        self.rules.extend(config.rules.iter().cloned());
+        self.max_depth = self.max_depth.or(config.max_depth);
+        self.max_properties = self.max_properties.or(config.max_properties);
+    }
+
+    // Analysis options from the command line; the name defaults to the file name
+    fn options(&self, file_path: Option<&Path>) -> AnalyzeOptions {
+        let mut builder = AnalyzeOptions::builder()
+            .verbose(self.verbose)
+            .trim_blocks(self.trim_blocks)
+            .lstrip_blocks(self.lstrip_blocks)
+            .keep_trailing_newline(self.keep_trailing_newline)
+            .chat_knowledge(self.chat_knowledge);
+        for filter in &self.custom_filters {
+            builder = builder.custom_filter(filter);
+        }
+        for var in &self.ignore_vars {
+            builder = builder.ignore_var(var);
+        }
+        for var in &self.only_vars {
+            builder = builder.only_var(var);
+        }
+        for rule in &self.rules {
+            builder = builder.rule(rule.clone());
+        }
+        if let Some(max_depth) = self.max_depth {
+            builder = builder.max_depth(max_depth);
+        }
+        if let Some(max_properties) = self.max_properties {
+            builder = builder.max_properties(max_properties);
+        }
+        let name = self.name.clone().or_else(|| {
+            file_path
+                .and_then(Path::file_stem)
+                .map(|stem| stem.to_string_lossy().into_owned())
+        });
+        if let Some(name) = name {
+            builder = builder.name(name);
+        }
+        // Included and extended templates are looked up next to the template
+        if let Some(dir) = file_path.and_then(Path::parent) {
+            let dir = dir.to_path_buf();
+            builder = builder.loader(move |name| fs::read_to_string(dir.join(name)).ok());
+        }
+        builder.build()
+    }
+}
+
+impl TemplateArgs {
+    // The template path given on the command line, if any
+    fn path(&self) -> Option<PathBuf> {
+        self.template.clone().or_else(|| self.file.clone())
+    }
+
+    // Reads and analyzes the template given on the command line (or the
+    // default template), exiting on errors
+    fn load(&self, provenance: bool) -> (String, TemplateAnalysis) {
+        let file_path = self
+            .path()
+            .unwrap_or_else(|| PathBuf::from("templates/example.jinja"));
+        let from_stdin = file_path.as_os_str() == "-";
+        let options = AnalyzeOptions {
+            provenance,
+            ..self.options((!from_stdin).then_some(file_path.as_path()))
+        };
+        load_template(&file_path, &options, self.analysis.unescape)
+    }
+
+    // Analysis options for the template file
+    fn options(&self, file_path: Option<&Path>) -> AnalyzeOptions {
+        self.analysis.options(file_path)
+    }
+}
+
+#[derive(Args, Debug)]
+struct ValidateArgs {
+    #[clap(flatten)]
+    input: TemplateArgs,
+
+    /// JSON file with the render context to check (`-` reads it from stdin)
+    #[clap(short, long, value_name = "FILE")]
+    context: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct RenderArgs {
+    #[clap(flatten)]
+    input: TemplateArgs,
+
+    /// JSON file with the render context to use instead of the sample (`-` reads it from stdin)
+    #[clap(short, long, value_name = "FILE")]
+    context: Option<PathBuf>,
+
+    /// Fail on undefined values and report every undefined access with the
+    /// template expressions the analysis ties it to
+    #[clap(long)]
+    strict: bool,
+}
+
+#[derive(Args, Debug)]
+struct LintArgs {
+    #[clap(flatten)]
+    input: TemplateArgs,
+
+    /// List the lint rules and exit
+    #[clap(long)]
+    rules: bool,
+}
+
+#[derive(Args, Debug)]
+struct DiffArgs {
+    /// The template before the change
+    old: PathBuf,
+
+    /// The template after the change
+    new: PathBuf,
+
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+}
+
+#[derive(Args, Debug)]
+struct CheckArgs {
+    #[clap(flatten)]
+    input: TemplateArgs,
+
+    /// The committed JSON Schema snapshot
+    #[clap(long, value_name = "FILE")]
+    schema: PathBuf,
+
+    /// JSON file mapping schema paths to fragments merged into the schema
+    #[clap(long, value_name = "FILE")]
+    overrides: Option<PathBuf>,
+
+    /// Write the inferred schema to the snapshot instead of checking it
+    #[clap(long)]
+    update: bool,
+}
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    /// The template files to measure
+    #[clap(value_name = "TEMPLATE", required = true)]
+    templates: Vec<PathBuf>,
+
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+
+    /// Print the metrics as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct QueryArgs {
+    #[clap(flatten)]
+    input: TemplateArgs,
+
+    /// The variable or schema path to look up (e.g. `messages` or `messages[].role`)
+    #[clap(long, value_name = "PATH")]
+    var: String,
+}
+
+#[derive(Args, Debug)]
+struct DepsArgs {
+    #[clap(flatten)]
+    input: TemplateArgs,
+
+    /// Print the graph as Graphviz DOT
+    #[clap(long)]
+    dot: bool,
+}
+
+#[derive(Args, Debug)]
+struct BatchArgs {
+    /// The corpus: a JSON object mapping template sources to model ids, a
+    /// JSON array or JSON Lines of `{"template", "model_ids"}` records, or a
+    /// directory of templates (`-` reads JSON from stdin)
+    #[clap(
+        value_name = "INPUT",
+        default_value = "chat_template_to_model_ids.json"
+    )]
+    input: PathBuf,
+
+    /// Template extensions picked up when the input is a directory (comma-separated)
+    #[clap(
+        long = "ext",
+        value_name = "EXT",
+        value_delimiter = ',',
+        default_value = "jinja,j2,html"
+    )]
+    extensions: Vec<String>,
+
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+
+    /// The JSON file to save the per-template analysis results to
+    #[clap(
+        short,
+        long,
+        value_name = "FILE",
+        default_value = "template_analysis_results.json"
+    )]
+    output: PathBuf,
+
+    /// The JSON file to save the shape frequencies to
+    #[clap(
+        short,
+        long,
+        value_name = "FILE",
+        default_value = "shape_frequency_results.json"
+    )]
+    shape_output: PathBuf,
+
+    /// The JSON file to save the shape families (similar shapes grouped together) to
+    #[clap(
+        long,
+        value_name = "FILE",
+        default_value = "shape_cluster_results.json"
+    )]
+    cluster_output: PathBuf,
+
+    /// Minimum similarity (0.0 - 1.0) for two shapes to belong to the same family
+    #[clap(long, default_value_t = DEFAULT_CLUSTER_THRESHOLD)]
+    cluster_threshold: f64,
+
+    /// Write the analysis results as JSON Lines, one object per template as it is
+    /// analyzed, instead of a single array at the end (`--output -` streams to stdout)
+    #[clap(long)]
+    jsonl: bool,
+
+    /// Also write a CSV summary with one row per template (id, model count,
+    /// external variables, shape hash, status)
+    #[clap(long, value_name = "FILE")]
+    csv: Option<PathBuf>,
+
+    /// Write a self-contained HTML report (sortable shape, failure and
+    /// per-template tables) instead of printing the coverage table
+    #[clap(long, value_name = "FILE")]
+    html: Option<PathBuf>,
+
+    /// Record every analyzed template in this file so an interrupted run can
+    /// be resumed
+    #[clap(long, value_name = "FILE")]
+    checkpoint: Option<PathBuf>,
+
+    /// Continue from the `--checkpoint` file, analyzing only the templates it
+    /// doesn't hold yet (use the same analysis flags as the interrupted run)
+    #[clap(long, requires = "checkpoint")]
+    resume: bool,
+
+    /// Keep analysis results in this directory, keyed by a hash of the
+    /// template and the analysis flags, so a re-run only analyzes new or
+    /// changed templates
+    #[clap(long, value_name = "DIR")]
+    cache: Option<PathBuf>,
+
+    /// Number of templates analyzed in parallel [default: number of CPUs]
+    #[clap(short, long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Percentage of the models the printed shapes must cover
+    #[clap(long, value_name = "PERCENT", default_value_t = 95.0)]
+    coverage_target: f64,
+
+    /// Order of the shapes in the shape frequencies and the coverage table
+    #[clap(long, value_enum, default_value_t = ShapeSort::ModelCount)]
+    sort_by: ShapeSort,
+
+    /// Print at most N shapes in the coverage table
+    #[clap(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// List the member templates of each shape in the shape frequencies: their
+    /// sources (`all`), their template ids (`hashes`) or the first
+    /// `--template-limit` sources (`first-n`)
+    #[clap(
+        long,
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        default_missing_value = "all"
+    )]
+    include_templates: Option<IncludeTemplates>,
+
+    /// Templates listed per shape with `--include-templates first-n`
+    #[clap(long, value_name = "N", default_value_t = 5)]
+    template_limit: usize,
+}
+
+#[derive(Args, Debug)]
+struct CompletionsArgs {
+    /// The shell to complete for
+    #[clap(value_enum)]
+    shell: Shell,
+}
+
+#[derive(Args, Debug)]
+struct AnalyzeArgs {
+    #[clap(flatten)]
+    input: TemplateArgs,
+
+    /// Analyze every template below the given directory
+    #[clap(short, long)]
+    recursive: bool,
+
+    /// Template extensions picked up by `--recursive` (comma-separated)
+    #[clap(
+        long = "ext",
+        value_name = "EXT",
+        value_delimiter = ',',
+        default_value = "jinja,j2,html"
+    )]
+    extensions: Vec<String>,
+
+    /// Write the results to a file instead of stdout (diagnostics stay on stderr)
+    #[clap(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Keep running and print what changed in the inferred schema whenever
+    /// the template file changes
+    #[clap(short, long, conflicts_with_all = ["recursive", "output"])]
+    watch: bool,
+
+    /// Analyze only the chat template with this name when the input is a
+    /// `tokenizer_config.json` with several
+    #[clap(long, value_name = "NAME")]
+    chat_template: Option<String>,
+
+    /// Analyze the chat template of a model on the Hugging Face Hub (e.g.
+    /// `meta-llama/Llama-3.1-8B-Instruct`); gated models need `HF_TOKEN` or
+    /// `huggingface-cli login`
+    #[clap(
+        long,
+        value_name = "ID",
+        conflicts_with_all = ["template", "file", "recursive", "watch"]
+    )]
+    model_id: Option<String>,
+
+    /// Branch, tag or commit of the `--model-id` model
+    #[clap(
+        long,
+        value_name = "REV",
+        default_value = "main",
+        requires = "model_id"
+    )]
+    revision: String,
+
+    /// Download the `--model-id` tokenizer config again instead of using the cached copy
+    #[clap(long, requires = "model_id")]
+    refresh: bool,
+
+    /// Print nothing but errors; the exit code tells the outcome (0 ok,
+    /// 2 template parse error, 3 validation or constraint failure)
+    #[clap(short, long, conflicts_with = "watch")]
+    quiet: bool,
+
+    /// Show which template expressions produced each field
+    #[clap(long)]
+    provenance: bool,
+
+    /// Explain why each field has its inferred type and requiredness
+    #[clap(long)]
+    explain: bool,
+
+    /// Output format [default: report]
+    #[clap(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Print the template source with each variable occurrence annotated
+    /// with its kind, schema path and type
+    #[clap(long)]
+    annotate: bool,
+
+    /// Print the shape as a tree instead of the example JSON
+    #[clap(long)]
+    tree: bool,
+
+    /// When to color the tree and annotated source (`auto` honors `NO_COLOR` and only colors terminals)
+    #[clap(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Print the JSON Schema of the context instead of the example shape
+    #[clap(long)]
+    schema: bool,
+
+    /// JSON file mapping schema paths to fragments merged into the schema (implies --schema)
+    #[clap(long, value_name = "FILE")]
+    overrides: Option<PathBuf>,
+
+    /// Overrides file of the configuration, used when `--overrides` is not given
+    #[clap(skip)]
+    config_overrides: Option<PathBuf>,
+}
+
+impl AnalyzeArgs {
+    fn format(&self) -> OutputFormat {
+        self.format.unwrap_or(OutputFormat::Report)
+    }
+
+    // The overrides file given on the command line or in the configuration
+    fn overrides(&self) -> Option<&Path> {
+        self.overrides
+            .as_deref()
+            .or(self.config_overrides.as_deref())
+    }
+}
+
+// Exit code when a template fails to parse
+const EXIT_PARSE_ERROR: i32 = 2;
+
+// Exit code when a check of the analysis fails
+const EXIT_VIOLATIONS: i32 = 3;
+
+// How many templates `batch` analyzes in parallel before aggregating them and
+// flushing the checkpoint
+const BATCH_CHUNK: usize = 256;
+
+// How often `--watch` checks the template for changes
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(300);
+
+/// How the analysis is printed
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Variable report followed by the example shape or schema
+    Report,
+    /// Variables, loops, constraints and the example shape as one JSON object
+    Json,
+    /// The same object as `json`, in YAML
+    Yaml,
+    /// JSON Schema of the context (honors `--overrides`)
+    Schema,
+    /// Markdown docs with a variable table and an example context
+    Markdown,
+    /// Aligned table of every field with its type, requiredness and confidence
+    Table,
+    /// Graphviz DOT graph of the variables, attributes, aliases and loops
+    Dot,
+    /// Mermaid class diagram of the object shapes
+    Mermaid,
+    /// JSON index of every field's JSON Pointer into the schema, with its metadata
+    Pointers,
+}
+
+/// How log events are written
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per event, for log pipelines
+    Json,
+}
+
+/// Order of the batch shape frequencies
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "snake_case")]
+enum ShapeSort {
+    /// Most templates first
+    TemplateCount,
+    /// Most models first
+    ModelCount,
+    /// Alphabetically by the shape's JSON
+    Name,
+}
+
+/// Which member templates the batch shape frequencies list
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum IncludeTemplates {
+    /// Every template source
+    All,
+    /// The template ids
+    Hashes,
+    /// The first `--template-limit` template sources
+    FirstN,
+}
+
+/// Whether to emit ANSI colors
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && std::io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Parse command line arguments
+    let cli = Cli::parse();
+    let config = load_config(cli.config.as_deref());
+
+    let mut command = cli.command.unwrap_or(Command::Analyze(cli.analyze));
+    command.apply_config(config);
+    let verbose = command.analysis_args().is_some_and(|args| args.verbose);
+    init_logging(cli.log_format, verbose);
+    match command {
+        Command::Analyze(args) => analyze_command(args),
+        Command::Validate(args) => validate_command(args),
+        Command::Render(args) => render_command(args),
+        Command::Lint(args) => lint_command(args),
+        Command::Diff(args) => diff_command(args),
+        Command::Check(args) => check_command(args),
+        Command::Stats(args) => stats_command(args),
+        Command::Query(args) => query_command(args),
+        Command::Deps(args) => deps_command(args),
+        Command::Batch(args) => batch_command(args),
+        Command::Completions(args) => completions_command(args),
+    }
+}
+
+impl Command {
+    // The analysis flags of the subcommand, if it analyzes templates
+    fn analysis_args(&mut self) -> Option<&mut AnalysisArgs> {
+        match self {
+            Command::Analyze(args) => Some(&mut args.input.analysis),
+            Command::Validate(args) => Some(&mut args.input.analysis),
+            Command::Render(args) => Some(&mut args.input.analysis),
+            Command::Lint(args) => Some(&mut args.input.analysis),
+            Command::Diff(args) => Some(&mut args.analysis),
+            Command::Check(args) => Some(&mut args.input.analysis),
+            Command::Stats(args) => Some(&mut args.analysis),
+            Command::Query(args) => Some(&mut args.input.analysis),
+            Command::Deps(args) => Some(&mut args.input.analysis),
+            Command::Batch(args) => Some(&mut args.analysis),
+            Command::Completions(_) => None,
+        }
+    }
+
+    // Fills in the defaults of the configuration file for the flags not given
+    fn apply_config(&mut self, config: Config) {
+        let Some(analysis) = self.analysis_args() else {
+            return;
+        };
+        analysis.apply_config(&config);
+
+        match self {
+            Command::Analyze(args) => {
+                args.config_overrides = config.overrides;
+                if let (None, Some(format)) = (args.format, &config.format) {
+                    match OutputFormat::from_str(format, true) {
+                        Ok(format) => args.format = Some(format),
+                        Err(_) => {
+                            eprintln!("Error in configuration file: unknown format `{format}`");
+                            process::exit(1);
+                        }
+                    }
+                }
+            }
+            Command::Check(args) => args.overrides = args.overrides.take().or(config.overrides),
+            _ => {}
+        }
+    }
+}
+
+// Sends the tracing events to stderr as text or JSON lines; `RUST_LOG` picks
+// the level, which defaults to every cleanplate event with `--verbose` and to
+// warnings otherwise
+fn init_logging(format: LogFormat, verbose: bool) {
+    let ansi = io::stderr().is_terminal();
+    log_subscriber(format, verbose, ansi, io::stderr).init();
+}
+
+// Subscriber writing the log events in the format to `writer`
+fn log_subscriber<W>(
+    format: LogFormat,
+    verbose: bool,
+    ansi: bool,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let default = if verbose { "cleanplate=trace" } else { "warn" };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(subscriber.without_time().finish()),
+        LogFormat::Json => Box::new(subscriber.json().finish()),
+    }
+}
+
+// The configuration given with `--config` or found from the working directory
+// upward, or the defaults without one; exits on errors
+fn load_config(path: Option<&Path>) -> Config {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match env::current_dir()
+            .ok()
+            .and_then(|dir| Config::discover(&dir))
+        {
+            Some(path) => path,
+            None => return Config::default(),
+        },
+    };
+    match Config::load(&path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error reading configuration file: {err}");
+            eprintln!("Path: {}", path.display());
+            process::exit(1);
+        }
+    }
+}
+
+fn analyze_command(cli: AnalyzeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    // Get the template file path
+    let file_path = match &cli.model_id {
+        Some(model_id) => match hub::tokenizer_config(model_id, &cli.revision, cli.refresh) {
+            Ok(path) => path,
+            Err(err) => {
+                eprintln!("Error fetching `{model_id}` from the Hugging Face Hub: {err}");
+                process::exit(1);
+            }
+        },
+        None => cli.input.path().unwrap_or_else(|| match cli.recursive {
+            true => PathBuf::from("."),
+            false => PathBuf::from("templates/example.jinja"),
+        }),
+    };
+    let from_stdin = file_path.as_os_str() == "-";
+
+    if cli.recursive {
+        return analyze_directory(&file_path, &cli);
+    }
+    if cli.watch {
+        return watch_template(&file_path, &cli);
+    }
+
+    // Tokenizer configs can hold several named chat templates
+    let mut templates = match read_templates(&file_path, cli.input.analysis.unescape) {
+        Ok(templates) => templates,
+        Err(err) => {
+            eprintln!("Error reading template file: {err}");
+            eprintln!("Path: {}", file_path.display());
+            process::exit(1);
+        }
+    };
+    if let Some(name) = &cli.chat_template {
+        templates.retain(|template| &template.name == name);
+        if templates.is_empty() {
+            eprintln!("Error: no chat template named `{name}`");
+            process::exit(1);
+        }
+    }
+
+    let mut options = analyze_options(&cli, (!from_stdin).then_some(file_path.as_path()));
+    if let Some(model_id) = &cli.model_id {
+        // Name Hub models after their id rather than `tokenizer_config`
+        options.name = cli.input.analysis.name.clone().or(Some(model_id.clone()));
+    }
+    let mut out = output_writer(cli.output.as_deref(), cli.quiet)?;
+    let mut stale = BTreeSet::new();
+    for (idx, template) in templates.iter().enumerate() {
+        let analysis = match templates.len() {
+            1 => analyze_source(&template.source, &options),
+            _ => {
+                let name = match &options.name {
+                    Some(base) => format!("{base}/{}", template.name),
+                    None => template.name.clone(),
+                };
+                if idx > 0 {
+                    writeln!(out)?;
+                }
+                writeln!(out, "==> {} <==", template.name)?;
+                let options = AnalyzeOptions {
+                    name: Some(name),
+                    ..options.clone()
+                };
+                analyze_source(&template.source, &options)
+            }
+        };
+
+        if !cli.quiet {
+            for warning in &analysis.warnings {
+                eprintln!("Warning [{}]: {}", warning.code.as_str(), warning.message);
+            }
+        }
+        print_analysis(&cli, &analysis, &template.source, &mut out)?;
+
+        // Overrides naming a path the template never uses are stale
+        stale.extend(
+            schema_options(cli.overrides.as_deref())
+                .overrides
+                .into_keys()
+                .filter(|path| !path.is_empty() && analysis.type_of(path).is_none()),
+        );
+    }
+    out.flush()?;
+
+    if !stale.is_empty() {
+        for path in stale {
+            eprintln!("Error: override path `{path}` matches no field of the template");
+        }
+        process::exit(EXIT_VIOLATIONS);
+    }
+    Ok(())
+}
+
+// Reads and analyzes a template, exiting on errors (with `EXIT_PARSE_ERROR`
+// when the template does not parse)
+fn load_template(
+    path: &Path,
+    options: &AnalyzeOptions,
+    unescape: bool,
+) -> (String, TemplateAnalysis) {
+    let content = match read_template(path, unescape) {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("Error reading template file: {err}");
+            eprintln!("Path: {}", path.display());
+            process::exit(1);
+        }
+    };
+    let analysis = analyze_source(&content, options);
+    (content, analysis)
+}
+
+// Analyzes a template, exiting with `EXIT_PARSE_ERROR` when it does not parse
+fn analyze_source(content: &str, options: &AnalyzeOptions) -> TemplateAnalysis {
+    match analyze_with(content, options) {
+        Ok(analysis) => analysis,
+        Err(err) => {
+            eprintln!("Error analyzing template: {err}");
+            process::exit(EXIT_PARSE_ERROR);
+        }
+    }
+}
+
+// Checks a context file against the template and prints every violation,
+// exiting with `EXIT_VIOLATIONS` if there are any; keys the template never
+// reads are reported but do not fail the check
+fn validate_command(cli: ValidateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if cli.input.path().is_some_and(|path| path.as_os_str() == "-")
+        && cli.context.as_os_str() == "-"
+    {
+        eprintln!("Error: the template and the context cannot both come from stdin");
+        process::exit(1);
+    }
+    let (_, analysis) = cli.input.load(false);
+    let context = read_context(&cli.context);
+
+    let violations = analysis.validate_context(&context);
+    for violation in &violations {
+        println!("{violation}");
+    }
+    for path in analysis.unused_keys(&context) {
+        println!("{path}: unused");
+    }
+    if !violations.is_empty() {
+        process::exit(EXIT_VIOLATIONS);
+    }
+    Ok(())
+}
+
+// Renders the template with the given or the sample context, printing the
+// output or the render error (and exiting with `EXIT_VIOLATIONS`); `--strict`
+// first reports every undefined access
+fn render_command(cli: RenderArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if cli.input.path().is_some_and(|path| path.as_os_str() == "-")
+        && cli
+            .context
+            .as_ref()
+            .is_some_and(|path| path.as_os_str() == "-")
+    {
+        eprintln!("Error: the template and the context cannot both come from stdin");
+        process::exit(1);
+    }
+    let (content, analysis) = cli.input.load(cli.strict);
+    let context = match &cli.context {
+        Some(path) => read_context(path),
+        None => analysis.sample_context(),
+    };
+    let mut env = render_environment(&cli.input.analysis);
+    let name = analysis.name.as_deref().unwrap_or("template");
+
+    if cli.strict {
+        let undefined = undefined_paths(&env, name, &content, &context, &analysis);
+        for path in &undefined {
+            let requirement = match analysis.is_required(path) {
+                true => "required",
+                false => "optional",
+            };
+            eprintln!("Undefined: {path} ({requirement} in the analysis)");
+            let origins = analysis.provenance.as_ref().and_then(|p| p.get(path));
+            for origin in origins.into_iter().flatten() {
+                eprintln!(
+                    "    {} (line {}, column {})",
+                    origin.expression, origin.span.start_line, origin.span.start_col
+                );
+            }
+        }
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        if !undefined.is_empty() {
+            if let Err(err) = env.render_named_str(name, &content, &context) {
+                eprintln!("Error rendering template: {err:#}");
+            }
+            process::exit(EXIT_VIOLATIONS);
+        }
+    }
+
+    match env.render_named_str(name, &content, &context) {
+        Ok(output) => {
+            print!("{output}");
+            Ok(())
+        }
+        Err(err) => {
+            eprintln!("Error rendering template: {err:#}");
+            process::exit(EXIT_VIOLATIONS);
+        }
+    }
+}
+
+// Schema paths (`messages[].name`) of the context variables and attributes a
+// lenient render looks up but the context lacks, leaving out the ones the
+// analysis only reads behind `is defined` checks and lookups of globals
+fn undefined_paths(
+    env: &Environment<'_>,
+    name: &str,
+    content: &str,
+    context: &Value,
+    analysis: &TemplateAnalysis,
+) -> BTreeSet<String> {
+    let missing = Arc::new(Mutex::new(BTreeSet::new()));
+    let root = TrackedValue::wrap(context, String::new(), &missing);
+    // Render errors surface in the strict render that follows
+    let _ = env.render_named_str(name, content, root);
+    let missing = missing.lock().unwrap().clone();
+    missing
+        .into_iter()
+        .filter(|path| {
+            let var = path.split(['.', '[']).next().unwrap_or_default();
+            analysis.external_vars.contains(var) && !analysis.optional_paths.contains(path)
+        })
+        .collect()
+}
+
+// Context value handed to the template that records the schema path of every
+// key looked up on it that it does not have
+#[derive(Debug)]
+struct TrackedValue {
+    value: Value,
+    path: String,
+    missing: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl TrackedValue {
+    fn wrap(
+        value: &Value,
+        path: String,
+        missing: &Arc<Mutex<BTreeSet<String>>>,
+    ) -> minijinja::Value {
+        match value {
+            Value::Object(_) | Value::Array(_) => minijinja::Value::from_object(TrackedValue {
+                value: value.clone(),
+                path,
+                missing: Arc::clone(missing),
+            }),
+            _ => minijinja::Value::from_serialize(value),
+        }
+    }
+}
+
+impl Object for TrackedValue {
+    fn repr(self: &Arc<Self>) -> ObjectRepr {
+        match self.value {
+            Value::Array(_) => ObjectRepr::Seq,
+            _ => ObjectRepr::Map,
+        }
+    }
+
+    fn get_value(self: &Arc<Self>, key: &minijinja::Value) -> Option<minijinja::Value> {
+        match &self.value {
+            Value::Array(items) => {
+                let item = items.get(usize::try_from(key.as_i64()?).ok()?)?;
+                Some(Self::wrap(item, format!("{}[]", self.path), &self.missing))
+            }
+            Value::Object(obj) => {
+                let key = key.as_str()?;
+                let path = match self.path.as_str() {
+                    "" => key.to_string(),
+                    parent => format!("{parent}.{key}"),
+                };
+                match obj.get(key) {
+                    Some(child) => Some(Self::wrap(child, path, &self.missing)),
+                    None => {
+                        self.missing.lock().unwrap().insert(path);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn enumerate(self: &Arc<Self>) -> Enumerator {
+        match &self.value {
+            Value::Array(items) => Enumerator::Seq(items.len()),
+            Value::Object(obj) => Enumerator::Values(
+                obj.keys()
+                    .map(|key| minijinja::Value::from(key.as_str()))
+                    .collect(),
+            ),
+            _ => Enumerator::Empty,
+        }
+    }
+
+    // Method calls are not context lookups
+    fn call_method(
+        self: &Arc<Self>,
+        state: &State<'_, '_>,
+        method: &str,
+        args: &[minijinja::Value],
+    ) -> Result<minijinja::Value, Error> {
+        match self.value.get(method) {
+            Some(value) => minijinja::Value::from_serialize(value).call(state, args),
+            None => Err(Error::from(ErrorKind::UnknownMethod)),
+        }
+    }
+}
+
+// Environment configured like the analysis: the same whitespace options,
+// custom filters passing their input through and a `raise_exception` that
+// fails the render with its message
+fn render_environment(input: &AnalysisArgs) -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_trim_blocks(input.trim_blocks);
+    env.set_lstrip_blocks(input.lstrip_blocks);
+    env.set_keep_trailing_newline(input.keep_trailing_newline);
+    for filter in &input.custom_filters {
+        env.add_filter(
+            filter.clone(),
+            |value: minijinja::Value, _: Rest<minijinja::Value>| value,
+        );
+    }
+    env.add_function("raise_exception", |message: String| -> Result<(), Error> {
+        Err(Error::new(ErrorKind::InvalidOperation, message))
+    });
+    env
+}
+
+// Prints every lint of the template as `file:line:col: CODE message`, exiting
+// with `EXIT_VIOLATIONS` if there are any
+fn lint_command(cli: LintArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if cli.rules {
+        for (code, name, summary) in lint::RULES {
+            println!("{code} {name}: {summary}");
+        }
+        return Ok(());
+    }
+
+    let file_path = cli
+        .input
+        .path()
+        .unwrap_or_else(|| PathBuf::from("templates/example.jinja"));
+    let content = match read_template(&file_path, cli.input.analysis.unescape) {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("Error reading template file: {err}");
+            eprintln!("Path: {}", file_path.display());
+            process::exit(1);
+        }
+    };
+    let lints = match lint::lint(&content, &cli.input.options(None)) {
+        Ok(lints) => lints,
+        Err(err) => {
+            eprintln!("Error parsing template: {err}");
+            process::exit(EXIT_PARSE_ERROR);
+        }
+    };
+    for lint in &lints {
+        println!("{}:{lint}", file_path.display());
+    }
+    if !lints.is_empty() {
+        process::exit(EXIT_VIOLATIONS);
+    }
+    Ok(())
+}
+
+// Prints the fields added, removed and retyped between two templates
+fn diff_command(cli: DiffArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if cli.old.as_os_str() == "-" && cli.new.as_os_str() == "-" {
+        eprintln!("Error: only one template can come from stdin");
+        process::exit(1);
+    }
+    let diff = diff_templates(&cli);
+    if diff.is_empty() {
+        println!("No schema changes");
+    } else {
+        print!("{diff}");
+    }
+    Ok(())
+}
+
+// Analyzes both templates of a diff, exiting on errors, and compares them
+fn diff_templates(cli: &DiffArgs) -> SchemaDiff {
+    let load = |path: &Path| {
+        let from_stdin = path.as_os_str() == "-";
+        let options = cli.analysis.options((!from_stdin).then_some(path));
+        load_template(path, &options, cli.analysis.unescape).1
+    };
+    load(&cli.old).diff(&load(&cli.new))
+}
+
+// Compares the inferred schema with the snapshot and prints one line per
+// differing JSON Pointer, exiting with `EXIT_VIOLATIONS` if they diverge
+fn check_command(cli: CheckArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (_, analysis) = cli.input.load(false);
+    let schema = analysis.to_json_schema_with(&schema_options(cli.overrides.as_deref()));
+    if cli.update {
+        fs::write(&cli.schema, serde_json::to_string_pretty(&schema)? + "\n")?;
+        return Ok(());
+    }
+
+    let expected = match fs::read_to_string(&cli.schema) {
+        Ok(content) => serde_json::from_str::<Value>(&content).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    let expected = match expected {
+        Ok(expected) => expected,
+        Err(err) => {
+            eprintln!("Error reading schema snapshot: {err}");
+            eprintln!("Path: {}", cli.schema.display());
+            process::exit(1);
+        }
+    };
+
+    let differences = schema_differences(&expected, &schema);
+    if !differences.is_empty() {
+        eprintln!(
+            "Schema differs from {} (rerun with --update to accept):",
+            cli.schema.display()
+        );
+        for difference in differences {
+            println!("{difference}");
+        }
+        process::exit(EXIT_VIOLATIONS);
+    }
+    Ok(())
+}
+
+// Differences between a snapshot and the inferred schema, leaving out the
+// root `$id`, `title` and `description`, which follow the template name
+fn schema_differences(expected: &Value, actual: &Value) -> Vec<String> {
+    let without_metadata = |schema: &Value| {
+        let mut schema = schema.clone();
+        if let Value::Object(obj) = &mut schema {
+            for key in ["$id", "title", "description"] {
+                obj.remove(key);
+            }
+        }
+        schema
+    };
+    let mut differences = Vec::new();
+    json_differences(
+        &without_metadata(expected),
+        &without_metadata(actual),
+        "",
+        &mut differences,
+    );
+    differences
+}
+
+// Lines `- pointer` for values only in `expected`, `+ pointer` for values only
+// in `actual` and `~ pointer: expected -> actual` for changed values
+fn json_differences(expected: &Value, actual: &Value, pointer: &str, out: &mut Vec<String>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            let keys = expected
+                .keys()
+                .chain(actual.keys())
+                .collect::<BTreeSet<_>>();
+            for key in keys {
+                let child = format!("{pointer}/{}", key.replace('~', "~0").replace('/', "~1"));
+                match (expected.get(key), actual.get(key)) {
+                    (Some(expected), Some(actual)) => {
+                        json_differences(expected, actual, &child, out)
+                    }
+                    (Some(_), None) => out.push(format!("- {child}")),
+                    (None, Some(_)) => out.push(format!("+ {child}")),
+                    (None, None) => {}
+                }
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) if expected.len() == actual.len() => {
+            for (idx, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+                json_differences(expected, actual, &format!("{pointer}/{idx}"), out);
+            }
+        }
+        _ if expected != actual => out.push(format!("~ {pointer}: {expected} -> {actual}")),
+        _ => {}
+    }
+}
+
+// Prints one row of metrics per template (or a JSON array with `--json`);
+// templates that fail to parse make it exit with `EXIT_PARSE_ERROR`
+fn stats_command(cli: StatsArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut failed = false;
+    let mut measured = Vec::new();
+    for path in &cli.templates {
+        let stats = read_template(path, cli.analysis.unescape)
+            .map_err(|err| err.to_string())
+            .and_then(|content| {
+                template_stats(&content, &cli.analysis.options(Some(path)))
+                    .map_err(|err| err.to_string())
+            });
+        match stats {
+            Ok(stats) => measured.push((path.display().to_string(), stats)),
+            Err(err) => {
+                eprintln!("{}: error: {err}", path.display());
+                failed = true;
+            }
+        }
+    }
+
+    if cli.json {
+        let output = measured
+            .iter()
+            .map(|(template, stats)| {
+                let mut row = json!(stats);
+                row["template"] = json!(template);
+                row
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        let mut rows = vec![[
+            "TEMPLATE",
+            "LOOPS",
+            "BRANCHES",
+            "DEPTH",
+            "PATHS",
+            "MACROS",
+            "COMPLEXITY",
+        ]
+        .map(String::from)];
+        for (template, stats) in measured {
+            rows.push([
+                template,
+                stats.loops.to_string(),
+                stats.branches.to_string(),
+                stats.max_depth.to_string(),
+                stats.variable_paths.to_string(),
+                stats.macros.to_string(),
+                stats.complexity.to_string(),
+            ]);
+        }
+        print!("{}", align_rows(&rows));
+    }
+    if failed {
+        process::exit(EXIT_PARSE_ERROR);
+    }
+    Ok(())
+}
+
+// Prints the type and requiredness of one schema path, the fields below it
+// and the template expressions that read them
+fn query_command(cli: QueryArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (_, analysis) = cli.input.load(true);
+    if analysis.type_of(&cli.var).is_none() {
+        eprintln!("Error: the template does not use `{}`", cli.var);
+        process::exit(1);
+    }
+    write_query(&mut io::stdout().lock(), &analysis, &cli.var)?;
+    Ok(())
+}
+
+// Writes the type and requiredness of a path the template uses, of the
+// fields below it, and where the template reads them
+fn write_query(out: &mut dyn Write, analysis: &TemplateAnalysis, var: &str) -> io::Result<()> {
+    let Some(shape) = analysis.type_of(var) else {
+        return Ok(());
+    };
+    let describe = |path: &str, shape: &Shape| {
+        let mut notes = vec![match analysis.is_required(path) {
+            true => "required",
+            false => "optional",
+        }];
+        if analysis.nullable_paths.contains(path) {
+            notes.push("nullable");
+        }
+        format!("{path}: {} ({})", shape.type_name(), notes.join(", "))
+    };
+    writeln!(out, "{}", describe(var, shape))?;
+
+    let fields = analysis.fields_under(var);
+    if !fields.is_empty() {
+        writeln!(out, "\nFields:")?;
+        for field in &fields {
+            if let Some(shape) = analysis.type_of(field) {
+                writeln!(out, "  {}", describe(field, shape))?;
+            }
+        }
+    }
+
+    let provenance = analysis.provenance.as_ref();
+    let origins = std::iter::once(var)
+        .chain(fields.iter().map(String::as_str))
+        .filter_map(|path| provenance.and_then(|provenance| provenance.get(path)))
+        .flatten()
+        .map(|origin| (origin.span.start_offset, origin))
+        .collect::<std::collections::BTreeMap<_, _>>();
+    if !origins.is_empty() {
+        writeln!(out, "\nUsed at:")?;
+        for origin in origins.values() {
+            writeln!(
+                out,
+                "  line {}, column {}: {}",
+                origin.span.start_line, origin.span.start_col, origin.expression
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// One template of a batch corpus and the models that ship it
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchRecord {
+    Source(String),
+    Entry {
+        template: String,
+        #[serde(default)]
+        model_ids: Vec<String>,
+    },
+}
+
+impl BatchRecord {
+    fn into_entry(self) -> (String, Vec<String>) {
+        match self {
+            BatchRecord::Source(template) => (template, Vec::new()),
+            BatchRecord::Entry {
+                template,
+                model_ids,
+            } => (template, model_ids),
+        }
+    }
+}
+
+impl From<ShapeSort> for BucketOrder {
+    fn from(sort: ShapeSort) -> BucketOrder {
+        match sort {
+            ShapeSort::TemplateCount => BucketOrder::TemplateCount,
+            ShapeSort::ModelCount => BucketOrder::ModelCount,
+            ShapeSort::Name => BucketOrder::Shape,
+        }
+    }
+}
+
+// Prints the templates reachable from a template and the references between
+// them, exiting with `EXIT_VIOLATIONS` on cycles and missing templates
+fn deps_command(cli: DepsArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let file_path = cli
+        .input
+        .path()
+        .unwrap_or_else(|| PathBuf::from("templates/example.jinja"));
+    if file_path.as_os_str() == "-" {
+        eprintln!("Error: the dependency graph needs a template file, not stdin");
+        process::exit(1);
+    }
+    let options = cli.input.analysis.options(Some(&file_path));
+    let root = file_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let graph = match dependency_graph(&root, &options) {
+        Ok(graph) => graph,
+        Err(err) => {
+            eprintln!("Error reading template file: {err}");
+            eprintln!("Path: {}", file_path.display());
+            process::exit(1);
+        }
+    };
+
+    if cli.dot {
+        print!("{}", graph.to_dot());
+    } else {
+        for (name, node) in &graph.templates {
+            match (node.found, &node.error) {
+                (false, _) => println!("{name} (missing)"),
+                (true, Some(err)) => println!("{name} (does not parse: {err})"),
+                (true, None) => println!("{name}"),
+            }
+            for edge in graph.dependencies_of(name) {
+                println!(
+                    "  {} {} (line {})",
+                    edge.kind.as_str(),
+                    edge.to,
+                    edge.span.start_line
+                );
+            }
+            for (_, span) in graph.dynamic.iter().filter(|(from, _)| from == name) {
+                println!("  computed template name (line {})", span.start_line);
+            }
+        }
+    }
+    let cycles = graph.cycles();
+    for cycle in &cycles {
+        eprintln!("Cycle: {} -> {}", cycle.join(" -> "), cycle[0]);
+    }
+    if !cycles.is_empty() || !graph.missing().is_empty() {
+        process::exit(EXIT_VIOLATIONS);
+    }
+    Ok(())
+}
+
+// Analyzes a corpus and writes the per-template results, the shape frequencies
+// and the shape families (plus the CSV summary and HTML report when asked),
+// then prints a summary and the coverage table
+fn batch_command(cli: BatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    // Streamed results go to stdout, so progress and the summary move to stderr
+    let stream_to_stdout = cli.jsonl && cli.output.as_os_str() == "-";
+    macro_rules! report {
+        ($($arg:tt)*) => {
+            if stream_to_stdout {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
+        };
+    }
+
+    if !(cli.coverage_target > 0.0 && cli.coverage_target <= 100.0) {
+        eprintln!("Error: --coverage-target must be above 0 and at most 100");
+        process::exit(1);
+    }
+
+    report!("Reading templates from: {}", cli.input.display());
+    let sources = match read_corpus(&cli.input, &cli.extensions, cli.analysis.unescape) {
+        Ok(sources) => sources,
+        Err(err) => {
+            eprintln!("Error reading corpus: {err}");
+            eprintln!("Path: {}", cli.input.display());
+            process::exit(1);
+        }
+    };
+
+    let template_count = sources.len();
+    // Byte-identical templates share an id, so each is analyzed once
+    let mut corpus = TemplateCorpus::from_sources(sources);
+    report!(
+        "Found {template_count} templates to analyze ({} unique)",
+        corpus.len()
+    );
+    let total_model_ids = corpus
+        .templates()
+        .iter()
+        .flat_map(|template| &template.model_ids)
+        .collect::<BTreeSet<_>>()
+        .len();
+    report!("Total unique model IDs: {total_model_ids}");
+    // Templates finished by an interrupted run are restored instead of analyzed
+    if let (Some(path), true) = (&cli.checkpoint, cli.resume) {
+        let mut restored = batch::read_checkpoint(path)?;
+        report!(
+            "Resuming with {} templates from the checkpoint",
+            restored.len()
+        );
+        for template in corpus.templates_mut() {
+            template.outcome = restored.remove(&template.name);
+        }
+    }
+    report!();
+
+    // Results are written as one array at the end, unless they are streamed
+    // to the output as they are produced
+    let mut jsonl_writer: Option<Box<dyn Write>> = match (cli.jsonl, stream_to_stdout) {
+        (false, _) => None,
+        (true, true) => Some(Box::new(io::BufWriter::new(io::stdout().lock()))),
+        (true, false) => Some(Box::new(io::BufWriter::new(fs::File::create(&cli.output)?))),
+    };
+    let mut checkpoint = match &cli.checkpoint {
+        Some(path) => Some(io::BufWriter::new(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(cli.resume)
+                .write(true)
+                .truncate(!cli.resume)
+                .open(path)?,
+        )),
+        None => None,
+    };
+
+    let options = cli.analysis.options(None);
+    let cache = match &cli.cache {
+        Some(dir) => Some(AnalysisCache::open(dir, &options)?),
+        None => None,
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.jobs.unwrap_or(0))
+        .build()?;
+    // Templates are analyzed in parallel a chunk at a time and written out in
+    // corpus order, so the outputs don't depend on `--jobs`
+    for start in (0..corpus.len()).step_by(BATCH_CHUNK) {
+        let end = (start + BATCH_CHUNK).min(corpus.len());
+        let fresh = corpus.templates()[start..end]
+            .iter()
+            .map(|template| template.outcome.is_none())
+            .collect::<Vec<_>>();
+        pool.install(|| corpus.analyze_range(start..end, &options, cache.as_ref()));
+        for (template, fresh) in corpus.templates()[start..end].iter().zip(fresh) {
+            if let (Some(writer), true) = (&mut checkpoint, fresh) {
+                batch::write_checkpoint(writer, template)?;
+            }
+            if let (Some(writer), Some(result)) =
+                (&mut jsonl_writer, batch::template_result(template))
+            {
+                writeln!(writer, "{result}")?;
+            }
+        }
+        if let Some(writer) = &mut checkpoint {
+            writer.flush()?;
+        }
+    }
+    match jsonl_writer {
+        Some(mut writer) => writer.flush()?,
+        None => {
+            let results = corpus
+                .templates()
+                .iter()
+                .filter_map(batch::template_result)
+                .collect::<Vec<_>>();
+            fs::write(&cli.output, serde_json::to_string_pretty(&results)?)?;
+        }
+    }
+    if let Some(path) = &cli.csv {
+        let mut writer = io::BufWriter::new(fs::File::create(path)?);
+        batch::write_csv(&mut writer, &corpus)?;
+        writer.flush()?;
+    }
+
+    let mut corpus_report = corpus.report();
+    if let Some(include_templates) = cli.include_templates {
+        let listing = match include_templates {
+            IncludeTemplates::All => TemplateListing::All,
+            IncludeTemplates::Hashes => TemplateListing::Ids,
+            IncludeTemplates::FirstN => TemplateListing::FirstN(cli.template_limit),
+        };
+        batch::list_templates(&mut corpus_report, &corpus, listing);
+    }
+    let order = BucketOrder::from(cli.sort_by);
+    let shape_frequency_results =
+        corpus_report.shape_frequencies(order, cli.include_templates.is_some());
+    fs::write(
+        &cli.shape_output,
+        serde_json::to_string_pretty(&shape_frequency_results)?,
+    )?;
+
+    // Group shapes that differ only slightly into families
+    let cluster_results = batch::shape_families(&corpus, cli.cluster_threshold);
+    fs::write(
+        &cli.cluster_output,
+        serde_json::to_string_pretty(&cluster_results)?,
+    )?;
+
+    report!(
+        "Analysis complete! Results saved to: {}",
+        cli.output.display()
+    );
+    report!(
+        "Shape frequency analysis saved to: {}",
+        cli.shape_output.display()
+    );
+    report!("Shape families saved to: {}", cli.cluster_output.display());
+
+    report!("\nSummary:");
+    report!("Total templates: {template_count}");
+    report!("Successfully analyzed: {}", corpus_report.analyzed_count);
+    report!(
+        "Total number of model IDs: {}",
+        corpus_report.analyzed_model_count
+    );
+    report!("Failed: {}", corpus_report.failed_count);
+    report!(
+        "Total number of model IDs of failures: {}",
+        corpus_report.failed_model_count
+    );
+    report!(
+        "Unique object shapes found: {}",
+        shape_frequency_results.len()
+    );
+    report!(
+        "Shape families (similarity >= {:.2}): {}",
+        cli.cluster_threshold,
+        cluster_results.len()
+    );
+    let cache_stats = cache.as_ref().map(AnalysisCache::stats).unwrap_or_default();
+    if let Some(cache) = &cache {
+        report!(
+            "Cache: {} hits, {} misses ({})",
+            cache_stats.hits,
+            cache_stats.misses,
+            cache.dir().display()
+        );
+    }
+    let warning_counts = batch::warning_counts(&corpus);
+    let warning_count = warning_counts.values().sum::<usize>();
+    if warning_count > 0 {
+        let by_code = warning_counts
+            .iter()
+            .map(|(code, count)| format!("{code}: {count}"))
+            .collect::<Vec<_>>();
+        report!("Warnings: {warning_count} ({})", by_code.join(", "));
+    }
+    info!(
+        templates = template_count,
+        analyzed = corpus_report.analyzed_count,
+        failed = corpus_report.failed_count,
+        shapes = shape_frequency_results.len(),
+        families = cluster_results.len(),
+        warnings = warning_count,
+        cache_hits = cache_stats.hits,
+        cache_misses = cache_stats.misses,
+        "batch complete"
+    );
+
+    if let Some(path) = &cli.html {
+        let report = batch::html_report(&corpus, &shape_frequency_results, &cluster_results);
+        fs::write(path, report)?;
+        report!("HTML report saved to: {}", path.display());
+        return Ok(());
+    }
+
+    // The shapes in `--sort-by` order, until they cover the target share of
+    // the models or `--top` of them are printed
+    let table = batch::coverage_table(&corpus_report, order, cli.coverage_target, cli.top);
+    if !table.is_empty() {
+        report!("{}", table.trim_end());
+    }
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Parse command line arguments
-    let cli = Cli::parse();
+// Reads a batch corpus as (template source, model ids) pairs: a directory of
+// templates (each file's chat templates, named after the file), a JSON object
+// mapping sources to model ids, or a JSON array or JSON Lines of records;
+// sources are decoded as `decode_template` does
+fn read_corpus(
+    path: &Path,
+    extensions: &[String],
+    unescape: bool,
+) -> io::Result<Vec<(String, Vec<String>)>> {
+    if path.is_dir() {
+        let mut files = Vec::new();
+        template_files(path, extensions, &mut files)?;
+        let mut corpus = Vec::new();
+        for file in files {
+            let display = file
+                .strip_prefix(path)
+                .unwrap_or(&file)
+                .display()
+                .to_string();
+            for template in read_templates(&file, unescape)? {
+                let model_id = match template.name.as_str() {
+                    "default" => display.clone(),
+                    name => format!("{display}/{name}"),
+                };
+                corpus.push((template.source, vec![model_id]));
+            }
+        }
+        return Ok(corpus);
+    }
 
-    // Get the template file path
-    let file_path = cli
-        .file
-        .unwrap_or_else(|| PathBuf::from("templates/example.jinja"));
+    let content = read_input(path)?;
+    let invalid = |err: serde_json::Error| io::Error::new(io::ErrorKind::InvalidData, err);
+    let corpus: io::Result<Vec<(String, Vec<String>)>> =
+        match serde_json::from_str::<Value>(&content) {
+            Ok(Value::Object(map)) => map
+                .into_iter()
+                .map(|(template, model_ids)| {
+                    let model_ids = serde_json::from_value(model_ids).map_err(invalid)?;
+                    Ok((template, model_ids))
+                })
+                .collect(),
+            Ok(Value::Array(records)) => records
+                .into_iter()
+                .map(|record| {
+                    let record = serde_json::from_value::<BatchRecord>(record).map_err(invalid)?;
+                    Ok(record.into_entry())
+                })
+                .collect(),
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a JSON object, array or JSON Lines",
+            )),
+            // Several JSON documents are JSON Lines
+            Err(_) => content
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    let record = serde_json::from_str::<BatchRecord>(line).map_err(invalid)?;
+                    Ok(record.into_entry())
+                })
+                .collect(),
+        };
+    Ok(corpus?
+        .into_iter()
+        .map(|(template, model_ids)| (decode_template(template, unescape), model_ids))
+        .collect())
+}
 
-    // Read the template file
-    let template_content = match fs::read_to_string(&file_path) {
-        Ok(content) => content,
+// Prints the completion script of the shell, e.g. for
+// `cleanplate completions bash > /etc/bash_completion.d/cleanplate`
+fn completions_command(cli: CompletionsArgs) -> Result<(), Box<dyn std::error::Error>> {
+    write_completions(cli.shell, &mut io::stdout());
+    Ok(())
+}
+
+// Writes the completion script of the command line for a shell
+fn write_completions(shell: Shell, out: &mut dyn Write) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+// Reads a JSON render context from a file, or from stdin for `-`; exits on errors
+fn read_context(path: &Path) -> Value {
+    let context = match read_input(path) {
+        Ok(content) => serde_json::from_str::<Value>(&content).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    match context {
+        Ok(context) => context,
         Err(err) => {
-            eprintln!("Error reading template file: {err}");
-            eprintln!("Path: {}", file_path.display());
+            eprintln!("Error reading context file: {err}");
+            eprintln!("Path: {}", path.display());
             process::exit(1);
         }
-    };
+    }
+}
 
-    // Analyze the template
-    let analysis = match analyze(&template_content, cli.verbose) {
-        Ok(a) => a,
-        Err(err) => {
-            eprintln!("Error analyzing template: {err}");
-            process::exit(1);
+// Writes the analysis in the requested format
+fn print_analysis(
+    cli: &AnalyzeArgs,
+    analysis: &TemplateAnalysis,
+    template_content: &str,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if cli.annotate {
+        write!(
+            out,
+            "{}",
+            analysis.annotate_source(template_content, cli.color.enabled())
+        )?;
+        return Ok(());
+    }
+
+    match cli.format() {
+        OutputFormat::Report => {}
+        OutputFormat::Json => {
+            writeln!(
+                out,
+                "{}",
+                serde_json::to_string_pretty(&analysis_json(analysis))?
+            )?;
+            return Ok(());
         }
-    };
+        OutputFormat::Yaml => {
+            write!(out, "{}", serde_yaml::to_string(&analysis_json(analysis))?)?;
+            return Ok(());
+        }
+        OutputFormat::Schema => {
+            let schema = analysis.to_json_schema_with(&schema_options(cli.overrides()));
+            writeln!(out, "{}", serde_json::to_string_pretty(&schema)?)?;
+            return Ok(());
+        }
+        OutputFormat::Table => {
+            write!(out, "{}", fields_table(analysis))?;
+            return Ok(());
+        }
+        OutputFormat::Markdown => {
+            write!(out, "{}", analysis.to_markdown())?;
+            return Ok(());
+        }
+        OutputFormat::Dot => {
+            write!(out, "{}", analysis.to_dot())?;
+            return Ok(());
+        }
+        OutputFormat::Mermaid => {
+            write!(out, "{}", to_mermaid(analysis, &CodegenOptions::default()))?;
+            return Ok(());
+        }
+        OutputFormat::Pointers => {
+            let index = analysis
+                .pointer_index()
+                .into_iter()
+                .map(|field| {
+                    json!({
+                        "pointer": field.pointer,
+                        "path": field.path,
+                        "type": field.type_name,
+                        "required": field.required,
+                        "nullable": field.nullable,
+                        "confidence": field.confidence.map(|confidence| confidence.as_str()),
+                    })
+                })
+                .collect::<Vec<_>>();
+            writeln!(out, "{}", serde_json::to_string_pretty(&index)?)?;
+            return Ok(());
+        }
+    }
 
     // Print the analysis results
-    println!("\n=== Variable Analysis Report ===\n");
+    writeln!(out, "\n=== Variable Analysis Report ===\n")?;
 
     // Print external variables (required context)
-    println!("External Variables (required context):");
+    writeln!(out, "External Variables (required context):")?;
     if analysis.external_vars.is_empty() {
-        println!("  None");
+        writeln!(out, "  None")?;
     } else {
         for var in &analysis.external_vars {
-            println!("  {var}");
+            if analysis.optional_paths.contains(var) {
+                writeln!(out, "  {var} (optional)")?;
+            } else {
+                writeln!(out, "  {var}")?;
+            }
         }
     }
 
     // Print internal variables
-    println!("\nInternal Variables (defined in template):");
+    writeln!(out, "\nInternal Variables (defined in template):")?;
     let internal_non_loop = analysis
         .internal_vars
         .iter()
@@ -67,30 +1910,1327 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .collect::<Vec<_>>();
 
     if internal_non_loop.is_empty() {
-        println!("  None");
+        writeln!(out, "  None")?;
     } else {
         for var in internal_non_loop {
-            println!("  {var}");
+            writeln!(out, "  {var}")?;
         }
     }
 
     // Print loop variables with their iterables
-    println!("\nLoop Variables:");
+    writeln!(out, "\nLoop Variables:")?;
     let loop_vars = analysis.loop_vars.iter().collect::<Vec<_>>();
     if loop_vars.is_empty() {
-        println!("  None");
+        writeln!(out, "  None")?;
     } else {
         for (var, iterable) in loop_vars {
-            println!("  {var} (from {iterable})");
+            writeln!(out, "  {var} (from {iterable})")?;
+        }
+    }
+
+    // Print requirements enforced with raise_exception
+    if !analysis.constraints.is_empty() {
+        writeln!(out, "\nConstraints:")?;
+        for constraint in &analysis.constraints {
+            match &constraint.message {
+                Some(message) => writeln!(out, "  {} ({message})", constraint.condition)?,
+                None => writeln!(out, "  {}", constraint.condition)?,
+            }
+        }
+    }
+
+    // Print where each field comes from
+    if let Some(provenance) = analysis.provenance.as_ref().filter(|_| cli.provenance) {
+        writeln!(out, "\nProvenance:")?;
+        for (path, origins) in provenance {
+            writeln!(out, "  {path}")?;
+            for origin in origins {
+                writeln!(
+                    out,
+                    "    {} (line {}, column {})",
+                    origin.expression, origin.span.start_line, origin.span.start_col
+                )?;
+            }
+        }
+    }
+
+    // Print the rationale behind each field
+    if cli.explain {
+        writeln!(out, "\nExplanations:")?;
+        for explanation in analysis.explain() {
+            writeln!(out, "  {explanation}")?;
         }
     }
 
+    if cli.schema || cli.overrides.is_some() {
+        let schema_options = schema_options(cli.overrides());
+        writeln!(out, "\nTemplate JSON Schema:")?;
+        writeln!(
+            out,
+            "{}",
+            serde_json::to_string_pretty(&analysis.to_json_schema_with(&schema_options))?
+        )?;
+        return Ok(());
+    }
+
+    if cli.tree {
+        writeln!(out, "\nTemplate Data Shape:")?;
+        write!(out, "{}", analysis.to_tree(cli.color.enabled()))?;
+        return Ok(());
+    }
+
     // Print JSON Schema
-    println!("\nTemplate Data Shape (JSON):");
-    println!(
+    writeln!(out, "\nTemplate Data Shape (JSON):")?;
+    writeln!(
+        out,
         "{}",
-        serde_json::to_string_pretty(&analysis.object_shapes_json)?
+        serde_json::to_string_pretty(&analysis.object_shapes_json())?
+    )?;
+
+    Ok(())
+}
+
+// Analysis options of `analyze`
+fn analyze_options(cli: &AnalyzeArgs, file_path: Option<&Path>) -> AnalyzeOptions {
+    AnalyzeOptions {
+        // Explanations and annotated source need the spans each field is used at
+        provenance: cli.provenance || cli.explain || cli.annotate,
+        ..cli.input.options(file_path)
+    }
+}
+
+// Analyzes every template below `dir` and prints one result per file and a
+// summary of the whole tree (as one object for `json` and `yaml`)
+fn analyze_directory(dir: &Path, cli: &AnalyzeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if !matches!(
+        cli.format(),
+        OutputFormat::Report | OutputFormat::Json | OutputFormat::Yaml
+    ) {
+        eprintln!("Error: --recursive supports the report, json and yaml formats");
+        process::exit(1);
+    }
+
+    let mut files = Vec::new();
+    if let Err(err) = template_files(dir, &cli.extensions, &mut files) {
+        eprintln!("Error reading template directory: {err}");
+        eprintln!("Path: {}", dir.display());
+        process::exit(1);
+    }
+
+    let mut out = output_writer(cli.output.as_deref(), cli.quiet)?;
+    let mut results = Vec::new();
+    let mut analyses = Vec::new();
+    for file in &files {
+        let display = file.strip_prefix(dir).unwrap_or(file).display().to_string();
+        let analysis = read_template(file, cli.input.analysis.unescape)
+            .map_err(|err| err.to_string())
+            .and_then(|content| {
+                analyze_with(&content, &analyze_options(cli, Some(file)))
+                    .map_err(|err| err.to_string())
+            });
+        match analysis {
+            Ok(analysis) => {
+                let mut result = analysis_json(&analysis);
+                result["file"] = json!(display);
+                result["status"] = json!("success");
+                results.push(result);
+                analyses.push(analysis);
+            }
+            Err(err) => results.push(json!({
+                "file": display,
+                "status": "error",
+                "error": err,
+            })),
+        }
+    }
+
+    // How many files use each context variable, and how many shapes there are
+    let mut variable_counts = std::collections::BTreeMap::<&str, usize>::new();
+    for analysis in &analyses {
+        for var in &analysis.external_vars {
+            *variable_counts.entry(var).or_default() += 1;
+        }
+    }
+    let shapes = analyses
+        .iter()
+        .map(TemplateAnalysis::shape_hash)
+        .collect::<std::collections::BTreeSet<_>>();
+    let summary = json!({
+        "files": files.len(),
+        "analyzed": analyses.len(),
+        "failed": files.len() - analyses.len(),
+        "unique_shapes": shapes.len(),
+        "external_vars": variable_counts,
+    });
+
+    match cli.format() {
+        OutputFormat::Json => {
+            let output = json!({ "results": results, "summary": summary });
+            writeln!(out, "{}", serde_json::to_string_pretty(&output)?)?;
+        }
+        OutputFormat::Yaml => {
+            let output = json!({ "results": results, "summary": summary });
+            write!(out, "{}", serde_yaml::to_string(&output)?)?;
+        }
+        _ => {
+            for result in &results {
+                match result["error"].as_str() {
+                    Some(err) => writeln!(
+                        out,
+                        "{}: error: {err}",
+                        result["file"].as_str().unwrap_or("")
+                    )?,
+                    None => {
+                        let vars = result["external_vars"]
+                            .as_array()
+                            .map(|vars| {
+                                vars.iter()
+                                    .filter_map(Value::as_str)
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            })
+                            .unwrap_or_default();
+                        writeln!(
+                            out,
+                            "{}: shape {} ({vars})",
+                            result["file"].as_str().unwrap_or(""),
+                            result["shape_hash"].as_str().unwrap_or("")
+                        )?;
+                    }
+                }
+            }
+            writeln!(out, "\nSummary:")?;
+            writeln!(out, "  Templates: {}", files.len())?;
+            writeln!(out, "  Analyzed: {}", analyses.len())?;
+            writeln!(out, "  Failed: {}", files.len() - analyses.len())?;
+            writeln!(out, "  Unique shapes: {}", shapes.len())?;
+            writeln!(out, "  Context variables (files using them):")?;
+            let mut counts = variable_counts.into_iter().collect::<Vec<_>>();
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+            for (var, count) in counts {
+                writeln!(out, "    {var}: {count}")?;
+            }
+        }
+    }
+    out.flush()?;
+
+    if analyses.len() < files.len() {
+        process::exit(EXIT_PARSE_ERROR);
+    }
+    Ok(())
+}
+
+// Buffered writer for the results: the `--output` file, stdout, or nowhere
+// with `--quiet`
+fn output_writer(path: Option<&Path>, quiet: bool) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(io::BufWriter::new(fs::File::create(path)?)),
+        None if quiet => Box::new(io::sink()),
+        None => Box::new(io::BufWriter::new(io::stdout().lock())),
+    })
+}
+
+// Re-analyzes the template whenever its modification time changes and prints
+// the schema diff against the last analysis that succeeded
+fn watch_template(path: &Path, cli: &AnalyzeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if path.as_os_str() == "-" {
+        eprintln!("Error: --watch needs a template file");
+        process::exit(1);
+    }
+    let options = analyze_options(cli, Some(path));
+    let analyze_file = || -> Result<TemplateAnalysis, String> {
+        let content =
+            read_template(path, cli.input.analysis.unescape).map_err(|err| err.to_string())?;
+        analyze_with(&content, &options).map_err(|err| err.to_string())
+    };
+    let modified = || {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+
+    let mut last_modified = modified();
+    let mut last = match analyze_file() {
+        Ok(analysis) => analysis,
+        Err(err) => {
+            eprintln!("Error analyzing template: {err}");
+            process::exit(1);
+        }
+    };
+    println!(
+        "Watching {} ({} fields, shape {:016x})",
+        path.display(),
+        last.shape.fields().len(),
+        last.shape_hash()
     );
 
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        // Editors may briefly remove the file while saving
+        let Some(current) = modified() else {
+            continue;
+        };
+        if Some(current) == last_modified {
+            continue;
+        }
+        last_modified = Some(current);
+
+        match analyze_file() {
+            Ok(analysis) => {
+                write_schema_change(&mut io::stdout().lock(), &last, &analysis)?;
+                last = analysis;
+            }
+            Err(err) => eprintln!("Error analyzing template: {err}"),
+        }
+    }
+}
+
+// Writes what changed in the schema between two analyses of a watched
+// template, and the new shape hash
+fn write_schema_change(
+    out: &mut dyn Write,
+    last: &TemplateAnalysis,
+    analysis: &TemplateAnalysis,
+) -> io::Result<()> {
+    let diff = last.diff(analysis);
+    if diff.is_empty() {
+        writeln!(out, "No schema changes")?;
+    } else {
+        write!(out, "{diff}")?;
+    }
+    writeln!(out, "Shape {:016x}", analysis.shape_hash())
+}
+
+// Collects the files below `dir` with one of the extensions, in path order,
+// skipping hidden files and directories
+fn template_files(dir: &Path, extensions: &[String], files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            template_files(&path, extensions, files)?;
+        } else if path.extension().is_some_and(|ext| {
+            extensions
+                .iter()
+                .any(|wanted| ext.to_string_lossy() == wanted.trim_start_matches('.'))
+        }) {
+            files.push(path);
+        }
+    }
     Ok(())
 }
+
+// Reads a file, or stdin for `-`
+fn read_input(path: &Path) -> io::Result<String> {
+    if path.as_os_str() == "-" {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        Ok(content)
+    } else {
+        fs::read_to_string(path)
+    }
+}
+
+// Reads the templates of a file: the chat templates of a GGUF model or a
+// tokenizer config, or the file itself as the `default` template; sources are
+// decoded as `decode_template` does
+fn read_templates(path: &Path, unescape: bool) -> io::Result<Vec<ChatTemplate>> {
+    let content = if path.as_os_str() == "-" {
+        read_input(path)?
+    } else {
+        let mut file = io::BufReader::new(fs::File::open(path)?);
+        if file.fill_buf()?.starts_with(b"GGUF") {
+            let templates = gguf_templates(&mut file)?;
+            if templates.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the GGUF file has no tokenizer.chat_template",
+                ));
+            }
+            return Ok(decode_templates(templates, unescape));
+        }
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        content
+    };
+    match tokenizer_config_templates(&content) {
+        Some(templates) if templates.is_empty() => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the tokenizer config has no named chat templates",
+        )),
+        Some(templates) => Ok(decode_templates(templates, unescape)),
+        None => Ok(decode_templates(
+            vec![ChatTemplate {
+                name: "default".to_string(),
+                source: content,
+            }],
+            unescape,
+        )),
+    }
+}
+
+fn decode_templates(templates: Vec<ChatTemplate>, unescape: bool) -> Vec<ChatTemplate> {
+    templates
+        .into_iter()
+        .map(|template| ChatTemplate {
+            source: decode_template(template.source, unescape),
+            ..template
+        })
+        .collect()
+}
+
+// Decodes the JSON string escaping of a template scraped into JSON: always
+// with `--unescape` (keeping the source when it is not a valid JSON string),
+// otherwise only when the source is evidently escaped
+fn decode_template(source: String, unescape: bool) -> String {
+    if unescape {
+        return unescape_template(&source).unwrap_or_else(|| {
+            warn!("the template is not a valid JSON string; reading it as it is");
+            source
+        });
+    }
+    match detect_escaped_template(&source) {
+        Some(decoded) => {
+            debug!("decoded a JSON-escaped template");
+            decoded
+        }
+        None => source,
+    }
+}
+
+// Reads a template file (the `default` or first chat template of a tokenizer
+// config), or stdin for `-`
+fn read_template(path: &Path, unescape: bool) -> io::Result<String> {
+    let mut templates = read_templates(path, unescape)?;
+    let idx = templates
+        .iter()
+        .position(|template| template.name == "default")
+        .unwrap_or(0);
+    Ok(templates.swap_remove(idx).source)
+}
+
+// Schema options with the overrides file, if any, loaded; exits on errors
+fn schema_options(overrides: Option<&Path>) -> SchemaOptions {
+    let mut schema_options = SchemaOptions::default();
+    if let Some(path) = overrides {
+        let overrides = match fs::read_to_string(path) {
+            Ok(content) => parse_overrides(&content),
+            Err(err) => {
+                eprintln!("Error reading overrides file: {err}");
+                eprintln!("Path: {}", path.display());
+                process::exit(1);
+            }
+        };
+        schema_options.overrides = match overrides {
+            Ok(overrides) => overrides,
+            Err(err) => {
+                eprintln!("Error parsing overrides file: {err}");
+                process::exit(1);
+            }
+        };
+    }
+    schema_options
+}
+
+// Machine-readable version of the report
+fn analysis_json(analysis: &TemplateAnalysis) -> Value {
+    let loop_vars = analysis
+        .loop_vars
+        .iter()
+        .collect::<std::collections::BTreeMap<_, _>>();
+    let constraints = analysis
+        .constraints
+        .iter()
+        .map(|constraint| {
+            json!({
+                "condition": constraint.condition,
+                "message": constraint.message,
+                "paths": constraint.paths,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "schema_version": analysis.schema_version,
+        "name": analysis.name,
+        "shape_hash": format!("{:016x}", analysis.shape_hash()),
+        "external_vars": analysis.external_vars,
+        "internal_vars": analysis.internal_vars,
+        "loop_vars": loop_vars,
+        "optional_paths": analysis.optional_paths,
+        "nullable_paths": analysis.nullable_paths,
+        "unknown_filters": analysis.unknown_filters,
+        "constraints": constraints,
+        "warnings": analysis
+            .warnings
+            .iter()
+            .map(AnalysisWarning::summary_json)
+            .collect::<Vec<_>>(),
+        "shape": analysis.object_shapes_json(),
+    })
+}
+
+// One row per schema path, columns padded to the widest cell
+fn fields_table(analysis: &TemplateAnalysis) -> String {
+    let mut rows = vec![[
+        "PATH".to_string(),
+        "TYPE".to_string(),
+        "REQUIRED".to_string(),
+        "NULLABLE".to_string(),
+        "CONFIDENCE".to_string(),
+    ]];
+    for field in analysis.pointer_index() {
+        rows.push([
+            field.path,
+            field.type_name,
+            field.required.to_string(),
+            field.nullable.to_string(),
+            field
+                .confidence
+                .map_or("-", |confidence| confidence.as_str())
+                .to_string(),
+        ]);
+    }
+    align_rows(&rows)
+}
+
+// Rows with every column padded to its widest cell
+fn align_rows<const N: usize>(rows: &[[String; N]]) -> String {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for row in rows {
+        let cells = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::ops::Deref;
+
+    // A directory unique to the test, removed when it is dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("cleanplate-cli-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        // Writes a file, and the directories it is in, under the directory
+        fn file(&self, name: &str, content: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Parses the arguments of a `render` run
+    fn render_args(args: &[&str]) -> RenderArgs {
+        let argv = ["cleanplate", "render"]
+            .into_iter()
+            .chain(args.iter().copied());
+        let Some(Command::Render(args)) = Cli::try_parse_from(argv).unwrap().command else {
+            panic!("expected the render subcommand");
+        };
+        args
+    }
+
+    // Runs `analyze` with the given arguments and returns what it wrote to
+    // the `--output` file
+    fn analyze_output(name: &str, args: &[&str]) -> String {
+        let dir = TempDir::new(name);
+        let output = dir.join("output");
+        let argv = ["cleanplate", "analyze"]
+            .into_iter()
+            .chain(args.iter().copied())
+            .chain(["--output", output.to_str().unwrap()]);
+        let Some(Command::Analyze(args)) = Cli::try_parse_from(argv).unwrap().command else {
+            panic!("expected the analyze subcommand");
+        };
+        analyze_command(args).unwrap();
+        fs::read_to_string(output).unwrap()
+    }
+
+    // A corpus with two models sharing a template and one broken template
+    const BATCH_CORPUS: &str = r#"{
+        "{% for m in messages %}{{ m.content }}{% endfor %}": ["a", "b"],
+        "{{ tools }}{{ prompt }}": ["c"],
+        "{% if %}": ["d"]
+    }"#;
+
+    // Runs `batch` on a corpus with its outputs in a fresh directory, which
+    // is returned; `{dir}` in the extra arguments is that directory
+    fn run_batch(name: &str, corpus: &str, args: &[&str]) -> TempDir {
+        let dir = TempDir::new(&format!("batch-{name}"));
+        let input = dir.file("corpus.json", corpus);
+        let path = |file: &str| dir.join(file).to_str().unwrap().to_string();
+        let mut argv = vec![
+            "cleanplate".to_string(),
+            "batch".to_string(),
+            input.to_str().unwrap().to_string(),
+            "--output".to_string(),
+            path("results.json"),
+            "--shape-output".to_string(),
+            path("shapes.json"),
+            "--cluster-output".to_string(),
+            path("clusters.json"),
+        ];
+        let dir_str = dir.to_str().unwrap();
+        argv.extend(args.iter().map(|arg| arg.replace("{dir}", dir_str)));
+        let Some(Command::Batch(args)) = Cli::try_parse_from(argv).unwrap().command else {
+            panic!("expected the batch subcommand");
+        };
+        batch_command(args).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_lint_arguments() {
+        let cli = Cli::try_parse_from(["cleanplate", "lint", "x.jinja"]).unwrap();
+        let Some(Command::Lint(args)) = cli.command else {
+            panic!("expected the lint subcommand");
+        };
+        assert_eq!(args.input.path(), Some(PathBuf::from("x.jinja")));
+        assert!(!args.rules);
+
+        let cli = Cli::try_parse_from(["cleanplate", "lint", "--rules"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Lint(args)) if args.rules));
+
+        let cli =
+            Cli::try_parse_from(["cleanplate", "lint", "x.jinja", "--rule", "x: string"]).unwrap();
+        let Some(Command::Lint(args)) = cli.command else {
+            panic!("expected the lint subcommand");
+        };
+        assert_eq!(args.input.analysis.rules.len(), 1);
+        assert!(!args.rules);
+    }
+
+    #[test]
+    fn test_validate_accepts_globals() {
+        let dir = TempDir::new("validate");
+        let template = dir.file(
+            "validate.jinja",
+            "{% if messages | length == 0 %}{{ raise_exception('no messages') }}{% endif %}{% for message in messages %}{{ message.content }}{% endfor %}{% set ns = namespace(n=0) %}{% for i in range(2) %}{% endfor %}",
+        );
+        let context = dir.file("validate.json", r#"{"messages": [{"content": "hi"}]}"#);
+        let cli = Cli::try_parse_from([
+            "cleanplate",
+            "validate",
+            template.to_str().unwrap(),
+            "--context",
+            context.to_str().unwrap(),
+        ])
+        .unwrap();
+        let Some(Command::Validate(args)) = cli.command else {
+            panic!("expected the validate subcommand");
+        };
+        let (_, analysis) = args.input.load(false);
+        let context = read_context(&args.context);
+        assert_eq!(analysis.validate_context(&context), vec![]);
+        assert_eq!(
+            analysis
+                .validate_context(&json!({}))
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["messages: missing"]
+        );
+    }
+
+    #[test]
+    fn test_check_ignores_template_name() {
+        let dir = TempDir::new("check");
+        let old = dir.file(
+            "old_name.jinja",
+            "{% for m in messages %}{{ m.role }}{% endfor %}",
+        );
+        let new = dir.file(
+            "new_name.jinja",
+            "{% for m in messages %}{{ m.role }}{% endfor %}",
+        );
+        let changed = dir.file(
+            "changed.jinja",
+            "{% for m in messages %}{{ m.content }}{% endfor %}",
+        );
+        let schema = |path: &Path| {
+            let cli = Cli::try_parse_from([
+                "cleanplate",
+                "check",
+                path.to_str().unwrap(),
+                "--schema",
+                "snapshot.json",
+            ])
+            .unwrap();
+            let Some(Command::Check(args)) = cli.command else {
+                panic!("expected the check subcommand");
+            };
+            let (_, analysis) = args.input.load(false);
+            analysis.to_json_schema()
+        };
+        let snapshot = schema(&old);
+        assert_eq!(snapshot["title"], "old_name");
+        assert_eq!(
+            schema_differences(&snapshot, &schema(&new)),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            schema_differences(&snapshot, &schema(&changed)),
+            vec![
+                "+ /properties/messages/items/properties/content",
+                "- /properties/messages/items/properties/role",
+                "~ /properties/messages/items/required/0: \"role\" -> \"content\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let options = AnalyzeOptions {
+            name: Some("formats".to_string()),
+            ..Default::default()
+        };
+        let analysis = analyze_with(
+            "{% for m in messages %}{{ m.content }}{% endfor %}",
+            &options,
+        )
+        .unwrap();
+
+        let json = analysis_json(&analysis);
+        assert_eq!(json["external_vars"], json!(["messages"]));
+        assert_eq!(json["name"], "formats");
+        // YAML holds the same object
+        let yaml: Value = serde_yaml::from_str(&serde_yaml::to_string(&json).unwrap()).unwrap();
+        assert_eq!(yaml, json);
+        assert!(fields_table(&analysis).contains("messages[].content"));
+
+        let cli = Cli::try_parse_from(["cleanplate", "--format", "yaml"]).unwrap();
+        assert_eq!(cli.analyze.format, Some(OutputFormat::Yaml));
+        assert!(Cli::try_parse_from(["cleanplate", "--format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn test_stdin_arguments() {
+        let cli = Cli::try_parse_from(["cleanplate", "-"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.analyze.input.path(), Some(PathBuf::from("-")));
+
+        let cli = Cli::try_parse_from(["cleanplate", "analyze", "--file", "-"]).unwrap();
+        let Some(Command::Analyze(args)) = cli.command else {
+            panic!("expected the analyze subcommand");
+        };
+        assert_eq!(args.input.path(), Some(PathBuf::from("-")));
+        // Templates from stdin have no file name to name the schema after
+        assert_eq!(args.input.options(None).name, None);
+        assert_eq!(
+            args.input.options(Some(Path::new("chat.jinja"))).name,
+            Some("chat".to_string())
+        );
+
+        assert!(Cli::try_parse_from(["cleanplate", "analyze", "-", "--file", "x.jinja"]).is_err());
+    }
+
+    #[test]
+    fn test_recursive_analysis() {
+        let dir = TempDir::new("tree");
+        for (file, content) in [
+            ("b.jinja", "{{ messages }}"),
+            ("sub/a.j2", "{{ messages }}{{ tools }}"),
+            ("sub/notes.txt", "{{ notes }}"),
+            ("sub/.draft.jinja", "{{ draft }}"),
+            (".cache/c.jinja", "{{ cached }}"),
+        ] {
+            dir.file(file, content);
+        }
+        let files = |extensions: &[&str]| {
+            let extensions = extensions
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            let mut files = Vec::new();
+            template_files(&dir, &extensions, &mut files).unwrap();
+            files
+                .iter()
+                .map(|file| {
+                    file.strip_prefix(&*dir)
+                        .unwrap()
+                        .to_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        // Hidden files and directories are skipped, the rest is sorted
+        assert_eq!(files(&["jinja", "j2", "html"]), vec!["b.jinja", "sub/a.j2"]);
+        assert_eq!(files(&[".txt"]), vec!["sub/notes.txt"]);
+        assert_eq!(files(&["md"]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_analyze_output_file() {
+        let dir = TempDir::new("output-tree");
+        dir.file("b.jinja", "{{ messages }}");
+        dir.file("sub/a.j2", "{{ messages }}{{ tools }}");
+        dir.file("sub/notes.txt", "{{ notes }}");
+
+        let template = dir.join("b.jinja");
+        let json: Value = serde_json::from_str(&analyze_output(
+            "single",
+            &[template.to_str().unwrap(), "--format", "json"],
+        ))
+        .unwrap();
+        assert_eq!(json["external_vars"], json!(["messages"]));
+        assert_eq!(json["name"], "b");
+
+        let output: Value = serde_json::from_str(&analyze_output(
+            "recursive",
+            &[dir.to_str().unwrap(), "--recursive", "--format", "json"],
+        ))
+        .unwrap();
+        assert_eq!(output["results"][1]["file"], "sub/a.j2");
+        assert_eq!(output["summary"]["files"], 2);
+        assert_eq!(output["summary"]["unique_shapes"], 2);
+        assert_eq!(
+            output["summary"]["external_vars"],
+            json!({"messages": 2, "tools": 1})
+        );
+
+        let report = analyze_output(
+            "report",
+            &[dir.to_str().unwrap(), "--recursive", "--ext", "txt"],
+        );
+        assert!(report.starts_with("sub/notes.txt: shape "));
+        assert!(report.contains("  Templates: 1\n"));
+    }
+
+    #[test]
+    fn test_quiet() {
+        let dir = TempDir::new("quiet");
+        let template = dir.file("quiet.jinja", "{{ messages }}");
+        // Quiet drops the report on stdout, but not the requested output file
+        let output = analyze_output(
+            "quiet-output",
+            &[template.to_str().unwrap(), "-q", "--format", "json"],
+        );
+        let json: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(json["external_vars"], json!(["messages"]));
+
+        let mut sink = output_writer(None, true).unwrap();
+        writeln!(sink, "dropped").unwrap();
+        sink.flush().unwrap();
+
+        assert_eq!((EXIT_PARSE_ERROR, EXIT_VIOLATIONS), (2, 3));
+    }
+
+    #[test]
+    fn test_watch() {
+        let analyze = |source: &str| analyze_with(source, &AnalyzeOptions::default()).unwrap();
+        let last = analyze("{{ messages }}");
+        let change = |analysis: &TemplateAnalysis| {
+            let mut out = Vec::new();
+            write_schema_change(&mut out, &last, analysis).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let unchanged = change(&analyze("{{ messages }}{{ messages }}"));
+        assert_eq!(
+            unchanged,
+            format!("No schema changes\nShape {:016x}\n", last.shape_hash())
+        );
+        let added = analyze("{{ messages }}{{ tools }}");
+        let changed = change(&added);
+        assert!(changed.contains("tools"));
+        assert!(changed.ends_with(&format!("Shape {:016x}\n", added.shape_hash())));
+
+        assert!(Cli::try_parse_from(["cleanplate", "analyze", "x.jinja", "--watch"]).is_ok());
+        for conflict in ["--recursive", "--quiet", "--output=out.json"] {
+            assert!(
+                Cli::try_parse_from(["cleanplate", "analyze", "x.jinja", "--watch", conflict])
+                    .is_err(),
+                "{conflict}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_render_sample_context() {
+        let dir = TempDir::new("render");
+        let template = dir.file(
+            "render.jinja",
+            "{% if not messages %}{{ raise_exception('no messages') }}{% endif %}{% for m in messages %}[{{ m.role | shout }}: {{ m.content }}{{ strftime_now('%Y') }}]{% endfor %}",
+        );
+        let template = template.to_str().unwrap();
+        let args = render_args(&["-f", template, "--custom-filter", "shout"]);
+        let (content, analysis) = args.input.load(false);
+        // Functions the template calls are not part of the sample context
+        let context = analysis.sample_context();
+        assert_eq!(context.as_object().unwrap().len(), 1);
+        let mut env = render_environment(&args.input.analysis);
+        env.add_function("strftime_now", |_: String| "2024".to_string());
+        let output = env.render_named_str("render", &content, &context).unwrap();
+        assert!(output.starts_with('[') && output.ends_with(']'), "{output}");
+
+        // The custom filter is only registered when it is given
+        let args = render_args(&["-f", template]);
+        let env = render_environment(&args.input.analysis);
+        let err = env
+            .render_named_str("render", &content, &context)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownFilter);
+
+        // raise_exception fails the render like it does in the chat template runtimes
+        let env = render_environment(&render_args(&["--custom-filter", "shout"]).input.analysis);
+        let err = env
+            .render_named_str("render", &content, json!({"messages": []}))
+            .unwrap_err();
+        assert!(err.to_string().contains("no messages"));
+
+        assert!(render_args(&["-", "--context", "ctx.json"])
+            .context
+            .is_some());
+    }
+
+    #[test]
+    fn test_render_strict_undefined() {
+        let dir = TempDir::new("strict");
+        let template = dir.file(
+            "strict.jinja",
+            "{% for m in messages %}{{ m.role }}{{ m.name }}{% endfor %}{% if tools is defined %}{{ tools }}{% endif %}",
+        );
+        let args = render_args(&["-f", template.to_str().unwrap(), "--strict"]);
+        // Strict renders point at the expressions behind each undefined path
+        let (content, analysis) = args.input.load(args.strict);
+        assert!(analysis.provenance.as_ref().unwrap()["messages[].name"]
+            .iter()
+            .any(|origin| origin.expression == "m.name"));
+
+        let env = render_environment(&args.input.analysis);
+        let undefined = |context: Value| {
+            undefined_paths(&env, "strict", &content, &context, &analysis)
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            undefined(json!({"messages": [{"role": "user"}]})),
+            vec!["messages[].name"]
+        );
+        // Optional variables behind `is defined` are not reported
+        assert_eq!(undefined(json!({})), vec!["messages"]);
+        assert_eq!(
+            undefined(json!({"messages": [], "tools": []})),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_diff_templates() {
+        let dir = TempDir::new("diff");
+        let old = dir.file(
+            "old.jinja",
+            "{% for m in messages %}{{ m.role }}{% endfor %}{{ bos_token }}",
+        );
+        let new = dir.file(
+            "new.jinja",
+            "{{ messages | length }}{{ tools }}{{ bos_token }}",
+        );
+        let diff = |args: &[&str]| {
+            let argv = ["cleanplate", "diff"]
+                .into_iter()
+                .chain(args.iter().copied());
+            let Some(Command::Diff(args)) = Cli::try_parse_from(argv).unwrap().command else {
+                panic!("expected the diff subcommand");
+            };
+            diff_templates(&args).to_string()
+        };
+        let (old, new) = (old.to_str().unwrap(), new.to_str().unwrap());
+        assert_eq!(diff(&[old, old]), "");
+        let changes = diff(&[old, new]);
+        assert!(changes.contains("- messages[].role: "), "{changes}");
+        assert!(changes.contains("~ messages[]: object -> "), "{changes}");
+        assert!(changes.contains("+ tools: "), "{changes}");
+        // The analysis flags apply to both templates
+        assert!(!diff(&[old, new, "--ignore-var", "tools"]).contains("tools"));
+
+        assert!(Cli::try_parse_from(["cleanplate", "diff", old]).is_err());
+    }
+
+    #[test]
+    fn test_query() {
+        let dir = TempDir::new("query");
+        let template = dir.file(
+            "query.jinja",
+            "{% for m in messages %}{{ m.role }}\n{% if m.name is defined %}{{ m.name }}{% endif %}{% endfor %}{{ tools }}",
+        );
+        let cli = Cli::try_parse_from([
+            "cleanplate",
+            "query",
+            template.to_str().unwrap(),
+            "--var",
+            "messages",
+        ])
+        .unwrap();
+        let Some(Command::Query(args)) = cli.command else {
+            panic!("expected the query subcommand");
+        };
+        let (_, analysis) = args.input.load(true);
+        let query = |var: &str| {
+            let mut out = Vec::new();
+            write_query(&mut out, &analysis, var).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let output = query(&args.var);
+        assert!(output.starts_with("messages: array (required)\n\nFields:\n"));
+        assert!(output.contains("  messages[].name: unknown (optional)\n"));
+        // Uses are listed in template order, the nested ones included
+        assert!(output.ends_with(
+            "\nUsed at:\n  line 1, column 12: messages\n  line 1, column 26: m.role\n  line 2, column 6: m.name\n  line 2, column 29: m.name\n"
+        ));
+
+        let output = query("tools");
+        assert!(!output.contains("Fields:"));
+        assert_eq!(query("unused"), "");
+
+        assert!(Cli::try_parse_from(["cleanplate", "query", "x.jinja"]).is_err());
+    }
+
+    #[test]
+    fn test_batch_jsonl() {
+        let dir = run_batch("jsonl", BATCH_CORPUS, &["--jsonl"]);
+        let results = fs::read_to_string(dir.join("results.json")).unwrap();
+        let lines = results
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        // One line per template, failures included
+        assert_eq!(lines.len(), 3);
+        let statuses = lines
+            .iter()
+            .map(|line| line["status"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(statuses.iter().filter(|&&s| s == "error").count(), 1);
+        let chat = lines
+            .iter()
+            .find(|line| line["model_ids"] == json!(["a", "b"]))
+            .unwrap();
+        assert_eq!(chat["external_vars"], json!(["messages"]));
+
+        // The shape frequencies are still written at the end
+        let shapes: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("shapes.json")).unwrap()).unwrap();
+        assert_eq!(shapes.as_array().unwrap().len(), 2);
+
+        // Without `--jsonl` the results are one array
+        let dir = run_batch("array", BATCH_CORPUS, &[]);
+        let results: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("results.json")).unwrap()).unwrap();
+        assert_eq!(results.as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_batch_csv() {
+        let dir = run_batch("csv", BATCH_CORPUS, &["--csv", "{dir}/summary.csv"]);
+        let csv = fs::read_to_string(dir.join("summary.csv")).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("template_id,model_count,external_vars,shape_hash,status")
+        );
+        let rows = lines.collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows.iter().filter(|row| row.ends_with(",error")).count(), 1);
+        assert!(rows.iter().any(|row| row.contains(",2,messages,")));
+    }
+
+    #[test]
+    fn test_batch_html() {
+        let dir = run_batch("html", BATCH_CORPUS, &["--html", "{dir}/report.html"]);
+        let html = fs::read_to_string(dir.join("report.html")).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<p>3 templates, 2 analyzed, 1 failed, 2 unique shapes"));
+        // The JSON outputs are written alongside the report
+        assert!(dir.join("results.json").exists());
+    }
+
+    #[test]
+    fn test_batch_resume() {
+        let dir = run_batch(
+            "checkpoint",
+            BATCH_CORPUS,
+            &["--checkpoint", "{dir}/checkpoint.jsonl"],
+        );
+        let checkpoint = fs::read_to_string(dir.join("checkpoint.jsonl")).unwrap();
+        assert_eq!(checkpoint.lines().count(), 3);
+
+        // A run interrupted after the first template picks up the other two
+        let first = checkpoint.lines().next().unwrap();
+        let partial = dir.file("resume.jsonl", &format!("{first}\n"));
+        let resumed = run_batch(
+            "resume",
+            BATCH_CORPUS,
+            &["--checkpoint", partial.to_str().unwrap(), "--resume"],
+        );
+        assert_eq!(fs::read_to_string(&partial).unwrap().lines().count(), 3);
+        for output in ["results.json", "shapes.json", "clusters.json"] {
+            assert_eq!(
+                fs::read_to_string(dir.join(output)).unwrap(),
+                fs::read_to_string(resumed.join(output)).unwrap(),
+                "{output}"
+            );
+        }
+
+        assert!(Cli::try_parse_from(["cleanplate", "batch", "--resume"]).is_err());
+    }
+
+    #[test]
+    fn test_batch_jobs() {
+        // Enough unique templates to span several chunks
+        let corpus = (0..BATCH_CHUNK + 10)
+            .map(|i| {
+                let template = format!("{{{{ var_{} }}}}{{# {i} #}}", i % 40);
+                (template, json!([format!("model-{i}")]))
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let corpus = serde_json::to_string(&corpus).unwrap();
+        let serial = run_batch("serial", &corpus, &["--jobs", "1", "--jsonl"]);
+        let parallel = run_batch("parallel", &corpus, &["--jobs", "4", "--jsonl"]);
+        let results = fs::read_to_string(parallel.join("results.json")).unwrap();
+        assert_eq!(results.lines().count(), BATCH_CHUNK + 10);
+        for output in ["results.json", "shapes.json", "clusters.json"] {
+            assert_eq!(
+                fs::read_to_string(serial.join(output)).unwrap(),
+                fs::read_to_string(parallel.join(output)).unwrap(),
+                "{output}"
+            );
+        }
+
+        assert!(Cli::try_parse_from(["cleanplate", "batch", "--jobs", "many"]).is_err());
+    }
+
+    #[test]
+    fn test_completions() {
+        for shell in ["bash", "zsh", "fish", "powershell", "elvish"] {
+            let cli = Cli::try_parse_from(["cleanplate", "completions", shell]).unwrap();
+            let Some(Command::Completions(args)) = cli.command else {
+                panic!("expected the completions subcommand");
+            };
+            let mut out = Vec::new();
+            write_completions(args.shell, &mut out);
+            let script = String::from_utf8(out).unwrap();
+            // Subcommands and their flags are completed
+            assert!(script.contains("batch"), "{shell}");
+            assert!(script.contains("checkpoint"), "{shell}");
+        }
+
+        assert!(Cli::try_parse_from(["cleanplate", "completions"]).is_err());
+        assert!(Cli::try_parse_from(["cleanplate", "completions", "tcsh"]).is_err());
+    }
+
+    // Log output shared with the test
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_logs() {
+        // The log format is a global flag, given before or after the subcommand
+        for argv in [
+            ["cleanplate", "--log-format", "json", "analyze", "-v"],
+            ["cleanplate", "analyze", "-v", "--log-format", "json"],
+        ] {
+            let cli = Cli::try_parse_from(argv).unwrap();
+            assert_eq!(cli.log_format, LogFormat::Json);
+        }
+        assert!(Cli::try_parse_from(["cleanplate", "--log-format", "xml"]).is_err());
+
+        let buffer = LogBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = log_subscriber(LogFormat::Json, true, false, move || writer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let options = AnalyzeOptions {
+                name: Some("chat".to_string()),
+                ..Default::default()
+            };
+            analyze_with("{{ messages }}", &options).unwrap();
+        });
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events = logs
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert!(!events.is_empty());
+        // Every event of the analysis is tagged with its template
+        assert!(events
+            .iter()
+            .all(|event| event["span"]["template"] == "chat"));
+    }
+
+    #[test]
+    fn test_batch_deduplicates_templates() {
+        let dir = TempDir::new("dedupe");
+        let corpus = dir.file(
+            "corpus.jsonl",
+            r#"{"template": "{{ messages }}", "model_ids": ["a"]}
+
+{"template": "{{ tools }}", "model_ids": ["c"]}
+{"template": "{{ messages }}", "model_ids": ["b"]}
+"{{ messages }}"
+"#,
+        );
+        let sources = read_corpus(&corpus, &[], false).unwrap();
+        assert_eq!(sources.len(), 4);
+        let corpus = TemplateCorpus::from_sources(sources);
+        assert_eq!(corpus.len(), 2);
+        assert_eq!(corpus.templates()[0].model_ids, vec!["a", "b"]);
+
+        // The results hold one entry per unique template with all its models
+        let batch = run_batch(
+            "dedup",
+            r#"[{"template": "{{ x }}", "model_ids": ["a"]}, {"template": "{{ x }}", "model_ids": ["b", "c"]}]"#,
+            &[],
+        );
+        let results: Value =
+            serde_json::from_str(&fs::read_to_string(batch.join("results.json")).unwrap()).unwrap();
+        assert_eq!(results.as_array().unwrap().len(), 1);
+        assert_eq!(results[0]["model_ids"], json!(["a", "b", "c"]));
+
+        assert!(read_corpus(&dir.file("corpus.txt", "42"), &[], false).is_err());
+        let empty = read_corpus(&dir.file("empty.json", "{}"), &[], false).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_batch_shape_order() {
+        // `b` has the most models, `a` the most templates
+        let corpus = r#"{
+            "{{ a }}": ["m1"],
+            "{{ a }}{# copy #}": ["m2"],
+            "{{ b }}": ["m3", "m4", "m5"]
+        }"#;
+        let first_shape = |sort: &str| {
+            let dir = run_batch(&format!("sort-{sort}"), corpus, &["--sort-by", sort]);
+            let shapes: Value =
+                serde_json::from_str(&fs::read_to_string(dir.join("shapes.json")).unwrap())
+                    .unwrap();
+            shapes[0]["object_shapes_json"].clone()
+        };
+        assert_eq!(first_shape("model_count"), json!({"b": ""}));
+        assert_eq!(first_shape("template_count"), json!({"a": ""}));
+        assert_eq!(first_shape("name"), json!({"a": ""}));
+
+        let cli = Cli::try_parse_from(["cleanplate", "batch"]).unwrap();
+        let Some(Command::Batch(args)) = cli.command else {
+            panic!("expected the batch subcommand");
+        };
+        assert_eq!(BucketOrder::from(args.sort_by), BucketOrder::ModelCount);
+        assert_eq!((args.coverage_target, args.top), (95.0, None));
+        assert_eq!(BucketOrder::from(ShapeSort::Name), BucketOrder::Shape);
+        assert!(Cli::try_parse_from(["cleanplate", "batch", "--sort-by", "size"]).is_err());
+        assert!(Cli::try_parse_from(["cleanplate", "batch", "--top", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_batch_include_templates() {
+        let corpus = r#"{
+            "{{ a }}": ["m1"],
+            "{{ a }}{# copy #}": ["m2"],
+            "{{ b }}": ["m3"]
+        }"#;
+        let listed = |name: &str, args: &[&str]| {
+            let dir = run_batch(name, corpus, args);
+            let shapes: Value =
+                serde_json::from_str(&fs::read_to_string(dir.join("shapes.json")).unwrap())
+                    .unwrap();
+            shapes
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|shape| shape.get("templates").cloned())
+                .collect::<Vec<_>>()
+        };
+        // Without the flag, the shapes have no template list at all
+        assert_eq!(listed("no-templates", &[]), vec![None, None]);
+        // A bare flag lists every source
+        assert_eq!(
+            listed(
+                "all-templates",
+                &["--sort-by", "name", "--include-templates"]
+            )[0],
+            Some(json!(["{{ a }}", "{{ a }}{# copy #}"]))
+        );
+        let first = listed(
+            "first-templates",
+            &[
+                "--sort-by",
+                "name",
+                "--include-templates",
+                "first-n",
+                "--template-limit",
+                "1",
+            ],
+        );
+        assert_eq!(first[0], Some(json!(["{{ a }}"])));
+        let hashes = listed(
+            "hash-templates",
+            &["--sort-by", "name", "--include-templates", "hashes"],
+        );
+        let ids = hashes[1].as_ref().unwrap().as_array().unwrap();
+        assert_eq!(
+            ids,
+            &vec![json!(cleanplate::corpus::template_id("{{ b }}"))]
+        );
+
+        assert!(
+            Cli::try_parse_from(["cleanplate", "batch", "--include-templates", "some"]).is_err()
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use crate::shape::Shape;
+use crate::TemplateAnalysis;
+
+// Renders the analysis as Markdown docs: a table of the context variables,
+// the constraints the template enforces and a fenced example context
+pub(crate) fn to_markdown(analysis: &TemplateAnalysis) -> String {
+    let mut out = match &analysis.name {
+        Some(name) => format!("# `{name}` template context\n"),
+        None => "# Template context\n".to_string(),
+    };
+
+    out.push_str("\n## Variables\n\n");
+    out.push_str("| Variable | Type | Required | Description |\n");
+    out.push_str("| --- | --- | --- | --- |\n");
+    for (path, shape) in analysis.shape.fields() {
+        // Items and values are described by their fields and container
+        if path.ends_with("[]") || path.ends_with("{}") {
+            continue;
+        }
+        let required = if analysis.optional_paths.contains(&path) {
+            "no"
+        } else {
+            "yes"
+        };
+        let description = analysis
+            .annotations
+            .get(&path)
+            .and_then(|annotation| annotation.description.as_deref())
+            .unwrap_or("");
+        out.push_str(&format!(
+            "| `{path}` | {} | {required} | {} |\n",
+            cell(&type_description(analysis, &path, shape)),
+            cell(description)
+        ));
+    }
+
+    if !analysis.constraints.is_empty() {
+        out.push_str("\n## Constraints\n\n");
+        for constraint in &analysis.constraints {
+            match &constraint.message {
+                Some(message) => {
+                    out.push_str(&format!("- `{}`: {message}\n", constraint.condition))
+                }
+                None => out.push_str(&format!("- `{}`\n", constraint.condition)),
+            }
+        }
+    }
+
+    out.push_str("\n## Example context\n\n```json\n");
+    let example = serde_json::to_string_pretty(&analysis.sample_context()).unwrap_or_default();
+    out.push_str(&example);
+    out.push_str("\n```\n");
+    out
+}
+
+// `array of object`, `string (date-time)`, `string: "user", "assistant"`
+fn type_description(analysis: &TemplateAnalysis, path: &str, shape: &Shape) -> String {
+    let mut description = match shape {
+        Shape::Array(item) => format!("array of {}", item.type_name()),
+        Shape::Map(value) => format!("map of {}", value.type_name()),
+        shape => shape.type_name(),
+    };
+    if let Some(format) = analysis.formats.get(path) {
+        description.push_str(&format!(" ({format})"));
+    }
+    if let Some(values) = analysis.enum_values.get(path) {
+        let values = values
+            .iter()
+            .map(|value| format!("`{value}`"))
+            .collect::<Vec<_>>();
+        description.push_str(&format!(": {}", values.join(", ")));
+    }
+    description
+}
+
+// Escapes pipes and line breaks, which would end a table cell or row
+fn cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{analyze_with, AnalyzeOptions};
+
+    #[test]
+    fn test_markdown_docs() {
+        let template = "{# @param messages: the conversation | newest last #}{% for message in messages %}{% if message.role == 'user' %}{{ message.content ~ '' }}{% endif %}{% endfor %}{% if tools is defined %}{{ tools | tojson }}{% endif %}";
+        let options = AnalyzeOptions {
+            name: Some("chat".to_string()),
+            ..Default::default()
+        };
+        let analysis = analyze_with(template, &options).unwrap();
+
+        assert_eq!(
+            analysis.to_markdown(),
+            r#"# `chat` template context
+
+## Variables
+
+| Variable | Type | Required | Description |
+| --- | --- | --- | --- |
+| `messages` | array of object | yes | the conversation \| newest last |
+| `messages[].content` | string | yes |  |
+| `messages[].role` | string: `"user"` | yes |  |
+| `tools` | any | no |  |
+
+## Example context
+
+```json
+{
+  "messages": [
+    {
+      "content": "",
+      "role": "user"
+    }
+  ]
+}
+```
+"#
+        );
+    }
+}
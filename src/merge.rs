@@ -0,0 +1,239 @@
+use crate::shape::Shape;
+use crate::{TemplateAnalysis, SCHEMA_VERSION};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Unifies the analyses of several templates into one analysis whose shape
+/// satisfies all of them; `presence` records the fraction of templates that
+/// use each schema path, and paths missing from some templates are optional
+pub fn merge_analyses(analyses: &[TemplateAnalysis]) -> TemplateAnalysis {
+    let mut merged = empty_analysis();
+    let mut presence_counts = BTreeMap::<String, usize>::new();
+    let mut template_paths = Vec::with_capacity(analyses.len());
+
+    for analysis in analyses {
+        let paths = analysis.shape.fields().into_keys().collect::<BTreeSet<_>>();
+        for path in &paths {
+            *presence_counts.entry(path.clone()).or_default() += 1;
+        }
+        template_paths.push(paths);
+
+        merged
+            .external_vars
+            .extend(analysis.external_vars.iter().cloned());
+        merged
+            .internal_vars
+            .extend(analysis.internal_vars.iter().cloned());
+        merged.loop_vars.extend(analysis.loop_vars.clone());
+        for warning in &analysis.warnings {
+            if !merged.warnings.contains(warning) {
+                merged.warnings.push(warning.clone());
+            }
+        }
+        merged.aliases.extend(analysis.aliases.clone());
+        merged
+            .nullable_paths
+            .extend(analysis.nullable_paths.iter().cloned());
+        merged
+            .optional_paths
+            .extend(analysis.optional_paths.iter().cloned());
+        for (path, values) in &analysis.enum_values {
+            let merged_values = merged.enum_values.entry(path.clone()).or_default();
+            for value in values {
+                if !merged_values.contains(value) {
+                    merged_values.push(value.clone());
+                }
+            }
+        }
+        for (path, default) in &analysis.defaults {
+            merged
+                .defaults
+                .entry(path.clone())
+                .or_insert(default.clone());
+        }
+        for (path, filters) in &analysis.filters {
+            merged
+                .filters
+                .entry(path.clone())
+                .or_default()
+                .extend(filters.clone());
+        }
+        for (path, tests) in &analysis.tests {
+            merged
+                .tests
+                .entry(path.clone())
+                .or_default()
+                .extend(tests.clone());
+        }
+        for (name, usage) in &analysis.globals_used {
+            let merged_usage = merged.globals_used.entry(name.clone()).or_default();
+            merged_usage.count += usage.count;
+            merged_usage.spans.extend(usage.spans.iter().copied());
+        }
+        merged
+            .unknown_filters
+            .extend(analysis.unknown_filters.iter().cloned());
+        for (path, hints) in &analysis.type_hints {
+            merged
+                .type_hints
+                .entry(path.clone())
+                .or_default()
+                .extend(hints);
+        }
+        merged.shape =
+            std::mem::replace(&mut merged.shape, Shape::Unknown).merge(analysis.shape.clone());
+        for (name, definition) in &analysis.definitions {
+            merged
+                .definitions
+                .entry(name.clone())
+                .or_insert(definition.clone());
+        }
+        for (path, confidence) in &analysis.confidence {
+            // A field is only as certain as its weakest evidence
+            merged
+                .confidence
+                .entry(path.clone())
+                .and_modify(|merged| *merged = (*merged).min(*confidence))
+                .or_insert(*confidence);
+        }
+        for constraint in &analysis.constraints {
+            if !merged.constraints.contains(constraint) {
+                merged.constraints.push(constraint.clone());
+            }
+        }
+        for (path, annotation) in &analysis.annotations {
+            merged
+                .annotations
+                .entry(path.clone())
+                .or_insert(annotation.clone());
+        }
+        merged
+            .truncated_paths
+            .extend(analysis.truncated_paths.iter().cloned());
+        for (path, format) in &analysis.formats {
+            merged.formats.entry(path.clone()).or_insert(format.clone());
+        }
+        for (path, count) in &analysis.usage_counts {
+            *merged.usage_counts.entry(path.clone()).or_default() += count;
+        }
+    }
+
+    // Only bounds that every template sets carry over
+    if let Some((first, rest)) = analyses.split_first() {
+        for (path, bounds) in &first.cardinality {
+            let widened = rest.iter().try_fold(*bounds, |widened, analysis| {
+                Some(widened.widen(*analysis.cardinality.get(path)?))
+            });
+            if let Some(widened) = widened {
+                merged.cardinality.insert(path.clone(), widened);
+            }
+        }
+    }
+
+    // An enum stays closed when every template using the path closes it; a
+    // template that reads it without comparing admits any value
+    merged.closed_enums = analyses
+        .iter()
+        .flat_map(|analysis| &analysis.closed_enums)
+        .filter(|path| {
+            analyses
+                .iter()
+                .zip(&template_paths)
+                .all(|(analysis, paths)| {
+                    !paths.contains(*path) || analysis.closed_enums.contains(*path)
+                })
+        })
+        .cloned()
+        .collect();
+
+    let total = analyses.len() as f64;
+    for (path, count) in presence_counts {
+        if count < analyses.len() {
+            merged.optional_paths.insert(path.clone());
+        }
+        merged.presence.insert(path, count as f64 / total);
+    }
+
+    merged
+}
+
+fn empty_analysis() -> TemplateAnalysis {
+    TemplateAnalysis {
+        schema_version: SCHEMA_VERSION,
+        external_vars: Default::default(),
+        internal_vars: Default::default(),
+        loop_vars: Default::default(),
+        aliases: Default::default(),
+        nullable_paths: Default::default(),
+        optional_paths: Default::default(),
+        enum_values: Default::default(),
+        closed_enums: Default::default(),
+        defaults: Default::default(),
+        filters: Default::default(),
+        tests: Default::default(),
+        globals_used: Default::default(),
+        unknown_filters: Default::default(),
+        type_hints: Default::default(),
+        shape: Shape::Object(BTreeMap::new()),
+        definitions: Default::default(),
+        confidence: Default::default(),
+        provenance: None,
+        constraints: Default::default(),
+        annotations: Default::default(),
+        truncated_paths: Default::default(),
+        usage_counts: Default::default(),
+        presence: Default::default(),
+        cardinality: Default::default(),
+        formats: Default::default(),
+        warnings: Default::default(),
+        name: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_analyses;
+    use crate::analyze;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge_analyses() {
+        let analyses = [
+            "{% for message in messages %}{{ message.role ~ message.content }}{% endfor %}",
+            "{% for message in messages %}{{ message.role ~ message.name }}{% endfor %}{{ bos_token }}",
+        ]
+        .map(|template| analyze(template, false).unwrap());
+
+        let merged = merge_analyses(&analyses);
+        assert_eq!(merged.presence["messages[].role"], 1.0);
+        assert_eq!(merged.presence["messages[].content"], 0.5);
+        assert_eq!(merged.presence["bos_token"], 0.5);
+
+        let schema = merged.to_json_schema();
+        assert_eq!(schema["required"], json!(["messages"]));
+        assert_eq!(
+            schema["properties"]["messages"]["items"]["required"],
+            json!(["role"])
+        );
+        assert_eq!(
+            merged.object_shapes_json(),
+            json!({"bos_token": "", "messages": [{"content": "", "name": "", "role": ""}]})
+        );
+    }
+
+    #[test]
+    fn test_merge_closed_enums() {
+        let exhaustive = "{% for m in messages %}{% if m.role == 'user' %}{% elif m.role == 'assistant' %}{% else %}{{ raise_exception('role') }}{% endif %}{% endfor %}";
+        let [closed, open, unrelated] = [
+            exhaustive,
+            "{% for m in messages %}{{ m.role }}{% endfor %}",
+            "{{ bos_token }}",
+        ]
+        .map(|template| analyze(template, false).unwrap());
+
+        let merged = merge_analyses(&[closed.clone(), open]);
+        assert!(merged.closed_enums.is_empty());
+
+        let merged = merge_analyses(&[closed, unrelated]);
+        assert!(merged.closed_enums.contains("messages[].role"));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{Confidence, TemplateAnalysis};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Location of a schema path in the emitted JSON Schema, as an RFC 6901
+/// JSON Pointer, with what the analysis knows about the field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldPointer {
+    /// JSON Pointer into `to_json_schema()`, e.g.
+    /// `/properties/messages/items/properties/role`; fields of shared and
+    /// recursive objects point into `$defs`
+    pub pointer: String,
+    /// Schema path (`messages[].role`)
+    pub path: String,
+    /// Inferred type (`string`, `array`, `string | null`, ...)
+    pub type_name: String,
+    pub required: bool,
+    pub nullable: bool,
+    pub confidence: Option<Confidence>,
+}
+
+// Walks the JSON Schema of the analysis and records where each schema path
+// of the shape is defined
+pub(crate) fn pointer_index(analysis: &TemplateAnalysis) -> Vec<FieldPointer> {
+    let schema = analysis.to_json_schema();
+    let mut pointers = BTreeMap::new();
+    walk(&schema, &schema, "", "", &mut Vec::new(), &mut pointers);
+
+    analysis
+        .shape
+        .fields()
+        .into_iter()
+        .filter_map(|(path, shape)| {
+            Some(FieldPointer {
+                pointer: pointers.remove(&path)?,
+                required: analysis.is_required(&path),
+                nullable: shape.is_nullable() || analysis.nullable_paths.contains(&path),
+                confidence: analysis.confidence.get(&path).copied(),
+                type_name: shape.type_name(),
+                path,
+            })
+        })
+        .collect()
+}
+
+// Records the pointer of `node` for `path` (keeping the first one found) and
+// descends into its properties, items, map values, variants and references
+fn walk(
+    root: &Value,
+    node: &Value,
+    path: &str,
+    pointer: &str,
+    refs: &mut Vec<String>,
+    pointers: &mut BTreeMap<String, String>,
+) {
+    if !path.is_empty() {
+        pointers
+            .entry(path.to_string())
+            .or_insert_with(|| pointer.to_string());
+    }
+
+    if let Some(reference) = node.get("$ref").and_then(Value::as_str) {
+        // Stop at recursive references already being walked
+        let Some(target) = reference.strip_prefix('#') else {
+            return;
+        };
+        if refs.iter().any(|seen| seen == target) {
+            return;
+        }
+        if let Some(definition) = root.pointer(target) {
+            refs.push(target.to_string());
+            descend(root, definition, path, target, refs, pointers);
+            refs.pop();
+        }
+        return;
+    }
+    descend(root, node, path, pointer, refs, pointers);
+}
+
+fn descend(
+    root: &Value,
+    node: &Value,
+    path: &str,
+    pointer: &str,
+    refs: &mut Vec<String>,
+    pointers: &mut BTreeMap<String, String>,
+) {
+    if let Some(Value::Object(properties)) = node.get("properties") {
+        for (key, property) in properties {
+            let child = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{path}.{key}")
+            };
+            let child_pointer = format!("{pointer}/properties/{}", escape(key));
+            walk(root, property, &child, &child_pointer, refs, pointers);
+        }
+    }
+    if let Some(item) = node.get("items").filter(|item| item.is_object()) {
+        let child_pointer = format!("{pointer}/items");
+        walk(
+            root,
+            item,
+            &format!("{path}[]"),
+            &child_pointer,
+            refs,
+            pointers,
+        );
+    }
+    if let Some(value) = node
+        .get("additionalProperties")
+        .filter(|value| value.is_object())
+    {
+        let child_pointer = format!("{pointer}/additionalProperties");
+        walk(
+            root,
+            value,
+            &format!("{path}{{}}"),
+            &child_pointer,
+            refs,
+            pointers,
+        );
+    }
+    if let Some(Value::Array(variants)) = node.get("anyOf") {
+        for (i, variant) in variants.iter().enumerate() {
+            descend(
+                root,
+                variant,
+                path,
+                &format!("{pointer}/anyOf/{i}"),
+                refs,
+                pointers,
+            );
+        }
+    }
+}
+
+// RFC 6901 escaping of a reference token
+fn escape(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+
+    #[test]
+    fn test_pointer_index() {
+        let template = "{% for message in messages %}{% if message.content is string %}{{ message.content }}{% else %}{% for part in message.content %}{{ part.text }}{% endfor %}{% endif %}{% endfor %}{% if meta['a/b'] is defined %}{{ meta['a/b'] ~ '' }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        let index = analysis.pointer_index();
+        let pointers = index
+            .iter()
+            .map(|field| (field.path.as_str(), field.pointer.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            pointers,
+            [
+                ("messages", "/properties/messages"),
+                ("messages[]", "/properties/messages/items"),
+                (
+                    "messages[].content",
+                    "/properties/messages/items/properties/content"
+                ),
+                (
+                    "messages[].content[]",
+                    "/properties/messages/items/properties/content/anyOf/1/items"
+                ),
+                (
+                    "messages[].content[].text",
+                    "/properties/messages/items/properties/content/anyOf/1/items/properties/text"
+                ),
+                ("meta", "/properties/meta"),
+                ("meta.a/b", "/properties/meta/properties/a~1b"),
+            ]
+        );
+        let text = &index[4];
+        assert!(text.required && !text.nullable);
+        assert!(!index[6].required);
+    }
+}
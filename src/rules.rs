@@ -0,0 +1,148 @@
+use crate::annotations;
+use crate::shape::Shape;
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Classification or typing rule for the variables and fields matching a
+/// pattern, written `PATTERN: ACTION`:
+///
+/// - a pattern without `.`, `[` or `{` matches variable and field names
+///   (`bos_token`, `*_json`); any other pattern matches whole schema paths
+///   (`messages[].content`, `tools[].*`), with `*` standing for any run of
+///   characters within a segment
+/// - the action is a type as in `@param` annotations (`string`,
+///   `integer|null`, `array`, ...) replacing the inferred one, `optional` to
+///   mark the paths optional, or `constant` for variables the rendering
+///   environment provides, which are left out of the context like
+///   `AnalyzeOptions::ignore_vars`
+///
+/// ```
+/// let rule = "*_json: string".parse::<cleanplate::rules::Rule>().unwrap();
+/// assert!(rule.matches("tools[].schema_json"));
+/// assert!(!rule.matches("messages[].content"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rule {
+    pub pattern: String,
+    pub action: RuleAction,
+}
+
+/// What a rule does to the matching paths
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Type replacing the inferred one
+    Type(Shape),
+    /// The context can leave the path out
+    Optional,
+    /// The environment provides the variable, so it is not part of the context
+    Constant,
+}
+
+impl Rule {
+    /// Whether the rule applies to a schema path
+    pub fn matches(&self, path: &str) -> bool {
+        if self.pattern.contains(['.', '[', '{']) {
+            return glob_match(self.pattern.as_bytes(), path.as_bytes());
+        }
+        // Item paths (`messages[]`) have no name of their own
+        if path.ends_with("[]") || path.ends_with("{}") {
+            return false;
+        }
+        let name = path.rsplit('.').next().unwrap_or(path);
+        glob_match(self.pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Rule, String> {
+        let Some((pattern, action)) = rule.split_once(':') else {
+            return Err(format!("expected `PATTERN: ACTION` in rule `{rule}`"));
+        };
+        let pattern = pattern.trim();
+        if pattern.is_empty() || pattern.contains(char::is_whitespace) {
+            return Err(format!("invalid pattern in rule `{rule}`"));
+        }
+        let action = match action.trim() {
+            "constant" => RuleAction::Constant,
+            "optional" => RuleAction::Optional,
+            ty => RuleAction::Type(
+                annotations::parse_type(ty)
+                    .ok_or_else(|| format!("unknown action `{ty}` in rule `{rule}`"))?,
+            ),
+        };
+        Ok(Rule {
+            pattern: pattern.to_string(),
+            action,
+        })
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = String;
+
+    fn try_from(rule: String) -> Result<Rule, String> {
+        rule.parse()
+    }
+}
+
+/// Parses a rules file: one rule per line, with blank lines and `#` comments
+/// skipped
+pub fn parse_rules(source: &str) -> Result<Vec<Rule>, String> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx, line.split_once('#').map_or(line, |(rule, _)| rule)))
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            line.parse()
+                .map_err(|err| format!("line {}: {err}", idx + 1))
+        })
+        .collect()
+}
+
+// Matches `*` against any run of characters other than `.`
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len())
+            .take_while(|&idx| idx == 0 || text[idx - 1] != b'.')
+            .any(|idx| glob_match(rest, &text[idx..])),
+        Some((byte, rest)) => text.first() == Some(byte) && glob_match(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analyze_with, AnalyzeOptions};
+
+    #[test]
+    fn test_rules() {
+        let rules = parse_rules(
+            "# special tokens come from the tokenizer\nbos_token: constant\n*_json: string\n\ntools[].*: optional\n",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 3);
+        assert!(rules[2].matches("tools[].function"));
+        assert!(!rules[2].matches("tools[].function.name"));
+        assert!(parse_rules("bos_token constant").is_err());
+        assert_eq!(
+            parse_rules("x: text").unwrap_err(),
+            "line 1: unknown action `text` in rule `x: text`"
+        );
+
+        let options = AnalyzeOptions {
+            rules,
+            ..Default::default()
+        };
+        let template = "{{ bos_token }}{% for tool in tools %}{{ tool.function.name }}{{ tool.args_json }}{% endfor %}";
+        let analysis = analyze_with(template, &options).unwrap();
+        assert!(!analysis.external_vars.contains("bos_token"));
+        assert_eq!(analysis.type_of("tools[].args_json"), Some(&Shape::String));
+        assert!(analysis.optional_paths.contains("tools[].function"));
+        assert!(!analysis.optional_paths.contains("tools[].function.name"));
+    }
+}
@@ -0,0 +1,373 @@
+use crate::shape::{child_path, Shape};
+use crate::{Constraint, TemplateAnalysis};
+use minijinja::Environment;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// Longest array or map a `ContextFuzzer` generates by default
+pub const DEFAULT_FUZZ_MAX_ITEMS: usize = 3;
+
+// How many times a recursive definition is expanded before its lists stay empty
+const MAX_RECURSION: usize = 3;
+
+// Strings that tend to trip up templates (whitespace, markup, non-ASCII)
+const FUZZ_STRINGS: &[&str] = &[
+    "",
+    " ",
+    "hello",
+    "  padded  ",
+    "line\nbreak",
+    "<|im_start|>",
+    "{{ not a tag }}",
+    "ünïcødé ✓",
+];
+
+// Builds the smallest context satisfying the shape of `analysis`: optional
+// paths are left out, arrays get a single item (or as many as their
+// cardinality requires), leaves take their default,
+// their first enum value or the empty value of their type. When that breaks
+// a constraint, the other enum values of its paths (or none) are tried, so the
+// template's `raise_exception` guards pass
+pub(crate) fn sample_context(analysis: &TemplateAnalysis) -> Value {
+    let mut choices = BTreeMap::new();
+    let mut context = sample(analysis, &choices, &analysis.shape, "");
+    let mut violations = count_violations(analysis, &context);
+
+    for constraint in &analysis.constraints {
+        if violations == 0 {
+            break;
+        }
+        if !is_violated(analysis, constraint, &context) {
+            continue;
+        }
+        let paths = constraint.paths.iter().filter(|path| {
+            analysis.enum_values.contains_key(*path) && !analysis.defaults.contains_key(*path)
+        });
+        'paths: for path in paths {
+            let previous = choices.remove(path);
+            for candidate in analysis.enum_values[path].iter().map(Some).chain([None]) {
+                choices.insert(path.clone(), candidate);
+                let candidate_context = sample(analysis, &choices, &analysis.shape, "");
+                let candidate_violations = count_violations(analysis, &candidate_context);
+                if candidate_violations < violations {
+                    context = candidate_context;
+                    violations = candidate_violations;
+                    break 'paths;
+                }
+            }
+            match previous {
+                Some(choice) => choices.insert(path.clone(), choice),
+                None => choices.remove(path),
+            };
+        }
+    }
+    context
+}
+
+// Value picked for a path with enum values; `None` falls back to its type
+type Choices<'a> = BTreeMap<String, Option<&'a Value>>;
+
+fn count_violations(analysis: &TemplateAnalysis, context: &Value) -> usize {
+    analysis
+        .constraints
+        .iter()
+        .filter(|constraint| is_violated(analysis, constraint, context))
+        .count()
+}
+
+// Whether `context` certainly breaks the constraint: loop variables are bound
+// to the first item of what they iterate, and conditions that fail to compile
+// or evaluate are given the benefit of the doubt
+fn is_violated(analysis: &TemplateAnalysis, constraint: &Constraint, context: &Value) -> bool {
+    let env = Environment::new();
+    let Ok(condition) = env.compile_expression(&constraint.condition) else {
+        return false;
+    };
+    let mut scope = context.as_object().cloned().unwrap_or_default();
+    for _ in 0..analysis.loop_vars.len() {
+        for (name, iterated) in &analysis.loop_vars {
+            if scope.contains_key(name) {
+                continue;
+            }
+            let first = env
+                .compile_expression(iterated)
+                .and_then(|expr| expr.eval(&scope))
+                .ok()
+                .and_then(|items| items.get_item_by_index(0).ok())
+                .filter(|item| !item.is_undefined());
+            if let Some(item) = first.and_then(|item| serde_json::to_value(item).ok()) {
+                scope.insert(name.clone(), item);
+            }
+        }
+    }
+    matches!(condition.eval(&scope), Ok(value) if !value.is_true())
+}
+
+fn sample(analysis: &TemplateAnalysis, choices: &Choices, shape: &Shape, path: &str) -> Value {
+    let choice = match choices.get(path) {
+        Some(choice) => *choice,
+        None => analysis
+            .enum_values
+            .get(path)
+            .and_then(|values| values.first()),
+    };
+    if let Some(value) = analysis.defaults.get(path).or(choice) {
+        return value.clone();
+    }
+
+    match shape {
+        Shape::Object(properties) => {
+            let mut obj = Map::new();
+            for (key, property) in properties {
+                let property_path = child_path(path, key);
+                if !analysis.optional_paths.contains(&property_path) {
+                    obj.insert(
+                        key.clone(),
+                        sample(analysis, choices, property, &property_path),
+                    );
+                }
+            }
+            Value::Object(obj)
+        }
+        // Recursive items end the recursion with an empty list
+        Shape::Array(item) if matches!(**item, Shape::Ref(_)) => json!([]),
+        Shape::Array(item) => {
+            let bounds = analysis.cardinality.get(path).copied().unwrap_or_default();
+            let len = bounds
+                .min_items
+                .max(1)
+                .min(bounds.max_items.unwrap_or(usize::MAX));
+            let item = sample(analysis, choices, item, &format!("{path}[]"));
+            Value::Array(vec![item; len])
+        }
+        Shape::Map(_) | Shape::Any | Shape::Ref(_) => json!({}),
+        Shape::Union(variants) => {
+            // A non-null value renders wherever `null` does, and then some
+            let preferred = variants
+                .iter()
+                .find(|shape| shape.is_container())
+                .or_else(|| variants.iter().find(|shape| **shape != Shape::Null));
+            match preferred {
+                Some(shape) => sample(analysis, choices, shape, path),
+                None => Value::Null,
+            }
+        }
+        Shape::Null => Value::Null,
+        Shape::String | Shape::Unknown => json!(""),
+        Shape::Number | Shape::Integer => json!(0),
+        Shape::Bool => json!(false),
+    }
+}
+
+/// Seeded generator of random contexts conforming to an analysis, for
+/// fuzz-rendering templates: array lengths, the presence of optional paths,
+/// union variants and leaf values vary from context to context. The same
+/// seed always yields the same contexts.
+#[derive(Debug, Clone)]
+pub struct ContextFuzzer<'a> {
+    analysis: &'a TemplateAnalysis,
+    state: u64,
+    /// Longest array or map to generate
+    pub max_items: usize,
+}
+
+impl<'a> ContextFuzzer<'a> {
+    pub fn new(analysis: &'a TemplateAnalysis, seed: u64) -> Self {
+        ContextFuzzer {
+            analysis,
+            state: seed,
+            max_items: DEFAULT_FUZZ_MAX_ITEMS,
+        }
+    }
+
+    /// Generates the next random context
+    pub fn next_context(&mut self) -> Value {
+        let shape = &self.analysis.shape;
+        self.generate(shape, "", 0)
+    }
+
+    // splitmix64, so sequences don't depend on an external RNG's version
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform index below `n` (which must be non-zero)
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn coin(&mut self) -> bool {
+        self.next_u64() & 1 == 1
+    }
+
+    fn generate(&mut self, shape: &Shape, path: &str, depth: usize) -> Value {
+        let analysis = self.analysis;
+        if let Some(values) = analysis.enum_values.get(path) {
+            if !values.is_empty() {
+                let idx = self.below(values.len());
+                return values[idx].clone();
+            }
+        }
+
+        match shape {
+            Shape::Object(properties) => {
+                let mut obj = Map::new();
+                for (key, property) in properties {
+                    let property_path = child_path(path, key);
+                    if analysis.optional_paths.contains(&property_path) && self.coin() {
+                        continue;
+                    }
+                    let value = self.generate(property, &property_path, depth);
+                    obj.insert(key.clone(), value);
+                }
+                Value::Object(obj)
+            }
+            Shape::Array(item) => {
+                let recursive = matches!(**item, Shape::Ref(_));
+                if recursive && depth >= MAX_RECURSION {
+                    return json!([]);
+                }
+                let depth = depth + usize::from(recursive);
+                let item_path = format!("{path}[]");
+                let bounds = analysis.cardinality.get(path).copied().unwrap_or_default();
+                let longest = bounds
+                    .max_items
+                    .unwrap_or(usize::MAX)
+                    .min(self.max_items)
+                    .max(bounds.min_items);
+                let len = bounds.min_items + self.below(longest - bounds.min_items + 1);
+                let items = (0..len)
+                    .map(|_| self.generate(item, &item_path, depth))
+                    .collect();
+                Value::Array(items)
+            }
+            Shape::Map(entry) => {
+                let entry_path = format!("{path}{{}}");
+                let len = self.below(self.max_items + 1);
+                let mut obj = Map::new();
+                for idx in 0..len {
+                    let value = self.generate(entry, &entry_path, depth);
+                    obj.insert(format!("key{idx}"), value);
+                }
+                Value::Object(obj)
+            }
+            Shape::Ref(name) => match analysis.definitions.get(name) {
+                Some(definition) => self.generate(&definition.shape, &definition.path, depth),
+                None => json!({}),
+            },
+            Shape::Union(variants) if !variants.is_empty() => {
+                let idx = self.below(variants.len());
+                self.generate(&variants[idx], path, depth)
+            }
+            Shape::Union(_) | Shape::Null => Value::Null,
+            Shape::String | Shape::Unknown => {
+                json!(FUZZ_STRINGS[self.below(FUZZ_STRINGS.len())])
+            }
+            Shape::Integer => json!(self.below(201) as i64 - 100),
+            Shape::Number => json!((self.below(20_001) as f64 - 10_000.0) / 100.0),
+            Shape::Bool => json!(self.coin()),
+            Shape::Any => match self.below(4) {
+                0 => json!({"key": "value"}),
+                1 => json!([1, "two"]),
+                2 => json!("text"),
+                _ => json!(1),
+            },
+        }
+    }
+}
+
+impl Iterator for ContextFuzzer<'_> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        Some(self.next_context())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+    use minijinja::{Environment, Error, ErrorKind};
+    use serde_json::json;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_sample_context() {
+        let template = "{{ bos_token }}{% for message in messages %}{% if message.role == 'user' %}{{ message.content | trim }}{% elif message.content is none %}-{% endif %}{% for part in message.parts %}{{ part.text }}{% endfor %}{% endfor %}{% if tools is defined %}{{ tools | length }}{% endif %}{% if add_generation_prompt %}>{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+
+        let ctx = analysis.sample_context();
+        assert_eq!(
+            ctx,
+            json!({
+                "add_generation_prompt": false,
+                "bos_token": "",
+                "messages": [{"content": "", "parts": [{"text": ""}], "role": "user"}]
+            })
+        );
+        assert_eq!(analysis.validate_context(&ctx), vec![]);
+
+        let env = Environment::new();
+        assert!(env.render_str(template, ctx).is_ok());
+    }
+
+    #[test]
+    fn test_sample_context_satisfies_constraints() {
+        let mut env = Environment::new();
+        env.add_function("raise_exception", |message: String| -> Result<(), Error> {
+            Err(Error::new(ErrorKind::InvalidOperation, message))
+        });
+
+        for (template, expected) in [
+            (
+                "{% if messages[0].role != 'system' %}{{ raise_exception('sys') }}{% endif %}",
+                json!({"messages": [{"role": "system"}]}),
+            ),
+            (
+                "{% if messages[0]['role'] == 'system' %}{{ raise_exception('sys') }}{% endif %}",
+                json!({"messages": [{"role": ""}]}),
+            ),
+            (
+                "{% for m in messages %}{% if m.role == 'tool' %}{{ raise_exception('tool') }}{% elif m.role == 'user' %}{% endif %}{% endfor %}",
+                json!({"messages": [{"role": "user"}]}),
+            ),
+        ] {
+            let ctx = analyze(template, false).unwrap().sample_context();
+            assert_eq!(ctx, expected, "{template}");
+            assert!(env.render_str(template, ctx).is_ok(), "{template}");
+        }
+    }
+
+    #[test]
+    fn test_fuzz_contexts() {
+        let template = "{% for message in messages %}{{ message.role }}{% if message.name is defined %}{{ message.name }}{% endif %}{{ message.weight | round }}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+
+        let contexts = analysis.fuzz_contexts(7).take(50).collect::<Vec<_>>();
+        assert_eq!(
+            contexts,
+            analysis.fuzz_contexts(7).take(50).collect::<Vec<_>>()
+        );
+        assert_ne!(
+            contexts,
+            analysis.fuzz_contexts(8).take(50).collect::<Vec<_>>()
+        );
+
+        let mut lengths = BTreeSet::new();
+        let mut named = BTreeSet::new();
+        for ctx in &contexts {
+            assert_eq!(analysis.validate_context(ctx), vec![]);
+            let messages = ctx["messages"].as_array().unwrap();
+            lengths.insert(messages.len());
+            for message in messages {
+                named.insert(message.get("name").is_some());
+            }
+        }
+        assert_eq!(lengths, BTreeSet::from([0, 1, 2, 3]));
+        assert_eq!(named, BTreeSet::from([false, true]));
+    }
+}
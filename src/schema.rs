@@ -0,0 +1,596 @@
+use crate::codegen::pascal_case;
+use crate::format::strftime_format;
+use crate::shape::{child_path, Shape};
+use crate::TemplateAnalysis;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// Dialect URI emitted as `$schema`
+pub const JSON_SCHEMA_DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+// Most property names a hoisted definition is named after
+const MAX_NAME_PROPERTIES: usize = 3;
+
+/// Options for emitting the JSON Schema of an analysis
+#[derive(Debug, Clone, Default)]
+pub struct SchemaOptions {
+    /// Annotate fields with `x-confidence` (`low`, `medium` or `high`)
+    pub confidence: bool,
+    /// Schema fragments deep-merged into the schema at their schema path
+    /// (`""` is the root, e.g. `messages[].role` -> `{"enum": ["user"]}`)
+    pub overrides: BTreeMap<String, Value>,
+}
+
+/// Parses an overrides document: an object mapping schema paths to the
+/// schema fragments to merge at those paths
+pub fn parse_overrides(json: &str) -> Result<BTreeMap<String, Value>, serde_json::Error> {
+    serde_json::from_str(json)
+}
+
+// Builds the JSON Schema document for the render context of an analysis,
+// identified by the analysis name when there is one
+pub(crate) fn json_schema(analysis: &TemplateAnalysis, options: &SchemaOptions) -> Value {
+    let mut schema = shape_schema(&analysis.shape, "", analysis, options);
+    if let (Some(name), Value::Object(obj)) = (&analysis.name, &mut schema) {
+        let hash = format!("{:016x}", analysis.shape_hash());
+        let id = name.split_whitespace().collect::<Vec<_>>().join("-");
+        obj.insert("title".to_string(), json!(name));
+        obj.insert(
+            "$id".to_string(),
+            json!(format!("urn:cleanplate:{id}:{hash}")),
+        );
+        obj.insert(
+            "description".to_string(),
+            json!(format!(
+                "Render context of the `{name}` template (shape {hash})"
+            )),
+        );
+    }
+    for (path, fragment) in &options.overrides {
+        apply_override(&mut schema, path, fragment);
+    }
+    if let Value::Object(obj) = &mut schema {
+        obj.insert("$schema".to_string(), json!(JSON_SCHEMA_DIALECT));
+        if !analysis.definitions.is_empty() {
+            let defs = analysis
+                .definitions
+                .iter()
+                .map(|(name, definition)| {
+                    let mut schema =
+                        shape_schema(&definition.shape, &definition.path, analysis, options);
+                    for (path, fragment) in &options.overrides {
+                        if let Some(rest) = path.strip_prefix(definition.path.as_str()) {
+                            apply_override(&mut schema, rest, fragment);
+                        }
+                    }
+                    (name.clone(), schema)
+                })
+                .collect::<Map<_, _>>();
+            obj.insert("$defs".to_string(), Value::Object(defs));
+        }
+    }
+    hoist_repeated_objects(&mut schema);
+    schema
+}
+
+// OpenAPI 3.1 `components.schemas` fragment holding the context schema as
+// component `name`, its definitions as `{name}{Definition}` components
+pub(crate) fn openapi_components(
+    analysis: &TemplateAnalysis,
+    name: &str,
+    options: &SchemaOptions,
+) -> Value {
+    let mut schema = json_schema(analysis, options);
+    let mut components = Map::new();
+    if let Value::Object(obj) = &mut schema {
+        // Components are resolved against the document, not their own URI
+        obj.remove("$schema");
+        obj.remove("$id");
+        if let Some(Value::Object(defs)) = obj.remove("$defs") {
+            for (def_name, def) in defs {
+                components.insert(format!("{name}{}", pascal_case(&def_name)), def);
+            }
+        }
+    }
+    components.insert(name.to_string(), schema);
+
+    let mut components = Value::Object(components);
+    rewrite_refs(&mut components, name);
+    json!({ "components": { "schemas": components } })
+}
+
+// Points `#/$defs/x` references at the matching component
+fn rewrite_refs(value: &mut Value, name: &str) {
+    match value {
+        Value::Object(obj) => {
+            for (key, child) in obj.iter_mut() {
+                match (key.as_str(), child) {
+                    ("$ref", Value::String(reference)) => {
+                        if let Some(def_name) = reference.strip_prefix("#/$defs/") {
+                            *reference =
+                                format!("#/components/schemas/{name}{}", pascal_case(def_name));
+                        }
+                    }
+                    (_, child) => rewrite_refs(child, name),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                rewrite_refs(item, name);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Deep-merges an override fragment into the schema at `path`, creating the
+// properties, items and map values along the way
+fn apply_override(schema: &mut Value, path: &str, fragment: &Value) {
+    let mut current = schema;
+    let mut rest = path;
+    while !rest.is_empty() {
+        // Nullable values nest their structure in an `anyOf` variant
+        current = structured_variant(current);
+        let Value::Object(obj) = current else {
+            return;
+        };
+        if let Some(after) = rest.strip_prefix("[]") {
+            current = obj.entry("items").or_insert_with(|| json!({}));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("{}") {
+            current = obj
+                .entry("additionalProperties")
+                .or_insert_with(|| json!({}));
+            rest = after;
+        } else {
+            let name = rest.trim_start_matches('.');
+            let end = name.find(['.', '[', '{']).unwrap_or(name.len());
+            let properties = obj.entry("properties").or_insert_with(|| json!({}));
+            let Value::Object(properties) = properties else {
+                return;
+            };
+            current = properties.entry(&name[..end]).or_insert_with(|| json!({}));
+            rest = &name[end..];
+        }
+    }
+    deep_merge(current, fragment);
+}
+
+fn structured_variant(schema: &mut Value) -> &mut Value {
+    let position = schema
+        .get("anyOf")
+        .and_then(Value::as_array)
+        .and_then(|variants| variants.iter().position(is_structured));
+    match position {
+        Some(idx) => &mut schema["anyOf"][idx],
+        None => schema,
+    }
+}
+
+fn is_structured(schema: &Value) -> bool {
+    ["properties", "items", "additionalProperties"]
+        .iter()
+        .any(|key| schema.get(key).is_some())
+}
+
+// Objects merge key by key, anything else is replaced
+fn deep_merge(target: &mut Value, fragment: &Value) {
+    match (target, fragment) {
+        (Value::Object(target), Value::Object(fragment)) => {
+            for (key, value) in fragment {
+                match target.get_mut(key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => {
+                        target.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (target, fragment) => *target = fragment.clone(),
+    }
+}
+
+// Moves object schemas that occur more than once into `$defs` and refers to
+// them with `$ref`; existing definitions are reused for identical objects.
+// A definition is named after the path it occurs under most often, or after
+// its properties when no path wins (`messages` and `history` -> `content_role`)
+fn hoist_repeated_objects(schema: &mut Value) {
+    let mut defs = match schema.get_mut("$defs").map(Value::take) {
+        Some(Value::Object(defs)) => defs,
+        _ => Map::new(),
+    };
+
+    // Occurrences of each object schema, keyed by its serialized form
+    let mut seen = BTreeMap::new();
+    count_objects(schema, "", &mut seen);
+    for def in defs.values_mut() {
+        for (child, child_name) in subschemas_mut(def, "") {
+            count_objects(child, &child_name, &mut seen);
+        }
+    }
+
+    let mut refs = defs
+        .iter()
+        .map(|(name, def)| (def.to_string(), name.clone()))
+        .collect::<BTreeMap<_, _>>();
+    for (key, occurrences) in seen {
+        if occurrences.count < 2 || refs.contains_key(&key) {
+            continue;
+        }
+        let name = occurrences.definition_name();
+        let name = name.as_str();
+        let mut unique = name.to_string();
+        let mut suffix = 2;
+        while refs.values().any(|used| *used == unique) {
+            unique = format!("{name}_{suffix}");
+            suffix += 1;
+        }
+        refs.insert(key, unique);
+    }
+    if refs.is_empty() {
+        return;
+    }
+
+    for (child, child_name) in subschemas_mut(schema, "") {
+        replace_repeated(child, &child_name, &refs, &mut defs);
+    }
+    for def in defs.clone().keys() {
+        let mut body = defs[def].take();
+        for (child, child_name) in subschemas_mut(&mut body, def) {
+            replace_repeated(child, &child_name, &refs, &mut defs);
+        }
+        defs.insert(def.clone(), body);
+    }
+    if let Value::Object(obj) = schema {
+        obj.insert("$defs".to_string(), Value::Object(defs));
+    }
+}
+
+// Where an object schema occurs: how often, under which names, and its
+// property names
+#[derive(Default)]
+struct Occurrences {
+    count: usize,
+    names: BTreeMap<String, usize>,
+    properties: Vec<String>,
+}
+
+impl Occurrences {
+    // Most frequent name, or the first property names joined when several
+    // names are equally frequent
+    fn definition_name(&self) -> String {
+        let named = || self.names.iter().filter(|(name, _)| !name.is_empty());
+        let most = named().map(|(_, count)| *count).max();
+        let mut winners = named().filter(|(_, count)| Some(**count) == most);
+        if let (Some((name, _)), None) = (winners.next(), winners.next()) {
+            return name.clone();
+        }
+        match self.properties.is_empty() {
+            true => "object".to_string(),
+            false => self.properties[..self.properties.len().min(MAX_NAME_PROPERTIES)].join("_"),
+        }
+    }
+}
+
+fn count_objects(schema: &mut Value, name: &str, seen: &mut BTreeMap<String, Occurrences>) {
+    let properties = schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .filter(|props| !props.is_empty())
+        .map(|props| props.keys().cloned().collect::<Vec<_>>());
+    if let Some(properties) = properties {
+        let occurrences = seen.entry(schema.to_string()).or_default();
+        occurrences.count += 1;
+        *occurrences.names.entry(name.to_string()).or_default() += 1;
+        occurrences.properties = properties;
+    }
+    for (child, child_name) in subschemas_mut(schema, name) {
+        count_objects(child, &child_name, seen);
+    }
+}
+
+fn replace_repeated(
+    schema: &mut Value,
+    name: &str,
+    refs: &BTreeMap<String, String>,
+    defs: &mut Map<String, Value>,
+) {
+    if let Some(def_name) = refs.get(&schema.to_string()) {
+        let reference = json!({ "$ref": format!("#/$defs/{def_name}") });
+        let mut body = std::mem::replace(schema, reference);
+        if !defs.contains_key(def_name) {
+            for (child, child_name) in subschemas_mut(&mut body, def_name) {
+                replace_repeated(child, &child_name, refs, defs);
+            }
+            defs.insert(def_name.clone(), body);
+        }
+        return;
+    }
+    for (child, child_name) in subschemas_mut(schema, name) {
+        replace_repeated(child, &child_name, refs, defs);
+    }
+}
+
+// Nested schemas with the name they are known by (property name, or the
+// name of the enclosing schema for array items, map values and variants)
+fn subschemas_mut<'a>(schema: &'a mut Value, name: &str) -> Vec<(&'a mut Value, String)> {
+    let mut children = Vec::new();
+    let Value::Object(obj) = schema else {
+        return children;
+    };
+    for (key, value) in obj.iter_mut() {
+        match (key.as_str(), value) {
+            ("properties", Value::Object(props)) => {
+                children.extend(props.iter_mut().map(|(key, value)| (value, key.clone())));
+            }
+            ("items" | "additionalProperties", value @ Value::Object(_)) => {
+                children.push((value, name.to_string()));
+            }
+            ("anyOf", Value::Array(variants)) => {
+                children.extend(variants.iter_mut().map(|value| (value, name.to_string())));
+            }
+            _ => {}
+        }
+    }
+    children
+}
+
+// Schema for the value at `path`, including path-level annotations such as defaults
+fn shape_schema(
+    shape: &Shape,
+    path: &str,
+    analysis: &TemplateAnalysis,
+    options: &SchemaOptions,
+) -> Value {
+    let mut schema = type_schema(shape, path, analysis, options);
+    if let Value::Object(obj) = &mut schema {
+        if let Some(confidence) = analysis.confidence.get(path).filter(|_| options.confidence) {
+            obj.insert("x-confidence".to_string(), json!(confidence.as_str()));
+        }
+        let description = analysis
+            .annotations
+            .get(path)
+            .and_then(|annotation| annotation.description.as_ref());
+        if let Some(description) = description {
+            obj.insert("description".to_string(), json!(description));
+        }
+        if analysis.truncated_paths.contains(path) {
+            obj.insert("x-truncated".to_string(), json!(true));
+        }
+        if let Some(format) = analysis.formats.get(path) {
+            if format.contains('%') {
+                if let Some(name) = strftime_format(format) {
+                    obj.insert("format".to_string(), json!(name));
+                }
+                obj.insert("x-strftime".to_string(), json!(format));
+            } else {
+                obj.insert("format".to_string(), json!(format));
+            }
+        }
+        if let Some(bounds) = analysis.cardinality.get(path) {
+            if bounds.min_items > 0 {
+                obj.insert("minItems".to_string(), json!(bounds.min_items));
+            }
+            if let Some(max_items) = bounds.max_items {
+                obj.insert("maxItems".to_string(), json!(max_items));
+            }
+        }
+        if let Some(default) = analysis.defaults.get(path) {
+            obj.insert("default".to_string(), default.clone());
+        }
+        if let Some(values) = analysis.enum_values.get(path) {
+            if analysis.closed_enums.contains(path) {
+                let mut values = values.clone();
+                // A nullable enum must also admit `null`
+                if shape.is_nullable() {
+                    values.push(Value::Null);
+                }
+                obj.insert("enum".to_string(), Value::Array(values));
+            } else {
+                // Constants the template happens to compare against don't rule out others
+                obj.insert("x-enum-candidates".to_string(), json!(values));
+            }
+        }
+    }
+    schema
+}
+
+// Schema for the type of a shape, without path-level annotations
+fn type_schema(
+    shape: &Shape,
+    path: &str,
+    analysis: &TemplateAnalysis,
+    options: &SchemaOptions,
+) -> Value {
+    match shape {
+        Shape::Object(properties) => {
+            let mut props = Map::new();
+            for (key, property) in properties {
+                props.insert(
+                    key.clone(),
+                    shape_schema(property, &child_path(path, key), analysis, options),
+                );
+            }
+            json!({
+                "type": "object",
+                "properties": props,
+                "required": properties
+                    .keys()
+                    .filter(|key| !analysis.optional_paths.contains(&child_path(path, key)))
+                    .collect::<Vec<_>>(),
+            })
+        }
+        Shape::Array(item) => match **item {
+            Shape::Unknown => json!({ "type": "array" }),
+            _ => json!({
+                "type": "array",
+                "items": shape_schema(item, &format!("{path}[]"), analysis, options),
+            }),
+        },
+        Shape::Map(value) => json!({
+            "type": "object",
+            "additionalProperties": shape_schema(value, &format!("{path}{{}}"), analysis, options),
+        }),
+        Shape::String => json!({ "type": "string" }),
+        Shape::Number => json!({ "type": "number" }),
+        Shape::Integer => json!({ "type": "integer" }),
+        Shape::Bool => json!({ "type": "boolean" }),
+        Shape::Null => json!({ "type": "null" }),
+        Shape::Unknown | Shape::Any => json!({}),
+        Shape::Ref(name) => json!({ "$ref": format!("#/$defs/{name}") }),
+        Shape::Union(variants) => {
+            let schemas = variants
+                .iter()
+                .map(|variant| type_schema(variant, path, analysis, options))
+                .collect::<Vec<_>>();
+
+            // Plain types collapse into a type list (`["string", "null"]`)
+            let simple_types = schemas
+                .iter()
+                .map(|schema| match schema.as_object() {
+                    Some(obj) if obj.len() == 1 => obj.get("type").cloned(),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+
+            match simple_types {
+                Some(types) => json!({ "type": types }),
+                None => json!({ "anyOf": schemas }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SchemaOptions;
+    use crate::analyze;
+    use serde_json::json;
+
+    #[test]
+    fn test_openapi_component() {
+        let template = "{% for turn in history %}{{ turn.role ~ turn.content }}{% endfor %}{% for message in messages %}{{ message.role ~ message.content }}{% endfor %}{% for item in items recursive %}{{ item.name ~ '' }}{{ loop(item.children) }}{% endfor %}";
+        let fragment = analyze(template, false)
+            .unwrap()
+            .to_openapi_component("ChatContext");
+        let schemas = &fragment["components"]["schemas"];
+        let names = schemas.as_object().unwrap().keys().collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["ChatContext", "ChatContextContentRole", "ChatContextItem"]
+        );
+
+        let context = &schemas["ChatContext"];
+        assert!(context.get("$schema").is_none() && context.get("$defs").is_none());
+        assert_eq!(
+            context["properties"]["messages"]["items"],
+            json!({"$ref": "#/components/schemas/ChatContextContentRole"})
+        );
+        assert_eq!(
+            schemas["ChatContextItem"]["properties"]["children"]["items"],
+            json!({"$ref": "#/components/schemas/ChatContextItem"})
+        );
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let template = "{% for message in messages %}{{ message.role ~ message.content }}{% if message.name is none %}{% endif %}{% endfor %}{{ tools }}";
+        let schema = analyze(template, false).unwrap().to_json_schema();
+        assert_eq!(schema["$schema"], json!(super::JSON_SCHEMA_DIALECT));
+        assert_eq!(schema["type"], json!("object"));
+        assert_eq!(schema["required"], json!(["messages", "tools"]));
+        let message = &schema["properties"]["messages"]["items"];
+        assert_eq!(message["properties"]["role"], json!({"type": "string"}));
+        assert_eq!(
+            message["properties"]["name"],
+            json!({"anyOf": [{}, {"type": "null"}]})
+        );
+        assert_eq!(schema["properties"]["tools"], json!({}));
+    }
+
+    #[test]
+    fn test_repeated_objects_hoisted() {
+        let template = "{% for message in messages %}{{ message.role ~ message.content }}{% endfor %}{% for turn in history %}{{ turn.role ~ turn.content }}{% endfor %}";
+        let schema = analyze(template, false).unwrap().to_json_schema();
+        // Neither path wins, so the definition is named after its properties
+        let reference = json!({"$ref": "#/$defs/content_role"});
+        assert_eq!(schema["properties"]["history"]["items"], reference);
+        assert_eq!(schema["properties"]["messages"]["items"], reference);
+        assert_eq!(
+            schema["$defs"]["content_role"]["properties"]["role"],
+            json!({"type": "string"})
+        );
+
+        // Otherwise after the path it occurs under most often
+        let template = "{% for entry in chat.messages %}{{ entry.role ~ entry.content }}{% endfor %}{{ chat.id ~ '' }}{% for m in messages %}{{ m.role ~ m.content }}{% endfor %}{% for turn in history %}{{ turn.role ~ turn.content }}{% endfor %}";
+        let schema = analyze(template, false).unwrap().to_json_schema();
+        let reference = json!({"$ref": "#/$defs/messages"});
+        assert_eq!(schema["properties"]["history"]["items"], reference);
+        assert_eq!(
+            schema["properties"]["chat"]["properties"]["messages"]["items"],
+            reference
+        );
+    }
+
+    #[test]
+    fn test_confidence_annotations() {
+        let template = "{% for message in messages %}{{ message.role ~ message.content }}{% if message.tool_calls %}{{ message.tool_calls.name }}{% endif %}{% endfor %}{{ count | int }}{% if message_count > 2 %}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        let options = SchemaOptions {
+            confidence: true,
+            ..Default::default()
+        };
+        let schema = analysis.to_json_schema_with(&options);
+        let message = &schema["properties"]["messages"]["items"];
+        assert_eq!(message["x-confidence"], json!("high"));
+        assert_eq!(
+            message["properties"]["role"]["x-confidence"],
+            json!("medium")
+        );
+        assert_eq!(
+            message["properties"]["tool_calls"]["x-confidence"],
+            json!("low")
+        );
+        assert_eq!(schema["properties"]["count"]["x-confidence"], json!("low"));
+        assert_eq!(
+            analysis.confidence["message_count"],
+            crate::Confidence::Medium
+        );
+        assert!(analysis.to_json_schema()["properties"]["count"]
+            .get("x-confidence")
+            .is_none());
+    }
+
+    #[test]
+    fn test_schema_overrides() {
+        let template = "{% for message in messages %}{{ message.role ~ message.content }}{% if message.name is none %}{% endif %}{% endfor %}{{ tools }}";
+        let overrides = super::parse_overrides(
+            r#"{
+                "messages[].role": {"enum": ["user", "assistant"], "description": "speaker"},
+                "messages[].name": {"anyOf": [{"type": "string"}, {"type": "null"}]},
+                "messages[]": {"required": ["role"]},
+                "tools": {"type": "array"}
+            }"#,
+        )
+        .unwrap();
+        let options = SchemaOptions {
+            overrides,
+            ..Default::default()
+        };
+        let schema = analyze(template, false)
+            .unwrap()
+            .to_json_schema_with(&options);
+        let message = &schema["properties"]["messages"]["items"];
+        assert_eq!(
+            message["properties"]["role"],
+            json!({"type": "string", "enum": ["user", "assistant"], "description": "speaker"})
+        );
+        assert_eq!(
+            message["properties"]["name"],
+            json!({"anyOf": [{"type": "string"}, {"type": "null"}]})
+        );
+        assert_eq!(message["required"], json!(["role"]));
+        assert_eq!(schema["properties"]["tools"], json!({"type": "array"}));
+    }
+}
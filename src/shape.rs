@@ -0,0 +1,390 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Typed representation of the data a template expects
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Shape {
+    /// Object with a fixed set of properties
+    Object(BTreeMap<String, Shape>),
+    /// Sequence of items sharing one shape
+    Array(Box<Shape>),
+    String,
+    Number,
+    Integer,
+    Bool,
+    Null,
+    /// Value that may take any of the listed shapes
+    Union(Vec<Shape>),
+    /// Value that is used but carries no type evidence
+    Unknown,
+    /// Arbitrary JSON the template handles wholesale (e.g. via `tojson`)
+    Any,
+    /// Object with arbitrary keys whose values share one shape
+    Map(Box<Shape>),
+    /// Reference to a named definition, used for recursive data
+    Ref(String),
+}
+
+/// Named shape that other shapes refer to through `Shape::Ref`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Definition {
+    /// Schema path the definition describes (e.g. `items[]`)
+    pub path: String,
+    pub shape: Shape,
+}
+
+impl Shape {
+    /// Wraps the shape in a union with `Null`
+    pub fn nullable(self) -> Shape {
+        match self {
+            Shape::Null => Shape::Null,
+            Shape::Union(mut variants) => {
+                if !variants.contains(&Shape::Null) {
+                    variants.push(Shape::Null);
+                }
+                Shape::Union(variants)
+            }
+            shape => Shape::Union(vec![shape, Shape::Null]),
+        }
+    }
+
+    /// Whether the shape admits `null`
+    pub fn is_nullable(&self) -> bool {
+        match self {
+            Shape::Null => true,
+            Shape::Union(variants) => variants.iter().any(Shape::is_nullable),
+            _ => false,
+        }
+    }
+
+    /// Combines the evidence of two uses of the same value, producing a
+    /// union when the uses disagree
+    pub fn merge(self, other: Shape) -> Shape {
+        match (self, other) {
+            (Shape::Unknown, shape) | (shape, Shape::Unknown) => shape,
+            (a, b) if a == b => a,
+            (Shape::Object(mut properties), Shape::Object(other)) => {
+                for (key, shape) in other {
+                    let merged = match properties.remove(&key) {
+                        Some(existing) => existing.merge(shape),
+                        None => shape,
+                    };
+                    properties.insert(key, merged);
+                }
+                Shape::Object(properties)
+            }
+            (Shape::Array(a), Shape::Array(b)) => Shape::Array(Box::new(a.merge(*b))),
+            (Shape::Map(a), Shape::Map(b)) => Shape::Map(Box::new(a.merge(*b))),
+            (Shape::Integer, Shape::Number) | (Shape::Number, Shape::Integer) => Shape::Number,
+            (a, b) => {
+                let mut variants: Vec<Shape> = Vec::new();
+                for shape in a.into_variants().into_iter().chain(b.into_variants()) {
+                    // Shapes of the same kind merge instead of sitting side by side
+                    match variants.iter().position(|v| v.same_kind(&shape)) {
+                        Some(idx) => {
+                            let existing = variants.remove(idx);
+                            variants.insert(idx, existing.merge(shape));
+                        }
+                        None => variants.push(shape),
+                    }
+                }
+                match variants.len() {
+                    1 => variants.remove(0),
+                    _ => Shape::Union(variants),
+                }
+            }
+        }
+    }
+
+    fn into_variants(self) -> Vec<Shape> {
+        match self {
+            Shape::Union(variants) => variants,
+            shape => vec![shape],
+        }
+    }
+
+    fn same_kind(&self, other: &Shape) -> bool {
+        match (self, other) {
+            (Shape::Null, _) | (_, Shape::Null) => self == other,
+            (Shape::Unknown, _) | (_, Shape::Unknown) => true,
+            (Shape::Integer | Shape::Number, Shape::Integer | Shape::Number) => true,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+
+    /// Whether the shape is an object, array or map
+    pub fn is_container(&self) -> bool {
+        matches!(self, Shape::Object(_) | Shape::Array(_) | Shape::Map(_))
+    }
+
+    /// Short type name (`string`, `array`, `string | null`, ...), without
+    /// the shapes nested inside containers
+    pub fn type_name(&self) -> String {
+        match self {
+            Shape::Object(_) => "object".to_string(),
+            Shape::Array(_) => "array".to_string(),
+            Shape::Map(_) => "map".to_string(),
+            Shape::String => "string".to_string(),
+            Shape::Number => "number".to_string(),
+            Shape::Integer => "integer".to_string(),
+            Shape::Bool => "boolean".to_string(),
+            Shape::Null => "null".to_string(),
+            Shape::Unknown => "unknown".to_string(),
+            Shape::Any => "any".to_string(),
+            Shape::Ref(name) => format!("ref {name}"),
+            Shape::Union(variants) => variants
+                .iter()
+                .map(Shape::type_name)
+                .collect::<Vec<_>>()
+                .join(" | "),
+        }
+    }
+
+    /// Every schema path below this shape with the shape found there
+    /// (`messages`, `messages[]`, `messages[].role`, ...)
+    pub fn fields(&self) -> BTreeMap<String, &Shape> {
+        let mut fields = BTreeMap::new();
+        self.collect_fields("", &mut fields);
+        fields
+    }
+
+    fn collect_fields<'a>(&'a self, path: &str, fields: &mut BTreeMap<String, &'a Shape>) {
+        match self {
+            Shape::Object(properties) => {
+                for (key, shape) in properties {
+                    let property_path = child_path(path, key);
+                    fields.insert(property_path.clone(), shape);
+                    shape.collect_fields(&property_path, fields);
+                }
+            }
+            Shape::Array(item) => {
+                let item_path = format!("{path}[]");
+                fields.insert(item_path.clone(), item);
+                item.collect_fields(&item_path, fields);
+            }
+            Shape::Map(value) => {
+                let value_path = format!("{path}{{}}");
+                fields.insert(value_path.clone(), value);
+                value.collect_fields(&value_path, fields);
+            }
+            Shape::Union(variants) => {
+                for variant in variants {
+                    variant.collect_fields(path, fields);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Structural similarity between two shapes, from 0.0 (nothing in common)
+    /// to 1.0 (same paths with the same types); a shared path whose type
+    /// differs counts half
+    pub fn similarity(&self, other: &Shape) -> f64 {
+        let ours = self.fields();
+        let theirs = other.fields();
+        let all_paths = ours.keys().chain(theirs.keys()).collect::<BTreeSet<_>>();
+        if all_paths.is_empty() {
+            return if self.type_name() == other.type_name() {
+                1.0
+            } else {
+                0.0
+            };
+        }
+
+        let score = all_paths
+            .iter()
+            .map(|path| match (ours.get(*path), theirs.get(*path)) {
+                (Some(a), Some(b)) if a.type_name() == b.type_name() => 1.0,
+                (Some(_), Some(_)) => 0.5,
+                _ => 0.0,
+            })
+            .sum::<f64>();
+        score / all_paths.len() as f64
+    }
+
+    /// Stable 64-bit hash of the canonical form of the shape: property order
+    /// and union variant order don't matter, and the value is the same
+    /// across runs and platforms
+    pub fn shape_hash(&self) -> u64 {
+        crate::fnv1a(self.canonical().bytes())
+    }
+
+    // Compact, order-insensitive encoding (`{"role":string}`, `[string|null]`)
+    fn canonical(&self) -> String {
+        match self {
+            Shape::Object(properties) => {
+                let properties = properties
+                    .iter()
+                    .map(|(key, shape)| {
+                        format!("{}:{}", Value::from(key.as_str()), shape.canonical())
+                    })
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", properties.join(","))
+            }
+            Shape::Array(item) => format!("[{}]", item.canonical()),
+            Shape::Map(value) => format!("map<{}>", value.canonical()),
+            Shape::Ref(name) => format!("ref<{}>", Value::from(name.as_str())),
+            Shape::Union(variants) => {
+                let variants = variants
+                    .iter()
+                    .map(Shape::canonical)
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                format!("({})", variants.join("|"))
+            }
+            shape => shape.type_name(),
+        }
+    }
+
+    /// Converts the shape into the example-shaped JSON skeleton
+    pub fn to_json(&self) -> Value {
+        self.to_json_with_defaults(&BTreeMap::new())
+    }
+
+    /// Converts the shape into the example-shaped JSON skeleton, using the
+    /// given defaults (keyed by schema path) as leaf values
+    pub fn to_json_with_defaults(&self, defaults: &BTreeMap<String, Value>) -> Value {
+        self.example_at("", defaults)
+    }
+
+    fn example_at(&self, path: &str, defaults: &BTreeMap<String, Value>) -> Value {
+        match self {
+            Shape::Object(properties) => {
+                let mut obj = Map::new();
+                for (key, shape) in properties {
+                    obj.insert(
+                        key.clone(),
+                        shape.example_at(&child_path(path, key), defaults),
+                    );
+                }
+                Value::Object(obj)
+            }
+            Shape::Array(item) => match **item {
+                // Nothing is known about the items (or they recurse), so don't invent one
+                Shape::Unknown | Shape::Ref(_) => json!([]),
+                _ => json!([item.example_at(&format!("{path}[]"), defaults)]),
+            },
+            Shape::Map(_) | Shape::Any | Shape::Ref(_) => json!({}),
+            Shape::Union(variants) => {
+                // Containers carry the most information, then nullability
+                let preferred = variants
+                    .iter()
+                    .find(|shape| shape.is_container())
+                    .or_else(|| variants.iter().find(|shape| **shape == Shape::Null))
+                    .or_else(|| variants.first());
+                match preferred {
+                    Some(shape) => shape.example_at(path, defaults),
+                    None => json!(""),
+                }
+            }
+            Shape::Null => Value::Null,
+            Shape::String | Shape::Unknown => defaults.get(path).cloned().unwrap_or(json!("")),
+            Shape::Number | Shape::Integer => defaults.get(path).cloned().unwrap_or(json!(0)),
+            Shape::Bool => defaults.get(path).cloned().unwrap_or(json!(false)),
+        }
+    }
+}
+
+// Joins a property onto a schema path (`messages[]` + `role` -> `messages[].role`)
+pub(crate) fn child_path(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{parent}.{key}")
+    }
+}
+
+// Whether `path` lies below `parent` (`messages[].role` is below `messages`
+// and `messages[]`, but not below `message`); everything is below ``
+pub(crate) fn is_descendant(path: &str, parent: &str) -> bool {
+    if parent.is_empty() {
+        return !path.is_empty();
+    }
+    path.len() > parent.len()
+        && path.starts_with(parent)
+        && matches!(path.as_bytes()[parent.len()], b'.' | b'[' | b'{')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_conversion() {
+        let shape = Shape::Object(BTreeMap::from([
+            (
+                "messages".to_string(),
+                Shape::Array(Box::new(Shape::Object(BTreeMap::from([
+                    ("content".to_string(), Shape::String.nullable()),
+                    ("role".to_string(), Shape::String),
+                ])))),
+            ),
+            ("tools".to_string(), Shape::Array(Box::new(Shape::Unknown))),
+            ("add_generation_prompt".to_string(), Shape::Unknown),
+        ]));
+        let defaults = BTreeMap::from([("add_generation_prompt".to_string(), json!(true))]);
+
+        assert_eq!(
+            shape.to_json_with_defaults(&defaults),
+            json!({
+                "add_generation_prompt": true,
+                "messages": [{"content": null, "role": ""}],
+                "tools": []
+            })
+        );
+    }
+
+    #[test]
+    fn test_similarity() {
+        let message = |extra: Option<(&str, Shape)>| {
+            let mut properties = BTreeMap::from([
+                ("role".to_string(), Shape::String),
+                ("content".to_string(), Shape::String),
+            ]);
+            if let Some((key, shape)) = extra {
+                properties.insert(key.to_string(), shape);
+            }
+            Shape::Object(BTreeMap::from([(
+                "messages".to_string(),
+                Shape::Array(Box::new(Shape::Object(properties))),
+            )]))
+        };
+
+        let base = message(None);
+        assert_eq!(base.similarity(&base), 1.0);
+        // messages, messages[], role, content shared out of five paths
+        assert_eq!(
+            base.similarity(&message(Some(("name", Shape::String)))),
+            0.8
+        );
+        let retyped = message(Some(("content", Shape::Array(Box::new(Shape::Unknown)))));
+        // content differs in type (half) and adds `messages[].content[]`
+        assert_eq!(base.similarity(&retyped), 0.7);
+        assert_eq!(base.similarity(&Shape::Object(BTreeMap::new())), 0.0);
+    }
+
+    #[test]
+    fn test_shape_hash() {
+        let a = Shape::Object(BTreeMap::from([
+            ("content".to_string(), Shape::String.nullable()),
+            ("role".to_string(), Shape::String),
+        ]));
+        let b = Shape::Object(BTreeMap::from([
+            ("role".to_string(), Shape::String),
+            (
+                "content".to_string(),
+                Shape::Union(vec![Shape::Null, Shape::String]),
+            ),
+        ]));
+        assert_eq!(a.shape_hash(), b.shape_hash());
+        // Pinned so hashes stored by earlier runs stay comparable
+        assert_eq!(Shape::String.shape_hash(), 0x704b_e0d8_faaf_fc58);
+        assert_ne!(
+            a.shape_hash(),
+            Shape::Array(Box::new(a.clone())).shape_hash()
+        );
+        assert_ne!(Shape::Integer.shape_hash(), Shape::Number.shape_hash());
+    }
+}
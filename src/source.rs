@@ -0,0 +1,280 @@
+use minijinja::machinery;
+use serde_json::Value;
+use std::io::{self, Read};
+
+/// Named chat template read from a model's metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatTemplate {
+    /// `default` for the main template, otherwise the name from the tokenizer
+    /// config list or the GGUF key suffix
+    pub name: String,
+    pub source: String,
+}
+
+/// Chat templates of a `tokenizer_config.json`: the `chat_template` string
+/// (named `default`) or each entry of the list-of-named-templates form
+/// (`[{"name": "tool_use", "template": "..."}]`); `None` when the document
+/// is not a JSON object with a `chat_template`
+pub fn tokenizer_config_templates(json: &str) -> Option<Vec<ChatTemplate>> {
+    let config = serde_json::from_str::<Value>(json).ok()?;
+    match config.get("chat_template")? {
+        Value::String(source) => Some(vec![ChatTemplate {
+            name: "default".to_string(),
+            source: source.clone(),
+        }]),
+        Value::Array(entries) => Some(
+            entries
+                .iter()
+                .filter_map(|entry| {
+                    Some(ChatTemplate {
+                        name: entry.get("name")?.as_str()?.to_string(),
+                        source: entry.get("template")?.as_str()?.to_string(),
+                    })
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Chat templates in the metadata of a GGUF model file: `tokenizer.chat_template`
+/// (named `default`) and every `tokenizer.chat_template.<name>`, read without
+/// loading the tensor data
+pub fn gguf_templates(reader: &mut impl Read) -> io::Result<Vec<ChatTemplate>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != b"GGUF" {
+        return Err(invalid_data("not a GGUF file"));
+    }
+    let version = read_u32(reader)?;
+    // Version 1 used 32-bit counts and lengths
+    let wide = version >= 2;
+    let _tensor_count = read_len(reader, wide)?;
+    let kv_count = read_len(reader, wide)?;
+
+    let mut templates = Vec::new();
+    for _ in 0..kv_count {
+        let key = read_string(reader, wide)?;
+        let value_type = read_u32(reader)?;
+        let name = match key.strip_prefix("tokenizer.chat_template") {
+            Some("") => Some("default"),
+            Some(name) => name.strip_prefix('.'),
+            None => None,
+        };
+        match name {
+            Some(name) if value_type == GGUF_STRING => templates.push(ChatTemplate {
+                name: name.to_string(),
+                source: read_string(reader, wide)?,
+            }),
+            _ => skip_value(reader, value_type, wide)?,
+        }
+    }
+    Ok(templates)
+}
+
+/// Decodes the JSON string escaping (`\n`, `\"`, `\u00e9`) of a template scraped
+/// into JSON, with or without the surrounding quotes; `None` when the text is
+/// not a valid JSON string body
+pub fn unescape_template(source: &str) -> Option<String> {
+    let trimmed = source.trim();
+    if trimmed.len() >= 2 && trimmed.starts_with('"') && trimmed.ends_with('"') {
+        if let Ok(decoded) = serde_json::from_str(trimmed) {
+            return Some(decoded);
+        }
+    }
+    serde_json::from_str(&format!("\"{source}\"")).ok()
+}
+
+/// The decoded template when `source` is evidently JSON-escaped: a single
+/// quoted JSON string, or text that only parses once unescaped; `None` when
+/// it is a template as it is
+pub fn detect_escaped_template(source: &str) -> Option<String> {
+    let trimmed = source.trim();
+    if trimmed.len() >= 2 && trimmed.starts_with('"') && trimmed.ends_with('"') {
+        if let Ok(decoded) = serde_json::from_str(trimmed) {
+            return Some(decoded);
+        }
+    }
+    if parses(source) {
+        return None;
+    }
+    unescape_template(source).filter(|decoded| parses(decoded))
+}
+
+fn parses(source: &str) -> bool {
+    machinery::parse(source, "<string>", Default::default(), Default::default()).is_ok()
+}
+
+const GGUF_STRING: u32 = 8;
+const GGUF_ARRAY: u32 = 9;
+
+// Skips a metadata value of the given GGUF type
+fn skip_value(reader: &mut impl Read, value_type: u32, wide: bool) -> io::Result<()> {
+    let size = match value_type {
+        GGUF_STRING => read_len(reader, wide)?,
+        GGUF_ARRAY => {
+            let item_type = read_u32(reader)?;
+            let len = read_len(reader, wide)?;
+            match fixed_size(item_type) {
+                Some(size) => len
+                    .checked_mul(size)
+                    .ok_or_else(|| invalid_data("GGUF array is too large"))?,
+                None => {
+                    for _ in 0..len {
+                        skip_value(reader, item_type, wide)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        other => fixed_size(other)
+            .ok_or_else(|| invalid_data(&format!("unknown GGUF value type {other}")))?,
+    };
+    let skipped = io::copy(&mut reader.take(size), &mut io::sink())?;
+    if skipped < size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+// Size in bytes of the numeric and boolean GGUF types
+fn fixed_size(value_type: u32) -> Option<u64> {
+    match value_type {
+        0 | 1 | 7 => Some(1),
+        2 | 3 => Some(2),
+        4..=6 => Some(4),
+        10..=12 => Some(8),
+        _ => None,
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_len(reader: &mut impl Read, wide: bool) -> io::Result<u64> {
+    if !wide {
+        return read_u32(reader).map(u64::from);
+    }
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_string(reader: &mut impl Read, wide: bool) -> io::Result<String> {
+    let len = read_len(reader, wide)?;
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("GGUF string is not UTF-8"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_tokenizer_config_templates() {
+        let single = json!({"bos_token": "<s>", "chat_template": "{{ messages }}"});
+        assert_eq!(
+            tokenizer_config_templates(&single.to_string()),
+            Some(vec![ChatTemplate {
+                name: "default".to_string(),
+                source: "{{ messages }}".to_string(),
+            }])
+        );
+
+        let named = json!({"chat_template": [
+            {"name": "default", "template": "{{ messages }}"},
+            {"name": "tool_use", "template": "{{ tools }}"},
+        ]});
+        let names = tokenizer_config_templates(&named.to_string())
+            .unwrap()
+            .into_iter()
+            .map(|template| template.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["default", "tool_use"]);
+
+        assert_eq!(tokenizer_config_templates("{{ messages }}"), None);
+        assert_eq!(tokenizer_config_templates(r#"{"bos_token": "<s>"}"#), None);
+    }
+
+    #[test]
+    fn test_unescape_template() {
+        let escaped = r#"{% for m in messages %}{{ m[\"content\"] }}\n{% endfor %}"#;
+        let decoded = "{% for m in messages %}{{ m[\"content\"] }}\n{% endfor %}";
+        assert_eq!(unescape_template(escaped).as_deref(), Some(decoded));
+        assert_eq!(detect_escaped_template(escaped).as_deref(), Some(decoded));
+        assert_eq!(
+            detect_escaped_template(r#""{{ a }}\n{{ b }}""#).as_deref(),
+            Some("{{ a }}\n{{ b }}")
+        );
+        // Escapes inside Jinja string literals of a parsing template are kept
+        assert_eq!(detect_escaped_template(r"{{ '\n' ~ a }}"), None);
+        assert_eq!(unescape_template(r#"{{ "a" }}"#), None);
+    }
+
+    #[test]
+    fn test_gguf_templates() {
+        fn string(out: &mut Vec<u8>, value: &str) {
+            out.extend((value.len() as u64).to_le_bytes());
+            out.extend(value.as_bytes());
+        }
+        let mut gguf = b"GGUF".to_vec();
+        gguf.extend(3u32.to_le_bytes());
+        gguf.extend(0u64.to_le_bytes());
+        gguf.extend(4u64.to_le_bytes());
+        string(&mut gguf, "general.architecture");
+        gguf.extend(GGUF_STRING.to_le_bytes());
+        string(&mut gguf, "llama");
+        string(&mut gguf, "tokenizer.ggml.tokens");
+        gguf.extend(GGUF_ARRAY.to_le_bytes());
+        gguf.extend(GGUF_STRING.to_le_bytes());
+        gguf.extend(2u64.to_le_bytes());
+        string(&mut gguf, "<s>");
+        string(&mut gguf, "</s>");
+        string(&mut gguf, "tokenizer.chat_template");
+        gguf.extend(GGUF_STRING.to_le_bytes());
+        string(&mut gguf, "{{ messages }}");
+        string(&mut gguf, "tokenizer.chat_template.tool_use");
+        gguf.extend(GGUF_STRING.to_le_bytes());
+        string(&mut gguf, "{{ tools }}");
+
+        let templates = gguf_templates(&mut gguf.as_slice()).unwrap();
+        assert_eq!(
+            templates,
+            vec![
+                ChatTemplate {
+                    name: "default".to_string(),
+                    source: "{{ messages }}".to_string(),
+                },
+                ChatTemplate {
+                    name: "tool_use".to_string(),
+                    source: "{{ tools }}".to_string(),
+                },
+            ]
+        );
+        assert!(gguf_templates(&mut b"{{ x }}".as_slice()).is_err());
+
+        // An array whose byte size overflows is rejected, not wrapped around
+        let mut overflow = b"GGUF".to_vec();
+        overflow.extend(3u32.to_le_bytes());
+        overflow.extend(0u64.to_le_bytes());
+        overflow.extend(1u64.to_le_bytes());
+        string(&mut overflow, "tokenizer.ggml.scores");
+        overflow.extend(GGUF_ARRAY.to_le_bytes());
+        overflow.extend(12u32.to_le_bytes());
+        overflow.extend(u64::MAX.to_le_bytes());
+        let err = gguf_templates(&mut overflow.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
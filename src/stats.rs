@@ -0,0 +1,180 @@
+use crate::{analyze_with, AnalyzeOptions};
+use minijinja::machinery;
+use minijinja::machinery::ast::{CallArg, Expr, Stmt};
+use serde::Serialize;
+
+/// Size and complexity metrics of a template
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct TemplateStats {
+    /// `for` loops
+    pub loops: usize,
+    /// `if` and `elif` conditions, including inline `x if y else z`
+    pub branches: usize,
+    /// Deepest nesting of loops, conditions, macros and other blocks
+    pub max_depth: usize,
+    /// Distinct schema paths in the inferred context
+    pub variable_paths: usize,
+    /// Macro definitions
+    pub macros: usize,
+    /// `1 + branches + 2 * loops + 2 * max_depth + macros + variable_paths / 5`:
+    /// every decision point counts, loops and nesting count double
+    pub complexity: usize,
+}
+
+/// Computes the metrics of a template source string
+pub fn template_stats(
+    template_content: &str,
+    options: &AnalyzeOptions,
+) -> Result<TemplateStats, Box<dyn std::error::Error>> {
+    let ast = machinery::parse(
+        template_content,
+        "<string>",
+        Default::default(),
+        options.whitespace_config(),
+    )?;
+    let analysis = analyze_with(template_content, options)?;
+
+    let mut stats = TemplateStats {
+        variable_paths: analysis.shape.fields().len(),
+        ..Default::default()
+    };
+    count_stmt(&ast, 0, &mut stats);
+    stats.complexity = 1
+        + stats.branches
+        + 2 * stats.loops
+        + 2 * stats.max_depth
+        + stats.macros
+        + stats.variable_paths / 5;
+    Ok(stats)
+}
+
+fn count_stmts(stmts: &[Stmt], depth: usize, stats: &mut TemplateStats) {
+    for stmt in stmts {
+        count_stmt(stmt, depth, stats);
+    }
+}
+
+fn count_stmt(stmt: &Stmt, depth: usize, stats: &mut TemplateStats) {
+    // Blocks below this statement are one level deeper
+    let nested = |stats: &mut TemplateStats| {
+        stats.max_depth = stats.max_depth.max(depth + 1);
+        depth + 1
+    };
+    match stmt {
+        Stmt::Template(template) => count_stmts(&template.children, depth, stats),
+        Stmt::EmitExpr(emit) => count_expr(&emit.expr, stats),
+        Stmt::ForLoop(for_loop) => {
+            stats.loops += 1;
+            count_expr(&for_loop.iter, stats);
+            let inner = nested(stats);
+            count_stmts(&for_loop.body, inner, stats);
+            count_stmts(&for_loop.else_body, inner, stats);
+        }
+        Stmt::IfCond(if_cond) => {
+            stats.branches += 1;
+            count_expr(&if_cond.expr, stats);
+            let inner = nested(stats);
+            count_stmts(&if_cond.true_body, inner, stats);
+            // An `elif` is an `if` alone in the `else` body, at the same depth
+            match if_cond.false_body.as_slice() {
+                [elif @ Stmt::IfCond(_)] => count_stmt(elif, depth, stats),
+                false_body => count_stmts(false_body, inner, stats),
+            }
+        }
+        Stmt::WithBlock(with_block) => {
+            let inner = nested(stats);
+            count_stmts(&with_block.body, inner, stats);
+        }
+        Stmt::Set(set) => count_expr(&set.expr, stats),
+        Stmt::SetBlock(set_block) => {
+            let inner = nested(stats);
+            count_stmts(&set_block.body, inner, stats);
+        }
+        Stmt::AutoEscape(auto_escape) => count_stmts(&auto_escape.body, depth, stats),
+        Stmt::FilterBlock(filter_block) => {
+            let inner = nested(stats);
+            count_stmts(&filter_block.body, inner, stats);
+        }
+        Stmt::Block(block) => count_stmts(&block.body, depth, stats),
+        Stmt::Macro(macro_decl) => {
+            stats.macros += 1;
+            let inner = nested(stats);
+            count_stmts(&macro_decl.body, inner, stats);
+        }
+        Stmt::CallBlock(call_block) => {
+            let inner = nested(stats);
+            count_stmts(&call_block.macro_decl.body, inner, stats);
+        }
+        Stmt::Do(do_stmt) => count_args(&do_stmt.call.args, stats),
+        Stmt::EmitRaw(_)
+        | Stmt::Import(_)
+        | Stmt::FromImport(_)
+        | Stmt::Extends(_)
+        | Stmt::Include(_) => {}
+    }
+}
+
+// Inline conditions count as branches
+fn count_expr(expr: &Expr, stats: &mut TemplateStats) {
+    match expr {
+        Expr::IfExpr(if_expr) => {
+            stats.branches += 1;
+            count_expr(&if_expr.test_expr, stats);
+            count_expr(&if_expr.true_expr, stats);
+            if let Some(false_expr) = &if_expr.false_expr {
+                count_expr(false_expr, stats);
+            }
+        }
+        Expr::UnaryOp(unary_op) => count_expr(&unary_op.expr, stats),
+        Expr::BinOp(bin_op) => {
+            count_expr(&bin_op.left, stats);
+            count_expr(&bin_op.right, stats);
+        }
+        Expr::Filter(filter) => {
+            if let Some(expr) = &filter.expr {
+                count_expr(expr, stats);
+            }
+            count_args(&filter.args, stats);
+        }
+        Expr::Call(call) => {
+            count_expr(&call.expr, stats);
+            count_args(&call.args, stats);
+        }
+        Expr::List(list) => list.items.iter().for_each(|item| count_expr(item, stats)),
+        Expr::Map(map) => map.values.iter().for_each(|value| count_expr(value, stats)),
+        _ => {}
+    }
+}
+
+fn count_args(args: &[CallArg], stats: &mut TemplateStats) {
+    for arg in args {
+        match arg {
+            CallArg::Pos(expr)
+            | CallArg::Kwarg(_, expr)
+            | CallArg::PosSplat(expr)
+            | CallArg::KwargSplat(expr) => count_expr(expr, stats),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_stats() {
+        let template = "{% macro sep() %}---{% endmacro %}{% for message in messages %}{% if message.role == 'user' %}{{ sep() }}{% elif message.role == 'tool' %}{% for call in message.tool_calls %}{{ call.name }}{% endfor %}{% else %}{{ message.content if message.content else '' }}{% endif %}{% endfor %}";
+        let stats = template_stats(template, &AnalyzeOptions::default()).unwrap();
+        assert_eq!(
+            stats,
+            TemplateStats {
+                loops: 2,
+                branches: 3,
+                max_depth: 3,
+                variable_paths: 7,
+                macros: 1,
+                complexity: 1 + 3 + 4 + 6 + 1 + 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use crate::graph::parent_of;
+use crate::shape::Shape;
+use crate::TemplateAnalysis;
+use std::collections::{BTreeMap, HashSet};
+
+const RESET: &str = "\x1b[0m";
+const EXTERNAL: &str = "\x1b[1;34m";
+const LOOP: &str = "\x1b[33m";
+const INTERNAL: &str = "\x1b[2m";
+const OPTIONAL: &str = "\x1b[35m";
+const TYPE: &str = "\x1b[2m";
+
+// Renders the shape as a tree: context variables are the roots, array items
+// iterated by a `for` loop name their loop variable, optional fields end in
+// `?`, and template-local variables are listed after the tree. With `color`,
+// external variables are blue, loop-derived fields yellow and locals dim
+pub(crate) fn render_tree(analysis: &TemplateAnalysis, color: bool) -> String {
+    // Item paths iterated by each loop variable (`messages[]` -> `message`)
+    let mut iterated = BTreeMap::<String, Vec<&str>>::new();
+    for (var, iterable) in &analysis.loop_vars {
+        let path = format!("{}[]", resolve(analysis, iterable));
+        iterated.entry(path).or_default().push(var);
+    }
+    for vars in iterated.values_mut() {
+        vars.sort();
+    }
+
+    let mut children = BTreeMap::<String, Vec<(String, String, &Shape)>>::new();
+    for (path, shape) in analysis.shape.fields() {
+        let (parent, key) = parent_of(&path).unwrap_or_else(|| (String::new(), path.clone()));
+        children.entry(parent).or_default().push((path, key, shape));
+    }
+
+    let tree = Tree {
+        analysis,
+        color,
+        iterated,
+        children,
+    };
+    let mut out = String::new();
+    tree.render("", "", false, &mut out);
+
+    // Template-local variables, with what they are bound to
+    if !analysis.internal_vars.is_empty() {
+        out.push_str(&format!("\n{}\n", tree.paint(INTERNAL, "locals:")));
+    }
+    for var in &analysis.internal_vars {
+        let line = match (analysis.aliases.get(var), analysis.loop_vars.get(var)) {
+            (Some(source), _) => format!("{var} = {source}"),
+            (_, Some(iterable)) => format!("{var} in {iterable}"),
+            _ => var.clone(),
+        };
+        out.push_str(&format!("  {}\n", tree.paint(INTERNAL, &line)));
+    }
+    out
+}
+
+struct Tree<'a> {
+    analysis: &'a TemplateAnalysis,
+    color: bool,
+    iterated: BTreeMap<String, Vec<&'a str>>,
+    // Parent schema path -> (path, key, shape) of each child, in key order
+    children: BTreeMap<String, Vec<(String, String, &'a Shape)>>,
+}
+
+impl Tree<'_> {
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{style}{text}{RESET}")
+        } else {
+            text.to_string()
+        }
+    }
+
+    // Renders the children of `parent`; `derived` is set below loop-iterated items
+    fn render(&self, parent: &str, prefix: &str, derived: bool, out: &mut String) {
+        let Some(nodes) = self.children.get(parent) else {
+            return;
+        };
+        for (i, (path, key, shape)) in nodes.iter().enumerate() {
+            let loop_vars = self.iterated.get(path);
+            let derived = derived || loop_vars.is_some();
+            let style = if parent.is_empty() {
+                EXTERNAL
+            } else if derived {
+                LOOP
+            } else {
+                ""
+            };
+            let (branch, indent) = match (parent.is_empty(), i + 1 == nodes.len()) {
+                (true, _) => ("", ""),
+                (false, false) => ("├── ", "│   "),
+                (false, true) => ("└── ", "    "),
+            };
+
+            out.push_str(prefix);
+            out.push_str(branch);
+            out.push_str(&self.paint(style, key));
+            if self.analysis.optional_paths.contains(path) {
+                out.push_str(&self.paint(OPTIONAL, "?"));
+            }
+            out.push_str(&self.paint(TYPE, &format!(": {}", shape.type_name())));
+            if let Some(vars) = loop_vars {
+                out.push_str(&self.paint(LOOP, &format!(" <- {}", vars.join(", "))));
+            }
+            out.push('\n');
+            self.render(path, &format!("{prefix}{indent}"), derived, out);
+        }
+    }
+}
+
+// Schema path behind a tracker path, following aliases and loop variables
+// (`loop_messages` -> `messages`, `message.content` -> `messages[].content`)
+fn resolve(analysis: &TemplateAnalysis, path: &str) -> String {
+    let mut current = path.to_string();
+    let mut visited = HashSet::new();
+    loop {
+        let idx = current.find(['.', '[', '{']).unwrap_or(current.len());
+        let (base, rest) = current.split_at(idx);
+        if !visited.insert(base.to_string()) {
+            return current;
+        }
+        current = match (analysis.aliases.get(base), analysis.loop_vars.get(base)) {
+            (Some(source), _) => format!("{source}{rest}"),
+            (_, Some(iterable)) => format!("{iterable}[]{rest}"),
+            _ => return current,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze;
+
+    #[test]
+    fn test_tree() {
+        let template = "{% set loop_messages = messages %}{% for message in loop_messages %}{{ message.role ~ '' }}{% for part in message.content %}{{ part.text ~ '' }}{% endfor %}{% endfor %}{% if tools is defined %}{{ tools | length }}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+
+        assert_eq!(
+            analysis.to_tree(false),
+            "messages: array
+└── []: object <- message
+    ├── content: array
+    │   └── []: object <- part
+    │       └── text: string
+    └── role: string
+tools?: array
+└── []: unknown
+
+locals:
+  loop_messages = messages
+  message in loop_messages
+  part in message.content
+"
+        );
+        assert!(analysis.to_tree(true).contains("\x1b[1;34mmessages\x1b[0m"));
+    }
+}
@@ -0,0 +1,292 @@
+use crate::shape::{child_path, Shape};
+use crate::TemplateAnalysis;
+use serde_json::Value;
+use std::fmt;
+
+/// Problem found when checking a concrete render context against an analysis
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextViolation {
+    /// Location in the context (e.g. `messages[1].role`)
+    pub path: String,
+    pub kind: ViolationKind,
+}
+
+/// What is wrong at a `ContextViolation` path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// A required variable or attribute is absent
+    Missing,
+    /// The value is not the container (or `null`) the template expects
+    WrongType { expected: String, found: String },
+}
+
+impl fmt::Display for ContextViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "context"
+        } else {
+            &self.path
+        };
+        match &self.kind {
+            ViolationKind::Missing => write!(f, "{path}: missing"),
+            ViolationKind::WrongType { expected, found } => {
+                write!(f, "{path}: expected {expected}, found {found}")
+            }
+        }
+    }
+}
+
+// Checks `ctx` against the shape of `analysis`; scalar leaves are not
+// type-checked since templates render them whatever their type
+pub(crate) fn validate_context(analysis: &TemplateAnalysis, ctx: &Value) -> Vec<ContextViolation> {
+    let mut violations = Vec::new();
+    check(analysis, &analysis.shape, "", "", ctx, &mut violations);
+    violations
+}
+
+fn check(
+    analysis: &TemplateAnalysis,
+    shape: &Shape,
+    schema_path: &str,
+    path: &str,
+    value: &Value,
+    violations: &mut Vec<ContextViolation>,
+) {
+    let mut wrong_type = || {
+        violations.push(ContextViolation {
+            path: path.to_string(),
+            kind: ViolationKind::WrongType {
+                expected: shape.type_name(),
+                found: json_type(value).to_string(),
+            },
+        })
+    };
+
+    match (shape, value) {
+        (Shape::Object(properties), Value::Object(obj)) => {
+            for (key, property) in properties {
+                let property_path = child_path(schema_path, key);
+                match obj.get(key) {
+                    Some(child) => check(
+                        analysis,
+                        property,
+                        &property_path,
+                        &child_path(path, key),
+                        child,
+                        violations,
+                    ),
+                    None if analysis.optional_paths.contains(&property_path) => {}
+                    None => violations.push(ContextViolation {
+                        path: child_path(path, key),
+                        kind: ViolationKind::Missing,
+                    }),
+                }
+            }
+        }
+        (Shape::Array(item), Value::Array(items)) => {
+            let item_path = format!("{schema_path}[]");
+            for (idx, child) in items.iter().enumerate() {
+                let path = format!("{path}[{idx}]");
+                check(analysis, item, &item_path, &path, child, violations);
+            }
+        }
+        (Shape::Map(entry), Value::Object(obj)) => {
+            let entry_path = format!("{schema_path}{{}}");
+            for (key, child) in obj {
+                let path = child_path(path, key);
+                check(analysis, entry, &entry_path, &path, child, violations);
+            }
+        }
+        (Shape::Ref(name), _) => {
+            if let Some(definition) = analysis.definitions.get(name) {
+                check(
+                    analysis,
+                    &definition.shape,
+                    &definition.path,
+                    path,
+                    value,
+                    violations,
+                );
+            }
+        }
+        (Shape::Union(variants), _) => {
+            // Check against the variant of the same JSON kind, if there is one
+            match variants.iter().find(|variant| accepts(variant, value)) {
+                Some(variant) => check(analysis, variant, schema_path, path, value, violations),
+                None if value.is_null()
+                    || value.is_object()
+                    || value.is_array()
+                    || variants.iter().any(Shape::is_container) =>
+                {
+                    wrong_type()
+                }
+                None => {}
+            }
+        }
+        (Shape::Object(_) | Shape::Array(_) | Shape::Map(_), _) => wrong_type(),
+        _ => {}
+    }
+}
+
+/// Context paths (`messages[0].name`) of object keys the template never reads
+pub(crate) fn unused_keys(analysis: &TemplateAnalysis, ctx: &Value) -> Vec<String> {
+    let mut unused = Vec::new();
+    collect_unused(analysis, &analysis.shape, ctx, "", &mut unused);
+    unused
+}
+
+fn collect_unused(
+    analysis: &TemplateAnalysis,
+    shape: &Shape,
+    value: &Value,
+    path: &str,
+    unused: &mut Vec<String>,
+) {
+    match (shape, value) {
+        (Shape::Object(properties), Value::Object(obj)) => {
+            for (key, child) in obj {
+                match properties.get(key) {
+                    Some(property) => {
+                        collect_unused(analysis, property, child, &child_path(path, key), unused)
+                    }
+                    None => unused.push(child_path(path, key)),
+                }
+            }
+        }
+        (Shape::Map(entry), Value::Object(obj)) => {
+            for (key, child) in obj {
+                collect_unused(analysis, entry, child, &child_path(path, key), unused);
+            }
+        }
+        (Shape::Array(item), Value::Array(items)) => {
+            for (idx, child) in items.iter().enumerate() {
+                collect_unused(analysis, item, child, &format!("{path}[{idx}]"), unused);
+            }
+        }
+        (Shape::Ref(name), _) => {
+            if let Some(definition) = analysis.definitions.get(name) {
+                collect_unused(analysis, &definition.shape, value, path, unused);
+            }
+        }
+        // Follow the container variant matching the value
+        (Shape::Union(variants), Value::Object(_) | Value::Array(_)) => {
+            let variant = variants.iter().find(|variant| match variant {
+                Shape::Object(_) | Shape::Map(_) => value.is_object(),
+                Shape::Array(_) => value.is_array(),
+                _ => false,
+            });
+            if let Some(variant) = variant {
+                collect_unused(analysis, variant, value, path, unused);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Whether a value has the JSON kind a shape describes
+fn accepts(shape: &Shape, value: &Value) -> bool {
+    match shape {
+        Shape::Object(_) | Shape::Map(_) => value.is_object(),
+        Shape::Array(_) => value.is_array(),
+        Shape::String => value.is_string(),
+        Shape::Number | Shape::Integer => value.is_number(),
+        Shape::Bool => value.is_boolean(),
+        Shape::Null => value.is_null(),
+        Shape::Union(variants) => variants.iter().any(|variant| accepts(variant, value)),
+        Shape::Unknown | Shape::Any | Shape::Ref(_) => true,
+    }
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_context() {
+        let template = "{% for message in messages %}{{ message.role }}{% if message.content is not none %}{{ message.content }}{% endif %}{% endfor %}{% if add_generation_prompt is defined %}x{% endif %}{{ bos_token }}";
+        let analysis = analyze(template, false).unwrap();
+
+        let valid = json!({
+            "messages": [{"role": "user", "content": null}],
+            "bos_token": "<s>"
+        });
+        assert_eq!(analysis.validate_context(&valid), vec![]);
+
+        let invalid = json!({
+            "messages": [{"role": "user", "content": "hi"}, {"content": "no role"}, "text"],
+        });
+        let violations = analysis
+            .validate_context(&invalid)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            violations,
+            vec![
+                "bos_token: missing",
+                "messages[1].role: missing",
+                "messages[2]: expected object, found string",
+            ]
+        );
+
+        let violations = analysis.validate_context(&json!({"messages": {}, "bos_token": ""}));
+        assert_eq!(
+            violations,
+            vec![ContextViolation {
+                path: "messages".to_string(),
+                kind: ViolationKind::WrongType {
+                    expected: "array".to_string(),
+                    found: "object".to_string(),
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_globals_not_required() {
+        let template = "{% if messages | length == 0 %}{{ raise_exception('no messages') }}{% endif %}{% for message in messages %}{{ loop.index }}{{ message.content }}{% endfor %}{% for i in range(3) %}{{ strftime_now('%d') }}{% endfor %}";
+        let analysis = analyze(template, false).unwrap();
+        let context = json!({"messages": [{"content": "hi"}]});
+        assert_eq!(analysis.validate_context(&context), vec![]);
+        assert_eq!(
+            analysis.validate_context(&json!({})),
+            vec![ContextViolation {
+                path: "messages".to_string(),
+                kind: ViolationKind::Missing,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unused_keys() {
+        let template = "{% for message in messages %}{{ message.role }}{% endfor %}{{ bos_token }}{% if tools %}{% for name, tool in tools.items() %}{{ name }}{% endfor %}{% endif %}";
+        let analysis = analyze(template, false).unwrap();
+        let context = json!({
+            "messages": [{"role": "user", "content": "hi"}, {"role": "assistant", "name": "bot"}],
+            "bos_token": "<s>",
+            "eos_token": "</s>",
+            "tools": {"search": {"description": "unread values are not keys"}}
+        });
+        assert_eq!(
+            analysis.unused_keys(&context),
+            vec!["eos_token", "messages[0].content", "messages[1].name"]
+        );
+        assert_eq!(
+            analysis.unused_keys(&json!({"messages": []})),
+            Vec::<String>::new()
+        );
+    }
+}
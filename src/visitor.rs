@@ -0,0 +1,152 @@
+use crate::SourceSpan;
+use std::collections::BTreeSet;
+
+/// Callbacks for custom analysis passes, invoked by `analyze_with_visitor` in
+/// template order while it walks the template; every callback defaults to
+/// doing nothing
+///
+/// ```
+/// use cleanplate::visitor::{AnalysisVisitor, VariableAccess};
+///
+/// // Collects every schema path the template reads
+/// #[derive(Default)]
+/// struct Reads(Vec<String>);
+///
+/// impl AnalysisVisitor for Reads {
+///     fn visit_access(&mut self, access: &VariableAccess) {
+///         if let Some(path) = &access.schema_path {
+///             self.0.push(path.clone());
+///         }
+///     }
+/// }
+///
+/// let mut reads = Reads::default();
+/// let template = "{% for m in messages %}{{ m.content }}{% endfor %}";
+/// cleanplate::analyze_with_visitor(template, &Default::default(), &mut reads).unwrap();
+/// assert!(reads.0.contains(&"messages[].content".to_string()));
+/// ```
+pub trait AnalysisVisitor {
+    /// A variable or attribute path is read or bound
+    fn visit_access(&mut self, _access: &VariableAccess) {}
+    /// A `for` loop starts, before its body is walked
+    fn enter_loop(&mut self, _entry: &LoopEntry) {}
+    /// A `set` statement or block assigns a template-local name
+    fn visit_set(&mut self, _set: &SetStatement) {}
+    /// An `if` condition is evaluated, before its branches are walked
+    fn visit_condition(&mut self, _condition: &Condition) {}
+}
+
+/// How a variable access binds or reads its name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessKind {
+    /// The value comes from the render context (or a local it shadows)
+    Read,
+    /// Template-local assignment
+    Set,
+    /// Template-local name forwarding to another variable (`set x = y`)
+    Alias(String),
+    /// Loop variable, with the expression it iterates
+    LoopVar(String),
+}
+
+/// Access to a variable or attribute path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VariableAccess {
+    /// The path as the template writes it (`message.role`)
+    pub name: String,
+    /// The path in the context schema (`messages[].role`), when it has one
+    pub schema_path: Option<String>,
+    pub kind: AccessKind,
+}
+
+/// Start of a `for` loop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopEntry {
+    /// Loop variables (`key`, `value` for `for key, value in data.items()`)
+    pub targets: Vec<String>,
+    /// Source text of the iterated expression
+    pub iterable: String,
+    /// Schema path of the items (`messages[]`), when it has one
+    pub item_path: Option<String>,
+    pub recursive: bool,
+    pub span: SourceSpan,
+}
+
+/// `set` statement or block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetStatement {
+    /// Assigned name
+    pub target: String,
+    /// Source text of the assigned expression; `None` for a `set` block
+    pub value: Option<String>,
+    pub span: SourceSpan,
+}
+
+/// Condition of an `if` statement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    /// Source text of the condition
+    pub expression: String,
+    /// Schema paths the condition reads
+    pub paths: BTreeSet<String>,
+    pub span: SourceSpan,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze_with_visitor;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl AnalysisVisitor for Recorder {
+        fn visit_access(&mut self, access: &VariableAccess) {
+            if let (AccessKind::Read, Some(path)) = (&access.kind, &access.schema_path) {
+                self.events.push(format!("read {path}"));
+            }
+        }
+
+        fn enter_loop(&mut self, entry: &LoopEntry) {
+            let item_path = entry.item_path.as_deref().unwrap_or_default();
+            self.events.push(format!(
+                "loop {} over {item_path}",
+                entry.targets.join(", ")
+            ));
+        }
+
+        fn visit_set(&mut self, set: &SetStatement) {
+            let value = set.value.as_deref().unwrap_or_default();
+            self.events.push(format!("set {} = {value}", set.target));
+        }
+
+        fn visit_condition(&mut self, condition: &Condition) {
+            let paths = Vec::from_iter(condition.paths.iter().cloned()).join(", ");
+            self.events
+                .push(format!("if {} [{paths}]", condition.expression));
+        }
+    }
+
+    #[test]
+    fn test_visitor_callbacks() {
+        let template = "{% set greeting = 'hi' %}{% for m in messages %}{% if m.role == 'user' %}{{ m.content }}{% endif %}{% endfor %}";
+        let mut recorder = Recorder::default();
+        let analysis = analyze_with_visitor(template, &Default::default(), &mut recorder).unwrap();
+        assert_eq!(analysis, crate::analyze(template, false).unwrap());
+        assert_eq!(
+            recorder.events,
+            vec![
+                "set greeting = 'hi'",
+                "read messages",
+                "loop m over messages[]",
+                "read messages[].role",
+                "read messages[]",
+                "if m.role == 'user' [messages[].role]",
+                "read messages[].content",
+                "read messages[]",
+            ]
+        );
+    }
+}